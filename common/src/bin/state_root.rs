@@ -0,0 +1,115 @@
+//! Print the state root of the node's last sealed block together with every user's leaf
+//! hash, so the live exchange root can be diffed against what the prover computes.
+//!
+//! Usage: `cargo run -p common --bin state_root -- [node_db_path]`, with `STATE_HASHER`
+//! set as for the exchange.
+
+use common::db::{BLOCKS_TREE, NODE_DB_PATH, open_db};
+use common::hasher::HashScheme;
+use common::state::{State, read_sealed_balances};
+use std::fmt::Write;
+
+fn main() {
+    let db_path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| NODE_DB_PATH.to_string());
+
+    // Hashed like the exchange, which reads the same STATE_HASHER setting
+    let state = match load_sealed_state(&db_path, HashScheme::from_env()) {
+        Ok(Some(state)) => state,
+        Ok(None) => {
+            eprintln!("No sealed block in {}", db_path);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    print!("{}", render_report(&db_path, &state));
+}
+
+// The balances the node sealed last, read where its block builder saves them
+fn load_sealed_state(db_path: &str, hash_scheme: HashScheme) -> anyhow::Result<Option<State>> {
+    let db = open_db(db_path)?;
+    let Some(balances) = read_sealed_balances(&db.open_tree(BLOCKS_TREE)?)? else {
+        return Ok(None);
+    };
+    let mut state = State::with_hash_scheme(hash_scheme);
+    state.user_balances = balances;
+    Ok(Some(state))
+}
+
+fn render_report(db_path: &str, state: &State) -> String {
+    let mut report = String::new();
+
+    writeln!(report, "db_path: {}", db_path).unwrap();
+    writeln!(report, "users: {}", state.user_balances.len()).unwrap();
+    match state.calculate_state_root() {
+        Some(root) => writeln!(report, "state_root: 0x{}", to_hex(&root)).unwrap(),
+        None => writeln!(report, "state_root: none (empty state)").unwrap(),
+    }
+    for (user_id, leaf_hash) in state.leaf_hashes() {
+        writeln!(report, "leaf {}: 0x{}", user_id, to_hex(&leaf_hash)).unwrap();
+    }
+    report
+}
+
+fn to_hex(bytes: &[u8; 32]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::db::SEALED_BALANCES_KEY;
+
+    #[test]
+    fn test_report_matches_sealed_state_root() {
+        let db_path = std::env::temp_dir().join(format!("state_root_tool_{}", std::process::id()));
+        let db_path = db_path.to_str().unwrap();
+
+        let mut state = State::with_hash_scheme(HashScheme::Keccak);
+        state.set_user_balance("alice".to_string(), "BTC".to_string(), 5);
+        state.set_user_balance("bob".to_string(), "USDT".to_string(), 700);
+        let expected_root = state.calculate_state_root().unwrap();
+        {
+            // Where and how the block builder saves a sealed block's balances
+            let db = open_db(db_path).unwrap();
+            db.open_tree(BLOCKS_TREE)
+                .unwrap()
+                .insert(
+                    SEALED_BALANCES_KEY,
+                    serde_json::to_vec(&state.user_balances).unwrap(),
+                )
+                .unwrap();
+            db.flush().unwrap();
+        }
+
+        let sealed = load_sealed_state(db_path, HashScheme::Keccak)
+            .unwrap()
+            .unwrap();
+        let report = render_report(db_path, &sealed);
+        assert!(report.contains("users: 2"));
+        assert!(report.contains(&format!("state_root: 0x{}", to_hex(&expected_root))));
+        assert!(report.contains("leaf alice: 0x"));
+        assert!(report.contains("leaf bob: 0x"));
+
+        let _ = std::fs::remove_dir_all(db_path);
+    }
+
+    #[test]
+    fn test_no_sealed_state_before_the_first_block() {
+        let db_path = std::env::temp_dir().join(format!("state_root_empty_{}", std::process::id()));
+        let db_path = db_path.to_str().unwrap();
+
+        assert!(
+            load_sealed_state(db_path, HashScheme::Keccak)
+                .unwrap()
+                .is_none()
+        );
+
+        let _ = std::fs::remove_dir_all(db_path);
+    }
+}
@@ -1,10 +1,230 @@
-use crate::traces::MatchedTrace;
+use crate::order::OrderStatus;
+use crate::traces::{EngineEvent, Funding, MatchedTrace, Transfer};
 use serde::{Deserialize, Serialize};
+use tiny_keccak::{Hasher, Sha3};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Block {
     pub block_num: u128,
     pub txns: Vec<MatchedTrace>,
+    // Transfers between users applied since the previous block
+    #[serde(default)]
+    pub transfers: Vec<Transfer>,
+    // Deposits and withdrawals made since the previous block
+    #[serde(default)]
+    pub funding: Vec<Funding>,
     pub txns_root: Option<[u8; 32]>,
     pub state_root: Option<[u8; 32]>,
+    // Where each order matched in the block ended up. Derived from `txns` when the block is
+    // sealed, so it isn't covered by `txns_root`
+    #[serde(default)]
+    pub order_updates: Vec<OrderUpdate>,
+    // The matching engines' events logged since the previous block, every fill among them
+    // with its trace in `txns`. Committed to by `events_root` rather than `txns_root`, which
+    // the prover recomputes
+    #[serde(default)]
+    pub events: Vec<EngineEvent>,
+    #[serde(default)]
+    pub events_root: Option<[u8; 32]>,
+}
+
+impl Block {
+    pub fn hash(&self) -> [u8; 32] {
+        block_hash(self.block_num, self.txns_root, self.state_root)
+    }
+}
+
+/// Root committing to a block's trades, then its transfers and its funding, each serialized
+/// as JSON. Computed by the exchange when it seals a block and again by the prover, which
+/// rejects a block whose `txns_root` differs. A block with nothing in it gets the Sha3-256 of
+/// no input.
+pub fn calculate_txns_root(
+    txns: &[MatchedTrace],
+    transfers: &[Transfer],
+    funding: &[Funding],
+) -> [u8; 32] {
+    let mut sha3 = Sha3::v256();
+    let mut output = [0u8; 32];
+
+    for txn in txns {
+        if let Ok(txn_data) = serde_json::to_vec(txn) {
+            sha3.update(&txn_data);
+        }
+    }
+    for transfer in transfers {
+        if let Ok(transfer_data) = serde_json::to_vec(transfer) {
+            sha3.update(&transfer_data);
+        }
+    }
+    for funding in funding {
+        if let Ok(funding_data) = serde_json::to_vec(funding) {
+            sha3.update(&funding_data);
+        }
+    }
+
+    sha3.finalize(&mut output);
+    output
+}
+
+/// Root committing to a block's engine events, in order, each serialized as JSON.
+pub fn calculate_events_root(events: &[EngineEvent]) -> [u8; 32] {
+    let mut sha3 = Sha3::v256();
+    let mut output = [0u8; 32];
+
+    for event in events {
+        if let Ok(event_data) = serde_json::to_vec(event) {
+            sha3.update(&event_data);
+        }
+    }
+
+    sha3.finalize(&mut output);
+    output
+}
+
+/// Check that a block's `txns_root` commits to exactly its trades, transfers and funding, and
+/// its `events_root` to its events, e.g. for a block fetched from the exchange's API. Its
+/// state root can only be checked by replaying the block on the state before it, as the
+/// prover does.
+pub fn verify_block(block: &Block) -> Result<(), String> {
+    let txns_root = calculate_txns_root(&block.txns, &block.transfers, &block.funding);
+    match block.txns_root {
+        Some(root) if root == txns_root => {}
+        Some(_) => {
+            return Err(format!(
+                "Block {} txns_root doesn't match its txns",
+                block.block_num
+            ));
+        }
+        None => return Err(format!("Block {} has no txns_root", block.block_num)),
+    }
+    // Blocks sealed before events were logged have neither
+    match block.events_root {
+        Some(root) if root != calculate_events_root(&block.events) => Err(format!(
+            "Block {} events_root doesn't match its events",
+            block.block_num
+        )),
+        None if !block.events.is_empty() => {
+            Err(format!("Block {} has no events_root", block.block_num))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Hash identifying a block to light clients, from its header alone: Sha3-256 over the
+/// big-endian block number, the txns root and the state root, an unset root hashing as zeros.
+pub fn block_hash(
+    block_num: u128,
+    txns_root: Option<[u8; 32]>,
+    state_root: Option<[u8; 32]>,
+) -> [u8; 32] {
+    let mut sha3 = Sha3::v256();
+    sha3.update(&block_num.to_be_bytes());
+    sha3.update(&txns_root.unwrap_or_default());
+    sha3.update(&state_root.unwrap_or_default());
+
+    let mut output = [0u8; 32];
+    sha3.finalize(&mut output);
+    output
+}
+
+// An order's status and cumulative filled amount at the end of a block
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OrderUpdate {
+    pub order_id: String,
+    pub status: OrderStatus,
+    pub filled_amount: u64,
+}
+
+/// Status and cumulative filled amount each order reached through `txns`, in order of first
+/// appearance. A trace carries its orders as they were before the match, so an order's last
+/// trace plus that trace's matched amount gives where the block left it.
+pub fn order_updates(txns: &[MatchedTrace]) -> Vec<OrderUpdate> {
+    let mut updates: Vec<OrderUpdate> = vec![];
+    for trace in txns {
+        for order in [&trace.buy_order, &trace.sell_order] {
+            let filled_amount = order.filled_amount + trace.matched_amount;
+            let status = if filled_amount >= order.amount {
+                OrderStatus::Filled
+            } else {
+                OrderStatus::PartiallyFilled
+            };
+            match updates
+                .iter_mut()
+                .find(|update| update.order_id == order.id)
+            {
+                Some(update) => {
+                    update.status = status;
+                    update.filled_amount = filled_amount;
+                }
+                None => updates.push(OrderUpdate {
+                    order_id: order.id.clone(),
+                    status,
+                    filled_amount,
+                }),
+            }
+        }
+    }
+    updates
+}
+
+// Prefix of the block db entries recording balances as of each sealed block
+pub static BALANCE_HISTORY_PREFIX: &str = "balance_";
+
+/// Block db key of `user_id`'s `token` balance recorded at `block_num`. Ids are length-prefixed
+/// and the block number is big-endian, so one balance's history is contiguous and ordered.
+pub fn balance_history_key(user_id: &str, token: &str, block_num: u128) -> Vec<u8> {
+    let mut key = BALANCE_HISTORY_PREFIX.as_bytes().to_vec();
+    for id in [user_id, token] {
+        key.extend_from_slice(&(id.len() as u64).to_be_bytes());
+        key.extend_from_slice(id.as_bytes());
+    }
+    key.extend_from_slice(&block_num.to_be_bytes());
+    key
+}
+
+/// Split a `balance_history_key` back into its user id, token and block number.
+pub fn parse_balance_history_key(key: &[u8]) -> Option<(String, String, u128)> {
+    let mut rest = key.strip_prefix(BALANCE_HISTORY_PREFIX.as_bytes())?;
+    let mut ids = vec![];
+    for _ in 0..2 {
+        let (len, tail) = rest.split_first_chunk::<8>()?;
+        let len = usize::try_from(u64::from_be_bytes(*len)).ok()?;
+        if tail.len() < len {
+            return None;
+        }
+        let (id, tail) = tail.split_at(len);
+        ids.push(String::from_utf8(id.to_vec()).ok()?);
+        rest = tail;
+    }
+    let block_num = u128::from_be_bytes(rest.try_into().ok()?);
+    let token = ids.pop()?;
+    let user_id = ids.pop()?;
+    Some((user_id, token, block_num))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_empty_block_txns_root() {
+        // Sha3-256 of no input, what both the exchange and the prover expect of an empty block
+        let root = calculate_txns_root(&[], &[], &[]);
+        let hex: String = root.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(
+            hex,
+            "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a"
+        );
+    }
+
+    #[test]
+    fn test_balance_history_key_round_trip() {
+        let key = balance_history_key("alice", "ETH", 42);
+        assert_eq!(
+            parse_balance_history_key(&key),
+            Some(("alice".to_string(), "ETH".to_string(), 42))
+        );
+        assert_eq!(parse_balance_history_key(&key[..key.len() - 1]), None);
+        assert_eq!(parse_balance_history_key(b"block_1"), None);
+    }
 }
@@ -0,0 +1,80 @@
+// Layout of the node's single sled database. Every subsystem keeps its data in its own
+// trees of it, so one flush or snapshot covers the exchange and the EVM together.
+
+// Where the node opens its database
+pub static NODE_DB_PATH: &str = "node_db";
+
+// Exchange balances
+pub static STATE_TREE: &str = "state";
+// Exchange blocks, their balance history and the pending traces log
+pub static BLOCKS_TREE: &str = "blocks";
+// Key in the blocks tree of the balances as of the last sealed block's root
+pub static SEALED_BALANCES_KEY: &str = "sealed_balances";
+// Deposits and withdrawals applied but not sealed yet, see the exchange's funds log
+pub static FUNDS_LOG_TREE: &str = "funds_log";
+// Executed trades, see the exchange's trade log
+pub static TRADES_TREE: &str = "trades";
+// The trade log's ids keyed by match time, to scan it by time
+pub static TRADE_TIMES_TREE: &str = "trade_times";
+// Pairs an operator halted, keyed by pair id
+pub static PAIR_HALTS_TREE: &str = "pair_halts";
+// Orders a pair's book settled or cancelled and no longer holds, keyed by order id, in one
+// tree per pair named by this prefix and the pair id
+pub static ORDER_ARCHIVE_TREE_PREFIX: &str = "order_archive/";
+// Resting orders of each pair's book as of the blocks where it changed
+pub static BOOK_HISTORY_TREE: &str = "book_history";
+// Compliance tiers and the users assigned to them
+pub static TIERS_TREE: &str = "tiers";
+// EVM blocks. EVM accounts, code and storage live in the `account_*`/`code_*`/`storage_*` trees
+pub static EVM_BLOCKS_TREE: &str = "evm_blocks";
+
+/// Reasons a sled database could not be opened.
+#[derive(Debug, thiserror::Error)]
+pub enum OpenDbError {
+    #[error("Database at {0} is already in use, another process or handle holds its lock")]
+    Locked(String),
+    #[error("Failed to open database at {path}: {source}")]
+    Sled { path: String, source: sled::Error },
+}
+
+/// Open the sled database at `path`, telling a db that is locked by another opener apart
+/// from other failures.
+pub fn open_db(path: &str) -> Result<sled::Db, OpenDbError> {
+    sled::open(path).map_err(|source| match &source {
+        // sled reports a failed file lock as a plain io error
+        sled::Error::Io(e) if e.to_string().contains("could not acquire lock") => {
+            OpenDbError::Locked(path.to_string())
+        }
+        _ => OpenDbError::Sled {
+            path: path.to_string(),
+            source,
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_second_open_reports_locked_db() {
+        let path = std::env::temp_dir().join(format!("open_db_lock_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let db = open_db(path).unwrap();
+        match open_db(path) {
+            Err(OpenDbError::Locked(locked)) => assert_eq!(locked, path),
+            other => panic!("expected a locked db error, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(
+            open_db(path).unwrap_err().to_string(),
+            format!(
+                "Database at {} is already in use, another process or handle holds its lock",
+                path
+            )
+        );
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hasher::HashScheme;
+use crate::state::State;
+
+// Environment variable naming the genesis config file
+pub const GENESIS_PATH_ENV: &str = "GENESIS_PATH";
+// Genesis config read when `GENESIS_PATH` is unset, relative to the working directory
+pub static DEFAULT_GENESIS_PATH: &str = "genesis.json";
+
+/// Balances the chain starts from, user -> token -> amount. Sealed as block 0 on a node's first
+/// start, so the first batch proven chains from a root everyone can recompute from the config.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Genesis {
+    #[serde(default)]
+    pub balances: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl Genesis {
+    /// Config at the path named by `GENESIS_PATH`, or `DEFAULT_GENESIS_PATH`. A missing
+    /// default file is an empty genesis; a file named by the variable has to exist.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(GENESIS_PATH_ENV) {
+            Ok(path) => Self::from_file(&path),
+            Err(_) if !std::path::Path::new(DEFAULT_GENESIS_PATH).exists() => Ok(Self::default()),
+            Err(_) => Self::from_file(DEFAULT_GENESIS_PATH),
+        }
+    }
+
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read genesis config {}: {}", path, e))?;
+        serde_json::from_slice(&data)
+            .map_err(|e| anyhow::anyhow!("Invalid genesis config {}: {}", path, e))
+    }
+
+    pub fn state(&self, hash_scheme: HashScheme) -> State {
+        let mut state = State::with_hash_scheme(hash_scheme);
+        for (user_id, balances) in &self.balances {
+            for (token, amount) in balances {
+                state.set_user_balance(user_id.clone(), token.clone(), *amount);
+            }
+        }
+        state
+    }
+
+    /// Root of the genesis state, the all-zero root when it holds no balances, like the root
+    /// the prover starts an empty state from.
+    pub fn state_root(&self, hash_scheme: HashScheme) -> [u8; 32] {
+        self.state(hash_scheme)
+            .calculate_state_root()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_genesis_root_is_pinned() {
+        let genesis: Genesis = serde_json::from_str(
+            r#"{"balances": {"alice": {"BTC": 100, "USDT": 5000}, "bob": {"USDT": 7}}}"#,
+        )
+        .unwrap();
+        let root = genesis.state_root(HashScheme::Keccak);
+        let hex: String = root.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(
+            hex,
+            "79d5259f01b666bac3073f1d357b2a24f9272e4b207a51224fb2a8c3d0d1d30d"
+        );
+
+        // Independent of the order balances are listed in
+        let reordered: Genesis = serde_json::from_str(
+            r#"{"balances": {"bob": {"USDT": 7}, "alice": {"USDT": 5000, "BTC": 100}}}"#,
+        )
+        .unwrap();
+        assert_eq!(reordered.state_root(HashScheme::Keccak), root);
+        assert_eq!(Genesis::default().state_root(HashScheme::Keccak), [0u8; 32]);
+    }
+}
@@ -0,0 +1,159 @@
+use ark_bn254::Fr;
+use ark_ff::{BigInteger, PrimeField};
+use light_poseidon::{Poseidon, PoseidonHasher as _};
+use serde::{Deserialize, Serialize};
+use tiny_keccak::{Hasher, Sha3};
+
+// Environment variable selecting the state tree hasher, read by the exchange and the prover host
+pub const STATE_HASHER_ENV: &str = "STATE_HASHER";
+
+// Domain tag of internal nodes. Leaf preimages start with their own tag, so no internal node
+// can pass for a leaf or the other way round
+const NODE_DOMAIN_TAG: &[u8] = b"clob-state-node-v1";
+
+/// Hash function of the state tree: leaves over a user's encoded balances, internal nodes over
+/// their two children.
+pub trait StateHasher {
+    fn hash_leaf(preimage: &[u8]) -> [u8; 32];
+    fn hash_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32];
+}
+
+// SHA3-256, the tree's original hash
+pub struct KeccakHasher;
+
+impl StateHasher for KeccakHasher {
+    fn hash_leaf(preimage: &[u8]) -> [u8; 32] {
+        let mut sha3 = Sha3::v256();
+        let mut output = [0u8; 32];
+        sha3.update(preimage);
+        sha3.finalize(&mut output);
+        output
+    }
+
+    fn hash_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        let mut sha3 = Sha3::v256();
+        let mut output = [0u8; 32];
+        sha3.update(NODE_DOMAIN_TAG);
+        sha3.update(left);
+        sha3.update(right);
+        sha3.finalize(&mut output);
+        output
+    }
+}
+
+// Poseidon over BN254 with the circom parameters, far cheaper to prove than keccak.
+// Hashes are field elements encoded as 32 big-endian bytes.
+pub struct PoseidonHasher;
+
+// Bytes per field element when absorbing a leaf, small enough to stay below the modulus
+const POSEIDON_CHUNK_BYTES: usize = 31;
+
+impl PoseidonHasher {
+    fn hash2(left: Fr, right: Fr) -> [u8; 32] {
+        let mut poseidon = Poseidon::<Fr>::new_circom(2).unwrap();
+        let hash = poseidon.hash(&[left, right]).unwrap();
+        hash.into_bigint().to_bytes_be().try_into().unwrap()
+    }
+}
+
+impl StateHasher for PoseidonHasher {
+    fn hash_leaf(preimage: &[u8]) -> [u8; 32] {
+        // Chain the chunks, starting from the length so a trailing short chunk is unambiguous
+        let mut acc = Fr::from(preimage.len() as u64);
+        for chunk in preimage.chunks(POSEIDON_CHUNK_BYTES) {
+            let hash = Self::hash2(acc, Fr::from_be_bytes_mod_order(chunk));
+            acc = Fr::from_be_bytes_mod_order(&hash);
+        }
+        acc.into_bigint().to_bytes_be().try_into().unwrap()
+    }
+
+    fn hash_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        // Three inputs, where leaves chain two, with the node tag first
+        let mut poseidon = Poseidon::<Fr>::new_circom(3).unwrap();
+        let hash = poseidon
+            .hash(&[
+                Fr::from_be_bytes_mod_order(NODE_DOMAIN_TAG),
+                Fr::from_be_bytes_mod_order(left),
+                Fr::from_be_bytes_mod_order(right),
+            ])
+            .unwrap();
+        hash.into_bigint().to_bytes_be().try_into().unwrap()
+    }
+}
+
+/// Which `StateHasher` a state is hashed with. It travels with the `State`, so the prover
+/// re-executes blocks under the same scheme the exchange sealed them with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashScheme {
+    #[default]
+    Keccak,
+    Poseidon,
+}
+
+impl HashScheme {
+    /// Scheme named by `STATE_HASHER` (`keccak` or `poseidon`), keccak when unset.
+    /// Panics on any other value rather than silently hashing with the wrong scheme.
+    pub fn from_env() -> Self {
+        match std::env::var(STATE_HASHER_ENV) {
+            Ok(name) => name
+                .parse()
+                .unwrap_or_else(|e| panic!("{}: {}", STATE_HASHER_ENV, e)),
+            Err(_) => HashScheme::default(),
+        }
+    }
+
+    pub fn hash_leaf(self, preimage: &[u8]) -> [u8; 32] {
+        match self {
+            HashScheme::Keccak => KeccakHasher::hash_leaf(preimage),
+            HashScheme::Poseidon => PoseidonHasher::hash_leaf(preimage),
+        }
+    }
+
+    pub fn hash_node(self, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        match self {
+            HashScheme::Keccak => KeccakHasher::hash_node(left, right),
+            HashScheme::Poseidon => PoseidonHasher::hash_node(left, right),
+        }
+    }
+}
+
+impl std::str::FromStr for HashScheme {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "keccak" => Ok(HashScheme::Keccak),
+            "poseidon" => Ok(HashScheme::Poseidon),
+            _ => Err(format!(
+                "unknown state hasher {:?}, expected keccak or poseidon",
+                name
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_poseidon_hashes_are_field_elements() {
+        // Node hashes must round-trip through the field, or a parent would hash a reduced child
+        let leaf = PoseidonHasher::hash_leaf(b"clob-state-leaf-v1");
+        let node = PoseidonHasher::hash_node(&leaf, &[0xff; 32]);
+        for hash in [leaf, node] {
+            let reduced: [u8; 32] = Fr::from_be_bytes_mod_order(&hash)
+                .into_bigint()
+                .to_bytes_be()
+                .try_into()
+                .unwrap();
+            assert_eq!(reduced, hash);
+        }
+        assert_ne!(
+            PoseidonHasher::hash_leaf(b"leaf"),
+            KeccakHasher::hash_leaf(b"leaf")
+        );
+        assert_eq!("Poseidon".parse(), Ok(HashScheme::Poseidon));
+        assert!("sha256".parse::<HashScheme>().is_err());
+    }
+}
@@ -1,4 +1,9 @@
 pub mod block;
+pub mod clock;
+pub mod db;
+pub mod genesis;
+pub mod hasher;
+pub mod math;
+pub mod order;
 pub mod state;
 pub mod traces;
-pub mod order;
\ No newline at end of file
@@ -0,0 +1,174 @@
+/// Arithmetic errors of order value computations.
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum MathError {
+    #[error("Arithmetic overflow: {amount} * {price} exceeds the balance range")]
+    Overflow { amount: u64, price: u64 },
+    #[error("Price scale of {0} decimals exceeds the maximum of {MAX_PRICE_DECIMALS}")]
+    PriceScale(u8),
+    #[error("Invalid price {price}: {reason}")]
+    InvalidPrice { price: String, reason: &'static str },
+}
+
+// Most decimals a pair's prices may have
+pub const MAX_PRICE_DECIMALS: u8 = 18;
+
+// Prices are fixed point: a price `p` with `price_decimals` decimals is worth
+// `p / 10^price_decimals` quote base units per base unit. A pair picks the decimals that
+// make its tick fine enough, e.g. a base token with more decimals than the quote token
+// trades far below one quote base unit per base unit.
+fn price_unit(price_decimals: u8) -> Result<u128, MathError> {
+    if price_decimals > MAX_PRICE_DECIMALS {
+        return Err(MathError::PriceScale(price_decimals));
+    }
+    Ok(10u128.pow(price_decimals as u32))
+}
+
+// Quote value of `amount` base units at `price`, in quote base units. Rounded down, which
+// is what a trade settles.
+pub fn notional(amount: u64, price: u64, price_decimals: u8) -> Result<u128, MathError> {
+    let value = (amount as u128)
+        .checked_mul(price as u128)
+        .ok_or(MathError::Overflow { amount, price })?;
+    Ok(value / price_unit(price_decimals)?)
+}
+
+// Notional as a token balance, failing when it doesn't fit the u64 balance range
+pub fn notional_balance(amount: u64, price: u64, price_decimals: u8) -> Result<u64, MathError> {
+    u64::try_from(notional(amount, price, price_decimals)?)
+        .map_err(|_| MathError::Overflow { amount, price })
+}
+
+// Notional rounded up, what a buy locks so that any of its fills is covered
+pub fn locked_notional(amount: u64, price: u64, price_decimals: u8) -> Result<u64, MathError> {
+    let unit = price_unit(price_decimals)?;
+    let value = (amount as u128 * price as u128).div_ceil(unit);
+    u64::try_from(value).map_err(|_| MathError::Overflow { amount, price })
+}
+
+/// Parse a decimal price such as "1234.5" into the integer `price * 10^shift`. A pair
+/// quoting whole tokens shifts by `quote_decimals - base_decimals + price_decimals`. Fails
+/// when the price has more precision than the shift keeps or doesn't fit a u64.
+pub fn scale_decimal(price: &str, shift: i32) -> Result<u64, MathError> {
+    let invalid = |reason| MathError::InvalidPrice {
+        price: price.to_string(),
+        reason,
+    };
+    let (whole, fraction) = price.split_once('.').unwrap_or((price, ""));
+    if whole.is_empty() && fraction.is_empty()
+        || !whole
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return Err(invalid("expected a decimal number"));
+    }
+    let digits = format!("{}{}", whole, fraction);
+    let digits = digits.trim_start_matches('0');
+    let mut value: u128 = if digits.is_empty() {
+        0
+    } else {
+        digits.parse().map_err(|_| invalid("out of range"))?
+    };
+
+    let exponent = shift - fraction.len() as i32;
+    for _ in 0..exponent.max(0) {
+        value = value.checked_mul(10).ok_or(invalid("out of range"))?;
+    }
+    for _ in 0..(-exponent).max(0) {
+        if !value.is_multiple_of(10) {
+            return Err(invalid("finer than the pair's price tick"));
+        }
+        value /= 10;
+    }
+    u64::try_from(value).map_err(|_| invalid("out of range"))
+}
+
+/// `value` base units of a token with `decimals` decimals, as a string of whole tokens that
+/// keeps every decimal: 123456789 with 8 decimals is "1.23456789".
+pub fn format_decimal(value: u64, decimals: u8) -> String {
+    if decimals == 0 {
+        return value.to_string();
+    }
+    let digits = format!("{:0>width$}", value, width = decimals as usize + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals as usize);
+    format!("{}.{}", whole, fraction)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_notional() {
+        assert_eq!(notional(0, u64::MAX, 0), Ok(0));
+        assert_eq!(notional(u64::MAX, 0, 0), Ok(0));
+        assert_eq!(notional(3, 7, 0), Ok(21));
+        assert_eq!(
+            notional(u64::MAX, u64::MAX, 0),
+            Ok(u64::MAX as u128 * u64::MAX as u128)
+        );
+
+        assert_eq!(notional_balance(0, u64::MAX, 0), Ok(0));
+        assert_eq!(notional_balance(u64::MAX, 1, 0), Ok(u64::MAX));
+        assert_eq!(
+            notional_balance(1 << 32, (1 << 32) - 1, 0),
+            Ok(u64::MAX - (1 << 32) + 1)
+        );
+        assert_eq!(
+            notional_balance(1 << 32, 1 << 32, 0),
+            Err(MathError::Overflow {
+                amount: 1 << 32,
+                price: 1 << 32
+            })
+        );
+        assert!(notional_balance(u64::MAX, u64::MAX, 0).is_err());
+    }
+
+    #[test]
+    fn test_scaled_notional() {
+        // 1.25 quote base units per base unit
+        assert_eq!(notional(10, 125, 2), Ok(12));
+        assert_eq!(notional_balance(10, 125, 2), Ok(12));
+        assert_eq!(locked_notional(10, 125, 2), Ok(13));
+        assert_eq!(locked_notional(4, 125, 2), Ok(5));
+        assert_eq!(notional(4, 125, 2), Ok(5));
+        // A product too large for u64 comes back in range once scaled
+        assert_eq!(notional_balance(u64::MAX, 100, 2), Ok(u64::MAX));
+        assert_eq!(
+            notional(1, 1, MAX_PRICE_DECIMALS + 1),
+            Err(MathError::PriceScale(MAX_PRICE_DECIMALS + 1))
+        );
+    }
+
+    #[test]
+    fn test_scale_decimal() {
+        assert_eq!(scale_decimal("123.45", 2), Ok(12345));
+        assert_eq!(scale_decimal("123.45", 4), Ok(1234500));
+        assert_eq!(
+            scale_decimal("0.5", 0).unwrap_err().to_string(),
+            "Invalid price 0.5: finer than the pair's price tick"
+        );
+        assert_eq!(scale_decimal("1200", -2), Ok(12));
+        assert!(scale_decimal("1250", -2).is_err());
+        assert_eq!(scale_decimal(".5", 1), Ok(5));
+        assert_eq!(scale_decimal("007", 0), Ok(7));
+        assert!(scale_decimal("", 0).is_err());
+        assert!(scale_decimal(".", 0).is_err());
+        assert!(scale_decimal("-1", 0).is_err());
+        assert!(scale_decimal("1e3", 0).is_err());
+        assert_eq!(scale_decimal("18446744073709551615", 0), Ok(u64::MAX));
+        assert!(scale_decimal("18446744073709551616", 0).is_err());
+        assert!(scale_decimal("1", 40).is_err());
+    }
+
+    #[test]
+    fn test_format_decimal() {
+        assert_eq!(format_decimal(123_456_789, 8), "1.23456789");
+        assert_eq!(format_decimal(5, 8), "0.00000005");
+        assert_eq!(format_decimal(100_000_000, 8), "1.00000000");
+        assert_eq!(format_decimal(0, 2), "0.00");
+        assert_eq!(format_decimal(42, 0), "42");
+        assert_eq!(format_decimal(u64::MAX, 19), "1.8446744073709551615");
+        assert_eq!(format_decimal(12_345_000, 6), "12.345000");
+    }
+}
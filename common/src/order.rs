@@ -1,5 +1,24 @@
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::clock::{Clock, SystemClock};
+use crate::math::locked_notional;
+
+/// Reasons an order is rejected before it reaches the order book.
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum OrderError {
+    #[error("Invalid pair id: {0}, expected BASE_QUOTE")]
+    InvalidPair(String),
+    #[error("Pair {0} has an empty token symbol")]
+    EmptyToken(String),
+    #[error("Pair {0} has an invalid token symbol {1}, expected 1 to 16 of A-Z and 0-9")]
+    InvalidSymbol(String, String),
+    #[error("Pair {0} trades a token against itself")]
+    SameToken(String),
+    #[error("Order amount must be greater than zero")]
+    ZeroAmount,
+    #[error("Order price must be greater than zero")]
+    ZeroPrice,
+}
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum OrderStatus {
@@ -7,6 +26,16 @@ pub enum OrderStatus {
     PartiallyFilled,
     Filled,
     Cancelled,
+    Settled, // filled, and its last fill is sealed into a block
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum OrderKind {
+    #[default]
+    Limit,
+    // Waits off the book until a trade prints at its trigger price, then rests or fills as a
+    // limit order at its price
+    StopLimit,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -23,6 +52,19 @@ pub struct Order {
     pub status: OrderStatus,
     pub created_at: u64,
     pub updated_at: u64,
+    // Arrival order in its book, breaks ties between orders created in the same second
+    #[serde(default)]
+    pub sequence: u64,
+    #[serde(default)]
+    pub kind: OrderKind,
+    // Trigger of an armed stop: a buy stop fires once a trade prints at or above it, a sell
+    // stop once one prints at or below it. Cleared when the stop fires
+    #[serde(default)]
+    pub trigger_price: Option<u64>,
+    // Decimals of `price`, the pair's price scale when the order was placed: the order
+    // trades at `price / 10^price_decimals` quote base units per base unit
+    #[serde(default)]
+    pub price_decimals: u8,
 }
 
 impl PartialEq for Order {
@@ -36,8 +78,8 @@ impl Eq for Order {}
 impl Order {
     // Create a new order with a given id, user_id, pair_id, amount, price, and side
     // The status is pending by default,
-    // then once the order is matched, the status is matched,
-    // then when block is settled on L1, then the status is settled
+    // then once the order is matched, the status is partially filled or filled,
+    // then when the block holding its last fill is sealed, the status is settled
     pub fn new(
         id: String,
         user_id: String,
@@ -46,12 +88,22 @@ impl Order {
         price: u64,
         side: bool,
     ) -> Self {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        Self::new_with_clock(id, user_id, pair_id, amount, price, side, &SystemClock)
+    }
 
-        let (token_a, token_b) = get_pair_tokens(pair_id.clone());
+    // Like `new`, stamped with `clock`'s time instead of the wall clock's
+    pub fn new_with_clock(
+        id: String,
+        user_id: String,
+        pair_id: String,
+        amount: u64,
+        price: u64,
+        side: bool,
+        clock: &dyn Clock,
+    ) -> Self {
+        let now = clock.now_secs();
+
+        let (token_a, token_b) = get_pair_tokens(&pair_id);
 
         Self {
             id,
@@ -66,40 +118,236 @@ impl Order {
             status: OrderStatus::Pending,
             created_at: now,
             updated_at: now,
+            sequence: 0,
+            kind: OrderKind::Limit,
+            trigger_price: None,
+            price_decimals: 0,
+        }
+    }
+
+    // Make the order a stop-limit order, armed until a trade prints at `trigger_price`
+    pub fn with_trigger(mut self, trigger_price: u64) -> Self {
+        self.kind = OrderKind::StopLimit;
+        self.trigger_price = Some(trigger_price);
+        self
+    }
+
+    // Whether a trade at `last_price` fires the order. Orders that aren't armed always are
+    pub fn is_triggered_at(&self, last_price: u64) -> bool {
+        match self.trigger_price {
+            None => true,
+            Some(trigger_price) if self.side => last_price >= trigger_price,
+            Some(trigger_price) => last_price <= trigger_price,
+        }
+    }
+
+    // Create a new order, validating the pair format, amount and price up front
+    pub fn try_new(
+        id: String,
+        user_id: String,
+        pair_id: String,
+        amount: u64,
+        price: u64,
+        side: bool,
+    ) -> Result<Self, OrderError> {
+        parse_pair(&pair_id)?;
+        if amount == 0 {
+            return Err(OrderError::ZeroAmount);
+        }
+        if price == 0 {
+            return Err(OrderError::ZeroPrice);
         }
+        Ok(Self::new(id, user_id, pair_id, amount, price, side))
     }
 
     pub fn set_status(&mut self, status: OrderStatus) {
+        self.set_status_with_clock(status, &SystemClock)
+    }
+
+    // Like `set_status`, stamped with `clock`'s time instead of the wall clock's
+    pub fn set_status_with_clock(&mut self, status: OrderStatus, clock: &dyn Clock) {
         self.status = status;
-        self.updated_at = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        self.updated_at = clock.now_secs();
     }
 
     pub fn remaining_amount(&self) -> u64 {
-        self.amount - self.filled_amount
+        debug_assert!(
+            self.filled_amount <= self.amount,
+            "order {} filled {} of {}",
+            self.id,
+            self.filled_amount,
+            self.amount
+        );
+        // Saturating, so a corrupted order reads as done instead of wrapping around
+        self.amount.saturating_sub(self.filled_amount)
+    }
+
+    // Quote a buy locks while `remaining` of it is open: its notional at the limit price,
+    // rounded up so that any fill is covered. The whole order's lock was checked when it
+    // was placed, so this only saturates for a corrupted order
+    pub fn quote_locked(&self, remaining: u64) -> u64 {
+        locked_notional(remaining, self.price, self.price_decimals).unwrap_or(u64::MAX)
     }
 
     pub fn is_filled(&self) -> bool {
         self.filled_amount >= self.amount
     }
 
-    pub fn fill(&mut self, amount: u64) {
+    // Fill up to `amount`, never past the order's size. Returns the amount actually filled
+    pub fn fill(&mut self, amount: u64) -> u64 {
+        let amount = amount.min(self.remaining_amount());
         self.filled_amount += amount;
         if self.is_filled() {
             self.status = OrderStatus::Filled;
         } else if self.filled_amount > 0 {
             self.status = OrderStatus::PartiallyFilled;
         }
+        amount
     }
 }
 
-fn get_pair_tokens(pair_id: String) -> (String, String) {
-    // Parse pair_id to get base and quote tokens (e.g., "ETH_USDT")
-    let tokens: Vec<&str> = pair_id.split('_').collect();
+// Longest accepted token symbol
+pub const MAX_SYMBOL_LEN: usize = 16;
 
-    let base_token = tokens[0];
-    let quote_token = tokens[1];
-    (base_token.to_string(), quote_token.to_string())
-}
\ No newline at end of file
+/// Whether `symbol` is a valid token symbol, i.e. matches `[A-Z0-9]{1,16}`
+pub fn is_valid_symbol(symbol: &str) -> bool {
+    (1..=MAX_SYMBOL_LEN).contains(&symbol.len())
+        && symbol
+            .bytes()
+            .all(|byte| byte.is_ascii_uppercase() || byte.is_ascii_digit())
+}
+
+/// Split a pair id (e.g. "ETH_USDT") into its base and quote tokens. The grammar is strict:
+/// exactly `BASE_QUOTE`, both tokens valid symbols (see `is_valid_symbol`) and different from
+/// each other. Anything else, such as a third segment, is rejected.
+pub fn parse_pair(pair_id: &str) -> Result<(String, String), OrderError> {
+    let mut tokens = pair_id.split('_');
+    let (Some(base_token), Some(quote_token), None) = (tokens.next(), tokens.next(), tokens.next())
+    else {
+        return Err(OrderError::InvalidPair(pair_id.to_string()));
+    };
+    if base_token.is_empty() || quote_token.is_empty() {
+        return Err(OrderError::EmptyToken(pair_id.to_string()));
+    }
+    if let Some(token) = [base_token, quote_token]
+        .into_iter()
+        .find(|token| !is_valid_symbol(token))
+    {
+        return Err(OrderError::InvalidSymbol(
+            pair_id.to_string(),
+            token.to_string(),
+        ));
+    }
+    if base_token == quote_token {
+        return Err(OrderError::SameToken(pair_id.to_string()));
+    }
+    Ok((base_token.to_string(), quote_token.to_string()))
+}
+
+// Lenient variant for `Order::new`, which never panics; malformed pairs yield empty tokens.
+fn get_pair_tokens(pair_id: &str) -> (String, String) {
+    parse_pair(pair_id).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn try_order(pair_id: &str, amount: u64, price: u64) -> Result<Order, OrderError> {
+        Order::try_new(
+            "order".to_string(),
+            "user".to_string(),
+            pair_id.to_string(),
+            amount,
+            price,
+            true,
+        )
+    }
+
+    #[test]
+    fn test_try_new_validation() {
+        let order = try_order("ETH_USDT", 10, 3).unwrap();
+        assert_eq!(order.token_a, "ETH");
+        assert_eq!(order.token_b, "USDT");
+
+        assert_eq!(
+            try_order("ETHUSDT", 10, 3).unwrap_err(),
+            OrderError::InvalidPair("ETHUSDT".to_string())
+        );
+        assert_eq!(
+            try_order("ETH_USDT", 0, 3).unwrap_err(),
+            OrderError::ZeroAmount
+        );
+        assert_eq!(
+            try_order("ETH_USDT", 10, 0).unwrap_err(),
+            OrderError::ZeroPrice
+        );
+
+        // The infallible constructor must not panic on a malformed pair
+        let order = Order::new(
+            "order".to_string(),
+            "user".to_string(),
+            "ETHUSDT".to_string(),
+            1,
+            1,
+            false,
+        );
+        assert_eq!(order.token_a, "");
+    }
+
+    #[test]
+    fn test_strict_pair_grammar() {
+        assert_eq!(
+            parse_pair("BTC_USDT").unwrap(),
+            ("BTC".to_string(), "USDT".to_string())
+        );
+        assert_eq!(
+            parse_pair("A_B_C").unwrap_err(),
+            OrderError::InvalidPair("A_B_C".to_string())
+        );
+        assert_eq!(
+            parse_pair("_USDT").unwrap_err(),
+            OrderError::EmptyToken("_USDT".to_string())
+        );
+        assert_eq!(
+            parse_pair("BTC_").unwrap_err(),
+            OrderError::EmptyToken("BTC_".to_string())
+        );
+        assert_eq!(
+            parse_pair("btc_USDT").unwrap_err(),
+            OrderError::InvalidSymbol("btc_USDT".to_string(), "btc".to_string())
+        );
+        let long = format!("BTC_{}", "U".repeat(MAX_SYMBOL_LEN + 1));
+        assert!(matches!(
+            parse_pair(&long).unwrap_err(),
+            OrderError::InvalidSymbol(..)
+        ));
+
+        // The lenient constructor leaves a malformed pair's tokens empty instead of guessing
+        let order = Order::new(
+            "order".to_string(),
+            "user".to_string(),
+            "A_B_C".to_string(),
+            1,
+            1,
+            true,
+        );
+        assert_eq!((order.token_a.as_str(), order.token_b.as_str()), ("", ""));
+    }
+
+    #[test]
+    fn test_over_fill_is_capped() {
+        let mut order = try_order("ETH_USDT", 10, 3).unwrap();
+        assert_eq!(order.fill(4), 4);
+        assert_eq!(order.status, OrderStatus::PartiallyFilled);
+
+        // Only the 6 left are filled, the order isn't pushed past its size
+        assert_eq!(order.fill(50), 6);
+        assert_eq!(order.filled_amount, 10);
+        assert_eq!(order.remaining_amount(), 0);
+        assert_eq!(order.status, OrderStatus::Filled);
+
+        assert_eq!(order.fill(1), 0);
+        assert_eq!(order.remaining_amount(), 0);
+    }
+}
@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use tiny_keccak::{Hasher, Sha3};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::db::{OpenDbError, SEALED_BALANCES_KEY, STATE_TREE, open_db};
+use crate::hasher::HashScheme;
+use crate::traces::{BalanceDeltas, MatchedTrace};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Account {
@@ -22,30 +25,126 @@ impl Account {
     }
 }
 
+// Format version of state snapshots, bumped whenever `StateSnapshot` changes shape
+pub const STATE_SNAPSHOT_VERSION: u32 = 1;
+
+/// Reasons a state snapshot can't be imported.
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("Malformed state snapshot: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error("Unsupported state snapshot version {0}, expected {STATE_SNAPSHOT_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("State snapshot hashed with {snapshot:?}, this state uses {state:?}")]
+    HashSchemeMismatch {
+        snapshot: HashScheme,
+        state: HashScheme,
+    },
+    #[error("State snapshot freezes {frozen} {token} of user {user_id}, more than its balance")]
+    FrozenAboveBalance {
+        user_id: String,
+        token: String,
+        frozen: u64,
+    },
+    #[error("Imported state root does not match the snapshot's checksum")]
+    RootMismatch,
+    #[error("State snapshots can only be imported into an empty state")]
+    NotEmpty,
+}
+
+// Portable copy of the balances and frozen amounts, written by `StateDB::export`. Maps are
+// ordered so the same state always exports to the same bytes
+#[derive(Serialize, Deserialize)]
+struct StateSnapshot {
+    version: u32,
+    hash_scheme: HashScheme,
+    balances: BTreeMap<String, BTreeMap<String, u64>>,
+    frozens: BTreeMap<String, BTreeMap<String, u64>>,
+    // Root of `balances`, checked against the imported state
+    state_root: Option<[u8; 32]>,
+}
+
+fn sorted_accounts(accounts: &HashMap<String, Account>) -> BTreeMap<String, BTreeMap<String, u64>> {
+    accounts
+        .iter()
+        .map(|(user_id, account)| {
+            let balances = account.balances.clone().into_iter().collect();
+            (user_id.clone(), balances)
+        })
+        .collect()
+}
+
+fn unsorted_accounts(
+    accounts: BTreeMap<String, BTreeMap<String, u64>>,
+) -> HashMap<String, Account> {
+    accounts
+        .into_iter()
+        .map(|(user_id, balances)| {
+            let account = Account {
+                balances: balances.into_iter().collect(),
+            };
+            (user_id, account)
+        })
+        .collect()
+}
+
+/// Balances as of the last sealed block's root, as the block builder saves them in `blocks`,
+/// the node's blocks tree. `None` before the first block is sealed.
+pub fn read_sealed_balances(
+    blocks: &sled::Tree,
+) -> anyhow::Result<Option<HashMap<String, Account>>> {
+    match blocks.get(SEALED_BALANCES_KEY)? {
+        Some(data) => Ok(Some(serde_json::from_slice(&data).map_err(|e| {
+            anyhow::anyhow!("Failed to deserialize sealed balances: {}", e)
+        })?)),
+        None => Ok(None),
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct State {
     pub user_balances: HashMap<String, Account>,
     pub user_frozens: HashMap<String, Account>,
     pub state_root: Option<[u8; 32]>,
+    // Hasher of the state tree, shared with the prover through the serialized state
+    #[serde(default)]
+    pub hash_scheme: HashScheme,
+    // Ids of traces whose freezes were released but whose block isn't sealed yet, so a
+    // trace settled again, e.g. replayed from the write-ahead log, releases nothing twice
+    #[serde(skip)]
+    settled_traces: HashSet<String>,
 }
 
 pub struct StateDB {
-    pub db: sled::Db,
+    pub db: sled::Tree,
     pub state: State,
 }
 
 impl StateDB {
-    pub fn new(db_path: &str) -> Self {
-        let db = sled::open(db_path).unwrap();
+    pub fn new(db_path: &str) -> Result<Self, OpenDbError> {
+        Ok(Self::with_db(&open_db(db_path)?, HashScheme::default()))
+    }
+
+    /// State kept in its own tree of `db`, next to the other subsystems' trees.
+    pub fn with_db(db: &sled::Db, hash_scheme: HashScheme) -> Self {
         StateDB {
-            db,
-            state: State::new(),
+            db: db.open_tree(STATE_TREE).unwrap(),
+            state: State::with_hash_scheme(hash_scheme),
         }
     }
 
+    /// Save the balances and frozen amounts, together in one batch
     pub fn save(&self) {
-        let serialized = serde_json::to_vec(&self.state.user_balances).unwrap();
-        self.db.insert("user_balances", serialized).unwrap();
+        let mut batch = sled::Batch::default();
+        batch.insert(
+            "user_balances",
+            serde_json::to_vec(&self.state.user_balances).unwrap(),
+        );
+        batch.insert(
+            "user_frozens",
+            serde_json::to_vec(&self.state.user_frozens).unwrap(),
+        );
+        self.db.apply_batch(batch).unwrap();
     }
 
     pub fn load(&mut self) {
@@ -54,17 +153,87 @@ impl StateDB {
                 self.state.user_balances = user_balances;
             }
         }
+        if let Ok(Some(data)) = self.db.get("user_frozens")
+            && let Ok(user_frozens) = serde_json::from_slice::<HashMap<String, Account>>(&data)
+        {
+            self.state.user_frozens = user_frozens;
+        }
+    }
+
+    /// Versioned snapshot of all balances and frozen amounts, to move the state to another
+    /// machine with `import` rather than by copying the database directory.
+    pub fn export(&self) -> Vec<u8> {
+        let snapshot = StateSnapshot {
+            version: STATE_SNAPSHOT_VERSION,
+            hash_scheme: self.state.hash_scheme,
+            balances: sorted_accounts(&self.state.user_balances),
+            frozens: sorted_accounts(&self.state.user_frozens),
+            state_root: self.state.calculate_state_root(),
+        };
+        serde_json::to_vec(&snapshot).expect("state snapshot serializes")
+    }
+
+    /// Restore a snapshot from `export` into this state, which must be empty, and save it.
+    /// Nothing is restored unless the snapshot's root matches the balances it carries.
+    pub fn import(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        if !self.state.user_balances.is_empty() || !self.state.user_frozens.is_empty() {
+            return Err(SnapshotError::NotEmpty);
+        }
+        let snapshot: StateSnapshot = serde_json::from_slice(bytes)?;
+        if snapshot.version != STATE_SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(snapshot.version));
+        }
+        if snapshot.hash_scheme != self.state.hash_scheme {
+            return Err(SnapshotError::HashSchemeMismatch {
+                snapshot: snapshot.hash_scheme,
+                state: self.state.hash_scheme,
+            });
+        }
+
+        let mut state = State::with_hash_scheme(snapshot.hash_scheme);
+        state.user_balances = unsorted_accounts(snapshot.balances);
+        state.user_frozens = unsorted_accounts(snapshot.frozens);
+        for (user_id, frozens) in &state.user_frozens {
+            for (token, &frozen) in &frozens.balances {
+                if frozen > state.get_user_balance(user_id, token) {
+                    return Err(SnapshotError::FrozenAboveBalance {
+                        user_id: user_id.clone(),
+                        token: token.clone(),
+                        frozen,
+                    });
+                }
+            }
+        }
+        if state.calculate_state_root() != snapshot.state_root {
+            return Err(SnapshotError::RootMismatch);
+        }
+
+        self.state = state;
+        self.save();
+        Ok(())
     }
 }
 impl State {
     pub fn new() -> Self {
+        Self::with_hash_scheme(HashScheme::default())
+    }
+
+    pub fn with_hash_scheme(hash_scheme: HashScheme) -> Self {
         State {
             user_balances: HashMap::new(),
             user_frozens: HashMap::new(),
             state_root: None,
+            hash_scheme,
+            settled_traces: HashSet::new(),
         }
     }
 
+    // Whether the user has ever been credited a balance, by a deposit, a transfer, a trade
+    // or the genesis config
+    pub fn has_account(&self, user_id: &str) -> bool {
+        self.user_balances.contains_key(user_id)
+    }
+
     // Helper method to get a user's balance for a specific token
     pub fn get_user_balance(&self, user_id: &str, token_id: &str) -> u64 {
         self.user_balances
@@ -101,6 +270,37 @@ impl State {
         }
     }
 
+    /// Apply net balance changes all at once. Every resulting balance is checked before any
+    /// is written, so if one would go negative (or overflow) the state is left untouched.
+    pub fn apply_deltas(&mut self, deltas: &BalanceDeltas) -> Result<(), String> {
+        let mut balances = Vec::with_capacity(deltas.len());
+        for ((user_id, token_id), delta) in deltas {
+            let balance = self.get_user_balance(user_id, token_id) as i128 + delta;
+            let balance = u64::try_from(balance).map_err(|_| {
+                format!(
+                    "settlement leaves {}'s {} balance at {}",
+                    user_id, token_id, balance
+                )
+            })?;
+            balances.push((user_id, token_id, balance));
+        }
+        for (user_id, token_id, balance) in balances {
+            self.set_user_balance(user_id.clone(), token_id.clone(), balance);
+        }
+        Ok(())
+    }
+
+    // Balance not locked by open orders, i.e. what the user can still withdraw or trade
+    pub fn get_available_balance(&self, user_id: &str, token_id: &str) -> u64 {
+        let frozen = self
+            .user_frozens
+            .get(user_id)
+            .map(|frozens| frozens.get_balance(token_id))
+            .unwrap_or(0);
+        self.get_user_balance(user_id, token_id)
+            .saturating_sub(frozen)
+    }
+
     pub fn get_frozen(&mut self, user_id: String, token_id: &str) -> u64 {
         self.user_frozens
             .get(&user_id)
@@ -108,124 +308,576 @@ impl State {
             .unwrap_or(0)
     }
 
-    pub fn freeze(&mut self, user_id: String, token_id: String, balance: u64) {
+    // Freeze `amount` more of the user's token, failing without change when that's more than
+    // the balance not frozen yet
+    pub fn freeze(&mut self, user_id: String, token_id: String, amount: u64) -> Result<(), String> {
+        let available = self.get_available_balance(&user_id, &token_id);
+        if amount > available {
+            return Err(format!(
+                "Cannot freeze {} {}, only {} available",
+                amount, token_id, available
+            ));
+        }
+        let frozen_balance = self.get_frozen(user_id.clone(), &token_id);
+        self.set_frozen(user_id, token_id, frozen_balance + amount);
+        Ok(())
+    }
+
+    // Overwrite the user's frozen amount of a token, e.g. to repair it from the open orders
+    pub fn set_frozen(&mut self, user_id: String, token_id: String, balance: u64) {
         self.user_frozens
             .entry(user_id)
             .or_insert_with(|| Account::new())
             .set_balance(token_id, balance);
     }
 
-    pub fn unfreeze(&mut self, user_id: String, token_id: String, balance: u64) {
+    // Release `amount` of the user's frozen token, stopping at zero
+    pub fn unfreeze(&mut self, user_id: String, token_id: String, amount: u64) {
         let frozen_balance = self.get_frozen(user_id.clone(), &token_id);
-        if frozen_balance < balance {
-            return;
+        self.set_frozen(user_id, token_id, frozen_balance.saturating_sub(amount));
+    }
+
+    /// Release what a settled trace's fill had frozen, see `MatchedTrace::settlement_locks`.
+    /// A trace already released since its block was last sealed releases nothing; returns
+    /// whether anything was released.
+    pub fn release_trace(&mut self, trace: &MatchedTrace) -> bool {
+        if !self.settled_traces.insert(trace.id()) {
+            return false;
         }
-        self.user_frozens
-            .entry(user_id)
-            .or_insert_with(|| Account::new())
-            .set_balance(token_id, frozen_balance - balance);
+        for (user_id, token, amount) in trace.settlement_locks() {
+            self.unfreeze(user_id.to_string(), token.to_string(), amount);
+        }
+        true
+    }
+
+    // Whether the trace was settled and released since its block was last sealed
+    pub fn is_trace_released(&self, trace: &MatchedTrace) -> bool {
+        self.settled_traces.contains(&trace.id())
     }
 
+    // Refund the part of a fill's quote lock its buy won't pay, see `price_improvement`
+    pub fn refund_price_improvement(&mut self, trace: &MatchedTrace) {
+        self.unfreeze(
+            trace.buy_order.user_id.clone(),
+            trace.buy_order.token_b.clone(),
+            trace.price_improvement(),
+        );
+    }
 
-    //  State root of binary tree
-    pub fn calculate_state_root(&self) -> Option<[u8; 32]> {
-        if self.user_balances.is_empty() {
-            return None;
+    // Forget the released traces of a sealed block: out of the write-ahead log, they can't
+    // be settled again
+    pub fn forget_released_traces(&mut self, traces: &[MatchedTrace]) {
+        for trace in traces {
+            self.settled_traces.remove(&trace.id());
         }
+    }
 
-        // Calculate hash for each user's balances
-        let mut leaf_hashes: Vec<[u8; 32]> = self
-            .user_balances
-            .iter()
-            .map(|(user_id, account)| calculate_user_hash(user_id, &account.balances))
-            .collect();
+    // Hash of each user's balances in leaf order of the state tree.
+    // Sorted by user_id: HashMap iteration order differs between runs and inside the zkVM
+    pub fn leaf_hashes(&self) -> Vec<(String, [u8; 32])> {
+        let mut sorted_users: Vec<_> = self.user_balances.iter().collect();
+        sorted_users.sort_by_key(|(user_id, _)| *user_id);
+
+        sorted_users
+            .into_iter()
+            .map(|(user_id, account)| {
+                (
+                    user_id.clone(),
+                    calculate_user_hash(self.hash_scheme, user_id, &account.balances),
+                )
+            })
+            .collect()
+    }
+
+    // Leaf hashes of the sub-tree restricted to `tokens`: each user's balances of those tokens
+    // only, skipping users holding none of them
+    pub fn leaf_hashes_for_tokens(&self, tokens: &[String]) -> Vec<(String, [u8; 32])> {
+        let mut sorted_users: Vec<_> = self.user_balances.iter().collect();
+        sorted_users.sort_by_key(|(user_id, _)| *user_id);
+
+        sorted_users
+            .into_iter()
+            .filter_map(|(user_id, account)| {
+                let balances: HashMap<String, u64> = account
+                    .balances
+                    .iter()
+                    .filter(|(token_id, _)| tokens.contains(token_id))
+                    .map(|(token_id, balance)| (token_id.clone(), *balance))
+                    .collect();
+                if balances.is_empty() {
+                    return None;
+                }
+                Some((
+                    user_id.clone(),
+                    calculate_user_hash(self.hash_scheme, user_id, &balances),
+                ))
+            })
+            .collect()
+    }
 
-        // If odd number of users, duplicate the last hash to make it even
-        if leaf_hashes.len() % 2 == 1 {
-            leaf_hashes.push(leaf_hashes[leaf_hashes.len() - 1]);
+    /// Root of the binary tree over the users' leaves in `leaf_hashes` order, None without
+    /// users. Each level pairs up its nodes left to right; the last node of an odd level is
+    /// paired with `EMPTY_NODE`, never with itself, so a tree always commits to its exact
+    /// leaves. A single leaf is its own root.
+    pub fn calculate_state_root(&self) -> Option<[u8; 32]> {
+        merkle_root(
+            self.hash_scheme,
+            self.leaf_hashes()
+                .into_iter()
+                .map(|(_, hash)| hash)
+                .collect(),
+        )
+    }
+
+    /// Inclusion proof of `user_id`'s leaf in the current state tree, None if the user holds
+    /// no balances.
+    pub fn gen_merkle_proof(&self, user_id: &str) -> Option<MerkleProof> {
+        let leaves = self.leaf_hashes();
+        let leaf_index = leaves.iter().position(|(id, _)| id == user_id)?;
+        let mut index = leaf_index;
+        let mut level: Vec<[u8; 32]> = leaves.into_iter().map(|(_, hash)| hash).collect();
+
+        // Walk up the tree merkle_root builds
+        let mut siblings = vec![];
+        while level.len() > 1 {
+            siblings.push(level.get(index ^ 1).copied().unwrap_or(EMPTY_NODE));
+            level = parent_level(self.hash_scheme, &level);
+            index /= 2;
         }
 
-        // Build binary tree bottom-up
-        let mut nodes: Vec<MerkleNode> =
-            leaf_hashes.into_iter().map(MerkleNode::new_leaf).collect();
+        Some(MerkleProof {
+            user_id: user_id.to_string(),
+            balances: self.user_balances[user_id].balances.clone(),
+            leaf_index,
+            siblings,
+        })
+    }
 
-        // Build tree level by level until we have one root node
-        while nodes.len() > 1 {
-            let mut next_level = Vec::new();
+    // Root of the sub-tree holding only the balances of `tokens`, so a batch can commit to
+    // the markets it touches without the rest of the state
+    pub fn calculate_state_root_for_tokens(&self, tokens: &[String]) -> Option<[u8; 32]> {
+        merkle_root(
+            self.hash_scheme,
+            self.leaf_hashes_for_tokens(tokens)
+                .into_iter()
+                .map(|(_, hash)| hash)
+                .collect(),
+        )
+    }
+}
 
-            for i in (0..nodes.len()).step_by(2) {
-                let left = nodes[i].clone();
-                let right = if i + 1 < nodes.len() {
-                    nodes[i + 1].clone()
-                } else {
-                    // If odd number of nodes, duplicate the last one
-                    left.clone()
-                };
+/// Sibling of the last node of an odd tree level. Nothing hashes to it, so a padded level
+/// can't be mistaken for one holding its last node twice.
+pub const EMPTY_NODE: [u8; 32] = [0u8; 32];
 
-                next_level.push(MerkleNode::new_internal(left, right));
-            }
+// Root of the binary tree over `leaf_hashes`, None without leaves
+fn merkle_root(hash_scheme: HashScheme, leaf_hashes: Vec<[u8; 32]>) -> Option<[u8; 32]> {
+    let mut level = leaf_hashes;
+    while level.len() > 1 {
+        level = parent_level(hash_scheme, &level);
+    }
+    level.first().copied()
+}
 
-            nodes = next_level;
-        }
+// Nodes of the level above `level`, padding an odd level with `EMPTY_NODE`
+fn parent_level(hash_scheme: HashScheme, level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| hash_scheme.hash_node(&pair[0], pair.get(1).unwrap_or(&EMPTY_NODE)))
+        .collect()
+}
 
-        // Return the root hash
-        if let Some(root) = nodes.into_iter().next() {
-            Some(root.hash)
-        } else {
-            None
+/// Proof that a user's balances are a leaf of the state tree: the balances hash to the leaf,
+/// and hashing it with `siblings` from the bottom up leads to the state root.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub user_id: String,
+    pub balances: HashMap<String, u64>,
+    pub leaf_index: usize,
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl MerkleProof {
+    /// Root the proof leads to when hashed under `hash_scheme`.
+    pub fn root(&self, hash_scheme: HashScheme) -> [u8; 32] {
+        let mut hash = calculate_user_hash(hash_scheme, &self.user_id, &self.balances);
+        let mut index = self.leaf_index;
+        for sibling in &self.siblings {
+            hash = if index.is_multiple_of(2) {
+                hash_scheme.hash_node(&hash, sibling)
+            } else {
+                hash_scheme.hash_node(sibling, &hash)
+            };
+            index /= 2;
         }
+        hash
+    }
+
+    pub fn verify(&self, hash_scheme: HashScheme, state_root: &[u8; 32]) -> bool {
+        self.root(hash_scheme) == *state_root
+    }
+}
+
+// Domain tag for state tree leaves, so a leaf encoding can't be confused with other hashed data
+const LEAF_DOMAIN_TAG: &[u8] = b"clob-state-leaf-v1";
+
+// Helper function to calculate hash for a user's balances.
+// Every variable-length field is length-prefixed so the encoding is unambiguous:
+// without it, ("alice", "BTC") and ("aliceB", "TC") would hash the same bytes.
+fn calculate_user_hash(
+    hash_scheme: HashScheme,
+    user_id: &str,
+    balances: &HashMap<String, u64>,
+) -> [u8; 32] {
+    let mut preimage = LEAF_DOMAIN_TAG.to_vec();
+
+    // Encode user_id
+    extend_length_prefixed(&mut preimage, user_id.as_bytes());
+
+    // Encode balances in a deterministic order (sorted by token_id)
+    let mut sorted_balances: Vec<_> = balances.iter().collect();
+    sorted_balances.sort_by_key(|(token_id, _)| *token_id);
+
+    preimage.extend_from_slice(&(sorted_balances.len() as u64).to_le_bytes());
+    for (token_id, balance) in sorted_balances {
+        extend_length_prefixed(&mut preimage, token_id.as_bytes());
+        preimage.extend_from_slice(&balance.to_le_bytes());
     }
+
+    hash_scheme.hash_leaf(&preimage)
 }
 
-#[derive(Clone, Debug)]
-pub struct MerkleNode {
-    pub hash: [u8; 32],
-    pub left: Option<Box<MerkleNode>>,
-    pub right: Option<Box<MerkleNode>>,
+fn extend_length_prefixed(preimage: &mut Vec<u8>, data: &[u8]) {
+    preimage.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    preimage.extend_from_slice(data);
 }
 
-impl MerkleNode {
-    fn new_leaf(hash: [u8; 32]) -> Self {
-        MerkleNode {
-            hash,
-            left: None,
-            right: None,
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::order::Order;
+
+    #[test]
+    fn test_freeze_never_exceeds_balance() {
+        let mut state = State::new();
+        state.set_user_balance("alice".to_string(), "USDT".to_string(), 100);
+        state
+            .freeze("alice".to_string(), "USDT".to_string(), 70)
+            .unwrap();
+        // Freezes add up, and can't take more than what's left unfrozen
+        assert_eq!(
+            state.freeze("alice".to_string(), "USDT".to_string(), 31),
+            Err("Cannot freeze 31 USDT, only 30 available".to_string())
+        );
+        assert_eq!(state.get_frozen("alice".to_string(), "USDT"), 70);
+        state
+            .freeze("alice".to_string(), "USDT".to_string(), 30)
+            .unwrap();
+        assert_eq!(state.get_available_balance("alice", "USDT"), 0);
+        assert!(
+            state
+                .freeze("bob".to_string(), "USDT".to_string(), 1)
+                .is_err()
+        );
+
+        // Unfreezing more than is frozen stops at zero
+        state.unfreeze("alice".to_string(), "USDT".to_string(), 40);
+        assert_eq!(state.get_frozen("alice".to_string(), "USDT"), 60);
+        state.unfreeze("alice".to_string(), "USDT".to_string(), 1_000);
+        assert_eq!(state.get_frozen("alice".to_string(), "USDT"), 0);
+    }
+
+    #[test]
+    fn test_double_release_of_trace() {
+        let mut state = State::new();
+        state.set_user_balance("buyer".to_string(), "USDT".to_string(), 100);
+        state.set_user_balance("seller".to_string(), "BTC".to_string(), 10);
+        state
+            .freeze("buyer".to_string(), "USDT".to_string(), 100)
+            .unwrap();
+        state
+            .freeze("seller".to_string(), "BTC".to_string(), 10)
+            .unwrap();
+        let order = |id: &str, user_id: &str, side| {
+            Order::new(
+                id.to_string(),
+                user_id.to_string(),
+                "BTC_USDT".to_string(),
+                10,
+                10,
+                side,
+            )
+        };
+        let trace = MatchedTrace {
+            buy_order: order("buy", "buyer", true),
+            sell_order: order("sell", "seller", false),
+            matched_amount: 4,
+            matched_price: 10,
+        };
+
+        assert!(state.release_trace(&trace));
+        assert_eq!(state.get_frozen("buyer".to_string(), "USDT"), 60);
+        assert_eq!(state.get_frozen("seller".to_string(), "BTC"), 6);
+        // Settled a second time, e.g. replayed after a crash: nothing more is released
+        assert!(!state.release_trace(&trace));
+        assert_eq!(state.get_frozen("buyer".to_string(), "USDT"), 60);
+        assert_eq!(state.get_frozen("seller".to_string(), "BTC"), 6);
+
+        // The next fill of the same orders is a different trace
+        let mut next = trace.clone();
+        next.buy_order.fill(4);
+        next.sell_order.fill(4);
+        assert!(state.release_trace(&next));
+        assert_eq!(state.get_frozen("buyer".to_string(), "USDT"), 20);
+
+        // Once sealed, the ids are dropped
+        state.forget_released_traces(&[trace, next]);
+        assert!(state.settled_traces.is_empty());
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let source_db = sled::Config::new().temporary(true).open().unwrap();
+        let mut source = StateDB::with_db(&source_db, HashScheme::Keccak);
+        for (i, user) in ["alice", "bob", "carol"].iter().enumerate() {
+            let i = i as u64;
+            source
+                .state
+                .set_user_balance(user.to_string(), "BTC".to_string(), i + 1);
+            source
+                .state
+                .set_user_balance(user.to_string(), "USDT".to_string(), 100 * i);
         }
+        source
+            .state
+            .freeze("bob".to_string(), "USDT".to_string(), 60)
+            .unwrap();
+        let snapshot = source.export();
+        assert_eq!(snapshot, source.export());
+
+        let target_db = sled::Config::new().temporary(true).open().unwrap();
+        let mut target = StateDB::with_db(&target_db, HashScheme::Keccak);
+        target.import(&snapshot).unwrap();
+        assert_eq!(
+            target.state.calculate_state_root(),
+            source.state.calculate_state_root()
+        );
+        assert_eq!(target.state.get_user_balance("carol", "USDT"), 200);
+        assert_eq!(target.state.get_available_balance("bob", "USDT"), 40);
+        // Saved, so it survives a reload
+        let mut reloaded = StateDB::with_db(&target_db, HashScheme::Keccak);
+        reloaded.load();
+        assert_eq!(reloaded.state.get_user_balance("alice", "BTC"), 1);
+        assert_eq!(reloaded.state.get_frozen("bob".to_string(), "USDT"), 60);
+        assert_eq!(reloaded.state.get_available_balance("bob", "USDT"), 40);
+        assert_eq!(
+            reloaded.state.calculate_state_root(),
+            source.state.calculate_state_root()
+        );
+
+        // Only into an empty state, with the scheme the root was taken under
+        assert!(matches!(
+            target.import(&snapshot),
+            Err(SnapshotError::NotEmpty)
+        ));
+        let poseidon_db = sled::Config::new().temporary(true).open().unwrap();
+        let mut poseidon = StateDB::with_db(&poseidon_db, HashScheme::Poseidon);
+        assert!(matches!(
+            poseidon.import(&snapshot),
+            Err(SnapshotError::HashSchemeMismatch { .. })
+        ));
+
+        // A balance edited in transit no longer matches the checksum
+        let tampered = String::from_utf8(snapshot)
+            .unwrap()
+            .replace("\"BTC\":3", "\"BTC\":4");
+        let fresh_db = sled::Config::new().temporary(true).open().unwrap();
+        let mut fresh = StateDB::with_db(&fresh_db, HashScheme::Keccak);
+        assert!(matches!(
+            fresh.import(tampered.as_bytes()),
+            Err(SnapshotError::RootMismatch)
+        ));
+        assert!(fresh.state.user_balances.is_empty());
     }
 
-    fn new_internal(left: MerkleNode, right: MerkleNode) -> Self {
-        let mut sha3 = Sha3::v256();
-        let mut output = [0u8; 32];
+    #[test]
+    fn test_state_root_independent_of_insertion_order() {
+        let users = ["alice", "bob", "carol", "dave", "erin"];
+
+        let mut forward = State::new();
+        for (i, user) in users.iter().enumerate() {
+            forward.set_user_balance(user.to_string(), "BTC".to_string(), i as u64 + 1);
+            forward.set_user_balance(user.to_string(), "USDT".to_string(), 100 * i as u64);
+        }
+        let mut backward = State::new();
+        for (i, user) in users.iter().enumerate().rev() {
+            backward.set_user_balance(user.to_string(), "USDT".to_string(), 100 * i as u64);
+            backward.set_user_balance(user.to_string(), "BTC".to_string(), i as u64 + 1);
+        }
 
-        sha3.update(&left.hash);
-        sha3.update(&right.hash);
-        sha3.finalize(&mut output);
+        assert!(forward.calculate_state_root().is_some());
+        assert_eq!(
+            forward.calculate_state_root(),
+            backward.calculate_state_root()
+        );
+    }
 
-        MerkleNode {
-            hash: output,
-            left: Some(Box::new(left)),
-            right: Some(Box::new(right)),
+    #[test]
+    fn test_merkle_proofs_lead_to_state_root() {
+        // Odd and even leaf counts take the padded-node paths differently
+        for users in 1..=6u64 {
+            for hash_scheme in [HashScheme::Keccak, HashScheme::Poseidon] {
+                let mut state = State::with_hash_scheme(hash_scheme);
+                for i in 0..users {
+                    state.set_user_balance(format!("user_{}", i), "BTC".to_string(), i + 1);
+                }
+                let root = state.calculate_state_root().unwrap();
+                for i in 0..users {
+                    let proof = state.gen_merkle_proof(&format!("user_{}", i)).unwrap();
+                    assert!(proof.verify(hash_scheme, &root));
+
+                    let mut forged = proof.clone();
+                    forged.balances.insert("BTC".to_string(), i + 2);
+                    assert!(!forged.verify(hash_scheme, &root));
+                }
+            }
         }
+        assert!(State::new().gen_merkle_proof("nobody").is_none());
     }
-}
 
-// Helper function to calculate hash for a user's balances
-fn calculate_user_hash(user_id: &str, balances: &HashMap<String, u64>) -> [u8; 32] {
-    let mut sha3 = Sha3::v256();
-    let mut output = [0u8; 32];
+    #[test]
+    fn test_padded_tree_differs_from_duplicated_leaf() {
+        let leaves: Vec<[u8; 32]> = (1..=3u8).map(|i| [i; 32]).collect();
+        let mut duplicated = leaves.clone();
+        duplicated.push(leaves[2]);
+
+        for hash_scheme in [HashScheme::Keccak, HashScheme::Poseidon] {
+            // Padding with a copy of the last leaf made these two the same tree
+            assert_ne!(
+                merkle_root(hash_scheme, leaves.clone()),
+                merkle_root(hash_scheme, duplicated.clone())
+            );
+
+            // An internal node isn't the leaf hash of its children's bytes
+            let node = merkle_root(hash_scheme, leaves[..2].to_vec()).unwrap();
+            assert_ne!(
+                node,
+                hash_scheme.hash_leaf(&[leaves[0], leaves[1]].concat())
+            );
+            assert_eq!(merkle_root(hash_scheme, vec![leaves[0]]), Some(leaves[0]));
+        }
+    }
 
-    // Hash user_id
-    sha3.update(user_id.as_bytes());
+    #[test]
+    fn test_token_subset_root_matches_full_leaves() {
+        let mut state = State::new();
+        for (i, user) in ["alice", "bob", "carol"].iter().enumerate() {
+            state.set_user_balance(user.to_string(), "BTC".to_string(), i as u64 + 1);
+            state.set_user_balance(user.to_string(), "USDT".to_string(), 100 * i as u64);
+        }
+        state.set_user_balance("dave".to_string(), "ETH".to_string(), 5);
+        state.set_user_balance("alice".to_string(), "ETH".to_string(), 9);
+
+        let tokens = vec!["BTC".to_string(), "USDT".to_string()];
+        let subset = state.leaf_hashes_for_tokens(&tokens);
+        let full: HashMap<String, [u8; 32]> = state.leaf_hashes().into_iter().collect();
+
+        // dave holds none of the tokens, alice's ETH is left out of her subset leaf
+        let users: Vec<&str> = subset.iter().map(|(user_id, _)| user_id.as_str()).collect();
+        assert_eq!(users, vec!["alice", "bob", "carol"]);
+        assert_ne!(subset[0].1, full["alice"]);
+        assert_eq!(subset[1].1, full["bob"]);
+        assert_eq!(subset[2].1, full["carol"]);
+
+        // Covering every token gives back the full tree
+        let all_tokens = vec!["BTC".to_string(), "ETH".to_string(), "USDT".to_string()];
+        assert_eq!(
+            state.calculate_state_root_for_tokens(&all_tokens),
+            state.calculate_state_root()
+        );
+
+        // Balances outside the subset don't affect its root
+        let subset_root = state.calculate_state_root_for_tokens(&tokens);
+        state.set_user_balance("dave".to_string(), "ETH".to_string(), 6);
+        assert_eq!(state.calculate_state_root_for_tokens(&tokens), subset_root);
+        state.set_user_balance("bob".to_string(), "USDT".to_string(), 1);
+        assert_ne!(state.calculate_state_root_for_tokens(&tokens), subset_root);
+
+        assert_eq!(
+            state.calculate_state_root_for_tokens(&["SOL".to_string()]),
+            None
+        );
+    }
 
-    // Hash balances in a deterministic order (sorted by token_id)
-    let mut sorted_balances: Vec<_> = balances.iter().collect();
-    sorted_balances.sort_by_key(|(token_id, _)| *token_id);
+    #[test]
+    fn test_roots_consistent_within_each_hasher() {
+        let users = ["alice", "bob", "carol"];
+        let all_tokens = vec!["BTC".to_string(), "USDT".to_string()];
+
+        let mut roots = vec![];
+        for hash_scheme in [HashScheme::Keccak, HashScheme::Poseidon] {
+            let mut state = State::with_hash_scheme(hash_scheme);
+            let mut reversed = State::with_hash_scheme(hash_scheme);
+            for (i, user) in users.iter().enumerate() {
+                state.set_user_balance(user.to_string(), "BTC".to_string(), i as u64 + 1);
+                state.set_user_balance(user.to_string(), "USDT".to_string(), 100 * i as u64);
+            }
+            for (i, user) in users.iter().enumerate().rev() {
+                reversed.set_user_balance(user.to_string(), "USDT".to_string(), 100 * i as u64);
+                reversed.set_user_balance(user.to_string(), "BTC".to_string(), i as u64 + 1);
+            }
 
-    for (token_id, balance) in sorted_balances {
-        sha3.update(token_id.as_bytes());
-        sha3.update(&balance.to_le_bytes());
+            let root = state.calculate_state_root();
+            assert!(root.is_some());
+            assert_eq!(reversed.calculate_state_root(), root);
+            assert_eq!(state.calculate_state_root_for_tokens(&all_tokens), root);
+
+            // A round trip through serialization keeps the scheme, and so the root
+            let decoded: State =
+                serde_json::from_slice(&serde_json::to_vec(&state).unwrap()).unwrap();
+            assert_eq!(decoded.calculate_state_root(), root);
+
+            state.set_user_balance("bob".to_string(), "BTC".to_string(), 7);
+            assert_ne!(state.calculate_state_root(), root);
+            roots.push(root);
+        }
+        assert_ne!(roots[0], roots[1]);
+    }
+
+    #[test]
+    fn test_leaf_hash_field_boundaries() {
+        // Plain concatenation of these is identical: "alice" "BTC" 7 == "aliceB" "TC" 7
+        let mut first = HashMap::new();
+        first.insert("BTC".to_string(), 7u64);
+        let mut second = HashMap::new();
+        second.insert("TC".to_string(), 7u64);
+
+        for hash_scheme in [HashScheme::Keccak, HashScheme::Poseidon] {
+            assert_ne!(
+                calculate_user_hash(hash_scheme, "alice", &first),
+                calculate_user_hash(hash_scheme, "aliceB", &second)
+            );
+        }
     }
 
-    sha3.finalize(&mut output);
-    output
+    #[test]
+    fn test_apply_deltas_is_all_or_nothing() {
+        let mut state = State::new();
+        state.set_user_balance("alice".to_string(), "BTC".to_string(), 5);
+        state.set_user_balance("bob".to_string(), "USDT".to_string(), 5);
+
+        let mut deltas = BalanceDeltas::new();
+        deltas.insert(("alice".to_string(), "BTC".to_string()), -5);
+        deltas.insert(("bob".to_string(), "USDT".to_string()), -6);
+        assert_eq!(
+            state.apply_deltas(&deltas),
+            Err("settlement leaves bob's USDT balance at -1".to_string())
+        );
+        assert_eq!(state.get_user_balance("alice", "BTC"), 5);
+
+        deltas.insert(("bob".to_string(), "USDT".to_string()), -5);
+        state.apply_deltas(&deltas).unwrap();
+        assert_eq!(state.get_user_balance("alice", "BTC"), 0);
+        assert_eq!(state.get_user_balance("bob", "USDT"), 0);
+    }
 }
@@ -1,10 +1,284 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-use crate::order::Order;
+use crate::math::notional_balance;
+use crate::order::{Order, parse_pair};
+
+// Net balance change per (user_id, token)
+pub type BalanceDeltas = BTreeMap<(String, String), i128>;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MatchedTrace {
     pub buy_order: Order,
     pub sell_order: Order,
     pub matched_amount: u64,
+    // Price the trade executed at, the resting order's, in the orders' price scale
+    #[serde(default)]
+    pub matched_price: u64,
+}
+
+impl MatchedTrace {
+    // Structural checks every settled trace must pass. The orders are snapshots taken before
+    // this fill, so the matched amount can't exceed what either one had left.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.buy_order.side {
+            return Err("trace buy order must be a buy".to_string());
+        }
+        if self.sell_order.side {
+            return Err("trace sell order must be a sell".to_string());
+        }
+        if self.buy_order.pair_id != self.sell_order.pair_id {
+            return Err("trace orders must trade the same pair".to_string());
+        }
+        if self.buy_order.price_decimals != self.sell_order.price_decimals {
+            return Err("trace orders must use the same price scale".to_string());
+        }
+        if self.matched_price < self.sell_order.price || self.matched_price > self.buy_order.price {
+            return Err(format!(
+                "trace price {} outside the orders' limits {} to {}",
+                self.matched_price, self.sell_order.price, self.buy_order.price
+            ));
+        }
+        // Saturating: a forged snapshot may claim more filled than its amount
+        let remaining = [&self.buy_order, &self.sell_order]
+            .iter()
+            .map(|order| order.amount.saturating_sub(order.filled_amount))
+            .min()
+            .unwrap_or(0);
+        if self.matched_amount > remaining {
+            return Err(format!(
+                "trace matched amount {} exceeds remaining {}",
+                self.matched_amount, remaining
+            ));
+        }
+        Ok(())
+    }
+
+    // Identifies the fill: the order snapshots record how much of each had filled before it,
+    // which differs between any two fills of the same orders
+    pub fn id(&self) -> String {
+        format!(
+            "{}:{}:{}:{}",
+            self.buy_order.id,
+            self.sell_order.id,
+            self.buy_order.filled_amount,
+            self.sell_order.filled_amount
+        )
+    }
+
+    // Quote paid for the trade: the matched amount at the matched price, rounded down
+    pub fn quote_amount(&self) -> Result<u64, String> {
+        notional_balance(
+            self.matched_amount,
+            self.matched_price,
+            self.buy_order.price_decimals,
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    // Quote the buy locked at its limit price for this fill: the difference of its locks before
+    // and after the fill, so the locks of all its fills add up to the order's even as they round
+    pub fn quote_lock(&self) -> u64 {
+        let buy_order = &self.buy_order;
+        let open = buy_order.remaining_amount();
+        buy_order.quote_locked(open)
+            - buy_order.quote_locked(open.saturating_sub(self.matched_amount))
+    }
+
+    /// Part of the fill's `quote_lock` the trade doesn't pay, when the buy traded below its
+    /// limit price, i.e. took a cheaper resting ask. The exchange refunds it as soon as the
+    /// fill is matched; settlement releases the rest of the lock, which covers the payment.
+    pub fn price_improvement(&self) -> u64 {
+        if self.matched_price >= self.buy_order.price {
+            return 0;
+        }
+        self.quote_lock()
+            .saturating_sub(self.quote_amount().unwrap_or(u64::MAX))
+    }
+
+    /// What the fill keeps frozen until its block settles, as (user, token, amount): the buy's
+    /// quote lock less the price improvement refunded at match time, and the sold base amount.
+    pub fn settlement_locks(&self) -> [(&str, &str, u64); 2] {
+        [
+            (
+                &self.buy_order.user_id,
+                &self.buy_order.token_b,
+                self.quote_lock() - self.price_improvement(),
+            ),
+            (
+                &self.sell_order.user_id,
+                &self.sell_order.token_a,
+                self.matched_amount,
+            ),
+        ]
+    }
+
+    /// Base and quote token of the trace's pair, checked against the tokens both orders were
+    /// placed for: settlement moves the pair's tokens, so an order whose `token_a` and
+    /// `token_b` aren't the pair's base and quote would settle tokens it never locked.
+    pub fn settled_tokens(&self) -> Result<(String, String), String> {
+        let (base_token, quote_token) =
+            parse_pair(&self.buy_order.pair_id).map_err(|e| e.to_string())?;
+        for order in [&self.buy_order, &self.sell_order] {
+            if order.token_a != base_token || order.token_b != quote_token {
+                return Err(format!(
+                    "order {} trades {}/{}, not base {} and quote {} of pair {}",
+                    order.id,
+                    order.token_a,
+                    order.token_b,
+                    base_token,
+                    quote_token,
+                    self.buy_order.pair_id
+                ));
+            }
+        }
+        Ok((base_token, quote_token))
+    }
+
+    // Add this trace's balance changes: the buyer gets the base token and pays the quote
+    // token, the seller the reverse
+    pub fn add_deltas(&self, deltas: &mut BalanceDeltas) -> Result<(), String> {
+        let (base_token, quote_token) = self.settled_tokens()?;
+        let amount = self.matched_amount as i128;
+        let quote_amount = self.quote_amount()? as i128;
+        let buyer = &self.buy_order.user_id;
+        let seller = &self.sell_order.user_id;
+        for (user_id, token, delta) in [
+            (buyer, &base_token, amount),
+            (seller, &base_token, -amount),
+            (buyer, &quote_token, -quote_amount),
+            (seller, &quote_token, quote_amount),
+        ] {
+            *deltas.entry((user_id.clone(), token.clone())).or_insert(0) += delta;
+        }
+        Ok(())
+    }
+}
+
+/// Balance moved from one user to another off the book, e.g. an OTC settlement. The exchange
+/// applies it right away and seals it into the next block, so the prover replays it as well.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transfer {
+    pub from: String,
+    pub to: String,
+    pub token: String,
+    pub amount: u64,
+}
+
+impl Transfer {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.amount == 0 {
+            return Err("transfer amount must be greater than zero".to_string());
+        }
+        if self.from == self.to {
+            return Err("transfer sender and receiver must differ".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn add_deltas(&self, deltas: &mut BalanceDeltas) {
+        let amount = self.amount as i128;
+        for (user_id, delta) in [(&self.from, -amount), (&self.to, amount)] {
+            *deltas
+                .entry((user_id.clone(), self.token.clone()))
+                .or_insert(0) += delta;
+        }
+    }
+}
+
+/// Net balance changes of a block of traces, so the block can be settled as a whole.
+pub fn settlement_deltas(traces: &[MatchedTrace]) -> Result<BalanceDeltas, String> {
+    let mut deltas = BalanceDeltas::new();
+    for trace in traces {
+        trace.add_deltas(&mut deltas)?;
+    }
+    Ok(deltas)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FundingKind {
+    Deposit,
+    Withdrawal,
+}
+
+/// Tokens entering or leaving the exchange. Like a transfer, the exchange applies it right
+/// away and seals it into the next block, so the block's root is one the prover can reproduce.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Funding {
+    pub kind: FundingKind,
+    pub user_id: String,
+    pub token: String,
+    pub amount: u64,
+}
+
+impl Funding {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.amount == 0 {
+            return Err(match self.kind {
+                FundingKind::Deposit => "deposit amount must be greater than zero".to_string(),
+                FundingKind::Withdrawal => {
+                    "withdrawal amount must be greater than zero".to_string()
+                }
+            });
+        }
+        Ok(())
+    }
+
+    pub fn add_deltas(&self, deltas: &mut BalanceDeltas) {
+        let amount = match self.kind {
+            FundingKind::Deposit => self.amount as i128,
+            FundingKind::Withdrawal => -(self.amount as i128),
+        };
+        *deltas
+            .entry((self.user_id.clone(), self.token.clone()))
+            .or_insert(0) += amount;
+    }
+}
+
+/// Net balance changes of a block's traces, transfers and funding, settled together.
+pub fn block_deltas(
+    traces: &[MatchedTrace],
+    transfers: &[Transfer],
+    funding: &[Funding],
+) -> Result<BalanceDeltas, String> {
+    let mut deltas = settlement_deltas(traces)?;
+    for transfer in transfers {
+        transfer.add_deltas(&mut deltas);
+    }
+    for funding in funding {
+        funding.add_deltas(&mut deltas);
+    }
+    Ok(deltas)
+}
+
+/// One action of a pair's matching engine, in the order the engine took them. Replaying a
+/// pair's events on an empty book reproduces its book and trades, fills included, so a
+/// verifier can check the whole book evolution rather than only the trades settled.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum EngineEvent {
+    // An order taken into its pair's book, as it was handed to the engine before matching
+    Accepted(Order),
+    // `quantity` traded at `price` between two orders, as the engine matched them
+    Fill {
+        pair_id: String,
+        buy_order_id: String,
+        sell_order_id: String,
+        price: u64,
+        quantity: u64,
+    },
+    // An order cancelled, or only shrunk by `reduce_by`
+    Cancelled {
+        pair_id: String,
+        order_id: String,
+        reduce_by: Option<u64>,
+    },
+}
+
+impl EngineEvent {
+    pub fn pair_id(&self) -> &str {
+        match self {
+            EngineEvent::Accepted(order) => &order.pair_id,
+            EngineEvent::Fill { pair_id, .. } | EngineEvent::Cancelled { pair_id, .. } => pair_id,
+        }
+    }
 }
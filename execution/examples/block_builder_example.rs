@@ -5,8 +5,8 @@ use tokio::time::sleep;
 // Use the crate's modules directly
 use common::order::Order;
 use common::traces::MatchedTrace;
-use execution::exchange::MATCHED_TRACES;
 use execution::block::block_builder::BlockBuilder;
+use execution::exchange::MATCHED_TRACES;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -48,6 +48,7 @@ async fn main() -> Result<()> {
             buy_order,
             sell_order,
             matched_amount: 100,
+            matched_price: 1000 + i,
         };
 
         // Add to global MATCHED_TRACES
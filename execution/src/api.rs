@@ -0,0 +1,384 @@
+// Request and response types of the exchange REST API, shared by the server and the client
+use crate::exchange::matching::{BookDiff, Fill};
+use crate::exchange::mempool::FrozenDiscrepancy;
+use crate::exchange::stats::LatencySummary;
+use crate::exchange::tiers::TierLimits;
+use crate::exchange::trade_log::TradeRecord;
+use common::block::Block;
+use common::order::{Order, OrderStatus};
+use common::state::MerkleProof;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DepositRequest {
+    pub user_id: String,
+    pub token: String,
+    pub amount: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WithdrawRequest {
+    pub user_id: String,
+    pub token: String,
+    pub amount: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferRequest {
+    pub from: String,
+    pub to: String,
+    pub token: String,
+    pub amount: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlaceOrderRequest {
+    pub user_id: String,
+    pub pair_id: String,
+    pub amount: u64,
+    // In the pair's price scale; or leave it out and give `decimal_price`
+    #[serde(default)]
+    pub price: u64,
+    // Price in whole quote tokens per whole base token, e.g. "123.45", converted to the
+    // pair's price scale
+    #[serde(default)]
+    pub decimal_price: Option<String>,
+    pub side: bool, // true for buy, false for sell
+    // Makes it a stop-limit order, entering the book once a trade prints at this price
+    #[serde(default)]
+    pub trigger_price: Option<u64>,
+    // The client's own id for the order, unique per user; cancels and lookups can use it
+    #[serde(default)]
+    pub client_order_id: Option<String>,
+}
+
+// An order to place, and how long to wait for it to be filled, cancelled or settled
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlaceAndWaitRequest {
+    #[serde(flatten)]
+    pub order: PlaceOrderRequest,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+// Cancel open order `order_id` and place `new_order` in its place, or neither
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplaceOrderRequest {
+    pub pair_id: String,
+    pub order_id: String,
+    pub new_order: PlaceOrderRequest,
+}
+
+// Names the order either by `order_id` or by the `user_id` and `client_order_id` it was
+// placed with
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelOrderRequest {
+    pub pair_id: String,
+    #[serde(default)]
+    pub order_id: Option<String>,
+    #[serde(default)]
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub client_order_id: Option<String>,
+    pub reduce_by: Option<u64>, // shrink the remaining size instead of a full cancel
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelBatchRequest {
+    pub pair_id: String,
+    pub order_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetBalanceRequest {
+    pub user_id: String,
+    pub token: String,
+    // Also return the state root and the user's inclusion proof, which costs a tree walk
+    #[serde(default)]
+    pub with_proof: bool,
+    // Also return amounts as decimal strings of whole tokens, see `DecimalQuery`
+    #[serde(default)]
+    pub decimal: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PortfolioRequest {
+    pub user_id: String,
+    #[serde(default)]
+    pub decimal: bool,
+}
+
+// `?decimal=true` on the GET balance and portfolio routes: next to each amount in base units,
+// also return it in whole tokens as a decimal string, for tokens whose decimals are configured
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DecimalQuery {
+    #[serde(default)]
+    pub decimal: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetOrderRequest {
+    pub pair_id: String,
+    // By id, or by `user_id` and `client_order_id` as in `CancelOrderRequest`
+    #[serde(default)]
+    pub order_id: Option<String>,
+    #[serde(default)]
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub client_order_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetOrderBookRequest {
+    pub pair_id: String,
+}
+
+// Page of the trade history, newest first. Every field is optional
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GetTradesRequest {
+    pub pair_id: Option<String>,
+    pub start_time: Option<u64>, // unix millis, inclusive
+    pub end_time: Option<u64>,   // unix millis, inclusive
+    pub before_id: Option<u64>, // only trades past this one in page order, e.g. the previous page's next_before_id
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReconcileRequest {
+    pub user_id: String,
+    #[serde(default)]
+    pub fix: bool, // overwrite drifted frozen balances with the expected amount
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PairHaltRequest {
+    pub pair_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetTierRequest {
+    pub name: String,
+    #[serde(flatten)]
+    pub limits: TierLimits,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AssignTierRequest {
+    pub user_id: String,
+    pub tier: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubmitEvmTxnRequest {
+    pub rlp_data: String, // Hex-encoded RLP transaction data
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiResponse<T> {
+    pub success: bool,
+    pub data: Option<T>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlaceOrderResponse {
+    pub order_id: String,
+    #[serde(default)]
+    pub client_order_id: Option<String>,
+    pub fills: Vec<Fill>,
+    pub status: OrderStatus,
+}
+
+// The order a replace cancelled and the one it placed, see /order/replace
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplaceOrderResponse {
+    pub replaced: Order,  // the old order, now cancelled
+    pub order_id: String, // id of the new order
+    pub fills: Vec<Fill>,
+    pub status: OrderStatus,
+}
+
+// A placed order as it was when the wait for it ended, see /order/place_and_wait
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlaceAndWaitResponse {
+    pub order_id: String,
+    #[serde(default)]
+    pub client_order_id: Option<String>,
+    pub fills: Vec<Fill>, // fills on placement, as for /order/place
+    pub order: Order,
+    pub timed_out: bool, // the order was still open when the wait ended
+}
+
+// What placing an order would do right now, see /order/simulate
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SimulateOrderResponse {
+    pub fills: Vec<Fill>,
+    pub filled_amount: u64,
+    pub average_price: Option<f64>, // quantity-weighted price of the fills, None without fills
+    pub resting_remaining: u64,
+    pub status: OrderStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelBatchResult {
+    pub order_id: String,
+    pub success: bool,
+    pub order: Option<Order>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BalanceResponse {
+    pub balance: u64,
+    // Set when requested with `decimal` and the token's decimals are known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance_decimal: Option<String>,
+    // Set when requested with `with_proof`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_root: Option<[u8; 32]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof: Option<MerkleProof>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenBalance {
+    pub token: String,
+    pub total: u64,
+    pub frozen: u64,    // locked by open orders
+    pub available: u64, // total - frozen
+    // The amounts above as decimal strings, set like `BalanceResponse::balance_decimal`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_decimal: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frozen_decimal: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub available_decimal: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PortfolioResponse {
+    pub user_id: String,
+    pub balances: Vec<TokenBalance>, // sorted by token
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderBookResponse {
+    pub best_bid: Option<u64>,
+    pub best_ask: Option<u64>,
+    pub checksum: u32, // see OrderBook::checksum
+    pub seq: u64,      // see OrderBook::seq
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TradesResponse {
+    pub trades: Vec<TradeRecord>,
+    pub next_before_id: Option<u64>, // cursor for the next page, None once the history is exhausted
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BookStats {
+    pub pair_id: String,
+    pub bids: usize, // resting buy orders
+    pub asks: usize, // resting sell orders
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatsResponse {
+    pub matching_latency: LatencySummary,
+    pub books: Vec<BookStats>,
+    pub pending_traces: usize, // matched traces waiting for the block builder
+    pub blocks_per_minute: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReconcileResponse {
+    pub user_id: String,
+    pub discrepancies: Vec<FrozenDiscrepancy>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PairHaltResponse {
+    pub pair_id: String,
+    pub halted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct L3Order {
+    pub order_id: String,
+    pub user_id: String,
+    pub price: u64,
+    pub remaining_amount: u64,
+    pub priority: usize, // 0 = next to match on its side
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct L3OrderBookResponse {
+    pub bids: Vec<L3Order>,
+    pub asks: Vec<L3Order>,
+    pub seq: u64,
+}
+
+// Asks for what changed in a book since the state at `seq`, served by /orderbook/l3 or a
+// previous resync
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResyncRequest {
+    pub pair_id: String,
+    pub seq: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResyncResponse {
+    pub from_seq: u64,
+    pub seq: u64,      // the state the diff leads to, to resync from next time
+    pub checksum: u32, // of that state, see OrderBookResponse
+    pub diff: BookDiff,
+}
+
+// Asks for a pair's book as it was when block `block_num` was produced
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BookAtBlockRequest {
+    pub pair_id: String,
+    pub block_num: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BookAtBlockResponse {
+    pub block_num: u64,
+    pub recorded_at: u64, // block the book was recorded at, the latest one at or before block_num
+    pub seq: u64,
+    pub bids: Vec<L3Order>,
+    pub asks: Vec<L3Order>,
+}
+
+// A sealed block, see GET /block/:num. `common::block::verify_block` checks its txns_root
+// against its txns locally
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockResponse {
+    pub block: Block,
+    pub hash: [u8; 32], // `Block::hash`, over the block number and its two roots
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubmitEvmTxnResponse {
+    pub tx_hash: String,
+}
+
+impl<T> ApiResponse<T> {
+    pub fn success(data: T) -> Self {
+        Self {
+            success: true,
+            data: Some(data),
+            error: None,
+        }
+    }
+
+    pub fn error(error: String) -> Self {
+        Self {
+            success: false,
+            data: None,
+            error: Some(error),
+        }
+    }
+}
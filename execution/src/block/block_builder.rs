@@ -1,29 +1,102 @@
 use anyhow::Result;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tiny_keccak::{Hasher, Sha3};
-use tokio::sync::RwLock;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::{RwLock, broadcast};
 use tokio::time::sleep;
 
-use crate::exchange::MATCHED_TRACES;
 use crate::exchange::STATE;
-use common::block::Block;
-use common::traces::MatchedTrace;
+use crate::exchange::book_history::BookHistory;
+use crate::exchange::funds_log::FundsMove;
+use crate::exchange::matching::OrderBook;
+use crate::exchange::mempool::MEMPOOL;
+use crate::exchange::{
+    ENGINE_EVENTS, FUNDS_LOG, MATCHED_TRACES, PENDING_FUNDING, PENDING_TRANSFERS, SETTLING_TRACES,
+    STATS, order_span, trace_backlog_depth,
+};
+use common::block::{
+    Block, balance_history_key, calculate_events_root, calculate_txns_root, order_updates,
+};
+use common::clock::{Clock, SystemClock};
+use common::db::{BLOCKS_TREE, SEALED_BALANCES_KEY, open_db};
+use common::genesis::Genesis;
+use common::state::{Account, read_sealed_balances};
+use common::traces::{
+    BalanceDeltas, EngineEvent, Funding, MatchedTrace, Transfer, settlement_deltas,
+};
 
 static MAX_TXN_SIZE: u64 = 100;
 static BLOCK_TIME_INTERVAL: Duration = Duration::from_millis(200);
+// Names the cumulative quote notional that seals a block early, see `max_block_notional`
+pub static MAX_BLOCK_NOTIONAL_ENV: &str = "MAX_BLOCK_NOTIONAL";
+// Write-ahead log of traces drained from MATCHED_TRACES but not yet sealed into a block
+static PENDING_TRACES_KEY: &str = "pending_traces";
+// Next to it, the engine events drained with those traces
+static PENDING_EVENTS_KEY: &str = "pending_events";
+// Last entry of the funds log the sealed balances cover
+static SEALED_FUNDS_SEQ_KEY: &str = "sealed_funds_seq";
+// Sealed block events kept for a subscriber that falls behind before it misses some
+pub const SEALED_BLOCK_EVENTS_CAPACITY: usize = 64;
+// Latest sealed blocks handed to a new subscriber to catch up from
+pub const RECENT_SEALED_BLOCKS: usize = 16;
+
+// Balances a block's state root was computed from, and the last funds log entry they cover
+type SettledBalances = (HashMap<String, Account>, u64);
+
+/// Published once a block is sealed and flushed, so a prover or L1 submitter can pick it up
+/// without polling the block store. Also the header light clients follow the chain by.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SealedBlock {
+    pub block_num: u128,
+    pub state_root: Option<[u8; 32]>,
+    pub txns_root: Option<[u8; 32]>,
+    pub block_hash: [u8; 32],
+}
+
+impl From<&Block> for SealedBlock {
+    fn from(block: &Block) -> Self {
+        Self {
+            block_num: block.block_num,
+            state_root: block.state_root,
+            txns_root: block.txns_root,
+            block_hash: block.hash(),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct BlockBuilder {
-    pub db: sled::Db,
+    pub db: sled::Tree,
     pub current_block_num: Arc<RwLock<u128>>,
-    pub last_block_time: Arc<RwLock<Instant>>,
+    // When the last block was sealed, in `clock`'s milliseconds
+    pub last_block_time: Arc<RwLock<u64>>,
+    // Times the block interval, the wall clock unless `with_clock` replaced it
+    clock: Arc<dyn Clock>,
+    // Balances as of the last sealed block, to record only what changed in the next one
+    pub sealed_balances: Arc<RwLock<HashMap<String, Account>>>,
+    // Balances each created but not yet saved block's state root was computed from, with the
+    // last funds log entry they cover, by block number, so its balance history matches its root
+    settled_balances: Arc<Mutex<HashMap<u128, SettledBalances>>>,
+    // Seal a block as soon as its trades' quote notional adds up to this, without waiting for
+    // `MAX_TXN_SIZE` trades or the block interval. Unset, only count and time seal blocks
+    pub max_block_notional: Option<u128>,
+    sealed_blocks: broadcast::Sender<SealedBlock>,
+    // The last `RECENT_SEALED_BLOCKS` events, oldest first. Locked while an event is sent, so
+    // a subscriber gets each block either here or from its receiver, never both
+    recent_sealed: Arc<Mutex<VecDeque<SealedBlock>>>,
+    // Each pair's resting orders as blocks were produced
+    book_history: BookHistory,
 }
 
 impl BlockBuilder {
     pub fn new(db_path: &str) -> Result<Self> {
-        let db = sled::open(db_path)?;
+        Self::with_db(&open_db(db_path)?)
+    }
 
+    /// Builder keeping its blocks in their own tree of `db`, next to the other subsystems' trees.
+    pub fn with_db(db: &sled::Db) -> Result<Self> {
+        let book_history = BookHistory::open(db)?;
+        let db = db.open_tree(BLOCKS_TREE)?;
         // Initialize block number from database or start from 0
         let current_block_num = match db.get("latest_block_num")? {
             Some(bytes) => {
@@ -36,52 +109,124 @@ impl BlockBuilder {
             None => 0,
         };
 
+        let mut recent_sealed = VecDeque::new();
+        let first_recent = current_block_num
+            .saturating_sub(RECENT_SEALED_BLOCKS as u128 - 1)
+            .max(1);
+        for block_num in first_recent..=current_block_num {
+            if let Some(block_data) = db.get(format!("block_{}", block_num))? {
+                let block: Block = serde_json::from_slice(&block_data)
+                    .map_err(|e| anyhow::anyhow!("Failed to deserialize block: {}", e))?;
+                recent_sealed.push_back(SealedBlock::from(&block));
+            }
+        }
+
         Ok(BlockBuilder {
             db,
             current_block_num: Arc::new(RwLock::new(current_block_num)),
-            last_block_time: Arc::new(RwLock::new(Instant::now())),
+            last_block_time: Arc::new(RwLock::new(SystemClock.now_millis())),
+            clock: Arc::new(SystemClock),
+            sealed_balances: Arc::new(RwLock::new(HashMap::new())),
+            settled_balances: Arc::new(Mutex::new(HashMap::new())),
+            max_block_notional: None,
+            sealed_blocks: broadcast::channel(SEALED_BLOCK_EVENTS_CAPACITY).0,
+            recent_sealed: Arc::new(Mutex::new(recent_sealed)),
+            book_history,
         })
     }
 
+    /// Time block intervals with `clock` instead of the wall clock, counting the first one
+    /// from its current time.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last_block_time = Arc::new(RwLock::new(clock.now_millis()));
+        self.clock = clock;
+        self
+    }
+
+    /// Receive an event for every block sealed from now on, in block order. A receiver more
+    /// than `SEALED_BLOCK_EVENTS_CAPACITY` blocks behind gets `RecvError::Lagged` and skips
+    /// ahead; the skipped blocks can still be read with `get_block`.
+    pub fn subscribe(&self) -> broadcast::Receiver<SealedBlock> {
+        self.sealed_blocks.subscribe()
+    }
+
+    /// Like `subscribe`, also returning the latest `RECENT_SEALED_BLOCKS` blocks sealed before
+    /// it, oldest first. The receiver picks up right after the last of them.
+    pub fn subscribe_with_recent(&self) -> (Vec<SealedBlock>, broadcast::Receiver<SealedBlock>) {
+        let recent_sealed = self
+            .recent_sealed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        (
+            recent_sealed.iter().cloned().collect(),
+            self.sealed_blocks.subscribe(),
+        )
+    }
+
     /// Async method to continuously monitor MATCHED_TRACES and generate blocks
     pub async fn start_block_generation(&self) -> Result<()> {
+        // Seal whatever a previous run settled but never sealed
+        self.recover().await?;
+
         let mut pending_traces = Vec::new();
+        let mut pending_events = Vec::new();
 
         loop {
-            // Read current matched traces
-            let traces = {
+            // Read current matched traces and the events that produced them
+            let (traces, events) = {
                 let mut traces_lock = MATCHED_TRACES.write().await;
                 let current_traces = traces_lock.clone();
                 traces_lock.clear(); // Clear processed traces
-                current_traces
+                SETTLING_TRACES
+                    .write()
+                    .await
+                    .extend(current_traces.iter().cloned());
+                let current_events: Vec<EngineEvent> =
+                    ENGINE_EVENTS.write().await.drain(..).collect();
+                (current_traces, current_events)
             };
 
-            // Add new traces to pending
-            pending_traces.extend(traces);
-
-            let should_generate_block = {
-                let last_time = *self.last_block_time.read().await;
-                let time_elapsed = last_time.elapsed() >= BLOCK_TIME_INTERVAL;
-                let txn_count_reached = pending_traces.len() as u64 >= MAX_TXN_SIZE;
-
-                (time_elapsed || txn_count_reached) && !pending_traces.is_empty()
-            };
+            // Add new traces to pending, logging them before any settlement is applied
+            if !traces.is_empty() || !events.is_empty() {
+                pending_traces.extend(traces);
+                pending_events.extend(events);
+                self.write_wal(&pending_traces, &pending_events)?;
+            }
 
-            if should_generate_block {
+            if self.seal_due(&pending_traces).await {
                 // Generate and save block
                 // NOTE: Delayed block creation(async), using memory pool consensus?
-                let block = self.create_block(pending_traces.clone()).await?;
+                let block = match self
+                    .create_block(pending_traces.clone(), pending_events.clone())
+                    .await
+                {
+                    Ok(block) => block,
+                    Err(e) => {
+                        // Nothing was applied. Drop the traces the state can't cover, e.g. of
+                        // a user whose funds left between matching and settlement, and settle
+                        // the rest next time around
+                        let dropped =
+                            drop_unsettleable(&mut pending_traces, &mut pending_events).await;
+                        tracing::error!("Dropped {} pending traces: {}", dropped.len(), e);
+                        self.write_wal(&pending_traces, &pending_events)?;
+                        continue;
+                    }
+                };
                 self.save_block(&block).await?;
 
-                log::info!(
-                    "Generated block #{} with {} transactions",
+                tracing::info!(
+                    "Generated block #{} with {} transactions, {} transfers and {} deposits or withdrawals, trace backlog: {}",
                     block.block_num,
-                    block.txns.len()
+                    block.txns.len(),
+                    block.transfers.len(),
+                    block.funding.len(),
+                    trace_backlog_depth().await
                 );
 
-                // Clear pending traces and update last block time
+                // Clear pending traces and events and update last block time
                 pending_traces.clear();
-                *self.last_block_time.write().await = Instant::now();
+                pending_events.clear();
+                *self.last_block_time.write().await = self.clock.now_millis();
             }
 
             // Sleep for a short interval before checking again
@@ -89,108 +234,366 @@ impl BlockBuilder {
         }
     }
 
-    /// Create a new block with the given transactions
-    async fn create_block(&self, txns: Vec<MatchedTrace>) -> Result<Block> {
+    /// Whether a block should be sealed from `pending_traces` and the funds moved since the
+    /// last one: once the block interval passed, `MAX_TXN_SIZE` trades are pending or their
+    /// quote notional reached `max_block_notional`, and there's anything to seal.
+    async fn seal_due(&self, pending_traces: &[MatchedTrace]) -> bool {
+        let last_time = *self.last_block_time.read().await;
+        let time_elapsed = self.clock.now_millis().saturating_sub(last_time)
+            >= BLOCK_TIME_INTERVAL.as_millis() as u64;
+        let txn_count_reached = pending_traces.len() as u64 >= MAX_TXN_SIZE;
+        let notional_reached = self
+            .max_block_notional
+            .is_some_and(|limit| block_notional(pending_traces) >= limit);
+        let has_moved_funds =
+            !PENDING_TRANSFERS.read().await.is_empty() || !PENDING_FUNDING.read().await.is_empty();
+
+        (time_elapsed || txn_count_reached || notional_reached)
+            && (!pending_traces.is_empty() || has_moved_funds)
+    }
+
+    /// Create a new block with the given transactions. The block is settled as a whole: the
+    /// net balance change of all its traces is applied atomically, or the block is rejected.
+    /// Traces already settled since the last seal, e.g. replayed from the write-ahead log, are
+    /// sealed without being settled again. It also seals the transfers applied since the
+    /// previous block, and the engine `events` as they were logged.
+    async fn create_block(
+        &self,
+        txns: Vec<MatchedTrace>,
+        events: Vec<EngineEvent>,
+    ) -> Result<Block> {
+        // Drop malformed traces instead of failing on them while the state lock is held
+        let txns: Vec<MatchedTrace> = txns
+            .into_iter()
+            .filter(|trace| match check_trace(trace) {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::error!(
+                        "Dropping trace of orders {} and {}: {}",
+                        trace.buy_order.id,
+                        trace.sell_order.id,
+                        e
+                    );
+                    false
+                }
+            })
+            .collect();
+
+        let (transfers, funding, state_root, balances) = {
+            let mut state_db = STATE.write().await;
+            let unsettled: Vec<MatchedTrace> = txns
+                .iter()
+                .filter(|trace| !state_db.state.is_trace_released(trace))
+                .cloned()
+                .collect();
+            let deltas = settlement_deltas(&unsettled).map_err(|e| anyhow::anyhow!(e))?;
+            state_db
+                .state
+                .apply_deltas(&deltas)
+                .map_err(|e| anyhow::anyhow!("Rejected block: {}", e))?;
+
+            // Unfreeze what the fills locked
+            for trace in &unsettled {
+                state_db.state.release_trace(trace);
+            }
+            forget_settling(&txns).await;
+
+            // Transfers, deposits and withdrawals were applied when made; take them and the
+            // root under the same lock, so none lands in the root without being in the block
+            let transfers: Vec<Transfer> = PENDING_TRANSFERS.write().await.drain(..).collect();
+            let funding: Vec<Funding> = PENDING_FUNDING.write().await.drain(..).collect();
+            (
+                transfers,
+                funding,
+                state_db.state.calculate_state_root(),
+                (state_db.state.user_balances.clone(), FUNDS_LOG.last_seq()),
+            )
+        };
+
+        // Numbered once settled, so a rejected block doesn't leave a gap
         let mut block_num_lock = self.current_block_num.write().await;
         *block_num_lock += 1;
         let block_num = *block_num_lock;
         drop(block_num_lock);
+        self.settled_balances
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(block_num, balances);
 
-        {
-            let mut state_db = STATE.write().await;
-            for trace in &txns {
-                let tokens: Vec<&str> = trace.buy_order.pair_id.split('_').collect();
-                let base_token = tokens[0];
-                let quote_token = tokens[1];
-
-                state_db.state.add_user_balance(
-                    trace.buy_order.user_id.clone(),
-                    base_token.to_owned(),
-                    trace.matched_amount,
-                );
-                state_db.state.sub_user_balance(
-                    trace.sell_order.user_id.clone(),
-                    base_token.to_owned(),
-                    trace.matched_amount,
-                );
+        // Calc txns root
+        // NOTE: Refer to SUI or ETH/EIP-7862 to implement delayed state root calculation
+        let txns_root = calculate_txns_root(&txns, &transfers, &funding);
+        let events_root = calculate_events_root(&events);
 
-                state_db.state.sub_user_balance(
-                    trace.buy_order.user_id.clone(),
-                    quote_token.to_owned(),
-                    trace.matched_amount,
-                );
-                state_db.state.add_user_balance(
-                    trace.sell_order.user_id.clone(),
-                    quote_token.to_owned(),
-                    trace.matched_amount,
-                );
+        let order_updates = order_updates(&txns);
 
-                // unfreeze
-                state_db.state.unfreeze(
-                    trace.buy_order.user_id.clone(),
-                    quote_token.to_owned(),
-                    trace.matched_amount,
-                );
-                state_db.state.unfreeze(
-                    trace.sell_order.user_id.clone(),
-                    base_token.to_owned(),
-                    trace.matched_amount,
+        // Losing the record of a book doesn't undo the settlement, so it doesn't fail the block
+        for (pair_id, book) in MEMPOOL.read().await.resting_books().await {
+            if let Err(e) = self.book_history.record(block_num, &pair_id, &book) {
+                tracing::error!(
+                    "Failed to record the {} book at block {}: {}",
+                    pair_id,
+                    block_num,
+                    e
                 );
             }
         }
 
-        // Calc state root using read lock.
-        let state_root = {
-            let state_db = STATE.read().await;
-            state_db.state.calculate_state_root()
-        };
-
-        // Calc txns root
-        // NOTE: Refer to SUI or ETH/EIP-7862 to implement delayed state root calculation
-        let txns_root = self.calculate_txns_root(&txns);
-
         Ok(Block {
             block_num,
             txns,
+            transfers,
+            funding,
             txns_root: Some(txns_root),
             state_root: state_root,
+            order_updates,
+            events,
+            events_root: Some(events_root),
         })
     }
 
+    /// Resting orders of `pair_id`'s book when block `block_num` was produced, taken from the
+    /// latest record at or before it, with the block of that record.
+    pub fn book_at(&self, pair_id: &str, block_num: u128) -> Result<Option<(u128, OrderBook)>> {
+        self.book_history.at(pair_id, block_num)
+    }
+
+    /// Seal `genesis` as block 0 on the chain's first start, putting its balances in the state,
+    /// so block 1 chains from the genesis root. Later starts only check that the stored block 0
+    /// still matches the config.
+    pub async fn seal_genesis(&self, genesis: &Genesis) -> Result<Block> {
+        let hash_scheme = STATE.read().await.state.hash_scheme;
+        let genesis_root = genesis.state_root(hash_scheme);
+        if let Some(block) = self.get_block(0).await? {
+            if block.state_root != Some(genesis_root) {
+                return Err(anyhow::anyhow!(
+                    "Genesis config doesn't match the chain's genesis block"
+                ));
+            }
+            return Ok(block);
+        }
+        if self.get_latest_block_num().await > 0 || !self.read_wal()?.0.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Chain already has blocks or traces but no genesis block"
+            ));
+        }
+
+        {
+            let mut state_db = STATE.write().await;
+            for (user_id, balances) in &genesis.balances {
+                for (token, amount) in balances {
+                    state_db
+                        .state
+                        .set_user_balance(user_id.clone(), token.clone(), *amount);
+                }
+            }
+            self.settled_balances
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(
+                    0,
+                    (state_db.state.user_balances.clone(), FUNDS_LOG.last_seq()),
+                );
+        }
+        let block = Block {
+            block_num: 0,
+            txns: vec![],
+            transfers: vec![],
+            funding: vec![],
+            txns_root: Some(calculate_txns_root(&[], &[], &[])),
+            state_root: Some(genesis_root),
+            order_updates: vec![],
+            events: vec![],
+            events_root: None,
+        };
+        self.save_block(&block).await?;
+        tracing::info!(
+            "Sealed genesis block with {} accounts",
+            genesis.balances.len()
+        );
+        Ok(block)
+    }
+
     /// Save block to local storage using sled
     async fn save_block(&self, block: &Block) -> Result<()> {
         // Serialize block
         let block_data = serde_json::to_vec(block)
             .map_err(|e| anyhow::anyhow!("Failed to serialize block: {}", e))?;
 
+        // Save block, latest block number and the balances as of its root, and clear the
+        // write-ahead log atomically
+        let mut batch = sled::Batch::default();
+
         // Save block with key "block_{block_num}"
         let block_key = format!("block_{}", block.block_num);
-        self.db.insert(block_key.as_bytes(), block_data)?;
+        batch.insert(block_key.as_bytes(), block_data);
 
         // Update latest block number
         let block_num_bytes = block.block_num.to_be_bytes();
-        self.db.insert("latest_block_num", &block_num_bytes[..])?;
+        batch.insert("latest_block_num", &block_num_bytes[..]);
+
+        // Its traces and events are sealed now
+        batch.remove(PENDING_TRACES_KEY);
+        batch.remove(PENDING_EVENTS_KEY);
+
+        // Record every balance that changed since the previous sealed block, as of its root:
+        // funds moved since then belong to the next block
+        let (balances, funds_seq) = self
+            .settled_balances
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&block.block_num)
+            .ok_or_else(|| {
+                anyhow::anyhow!("Block {} wasn't created by this builder", block.block_num)
+            })?;
+        let sealed_state = serde_json::to_vec(&balances)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize sealed balances: {}", e))?;
+        batch.insert(SEALED_BALANCES_KEY, sealed_state);
+        batch.insert(SEALED_FUNDS_SEQ_KEY, &funds_seq.to_be_bytes()[..]);
+        let mut sealed_balances = self.sealed_balances.write().await;
+        for (user_id, account) in &balances {
+            for (token, balance) in &account.balances {
+                let sealed = sealed_balances
+                    .get(user_id)
+                    .map(|account| account.get_balance(token));
+                if sealed != Some(*balance) {
+                    batch.insert(
+                        balance_history_key(user_id, token, block.block_num),
+                        &balance.to_be_bytes()[..],
+                    );
+                }
+            }
+        }
+        self.db.apply_batch(batch)?;
+        *sealed_balances = balances;
 
         // Flush to ensure data is persisted
         self.db.flush()?;
+        STATS.record_block();
+        drop(sealed_balances);
+
+        // What's left of them is skipped on replay, so failing here loses nothing
+        if let Err(e) = FUNDS_LOG.forget_through(funds_seq) {
+            tracing::error!("Failed to trim the funds log: {}", e);
+        }
+
+        // Out of the write-ahead log now, so they can't be settled again
+        STATE
+            .write()
+            .await
+            .state
+            .forget_released_traces(&block.txns);
+
+        for trace in &block.txns {
+            for order in [&trace.buy_order, &trace.sell_order] {
+                order_span(&order.id).in_scope(|| {
+                    tracing::info!(block_num = block.block_num, "Order trace sealed in block")
+                });
+            }
+        }
+
+        // Orders completed in this block are final now
+        MEMPOOL.read().await.settle_traces(&block.txns).await;
+
+        let sealed = SealedBlock::from(block);
+        let mut recent_sealed = self
+            .recent_sealed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if recent_sealed.len() == RECENT_SEALED_BLOCKS {
+            recent_sealed.pop_front();
+        }
+        recent_sealed.push_back(sealed.clone());
+        // Nobody listening is fine, the block is stored either way
+        let _ = self.sealed_blocks.send(sealed);
 
         Ok(())
     }
 
-    /// Calculate txns root for the block (simplified implementation)
-    fn calculate_txns_root(&self, txns: &[MatchedTrace]) -> [u8; 32] {
-        let mut sha3 = Sha3::v256();
-        let mut output = [0u8; 32];
+    /// Persist the traces and events pending for the next block, so a crash before the block
+    /// is sealed doesn't lose trades that were already matched
+    fn write_wal(&self, traces: &[MatchedTrace], events: &[EngineEvent]) -> Result<()> {
+        let traces_data = serde_json::to_vec(traces)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize pending traces: {}", e))?;
+        let events_data = serde_json::to_vec(events)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize pending events: {}", e))?;
+        let mut batch = sled::Batch::default();
+        batch.insert(PENDING_TRACES_KEY, traces_data);
+        batch.insert(PENDING_EVENTS_KEY, events_data);
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn read_wal(&self) -> Result<(Vec<MatchedTrace>, Vec<EngineEvent>)> {
+        let traces = match self.db.get(PENDING_TRACES_KEY)? {
+            Some(data) => serde_json::from_slice(&data)
+                .map_err(|e| anyhow::anyhow!("Failed to deserialize pending traces: {}", e))?,
+            None => vec![],
+        };
+        let events = match self.db.get(PENDING_EVENTS_KEY)? {
+            Some(data) => serde_json::from_slice(&data)
+                .map_err(|e| anyhow::anyhow!("Failed to deserialize pending events: {}", e))?,
+            None => vec![],
+        };
+        Ok((traces, events))
+    }
+
+    /// Balances as of the last sealed block's root, `None` before the first block is sealed
+    pub fn read_sealed_state(&self) -> Result<Option<HashMap<String, Account>>> {
+        read_sealed_balances(&self.db)
+    }
 
-        // Hash all transactions in the block
-        for txn in txns {
-            if let Ok(txn_data) = serde_json::to_vec(txn) {
-                sha3.update(&txn_data);
+    /// Put the balances of the last sealed block back in the state on startup, with the
+    /// transfers, deposits and withdrawals acknowledged after it queued again for the next
+    /// block, so
+    /// `recover` replays the write-ahead log onto what it was logged against. Returns whether
+    /// there was a sealed block to restore.
+    pub async fn restore_state(&self) -> Result<bool> {
+        let Some(balances) = self.read_sealed_state()? else {
+            return Ok(false);
+        };
+        let sealed_funds_seq = match self.db.get(SEALED_FUNDS_SEQ_KEY)? {
+            Some(bytes) => u64::from_be_bytes(bytes.as_ref().try_into()?),
+            None => 0,
+        };
+        let mut state_db = STATE.write().await;
+        state_db.state.user_balances = balances.clone();
+        *self.sealed_balances.write().await = balances;
+        let moves = FUNDS_LOG.replay(sealed_funds_seq, &mut state_db.state)?;
+        FUNDS_LOG.forget_through(sealed_funds_seq)?;
+        if !moves.is_empty() {
+            tracing::warn!(
+                "Restored {} transfers, deposits and withdrawals not sealed yet",
+                moves.len()
+            );
+        }
+        for funds in moves {
+            match funds {
+                FundsMove::Transfer(transfer) => PENDING_TRANSFERS.write().await.push(transfer),
+                FundsMove::Funding(funding) => PENDING_FUNDING.write().await.push(funding),
             }
         }
+        Ok(true)
+    }
 
-        sha3.finalize(&mut output);
-        output
+    /// Replay traces left in the write-ahead log by a previous run into a new block. Run after
+    /// `restore_state`: traces are settled onto the last sealed balances, except those this
+    /// process already settled, which are only sealed.
+    pub async fn recover(&self) -> Result<Option<Block>> {
+        let (traces, events) = self.read_wal()?;
+        if traces.is_empty() && events.is_empty() {
+            return Ok(None);
+        }
+
+        tracing::warn!(
+            "Recovering {} unsealed traces from the write-ahead log",
+            traces.len()
+        );
+        let block = self.create_block(traces, events).await?;
+        self.save_block(&block).await?;
+        tracing::info!("Recovered block #{}", block.block_num);
+        Ok(Some(block))
     }
 
     /// Get a block by block number
@@ -207,6 +610,43 @@ impl BlockBuilder {
         }
     }
 
+    /// Balance of a user's token as of the sealed block `block_num`, unaffected by
+    /// settlement of the block currently being built
+    pub async fn get_balance_at_block(
+        &self,
+        user_id: &str,
+        token: &str,
+        block_num: u128,
+    ) -> Result<u64> {
+        let sealed_block_num = match self.db.get("latest_block_num")? {
+            Some(bytes) => u128::from_be_bytes(
+                bytes
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Invalid block number format"))?,
+            ),
+            None => 0,
+        };
+        if block_num > sealed_block_num {
+            return Err(anyhow::anyhow!("Block {} is not sealed yet", block_num));
+        }
+
+        // Latest change recorded at or before the block
+        let start = balance_history_key(user_id, token, 0);
+        let end = balance_history_key(user_id, token, block_num);
+        match self.db.range(start..=end).next_back() {
+            Some(entry) => {
+                let (_, value) = entry?;
+                let balance_bytes: [u8; 8] = value
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Invalid balance format"))?;
+                Ok(u64::from_be_bytes(balance_bytes))
+            }
+            None => Ok(0),
+        }
+    }
+
     /// Get the latest block number
     pub async fn get_latest_block_num(&self) -> u128 {
         *self.current_block_num.read().await
@@ -225,3 +665,1218 @@ impl BlockBuilder {
         Ok(blocks)
     }
 }
+
+// Why a trace can't be settled, if it can't
+fn check_trace(trace: &MatchedTrace) -> std::result::Result<(), String> {
+    trace.validate()?;
+    trace.settled_tokens()?;
+    Ok(())
+}
+
+// Quote notional of `traces` together. A trace whose notional doesn't fit counts for nothing,
+// it's dropped when the block is created anyway
+fn block_notional(traces: &[MatchedTrace]) -> u128 {
+    traces
+        .iter()
+        .map(|trace| trace.quote_amount().unwrap_or(0) as u128)
+        .sum()
+}
+
+// Drop the traces of `traces` the state can't settle, taking them in order and keeping each
+// that leaves no balance it touches negative on top of the ones kept before it. Their freezes
+// are released and their fills taken out of `events`, as if they were never matched. If none
+// is at fault, drops them all, so a block that fails anyway isn't retried forever.
+async fn drop_unsettleable(
+    traces: &mut Vec<MatchedTrace>,
+    events: &mut Vec<EngineEvent>,
+) -> Vec<MatchedTrace> {
+    let mut state_db = STATE.write().await;
+    let state = &mut state_db.state;
+    let mut kept_deltas = BalanceDeltas::new();
+    let (kept, mut dropped): (Vec<MatchedTrace>, Vec<MatchedTrace>) =
+        std::mem::take(traces).into_iter().partition(|trace| {
+            // Already applied, they're only left to be sealed
+            if state.is_trace_released(trace) {
+                return true;
+            }
+            let mut deltas = BalanceDeltas::new();
+            if trace.add_deltas(&mut deltas).is_err() {
+                return false;
+            }
+            let settles = deltas.iter().all(|(key, delta)| {
+                let (user_id, token) = key;
+                let balance = state.get_user_balance(user_id, token) as i128
+                    + kept_deltas.get(key).unwrap_or(&0)
+                    + delta;
+                u64::try_from(balance).is_ok()
+            });
+            if settles {
+                for (key, delta) in deltas {
+                    *kept_deltas.entry(key).or_insert(0) += delta;
+                }
+            }
+            settles
+        });
+    if dropped.is_empty() {
+        dropped = kept;
+    } else {
+        *traces = kept;
+    }
+
+    for trace in &dropped {
+        tracing::error!(
+            "Dropping trace of orders {} and {}, the state can't settle it",
+            trace.buy_order.id,
+            trace.sell_order.id
+        );
+        if let Some(i) = events.iter().position(|event| match event {
+            EngineEvent::Fill {
+                buy_order_id,
+                sell_order_id,
+                price,
+                quantity,
+                ..
+            } => {
+                *buy_order_id == trace.buy_order.id
+                    && *sell_order_id == trace.sell_order.id
+                    && *price == trace.matched_price
+                    && *quantity == trace.matched_amount
+            }
+            _ => false,
+        }) {
+            events.remove(i);
+        }
+    }
+    // Released but never sealed, so nothing is left to forget them later
+    for trace in &dropped {
+        state.release_trace(trace);
+    }
+    state.forget_released_traces(&dropped);
+    forget_settling(&dropped).await;
+    dropped
+}
+
+// Take `traces` out of SETTLING_TRACES once their locks are released, under STATE's write lock
+async fn forget_settling(traces: &[MatchedTrace]) {
+    let ids: HashSet<String> = traces.iter().map(MatchedTrace::id).collect();
+    SETTLING_TRACES
+        .write()
+        .await
+        .retain(|trace| !ids.contains(&trace.id()));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::{MAX_BODY_BYTES, create_exchange_router};
+    use axum::Extension;
+    use common::block::{OrderUpdate, block_hash};
+    use common::clock::MockClock;
+    use common::order::{Order, OrderStatus};
+    use futures::StreamExt;
+
+    fn trace(id: &str, amount: u64) -> MatchedTrace {
+        MatchedTrace {
+            buy_order: Order::new(
+                format!("{}_buy", id),
+                "wal_buyer".to_string(),
+                "WLA_WLB".to_string(),
+                amount,
+                1,
+                true,
+            ),
+            sell_order: Order::new(
+                format!("{}_sell", id),
+                "wal_seller".to_string(),
+                "WLA_WLB".to_string(),
+                amount,
+                1,
+                false,
+            ),
+            matched_amount: amount,
+            matched_price: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_balance_history_as_of_block_root() {
+        STATE.write().await.state.set_user_balance(
+            "history_user".to_string(),
+            "HSA".to_string(),
+            10,
+        );
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let block = builder.create_block(vec![], vec![]).await.unwrap();
+
+        // A deposit landing between settling the block and saving it is the next block's
+        crate::exchange::apply_funding(Funding {
+            kind: common::traces::FundingKind::Deposit,
+            user_id: "history_user".to_string(),
+            token: "HSA".to_string(),
+            amount: 5,
+        })
+        .await
+        .unwrap();
+        builder.save_block(&block).await.unwrap();
+
+        assert_eq!(
+            builder
+                .get_balance_at_block("history_user", "HSA", block.block_num)
+                .await
+                .unwrap(),
+            10
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recover_unsealed_traces() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let traces = vec![trace("wal_1", 3), trace("wal_2", 4)];
+        {
+            // Just enough to settle the traces once
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("wal_buyer".to_string(), "WLB".to_string(), 7);
+            state_db
+                .state
+                .set_user_balance("wal_seller".to_string(), "WLA".to_string(), 7);
+        }
+
+        // First run: traces are logged and settled, then the process dies before sealing
+        let expected = {
+            let builder = BlockBuilder::with_db(&db).unwrap();
+            builder.write_wal(&traces, &[]).unwrap();
+            builder.create_block(traces.clone(), vec![]).await.unwrap()
+        };
+        assert!(
+            db.open_tree(BLOCKS_TREE)
+                .unwrap()
+                .get("block_1")
+                .unwrap()
+                .is_none()
+        );
+
+        // Restart on the same storage
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let recovered = builder.recover().await.unwrap().unwrap();
+
+        assert_eq!(recovered.block_num, expected.block_num);
+        assert_eq!(recovered.txns_root, expected.txns_root);
+        assert_eq!(recovered.txns.len(), traces.len());
+        assert!(builder.get_block(1).await.unwrap().is_some());
+        assert_eq!(builder.get_latest_block_num().await, 1);
+
+        // Settled exactly once, and saved with the seal for a restart to resume from
+        let sealed_state = builder.read_sealed_state().unwrap().unwrap();
+        {
+            let state = &STATE.read().await.state;
+            for (user_id, token, amount) in [
+                ("wal_buyer", "WLA", 7),
+                ("wal_buyer", "WLB", 0),
+                ("wal_seller", "WLA", 0),
+                ("wal_seller", "WLB", 7),
+            ] {
+                assert_eq!(state.get_user_balance(user_id, token), amount);
+                assert_eq!(sealed_state[user_id].get_balance(token), amount);
+            }
+        }
+
+        // The log is cleared with the seal, so nothing is replayed twice
+        assert!(builder.recover().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_balance_at_sealed_block_is_stable() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let snapshot_trace = |id: &str, amount| MatchedTrace {
+            buy_order: Order::new(
+                format!("{}_buy", id),
+                "snap_buyer".to_string(),
+                "SNA_SNB".to_string(),
+                amount,
+                1,
+                true,
+            ),
+            sell_order: Order::new(
+                format!("{}_sell", id),
+                "snap_seller".to_string(),
+                "SNA_SNB".to_string(),
+                amount,
+                1,
+                false,
+            ),
+            matched_amount: amount,
+            matched_price: 1,
+        };
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("snap_buyer".to_string(), "SNB".to_string(), 100);
+            state_db
+                .state
+                .set_user_balance("snap_seller".to_string(), "SNA".to_string(), 100);
+        }
+
+        let block = builder
+            .create_block(vec![snapshot_trace("snap_1", 10)], vec![])
+            .await
+            .unwrap();
+        builder.save_block(&block).await.unwrap();
+        assert_eq!(
+            builder
+                .get_balance_at_block("snap_buyer", "SNA", 1)
+                .await
+                .unwrap(),
+            10
+        );
+
+        // Block 2 is settled into the live state but not sealed yet
+        let block = builder
+            .create_block(vec![snapshot_trace("snap_2", 5)], vec![])
+            .await
+            .unwrap();
+        {
+            let state_db = STATE.read().await;
+            assert_eq!(state_db.state.get_user_balance("snap_buyer", "SNA"), 15);
+        }
+        assert_eq!(
+            builder
+                .get_balance_at_block("snap_buyer", "SNA", 1)
+                .await
+                .unwrap(),
+            10
+        );
+        assert!(
+            builder
+                .get_balance_at_block("snap_buyer", "SNA", 2)
+                .await
+                .is_err()
+        );
+
+        builder.save_block(&block).await.unwrap();
+        for (user_id, token, at_block_1, at_block_2) in [
+            ("snap_buyer", "SNA", 10, 15),
+            ("snap_buyer", "SNB", 90, 85),
+            ("snap_seller", "SNA", 90, 85),
+            ("snap_seller", "SNB", 10, 15),
+        ] {
+            assert_eq!(
+                builder
+                    .get_balance_at_block(user_id, token, 1)
+                    .await
+                    .unwrap(),
+                at_block_1
+            );
+            assert_eq!(
+                builder
+                    .get_balance_at_block(user_id, token, 2)
+                    .await
+                    .unwrap(),
+                at_block_2
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_order_settled_after_block() {
+        let pair_id = "STA_STB".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("settle_seller".to_string(), "STA".to_string(), 10);
+            state_db
+                .state
+                .set_user_balance("settle_buyer".to_string(), "STB".to_string(), 100);
+        }
+        let order_status = || async {
+            MEMPOOL
+                .read()
+                .await
+                .get_order(&pair_id, "settle_sell")
+                .await
+                .unwrap()
+                .status
+        };
+
+        let sell = Order::new(
+            "settle_sell".to_string(),
+            "settle_seller".to_string(),
+            pair_id.clone(),
+            10,
+            2,
+            false,
+        );
+        MEMPOOL.read().await.place_order(sell).await.unwrap();
+        assert_eq!(order_status().await, OrderStatus::Pending);
+
+        // Filled by two takers, the last fill sealed one block later
+        for (i, amount) in [4, 6].iter().enumerate() {
+            let buy = Order::new(
+                format!("settle_buy_{}", i),
+                "settle_buyer".to_string(),
+                pair_id.clone(),
+                *amount,
+                2,
+                true,
+            );
+            MEMPOOL.read().await.place_order(buy).await.unwrap();
+        }
+        assert_eq!(order_status().await, OrderStatus::Filled);
+
+        // Other tests share the global traces, so only take this pair's
+        let traces: Vec<MatchedTrace> = MATCHED_TRACES
+            .read()
+            .await
+            .iter()
+            .filter(|trace| trace.buy_order.pair_id == pair_id)
+            .cloned()
+            .collect();
+        assert_eq!(traces.len(), 2);
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let block = builder
+            .create_block(vec![traces[0].clone()], vec![])
+            .await
+            .unwrap();
+        builder.save_block(&block).await.unwrap();
+        assert_eq!(order_status().await, OrderStatus::Filled);
+
+        let block = builder
+            .create_block(vec![traces[1].clone()], vec![])
+            .await
+            .unwrap();
+        builder.save_block(&block).await.unwrap();
+        assert_eq!(order_status().await, OrderStatus::Settled);
+    }
+
+    #[tokio::test]
+    async fn test_block_records_order_updates() {
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("ou_seller".to_string(), "OUA".to_string(), 10);
+            state_db
+                .state
+                .set_user_balance("ou_buyer".to_string(), "OUB".to_string(), 10);
+        }
+        let order = |id: &str, user_id: &str, amount, side| {
+            Order::new(
+                id.to_string(),
+                user_id.to_string(),
+                "OUA_OUB".to_string(),
+                amount,
+                1,
+                side,
+            )
+        };
+        let update = |order_id: &str, status, filled_amount| OrderUpdate {
+            order_id: order_id.to_string(),
+            status,
+            filled_amount,
+        };
+
+        // A sell of 10 taken by buys of 4 and 6, each fill sealed in its own block
+        let mut sell = order("ou_sell", "ou_seller", 10, false);
+        let first = MatchedTrace {
+            buy_order: order("ou_buy_1", "ou_buyer", 4, true),
+            sell_order: sell.clone(),
+            matched_amount: 4,
+            matched_price: 1,
+        };
+        sell.fill(4);
+        let second = MatchedTrace {
+            buy_order: order("ou_buy_2", "ou_buyer", 6, true),
+            sell_order: sell.clone(),
+            matched_amount: 6,
+            matched_price: 1,
+        };
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let block = builder.create_block(vec![first], vec![]).await.unwrap();
+        assert_eq!(
+            block.order_updates,
+            vec![
+                update("ou_buy_1", OrderStatus::Filled, 4),
+                update("ou_sell", OrderStatus::PartiallyFilled, 4),
+            ]
+        );
+        builder.save_block(&block).await.unwrap();
+
+        let block = builder.create_block(vec![second], vec![]).await.unwrap();
+        builder.save_block(&block).await.unwrap();
+        // Read back as a client watching blocks would see it
+        let block = builder.get_block(block.block_num).await.unwrap().unwrap();
+        assert_eq!(
+            block.order_updates,
+            vec![
+                update("ou_buy_2", OrderStatus::Filled, 6),
+                update("ou_sell", OrderStatus::Filled, 10),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_genesis_block_anchors_chain() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let mut genesis = Genesis::default();
+        genesis.balances.insert(
+            "genesis_user".to_string(),
+            [("GNA".to_string(), 500)].into(),
+        );
+        let hash_scheme = STATE.read().await.state.hash_scheme;
+
+        let block = builder.seal_genesis(&genesis).await.unwrap();
+        assert_eq!(block.block_num, 0);
+        assert_eq!(block.state_root, Some(genesis.state_root(hash_scheme)));
+        assert_eq!(
+            STATE
+                .read()
+                .await
+                .state
+                .get_user_balance("genesis_user", "GNA"),
+            500
+        );
+        assert_eq!(
+            builder
+                .get_balance_at_block("genesis_user", "GNA", 0)
+                .await
+                .unwrap(),
+            500
+        );
+
+        // The next block is block 1, and a restart keeps the genesis block
+        let next = builder.create_block(vec![], vec![]).await.unwrap();
+        assert_eq!(next.block_num, 1);
+        builder.save_block(&next).await.unwrap();
+        let restarted = BlockBuilder::with_db(&db).unwrap();
+        let resealed = restarted.seal_genesis(&genesis).await.unwrap();
+        assert_eq!(resealed.hash(), block.hash());
+
+        // A different config can't be swapped in under a running chain
+        genesis
+            .balances
+            .insert("genesis_other".to_string(), [("GNA".to_string(), 1)].into());
+        assert!(restarted.seal_genesis(&genesis).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sealed_block_events() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let mut events = builder.subscribe();
+
+        let mut sealed = vec![];
+        for _ in 0..3 {
+            let block = builder.create_block(vec![], vec![]).await.unwrap();
+            builder.save_block(&block).await.unwrap();
+            sealed.push(SealedBlock {
+                block_num: block.block_num,
+                state_root: block.state_root,
+                txns_root: block.txns_root,
+                block_hash: block.hash(),
+            });
+        }
+
+        // One event per block, in order, carrying what the block committed to
+        for expected in sealed {
+            assert_eq!(events.recv().await.unwrap(), expected);
+        }
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bad_trace_does_not_wedge_exchange() {
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("wedge_seller".to_string(), "WGA".to_string(), 10);
+            state_db
+                .state
+                .set_user_balance("wedge_buyer".to_string(), "WGB".to_string(), 10);
+        }
+        let trace = |pair_id: &str, id: &str| MatchedTrace {
+            buy_order: Order::new(
+                format!("{}_buy", id),
+                "wedge_buyer".to_string(),
+                pair_id.to_string(),
+                5,
+                1,
+                true,
+            ),
+            sell_order: Order::new(
+                format!("{}_sell", id),
+                "wedge_seller".to_string(),
+                pair_id.to_string(),
+                5,
+                1,
+                false,
+            ),
+            matched_amount: 5,
+            matched_price: 1,
+        };
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let block = builder
+            .create_block(
+                vec![
+                    trace("WEDGEPAIR", "wedge_bad"),
+                    trace("WGA_WGB", "wedge_good"),
+                ],
+                vec![],
+            )
+            .await
+            .unwrap();
+        builder.save_block(&block).await.unwrap();
+
+        // Only the well-formed trace is settled and sealed
+        assert_eq!(block.txns.len(), 1);
+        assert_eq!(block.txns[0].buy_order.id, "wedge_good_buy");
+        assert_eq!(
+            STATE
+                .read()
+                .await
+                .state
+                .get_user_balance("wedge_buyer", "WGA"),
+            5
+        );
+
+        // And orders are still served
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("wedge_seller".to_string(), "WHA".to_string(), 10);
+        }
+        let sell = Order::new(
+            "wedge_after_sell".to_string(),
+            "wedge_seller".to_string(),
+            "WHA_WHB".to_string(),
+            10,
+            1,
+            false,
+        );
+        assert!(MEMPOOL.read().await.place_order(sell).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_trace_with_swapped_token_roles_dropped() {
+        {
+            let mut state_db = STATE.write().await;
+            for token in ["TRA", "TRB"] {
+                for user_id in ["roles_buyer", "roles_seller"] {
+                    state_db
+                        .state
+                        .set_user_balance(user_id.to_string(), token.to_string(), 10);
+                }
+            }
+        }
+        let trace = |id: &str| MatchedTrace {
+            buy_order: Order::new(
+                format!("{}_buy", id),
+                "roles_buyer".to_string(),
+                "TRA_TRB".to_string(),
+                5,
+                1,
+                true,
+            ),
+            sell_order: Order::new(
+                format!("{}_sell", id),
+                "roles_seller".to_string(),
+                "TRA_TRB".to_string(),
+                5,
+                1,
+                false,
+            ),
+            matched_amount: 5,
+            matched_price: 1,
+        };
+        // The sell order claims the pair the other way round
+        let mut swapped = trace("roles_bad");
+        swapped.sell_order.token_a = "TRB".to_string();
+        swapped.sell_order.token_b = "TRA".to_string();
+        assert!(settlement_deltas(std::slice::from_ref(&swapped)).is_err());
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let block = builder
+            .create_block(vec![swapped, trace("roles_good")], vec![])
+            .await
+            .unwrap();
+
+        // Only the consistent trace is settled
+        assert_eq!(block.txns.len(), 1);
+        assert_eq!(block.txns[0].buy_order.id, "roles_good_buy");
+        let state = &STATE.read().await.state;
+        assert_eq!(state.get_user_balance("roles_buyer", "TRA"), 15);
+        assert_eq!(state.get_user_balance("roles_seller", "TRA"), 5);
+        assert_eq!(state.get_user_balance("roles_seller", "TRB"), 15);
+    }
+
+    #[tokio::test]
+    async fn test_notional_burst_seals_before_count() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut builder = BlockBuilder::with_db(&db)
+            .unwrap()
+            .with_clock(Arc::new(MockClock::new(0)));
+        let dust = vec![trace("notional_dust", 5)];
+        let burst = vec![
+            trace("notional_dust", 5),
+            trace("notional_whale", 1_000_000),
+        ];
+        assert!(burst.len() as u64 + 1 < MAX_TXN_SIZE);
+
+        // Well inside the block interval and short of the trade count, nothing is due yet
+        assert!(!builder.seal_due(&burst).await);
+
+        // With a notional cap the burst is due right away, the dust still waits
+        builder.max_block_notional = Some(1_000_000);
+        assert!(builder.seal_due(&burst).await);
+        assert!(!builder.seal_due(&dust).await);
+    }
+
+    #[tokio::test]
+    async fn test_block_interval_follows_the_clock() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let clock = MockClock::new(1_000);
+        let builder = BlockBuilder::with_db(&db)
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        let pending = vec![trace("interval_1", 1)];
+
+        // Due exactly once the interval has passed on the clock, however long the test takes
+        clock.advance(BLOCK_TIME_INTERVAL - Duration::from_millis(1));
+        assert!(!builder.seal_due(&pending).await);
+        clock.advance(Duration::from_millis(1));
+        assert!(builder.seal_due(&pending).await);
+
+        // Sealing restarts the interval from the clock's time
+        *builder.last_block_time.write().await = clock.now_millis();
+        assert!(!builder.seal_due(&pending).await);
+        clock.advance(BLOCK_TIME_INTERVAL);
+        assert!(builder.seal_due(&pending).await);
+    }
+
+    #[tokio::test]
+    async fn test_block_settles_atomically() {
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("atomic_a".to_string(), "ATA".to_string(), 10);
+            state_db
+                .state
+                .set_user_balance("atomic_b".to_string(), "ATB".to_string(), 10);
+        }
+        let trace = |pair_id: &str, buyer: &str, seller: &str, amount| MatchedTrace {
+            buy_order: Order::new(
+                format!("{}_{}_{}_buy", pair_id, buyer, amount),
+                buyer.to_string(),
+                pair_id.to_string(),
+                amount,
+                1,
+                true,
+            ),
+            sell_order: Order::new(
+                format!("{}_{}_{}_sell", pair_id, seller, amount),
+                seller.to_string(),
+                pair_id.to_string(),
+                amount,
+                1,
+                false,
+            ),
+            matched_amount: amount,
+            matched_price: 1,
+        };
+        let balances = || async {
+            let state_db = STATE.read().await;
+            [
+                ("atomic_a", "ATA"),
+                ("atomic_a", "ATB"),
+                ("atomic_b", "ATA"),
+                ("atomic_b", "ATB"),
+            ]
+            .map(|(user, token)| state_db.state.get_user_balance(user, token))
+        };
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+
+        // a pays for the second trade with ATB from the first, but 15 is more than it gets
+        let block = builder
+            .create_block(
+                vec![
+                    trace("ATA_ATB", "atomic_b", "atomic_a", 10),
+                    trace("ATA_ATB", "atomic_a", "atomic_b", 15),
+                ],
+                vec![],
+            )
+            .await;
+        assert!(block.is_err());
+        // The first trade isn't applied on its own, and no block number is used up
+        assert_eq!(balances().await, [10, 0, 0, 10]);
+        assert_eq!(builder.get_latest_block_num().await, 0);
+
+        let block = builder
+            .create_block(
+                vec![
+                    trace("ATA_ATB", "atomic_b", "atomic_a", 10),
+                    trace("ATA_ATB", "atomic_a", "atomic_b", 4),
+                ],
+                vec![],
+            )
+            .await
+            .unwrap();
+        assert_eq!(block.block_num, 1);
+        assert_eq!(balances().await, [4, 6, 6, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_unsettleable_trace_dropped_alone() {
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("partial_buyer".to_string(), "PTB".to_string(), 10);
+            state_db
+                .state
+                .set_user_balance("partial_seller".to_string(), "PTA".to_string(), 15);
+            // Matched with 5 frozen, then the funds left before settlement
+            state_db
+                .state
+                .set_frozen("partial_broke".to_string(), "PTB".to_string(), 5);
+        }
+        let trace = |buyer: &str, amount| MatchedTrace {
+            buy_order: Order::new(
+                format!("partial_{}_{}_buy", buyer, amount),
+                buyer.to_string(),
+                "PTA_PTB".to_string(),
+                amount,
+                1,
+                true,
+            ),
+            sell_order: Order::new(
+                format!("partial_{}_{}_sell", buyer, amount),
+                "partial_seller".to_string(),
+                "PTA_PTB".to_string(),
+                amount,
+                1,
+                false,
+            ),
+            matched_amount: amount,
+            matched_price: 1,
+        };
+        let fill = |trace: &MatchedTrace| EngineEvent::Fill {
+            pair_id: "PTA_PTB".to_string(),
+            buy_order_id: trace.buy_order.id.clone(),
+            sell_order_id: trace.sell_order.id.clone(),
+            price: trace.matched_price,
+            quantity: trace.matched_amount,
+        };
+        let mut traces = vec![
+            trace("partial_buyer", 4),
+            trace("partial_broke", 5),
+            trace("partial_buyer", 6),
+        ];
+        let mut events: Vec<EngineEvent> = traces.iter().map(fill).collect();
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        assert!(
+            builder
+                .create_block(traces.clone(), events.clone())
+                .await
+                .is_err()
+        );
+
+        let dropped = drop_unsettleable(&mut traces, &mut events).await;
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].buy_order.user_id, "partial_broke");
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| matches!(
+            event,
+            EngineEvent::Fill { buy_order_id, .. } if !buy_order_id.contains("broke")
+        )));
+
+        // The others settle without it, and its freeze is given back
+        let block = builder.create_block(traces, events).await.unwrap();
+        assert_eq!(block.txns.len(), 2);
+        let mut state_db = STATE.write().await;
+        for (user_id, token, amount) in [
+            ("partial_buyer", "PTA", 10),
+            ("partial_buyer", "PTB", 0),
+            ("partial_seller", "PTA", 5),
+            ("partial_seller", "PTB", 10),
+        ] {
+            assert_eq!(state_db.state.get_user_balance(user_id, token), amount);
+        }
+        assert_eq!(
+            state_db
+                .state
+                .get_frozen("partial_broke".to_string(), "PTB"),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_settles_quote_at_trade_price() {
+        // 8 decimal base token, 6 decimal quote token, prices with 4 decimals
+        let order = |id: &str, user_id: &str, price, side| Order {
+            price_decimals: 4,
+            ..Order::new(
+                id.to_string(),
+                user_id.to_string(),
+                "DCA_DCB".to_string(),
+                50_000_000,
+                price,
+                side,
+            )
+        };
+        {
+            let mut state_db = STATE.write().await;
+            let state = &mut state_db.state;
+            state.set_user_balance("dc_buyer".to_string(), "DCB".to_string(), 100_000_000);
+            state.set_user_balance("dc_seller".to_string(), "DCA".to_string(), 50_000_000);
+            // What the orders locked: 0.5 DCA at the 124 limit less the 0.55 DCB price
+            // improvement refunded when matched, and the 0.5 DCA sold
+            state.set_frozen("dc_buyer".to_string(), "DCB".to_string(), 61_725_000);
+            state.set_frozen("dc_seller".to_string(), "DCA".to_string(), 50_000_000);
+        }
+
+        // Half a DCA at 123.45 DCB each
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        builder
+            .create_block(
+                vec![MatchedTrace {
+                    buy_order: order("dc_buy", "dc_buyer", 12_400, true),
+                    sell_order: order("dc_sell", "dc_seller", 12_345, false),
+                    matched_amount: 50_000_000,
+                    matched_price: 12_345,
+                }],
+                vec![],
+            )
+            .await
+            .unwrap();
+
+        let mut state_db = STATE.write().await;
+        let state = &mut state_db.state;
+        assert_eq!(state.get_user_balance("dc_buyer", "DCA"), 50_000_000);
+        assert_eq!(state.get_user_balance("dc_buyer", "DCB"), 38_275_000);
+        assert_eq!(state.get_user_balance("dc_seller", "DCA"), 0);
+        assert_eq!(state.get_user_balance("dc_seller", "DCB"), 61_725_000);
+        // The rest of the limit-price lock is released
+        assert_eq!(state.get_frozen("dc_buyer".to_string(), "DCB"), 0);
+        assert_eq!(state.get_frozen("dc_seller".to_string(), "DCA"), 0);
+    }
+
+    // Log sink for a test subscriber
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_order_journey_logs_correlated() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        // Scoped to this thread, which also runs the pair's matching task
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let pair_id = "LGA_LGB".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("log_seller".to_string(), "LGA".to_string(), 10);
+            state_db
+                .state
+                .set_user_balance("log_buyer".to_string(), "LGB".to_string(), 100);
+        }
+        for (id, user_id, side) in [
+            ("log_sell", "log_seller", false),
+            ("log_buy", "log_buyer", true),
+        ] {
+            let order = Order::new(
+                id.to_string(),
+                user_id.to_string(),
+                pair_id.clone(),
+                10,
+                2,
+                side,
+            );
+            MEMPOOL.read().await.place_order(order).await.unwrap();
+        }
+
+        let traces: Vec<MatchedTrace> = MATCHED_TRACES
+            .read()
+            .await
+            .iter()
+            .filter(|trace| trace.buy_order.pair_id == pair_id)
+            .cloned()
+            .collect();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let block = builder.create_block(traces, vec![]).await.unwrap();
+        builder.save_block(&block).await.unwrap();
+
+        // Filtering on the order's span yields its whole journey
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let journey = |order_id: &str| {
+            let span = format!("order{{order_id={}}}", order_id);
+            logs.lines()
+                .filter(|line| line.contains(&span))
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>()
+        };
+        for order_id in ["log_sell", "log_buy"] {
+            let lines = journey(order_id);
+            for step in [
+                "Processing order in mempool",
+                "Order filled",
+                "Order trace sealed in block",
+                "Order settled",
+            ] {
+                assert!(
+                    lines.iter().any(|line| line.contains(step)),
+                    "{} missing from {}'s logs: {:#?}",
+                    step,
+                    order_id,
+                    lines
+                );
+            }
+        }
+        assert!(
+            journey("log_sell")
+                .iter()
+                .all(|line| !line.contains("Processing order in mempool: id=log_buy"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_book_at_past_block() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let pair_id = "BHA_BHB";
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("bh_maker".to_string(), "BHB".to_string(), 1_000);
+        }
+        let bid = |id: &str, price| {
+            Order::new(
+                id.to_string(),
+                "bh_maker".to_string(),
+                pair_id.to_string(),
+                10,
+                price,
+                true,
+            )
+        };
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let produce = || async {
+            builder
+                .create_block(vec![], vec![])
+                .await
+                .unwrap()
+                .block_num
+        };
+
+        let mempool = MEMPOOL.read().await;
+        mempool.place_order(bid("bh_bid_1", 10)).await.unwrap();
+        let first = produce().await;
+        mempool.place_order(bid("bh_bid_2", 11)).await.unwrap();
+        let second = produce().await;
+        // Unchanged book, nothing new recorded
+        let third = produce().await;
+        mempool
+            .cancel_order(pair_id, "bh_bid_1", None)
+            .await
+            .unwrap();
+        let fourth = produce().await;
+        drop(mempool);
+
+        let bid_ids = |book: &OrderBook| -> Vec<String> {
+            book.iter_bids().map(|order| order.id.clone()).collect()
+        };
+        let (recorded_at, book) = builder.book_at(pair_id, first).unwrap().unwrap();
+        assert_eq!(recorded_at, first);
+        assert_eq!(bid_ids(&book), ["bh_bid_1"]);
+        let (recorded_at, book) = builder.book_at(pair_id, third).unwrap().unwrap();
+        assert_eq!(recorded_at, second);
+        assert_eq!(bid_ids(&book), ["bh_bid_2", "bh_bid_1"]);
+        let (_, book) = builder.book_at(pair_id, fourth + 5).unwrap().unwrap();
+        assert_eq!(bid_ids(&book), ["bh_bid_2"]);
+        assert!(builder.book_at(pair_id, first - 1).unwrap().is_none());
+        assert!(builder.book_at("BHX_BHB", fourth).unwrap().is_none());
+
+        // Served over GET /orderbook/at
+        let request = Request::get(format!(
+            "/orderbook/at?pair_id={}&block_num={}",
+            pair_id, third
+        ))
+        .body(Body::empty())
+        .unwrap();
+        let response = create_exchange_router(MAX_BODY_BYTES)
+            .layer(Extension(builder.clone()))
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["data"]["recorded_at"], second as u64);
+        assert_eq!(response["data"]["bids"][0]["order_id"], "bh_bid_2");
+        assert_eq!(response["data"]["bids"][1]["priority"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_fetched_block_verifies_locally() {
+        use crate::api::{ApiResponse, BlockResponse};
+        use axum::body::Body;
+        use axum::http::Request;
+        use common::block::verify_block;
+        use tower::ServiceExt;
+
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("vb_buyer".to_string(), "VBB".to_string(), 100);
+            state_db
+                .state
+                .set_user_balance("vb_seller".to_string(), "VBA".to_string(), 100);
+        }
+        let trace = |id: &str, amount| MatchedTrace {
+            buy_order: Order::new(
+                format!("{}_buy", id),
+                "vb_buyer".to_string(),
+                "VBA_VBB".to_string(),
+                amount,
+                2,
+                true,
+            ),
+            sell_order: Order::new(
+                format!("{}_sell", id),
+                "vb_seller".to_string(),
+                "VBA_VBB".to_string(),
+                amount,
+                2,
+                false,
+            ),
+            matched_amount: amount,
+            matched_price: 2,
+        };
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let block = builder
+            .create_block(vec![trace("vb_1", 5), trace("vb_2", 3)], vec![])
+            .await
+            .unwrap();
+        builder.save_block(&block).await.unwrap();
+
+        let get_block = |num: u64| {
+            let request = Request::get(format!("/block/{}", num))
+                .body(Body::empty())
+                .unwrap();
+            let router = create_exchange_router(MAX_BODY_BYTES).layer(Extension(builder.clone()));
+            async move {
+                let response = router.oneshot(request).await.unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<ApiResponse<BlockResponse>>(&body).unwrap()
+            }
+        };
+
+        let fetched = get_block(1).await.data.unwrap();
+        assert_eq!(fetched.block.txns.len(), 2);
+        assert_eq!(fetched.hash, fetched.block.hash());
+        assert_eq!(verify_block(&fetched.block), Ok(()));
+
+        // Any change to the txns breaks the stored root
+        let mut tampered = fetched.block.clone();
+        tampered.txns[0].matched_amount = 4;
+        assert_eq!(
+            verify_block(&tampered),
+            Err("Block 1 txns_root doesn't match its txns".to_string())
+        );
+        let mut dropped = fetched.block;
+        dropped.txns.pop();
+        assert!(verify_block(&dropped).is_err());
+
+        assert_eq!(
+            get_block(9).await.error.as_deref(),
+            Some("Block 9 not found")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_stream_catches_up_then_follows() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let builder = BlockBuilder::with_db(&db).unwrap();
+        let seal = || async {
+            let block = builder.create_block(vec![], vec![]).await.unwrap();
+            builder.save_block(&block).await.unwrap();
+            SealedBlock::from(&block)
+        };
+        // Sealed before the client connects, so only reachable through the catch-up
+        let mut expected = vec![seal().await, seal().await];
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = create_exchange_router(MAX_BODY_BYTES).layer(Extension(builder.clone()));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let (mut stream, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws/blocks", addr))
+            .await
+            .unwrap();
+
+        expected.push(seal().await);
+        expected.push(seal().await);
+        for header in &expected {
+            let message = stream.next().await.unwrap().unwrap();
+            let received: SealedBlock = serde_json::from_str(message.to_text().unwrap()).unwrap();
+            assert_eq!(&received, header);
+            assert_eq!(
+                received.block_hash,
+                block_hash(received.block_num, received.txns_root, received.state_root)
+            );
+        }
+        assert_eq!(
+            expected
+                .iter()
+                .map(|header| header.block_num)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3, 4]
+        );
+    }
+}
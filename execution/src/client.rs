@@ -0,0 +1,261 @@
+// Typed async client for the exchange REST API
+use crate::api::{
+    ApiResponse, BalanceResponse, CancelOrderRequest, DepositRequest, GetBalanceRequest,
+    GetOrderBookRequest, GetTradesRequest, OrderBookResponse, PlaceAndWaitRequest,
+    PlaceAndWaitResponse, PlaceOrderRequest, PlaceOrderResponse, PortfolioRequest,
+    PortfolioResponse, SimulateOrderResponse, TradesResponse, TransferRequest, WithdrawRequest,
+};
+use common::order::Order;
+use serde::{Serialize, de::DeserializeOwned};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Unexpected HTTP status: {0}")]
+    Status(reqwest::StatusCode),
+    // The server handled the request and answered with `success: false`
+    #[error("{0}")]
+    Server(String),
+    #[error("Response has no data")]
+    MissingData,
+}
+
+#[derive(Clone)]
+pub struct ExchangeClient {
+    base_url: String,
+    http: reqwest::Client,
+}
+
+impl ExchangeClient {
+    /// Client for the exchange served at `base_url`, e.g. `http://[::1]:3030`.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn deposit(&self, request: &DepositRequest) -> Result<(), ApiError> {
+        self.post::<_, ()>("/deposit", request).await.map(|_| ())
+    }
+
+    pub async fn withdraw(&self, request: &WithdrawRequest) -> Result<(), ApiError> {
+        self.post::<_, ()>("/withdraw", request).await.map(|_| ())
+    }
+
+    pub async fn transfer(&self, request: &TransferRequest) -> Result<(), ApiError> {
+        self.post::<_, ()>("/transfer", request).await.map(|_| ())
+    }
+
+    pub async fn place_order(
+        &self,
+        request: &PlaceOrderRequest,
+    ) -> Result<PlaceOrderResponse, ApiError> {
+        self.post_data("/order/place", request).await
+    }
+
+    /// Place an order and wait until it is filled, cancelled or settled, or the request's
+    /// timeout passes
+    pub async fn place_and_wait(
+        &self,
+        request: &PlaceAndWaitRequest,
+    ) -> Result<PlaceAndWaitResponse, ApiError> {
+        self.post_data("/order/place_and_wait", request).await
+    }
+
+    /// What placing the order would do right now, without placing it
+    pub async fn simulate_order(
+        &self,
+        request: &PlaceOrderRequest,
+    ) -> Result<SimulateOrderResponse, ApiError> {
+        self.post_data("/order/simulate", request).await
+    }
+
+    pub async fn cancel_order(&self, request: &CancelOrderRequest) -> Result<Order, ApiError> {
+        self.post_data("/order/cancel", request).await
+    }
+
+    pub async fn get_balance(&self, user_id: &str, token: &str) -> Result<u64, ApiError> {
+        let request = GetBalanceRequest {
+            user_id: user_id.to_string(),
+            token: token.to_string(),
+            with_proof: false,
+            decimal: false,
+        };
+        let response: BalanceResponse = self.post_data("/balance", &request).await?;
+        Ok(response.balance)
+    }
+
+    /// Balance together with the state root and the user's inclusion proof under it.
+    pub async fn get_balance_with_proof(
+        &self,
+        user_id: &str,
+        token: &str,
+    ) -> Result<BalanceResponse, ApiError> {
+        let request = GetBalanceRequest {
+            user_id: user_id.to_string(),
+            token: token.to_string(),
+            with_proof: true,
+            decimal: false,
+        };
+        self.post_data("/balance", &request).await
+    }
+
+    pub async fn get_portfolio(&self, user_id: &str) -> Result<PortfolioResponse, ApiError> {
+        let request = PortfolioRequest {
+            user_id: user_id.to_string(),
+            decimal: false,
+        };
+        self.post_data("/portfolio", &request).await
+    }
+
+    pub async fn get_orderbook(&self, pair_id: &str) -> Result<OrderBookResponse, ApiError> {
+        let request = GetOrderBookRequest {
+            pair_id: pair_id.to_string(),
+        };
+        self.post_data("/orderbook", &request).await
+    }
+
+    /// One page of the trade history, newest first.
+    pub async fn get_trades(&self, request: &GetTradesRequest) -> Result<TradesResponse, ApiError> {
+        self.post_data("/trades", request).await
+    }
+
+    // Like `post`, for endpoints that always return data on success
+    async fn post_data<B: Serialize, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> Result<T, ApiError> {
+        self.post(path, body).await?.ok_or(ApiError::MissingData)
+    }
+
+    // Send a JSON POST and unwrap the response envelope
+    async fn post<B: Serialize, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> Result<Option<T>, ApiError> {
+        let response = self
+            .http
+            .post(format!("{}{}", self.base_url, path))
+            .json(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(ApiError::Status(response.status()));
+        }
+
+        let envelope: ApiResponse<T> = response.json().await?;
+        if envelope.success {
+            Ok(envelope.data)
+        } else {
+            Err(ApiError::Server(envelope.error.unwrap_or_default()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::{MAX_BODY_BYTES, create_exchange_router};
+    use common::order::OrderStatus;
+
+    // Serve the exchange router on an ephemeral local port
+    async fn spawn_server() -> ExchangeClient {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, create_exchange_router(MAX_BODY_BYTES))
+                .await
+                .unwrap()
+        });
+        ExchangeClient::new(format!("http://{}", addr))
+    }
+
+    #[tokio::test]
+    async fn test_client_round_trip() {
+        let client = spawn_server().await;
+        let pair_id = "CLA_CLB";
+        let deposit = |user_id: &str, token: &str, amount| DepositRequest {
+            user_id: user_id.to_string(),
+            token: token.to_string(),
+            amount,
+        };
+        client
+            .deposit(&deposit("client_seller", "CLA", 100))
+            .await
+            .unwrap();
+        client
+            .deposit(&deposit("client_buyer", "CLB", 1_000))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.get_balance("client_buyer", "CLB").await.unwrap(),
+            1_000
+        );
+
+        let order = |user_id: &str, amount, price, side| PlaceOrderRequest {
+            user_id: user_id.to_string(),
+            pair_id: pair_id.to_string(),
+            amount,
+            price,
+            decimal_price: None,
+            side,
+            trigger_price: None,
+            client_order_id: None,
+        };
+        let sell = client
+            .place_order(&order("client_seller", 10, 5, false))
+            .await
+            .unwrap();
+        assert_eq!(sell.status, OrderStatus::Pending);
+        let book = client.get_orderbook(pair_id).await.unwrap();
+        assert_eq!(book.best_ask, Some(5));
+        assert_eq!(book.best_bid, None);
+
+        let buy = client
+            .place_order(&order("client_buyer", 4, 5, true))
+            .await
+            .unwrap();
+        assert_eq!(buy.status, OrderStatus::Filled);
+        assert_eq!(buy.fills[0].maker_order_id, sell.order_id);
+        let history = client
+            .get_trades(&GetTradesRequest {
+                pair_id: Some(pair_id.to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(history.trades.iter().any(|record| {
+            record.trade.sell_order_id == sell.order_id && record.trade.buy_order_id == buy.order_id
+        }));
+
+        let cancelled = client
+            .cancel_order(&CancelOrderRequest {
+                pair_id: pair_id.to_string(),
+                order_id: Some(sell.order_id.clone()),
+                user_id: None,
+                client_order_id: None,
+                reduce_by: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(cancelled.status, OrderStatus::Cancelled);
+        assert_eq!(client.get_orderbook(pair_id).await.unwrap().best_ask, None);
+
+        // Server-side failures surface as typed errors
+        let err = client.get_orderbook("NOPE_CLIENT").await.unwrap_err();
+        assert!(matches!(err, ApiError::Server(ref e) if e == "Trading pair not found"));
+        let err = client
+            .withdraw(&WithdrawRequest {
+                user_id: "client_buyer".to_string(),
+                token: "CLB".to_string(),
+                amount: 1_000_000,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ApiError::Server(_)));
+    }
+}
@@ -1,20 +1,38 @@
+use alloy_primitives::B256;
 use anyhow::Result;
 use revm::context::TxEnv;
-use revm::database::CacheDB;
+use revm::database::{Cache, CacheDB};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tiny_keccak::{Hasher, Sha3};
 use tokio::sync::RwLock;
 use tokio::time::sleep;
 
-use crate::evm::executor::EvmExecutor;
+use crate::evm::executor::{BASE_FEE, EvmExecutor, TxReceipt};
+use crate::evm::gas_oracle::{BlockGasData, GAS_ORACLE};
 use crate::evm::mempool::EVM_MEMPOOL;
 use crate::evm::storage::EvmDatabase;
+use common::db::{EVM_BLOCKS_TREE, open_db};
 
 static MAX_TXN_SIZE: u64 = 100;
 static BLOCK_TIME_INTERVAL: Duration = Duration::from_millis(200);
 
+// Receipt of an executed txn and the block that included it.
+#[derive(Clone, Debug)]
+pub struct BlockReceipt {
+    pub block_num: u128,
+    pub receipt: TxReceipt,
+}
+
+// Receipts by tx hash, served by eth_getTransactionReceipt
+lazy_static::lazy_static! {
+    pub static ref EVM_RECEIPTS: Arc<RwLock<HashMap<B256, BlockReceipt>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
 pub struct BlockBuilder {
-    pub block_db: sled::Db,
+    pub block_db: sled::Tree,
     pub state_db: CacheDB<EvmDatabase>,
     pub current_block_num: Arc<RwLock<u128>>,
     pub last_block_time: Arc<RwLock<Instant>>,
@@ -22,8 +40,14 @@ pub struct BlockBuilder {
 
 impl BlockBuilder {
     pub fn new(db_path: &str) -> Result<Self> {
-        let block_db = sled::open(db_path)?;
-        let database = EvmDatabase::new();
+        Self::with_db(&open_db(db_path)?)
+    }
+
+    /// Builder keeping its blocks and the EVM state in their own trees of `db`, next to the
+    /// exchange's trees.
+    pub fn with_db(db: &sled::Db) -> Result<Self> {
+        let block_db = db.open_tree(EVM_BLOCKS_TREE)?;
+        let database = EvmDatabase::with_db(db)?;
         let state_db = CacheDB::<EvmDatabase>::new(database);
 
         // Initialize block number from database or start from 0
@@ -53,10 +77,8 @@ impl BlockBuilder {
             // Read new txns
             let poped_txns = {
                 let mut mempool = EVM_MEMPOOL.write().await;
-                mempool
-                    .txns
-                    .drain(1..MAX_TXN_SIZE as usize - pending_txns.len())
-                    .collect::<Vec<_>>()
+                let take = (MAX_TXN_SIZE as usize - pending_txns.len()).min(mempool.txns.len());
+                mempool.txns.drain(..take).collect::<Vec<_>>()
             };
 
             // Add new txns to pending
@@ -72,8 +94,8 @@ impl BlockBuilder {
 
             if should_generate_block {
                 // Generate and save block
-                let block = self.create_block(pending_txns.clone()).await;
-                self.save_block(&block).await;
+                let block = self.create_block(pending_txns.clone()).await?;
+                self.save_block(&block).await?;
 
                 log::info!(
                     "Generated block #{} with {} transactions",
@@ -91,18 +113,90 @@ impl BlockBuilder {
         }
     }
 
-    pub async fn create_block(&mut self, txns: Vec<TxEnv>) -> Block {
+    /// Execute the txns on top of the persisted state and seal them into a block
+    pub async fn create_block(&mut self, txns: Vec<(B256, TxEnv)>) -> Result<Block> {
+        let mut block_num_lock = self.current_block_num.write().await;
+        *block_num_lock += 1;
+        let block_num = *block_num_lock;
+        drop(block_num_lock);
+
+        let (tx_hashes, txns): (Vec<B256>, Vec<TxEnv>) = txns.into_iter().unzip();
         let mut executor = EvmExecutor::new(&mut self.state_db);
-        executor.execute_block(txns);
-        let block = Block {
-            block_num: todo!(),
+        let receipts = executor.execute_block(txns.clone());
+        let state_root = self.commit_state()?;
+
+        // Index the receipts of the included txns by tx hash
+        let mut included = Vec::new();
+        let mut receipts_lock = EVM_RECEIPTS.write().await;
+        for (tx_hash, receipt) in tx_hashes.into_iter().zip(receipts) {
+            if let Some(receipt) = receipt {
+                receipts_lock.insert(
+                    tx_hash,
+                    BlockReceipt {
+                        block_num,
+                        receipt: receipt.clone(),
+                    },
+                );
+                included.push(receipt);
+            }
+        }
+        drop(receipts_lock);
+
+        // Feed the gas price oracle with this block's fees
+        GAS_ORACLE
+            .write()
+            .await
+            .record_block(BlockGasData::from_receipts(block_num, BASE_FEE, &included));
+        let txns_root = calculate_txns_root(&txns);
+
+        Ok(Block {
+            block_num,
             txns,
-            txns_root: todo!(),
-            state_root: todo!(),
-        };
-        block
+            txns_root: Some(txns_root),
+            state_root: Some(state_root.0),
+        })
+    }
+
+    /// Flush the block's writes from the cache to the persistent db, then drop the cache
+    /// so the next block reads its state back from disk instead of a stale overlay
+    fn commit_state(&mut self) -> Result<B256> {
+        let state_root = EvmExecutor::new(&mut self.state_db)
+            .persistent()
+            .map_err(|e| anyhow::anyhow!("Failed to persist block state: {}", e))?;
+        self.state_db.cache = Cache::default();
+        Ok(state_root)
+    }
+
+    /// Save block to local storage using sled
+    pub async fn save_block(&self, block: &Block) -> Result<()> {
+        let block_data = serde_json::to_vec(block)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize block: {}", e))?;
+
+        let mut batch = sled::Batch::default();
+        batch.insert(
+            format!("evm_block_{}", block.block_num).as_bytes(),
+            block_data,
+        );
+        batch.insert("evm_latest_block_num", &block.block_num.to_be_bytes()[..]);
+        self.block_db.apply_batch(batch)?;
+        self.block_db.flush()?;
+
+        Ok(())
+    }
+}
+
+fn calculate_txns_root(txns: &[TxEnv]) -> [u8; 32] {
+    let mut sha3 = Sha3::v256();
+    let mut output = [0u8; 32];
+
+    for txn in txns {
+        if let Ok(txn_data) = serde_json::to_vec(txn) {
+            sha3.update(&txn_data);
+        }
     }
-    pub async fn save_block(&self, block: &Block) {}
+
+    sha3.finalize(&mut output);
+    output
 }
 
 use serde::{Deserialize, Serialize};
@@ -114,3 +208,136 @@ pub struct Block {
     pub txns_root: Option<[u8; 32]>,
     pub state_root: Option<[u8; 32]>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exchange::matching::Trade;
+    use crate::exchange::trade_log::{TradeFilter, TradeLog};
+    use alloy_primitives::{Address, Bytes, U256};
+    use common::hasher::HashScheme;
+    use common::state::StateDB;
+    use revm::database::DatabaseRef;
+    use revm::primitives::TxKind;
+    use revm::state::{AccountInfo, Bytecode};
+
+    fn transfer(caller: Address, to: Address, nonce: u64) -> TxEnv {
+        TxEnv {
+            tx_type: 0,
+            caller,
+            gas_limit: 21000,
+            gas_price: BASE_FEE as u128,
+            kind: TxKind::Call(to),
+            value: U256::from(1000),
+            data: Bytes::new(),
+            nonce,
+            chain_id: Some(1),
+            access_list: vec![].into(),
+            gas_priority_fee: None,
+            blob_hashes: vec![],
+            max_fee_per_blob_gas: 0,
+            authorization_list: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_block_state_read_back_from_disk() {
+        let base = std::env::temp_dir().join(format!("evm_block_builder_{}", std::process::id()));
+        let mut builder = BlockBuilder::new(base.to_str().unwrap()).unwrap();
+
+        let caller = Address::from([0x5; 20]);
+        let recipient = Address::from([0x6; 20]);
+        let account = AccountInfo::new(
+            U256::from(10u64.pow(18)),
+            0,
+            B256::default(),
+            Bytecode::default(),
+        );
+        builder.state_db.db.save_account(&caller, &account).unwrap();
+
+        let block_1 = builder
+            .create_block(vec![(
+                B256::with_last_byte(1),
+                transfer(caller, recipient, 0),
+            )])
+            .await
+            .unwrap();
+        assert!(builder.state_db.cache.accounts.is_empty());
+
+        // Only valid if block 1's nonce bump was persisted and read back
+        let block_2 = builder
+            .create_block(vec![(
+                B256::with_last_byte(2),
+                transfer(caller, recipient, 1),
+            )])
+            .await
+            .unwrap();
+        assert_eq!(block_2.block_num, block_1.block_num + 1);
+
+        let disk = &builder.state_db.db;
+        assert_eq!(disk.basic_ref(caller).unwrap().unwrap().nonce, 2);
+        assert_eq!(
+            disk.basic_ref(recipient).unwrap().unwrap().balance,
+            U256::from(2000)
+        );
+
+        drop(builder);
+        let _ = std::fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn test_one_flush_persists_exchange_and_evm() {
+        let base = std::env::temp_dir().join(format!("node_db_{}", std::process::id()));
+        let address = Address::from([0x8; 20]);
+        {
+            let db = sled::open(&base).unwrap();
+            let mut builder = BlockBuilder::with_db(&db).unwrap();
+            let account = AccountInfo::new(U256::from(7), 0, B256::default(), Bytecode::default());
+            builder
+                .state_db
+                .db
+                .save_account(&address, &account)
+                .unwrap();
+
+            let mut state_db = StateDB::with_db(&db, HashScheme::Keccak);
+            state_db
+                .state
+                .set_user_balance("node_user".to_string(), "NDA".to_string(), 9);
+            state_db.save();
+            let trade = Trade {
+                buy_order_id: "node_buy".to_string(),
+                sell_order_id: "node_sell".to_string(),
+                price: 3,
+                quantity: 4,
+                timestamp: 1,
+            };
+            TradeLog::open(&db)
+                .unwrap()
+                .append("NDA_NDB", &[trade])
+                .unwrap();
+
+            db.flush().unwrap();
+        }
+
+        let db = sled::open(&base).unwrap();
+        let database = EvmDatabase::with_db(&db).unwrap();
+        assert_eq!(
+            database.basic_ref(address).unwrap().unwrap().balance,
+            U256::from(7)
+        );
+        let mut state_db = StateDB::with_db(&db, HashScheme::Keccak);
+        state_db.load();
+        assert_eq!(state_db.state.get_user_balance("node_user", "NDA"), 9);
+        let trades = TradeLog::open(&db)
+            .unwrap()
+            .query(&TradeFilter::default(), None, 0, 10)
+            .unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].trade.buy_order_id, "node_buy");
+
+        drop(database);
+        drop(state_db);
+        drop(db);
+        let _ = std::fs::remove_dir_all(base);
+    }
+}
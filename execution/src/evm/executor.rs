@@ -1,4 +1,4 @@
-use crate::evm::storage::EvmDatabase;
+use crate::evm::storage::{DatabaseError, EvmDatabase};
 use crate::evm::trie::calculate_trie_updates;
 use alloy_primitives::map::foldhash::{HashMap, HashMapExt};
 use alloy_primitives::{Address, B256, U256};
@@ -7,13 +7,15 @@ use alloy_trie::Nibbles;
 pub use alloy_trie::TrieAccount;
 use alloy_trie::nodes::{LeafNode, TrieNode};
 use revm::DatabaseCommit;
-use revm::context::ContextTr;
+use revm::context::{ContextTr, Transaction};
 use revm::database::{AccountState, CacheDB};
 use revm::state::AccountInfo;
 use revm::{Context, ExecuteEvm, MainBuilder, MainContext, context::TxEnv};
 use tiny_keccak::{Hasher, Sha3};
 
 pub const ACCOUNT_RLP_MAX_SIZE: usize = 110;
+// Block base fee per gas in wei (1 Gwei), burned for every unit of gas used
+pub const BASE_FEE: u64 = 1_000_000_000;
 
 // AccountInfo and Storage changed after execute_block.
 type PostState = (
@@ -21,9 +23,21 @@ type PostState = (
     HashMap<B256, HashMap<B256, U256>>,
 );
 
+// Outcome of a single executed transaction.
+#[derive(Clone, Debug)]
+pub struct TxReceipt {
+    pub output: Vec<u8>,
+    pub gas_used: u64,
+    pub effective_gas_price: u128,
+    pub success: bool,
+    // Address of the deployed contract, for successful create txs
+    pub contract_address: Option<Address>,
+}
+
 pub struct EvmExecutor<'a> {
     database: &'a mut CacheDB<EvmDatabase>,
     post_state: Option<PostState>,
+    base_fee: u64,
 }
 
 impl<'a> EvmExecutor<'a> {
@@ -31,36 +45,43 @@ impl<'a> EvmExecutor<'a> {
         Self {
             database,
             post_state: None,
+            base_fee: BASE_FEE,
         }
     }
 
-    pub fn execute_block(&mut self, block: Vec<TxEnv>) {
-        for tx in block {
-            let _ = self.execute_tx(tx);
-        }
+    pub fn with_base_fee(mut self, base_fee: u64) -> Self {
+        self.base_fee = base_fee;
+        self
+    }
+
+    /// Execute the txns in order, returning a receipt for each txn that was included
+    pub fn execute_block(&mut self, block: Vec<TxEnv>) -> Vec<Option<TxReceipt>> {
+        block
+            .into_iter()
+            .map(|tx| self.execute_tx(tx).ok())
+            .collect()
     }
 
-    pub fn persistent(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Persists the executed state and returns the new account trie root.
+    pub fn persistent(&mut self) -> Result<B256, Box<dyn std::error::Error>> {
         let account_cache = &self.database.cache.accounts;
         let contract_cache = &self.database.cache.contracts;
         // Save account
         for (address, acc) in account_cache.iter() {
-            if acc.account_state == AccountState::Touched {
-                // No persistence required
+            if acc.account_state == AccountState::None {
+                // Only loaded, never modified by the EVM
                 continue;
             }
             let acc_info = &acc.info;
             let acc_storage = &acc.storage;
-            for (key, value) in acc_storage.iter() {
-                self.database.db.save_storage(key, value);
+            for (slot, value) in acc_storage.iter() {
+                self.database.db.save_storage(address, slot, value)?;
             }
-            self.database.db.save_account(address, acc_info);
+            self.database.db.save_account(address, acc_info)?;
         }
         // Save code
-        for (address, code) in contract_cache.iter() {
-            self.database
-                .db
-                .save_code(&Address::from_word(*address), code);
+        for (code_hash, code) in contract_cache.iter() {
+            self.database.db.save_code(code_hash, code)?;
         }
         // Save hashed state and mpt trie.
         let mut hashed_accounts = HashMap::with_capacity(account_cache.len());
@@ -99,34 +120,37 @@ impl<'a> EvmExecutor<'a> {
 
         for (address, _acc) in self.post_state.as_ref().unwrap().0.iter() {
             let target_path = Nibbles::unpack(address);
-            let acc_trie_updates = self.fetch_trie_updates(target_path).unwrap_or_default();
+            let acc_trie_updates = self.fetch_trie_updates(target_path)?.unwrap_or_default();
             trie_updates.extend(acc_trie_updates);
         }
 
-        calculate_trie_updates(nodes_to_update, &mut trie_updates);
+        let state_root = calculate_trie_updates(nodes_to_update, &mut trie_updates);
 
         // Save Mpt node to DB.
         for trie_node in trie_updates.iter() {
             self.database
                 .db
-                .insert_account_trie_node(trie_node.0, trie_node.1);
+                .insert_account_trie_node(trie_node.0, trie_node.1)?;
         }
 
-        Ok(())
+        Ok(state_root)
     }
 
-    fn fetch_trie_updates(&self, target_path: Nibbles) -> Option<HashMap<Nibbles, TrieNode>> {
+    fn fetch_trie_updates(
+        &self,
+        target_path: Nibbles,
+    ) -> Result<Option<HashMap<Nibbles, TrieNode>>, DatabaseError> {
         let mut current_path = Nibbles::default();
         let mut account_nodes = HashMap::new();
         loop {
-            match self.database.db.get_account_trie_node(&current_path) {
+            match self.database.db.get_account_trie_node(&current_path)? {
                 Some(TrieNode::Branch(node)) => {
                     account_nodes.insert(current_path.clone(), TrieNode::Branch(node.clone()));
                     let next_nibble = target_path.get(current_path.len()).unwrap();
                     if node.state_mask.is_bit_set(next_nibble) {
                         current_path.extend_from_slice(&vec![next_nibble]);
                     } else {
-                        return None;
+                        return Ok(None);
                     }
                 }
                 Some(TrieNode::EmptyRoot) => {
@@ -136,7 +160,7 @@ impl<'a> EvmExecutor<'a> {
                     account_nodes.insert(current_path.clone(), TrieNode::Extension(node.clone()));
                     current_path.extend(&node.key);
                     if !target_path.to_vec().starts_with(&current_path.to_vec()) {
-                        return None;
+                        return Ok(None);
                     }
                 }
                 Some(TrieNode::Leaf(leaf_node)) => {
@@ -151,11 +175,11 @@ impl<'a> EvmExecutor<'a> {
                 }
             }
         }
-        Some(account_nodes)
+        Ok(Some(account_nodes))
     }
 
     /// Execute a transaction using revm
-    pub fn execute_tx(&mut self, tx: TxEnv) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    pub fn execute_tx(&mut self, tx: TxEnv) -> Result<TxReceipt, Box<dyn std::error::Error>> {
         // Handler::run(&mut self, evm);
         // Validate
         if tx.gas_limit == 0 {
@@ -166,8 +190,16 @@ impl<'a> EvmExecutor<'a> {
             return Err("Invalid gas price: cannot be zero".into());
         }
 
+        // Legacy txs pay gas_price, 1559 txs pay min(max_fee_per_gas, base_fee + priority_fee).
+        // Unused gas is refunded to the caller and the base fee part is burned by revm.
+        let effective_gas_price = tx.effective_gas_price(self.base_fee as u128);
+        // Contracts are deployed at an address derived from the sender and its nonce
+        let create_address = tx.kind.is_create().then(|| tx.caller.create(tx.nonce));
+
+        let base_fee = self.base_fee;
         let mut evm = Context::mainnet()
             .with_db(&mut self.database)
+            .modify_block_chained(|block| block.basefee = base_fee)
             .build_mainnet();
         let out = evm.transact(tx)?;
 
@@ -175,12 +207,19 @@ impl<'a> EvmExecutor<'a> {
             Some(output) => output.to_vec(),
             None => Vec::new(),
         };
-
-        // Handle state finalization and commit properly
-        let state = evm.ctx.journal_mut().finalize();
-        evm.ctx.db_mut().commit(state);
-
-        Ok(output_bytes)
+        let gas_used = out.result.gas_used();
+        let success = out.result.is_success();
+
+        // `transact` already finalized the journal, commit the state it returned
+        evm.ctx.db_mut().commit(out.state);
+
+        Ok(TxReceipt {
+            output: output_bytes,
+            gas_used,
+            effective_gas_price,
+            success,
+            contract_address: create_address.filter(|_| success),
+        })
     }
 }
 
@@ -202,16 +241,17 @@ fn keccak_slot(slot: &U256) -> B256 {
     B256::from(output)
 }
 
-
-
 #[cfg(test)]
 mod test {
     use super::*;
     use alloy_primitives::B256;
     use alloy_primitives::{Address, Bytes, U256};
+    use revm::database::DatabaseRef;
     use revm::primitives::TxKind;
     use revm::state::{AccountInfo, Bytecode};
 
+    const ONE_ETH: U256 = U256::from_limbs([1_000_000_000_000_000_000, 0, 0, 0]);
+
     #[test]
     fn test_execute_tx() {
         // Create a legacy transaction (type 0) with minimal gas requirements
@@ -232,12 +272,16 @@ mod test {
             authorization_list: vec![],
         };
 
-        let mut database = EvmDatabase::new();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut database = EvmDatabase::with_db(&db).unwrap();
         let account = AccountInfo::new(U256::from(100000), 0, B256::default(), Bytecode::default());
-        database.persistent_db.set_account(
-            &Address::from([0x1; 20]).to_vec(),
-            serde_json::to_vec(&account).unwrap(),
-        );
+        database
+            .persistent_db
+            .set_account(
+                Address::from([0x1; 20]).as_ref(),
+                serde_json::to_vec(&account).unwrap(),
+            )
+            .unwrap();
         let mut cache_db = CacheDB::<EvmDatabase>::new(database);
 
         let mut executor = EvmExecutor::new(&mut cache_db);
@@ -250,4 +294,178 @@ mod test {
             .unwrap();
         println!("acc {:?}", acc);
     }
+
+    #[test]
+    fn test_persistent_storage_survives_reopen() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let caller = Address::from([0x3; 20]);
+        let contract = Address::from([0x4; 20]);
+
+        let mut database = EvmDatabase::with_db(&db).unwrap();
+        let account = AccountInfo::new(ONE_ETH, 0, B256::default(), Bytecode::default());
+        database.save_account(&caller, &account).unwrap();
+        let mut cache_db = CacheDB::<EvmDatabase>::new(database);
+
+        // PUSH1 0x2a PUSH1 0x01 SSTORE STOP
+        let code = Bytecode::new_raw(Bytes::from_static(&[0x60, 0x2a, 0x60, 0x01, 0x55, 0x00]));
+        cache_db.insert_account_info(
+            contract,
+            AccountInfo::new(U256::ZERO, 1, code.hash_slow(), code),
+        );
+
+        let tx = TxEnv {
+            tx_type: 0,
+            caller,
+            gas_limit: 100_000,
+            gas_price: BASE_FEE as u128,
+            kind: TxKind::Call(contract),
+            value: U256::ZERO,
+            data: Bytes::new(),
+            nonce: 0,
+            chain_id: Some(1),
+            access_list: vec![].into(),
+            gas_priority_fee: None,
+            blob_hashes: vec![],
+            max_fee_per_blob_gas: 0,
+            authorization_list: vec![],
+        };
+
+        let mut executor = EvmExecutor::new(&mut cache_db);
+        executor.execute_tx(tx).unwrap();
+        executor.persistent().unwrap();
+        drop(cache_db);
+
+        // A fresh database has no cache, so the slot must come from disk
+        let reopened = EvmDatabase::with_db(&db).unwrap();
+        assert_eq!(
+            reopened.storage_ref(contract, U256::from(1)).unwrap(),
+            U256::from(0x2a)
+        );
+    }
+
+    #[test]
+    fn test_eip1559_gas_accounting() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let caller = Address::from([0x7; 20]);
+        let recipient = Address::from([0x8; 20]);
+
+        let mut database = EvmDatabase::with_db(&db).unwrap();
+        let account = AccountInfo::new(ONE_ETH, 0, B256::default(), Bytecode::default());
+        database.save_account(&caller, &account).unwrap();
+        let mut cache_db = CacheDB::<EvmDatabase>::new(database);
+
+        let tx = TxEnv {
+            tx_type: 2,
+            caller,
+            gas_limit: 50_000,
+            // max_fee_per_gas, well above base fee + tip
+            gas_price: 100,
+            kind: TxKind::Call(recipient),
+            value: U256::from(1000),
+            data: Bytes::new(),
+            nonce: 0,
+            chain_id: Some(1),
+            access_list: vec![].into(),
+            gas_priority_fee: Some(2),
+            blob_hashes: vec![],
+            max_fee_per_blob_gas: 0,
+            authorization_list: vec![],
+        };
+
+        let mut executor = EvmExecutor::new(&mut cache_db).with_base_fee(10);
+        let receipt = executor.execute_tx(tx).unwrap();
+        assert_eq!(receipt.gas_used, 21000);
+        assert_eq!(receipt.effective_gas_price, 12);
+
+        // Only the gas actually used is charged, at the effective price, not gas_limit * max fee
+        let caller_balance = cache_db.basic_ref(caller).unwrap().unwrap().balance;
+        assert_eq!(caller_balance, ONE_ETH - U256::from(1000 + 21000 * 12));
+        // The coinbase only receives the tip, the base fee is burned
+        let coinbase = cache_db.basic_ref(Address::ZERO).unwrap().unwrap().balance;
+        assert_eq!(coinbase, U256::from(21000 * 2));
+    }
+
+    #[test]
+    fn test_deploy_contract() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let deployer = Address::from([0x9; 20]);
+
+        let mut database = EvmDatabase::with_db(&db).unwrap();
+        let account = AccountInfo::new(ONE_ETH, 0, B256::default(), Bytecode::default());
+        database.save_account(&deployer, &account).unwrap();
+        let mut cache_db = CacheDB::<EvmDatabase>::new(database);
+
+        // Runtime code: PUSH1 0x2a PUSH1 0x01 SSTORE STOP
+        let runtime = [0x60, 0x2a, 0x60, 0x01, 0x55, 0x00];
+        // Init code copies the runtime code appended after it into memory and returns it
+        let mut init_code = vec![
+            0x60, 0x06, 0x60, 0x0c, 0x60, 0x00, 0x39, 0x60, 0x06, 0x60, 0x00, 0xf3,
+        ];
+        init_code.extend_from_slice(&runtime);
+
+        let mut executor = EvmExecutor::new(&mut cache_db);
+        let receipt = executor
+            .execute_tx(TxEnv {
+                tx_type: 0,
+                caller: deployer,
+                gas_limit: 200_000,
+                gas_price: BASE_FEE as u128,
+                kind: TxKind::Create,
+                value: U256::ZERO,
+                data: Bytes::from(init_code),
+                nonce: 0,
+                chain_id: Some(1),
+                access_list: vec![].into(),
+                gas_priority_fee: None,
+                blob_hashes: vec![],
+                max_fee_per_blob_gas: 0,
+                authorization_list: vec![],
+            })
+            .unwrap();
+        let contract = deployer.create(0);
+        assert!(receipt.success);
+        assert_eq!(receipt.contract_address, Some(contract));
+
+        executor.persistent().unwrap();
+        drop(cache_db);
+
+        // The code is read back from disk by its hash
+        let reopened = EvmDatabase::with_db(&db).unwrap();
+        let code_hash = reopened.basic_ref(contract).unwrap().unwrap().code_hash;
+        assert_eq!(
+            reopened
+                .code_by_hash_ref(code_hash)
+                .unwrap()
+                .original_bytes(),
+            Bytes::copy_from_slice(&runtime)
+        );
+
+        // And the deployed contract can be called
+        let mut cache_db = CacheDB::<EvmDatabase>::new(reopened);
+        let mut executor = EvmExecutor::new(&mut cache_db);
+        let receipt = executor
+            .execute_tx(TxEnv {
+                tx_type: 0,
+                caller: deployer,
+                gas_limit: 100_000,
+                gas_price: BASE_FEE as u128,
+                kind: TxKind::Call(contract),
+                value: U256::ZERO,
+                data: Bytes::new(),
+                nonce: 1,
+                chain_id: Some(1),
+                access_list: vec![].into(),
+                gas_priority_fee: None,
+                blob_hashes: vec![],
+                max_fee_per_blob_gas: 0,
+                authorization_list: vec![],
+            })
+            .unwrap();
+        assert!(receipt.success);
+        assert_eq!(receipt.contract_address, None);
+        assert_eq!(
+            cache_db.storage_ref(contract, U256::from(1)).unwrap(),
+            U256::from(0x2a)
+        );
+    }
 }
@@ -0,0 +1,217 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use tokio::sync::RwLock;
+
+use crate::evm::executor::{BASE_FEE, TxReceipt};
+
+// Gas available in a block, used for the gas used ratio of fee history
+pub const BLOCK_GAS_LIMIT: u64 = 30_000_000;
+// Number of recent blocks the oracle keeps
+static MAX_HISTORY_BLOCKS: usize = 1024;
+// Number of recent blocks sampled for a gas price suggestion
+static SUGGESTION_BLOCKS: usize = 20;
+// Percentile of sampled priority fees suggested by eth_gasPrice
+static SUGGESTION_PERCENTILE: f64 = 60.0;
+
+// Gas data captured for every generated block.
+#[derive(Clone, Debug)]
+pub struct BlockGasData {
+    pub block_num: u128,
+    pub base_fee: u64,
+    pub gas_used: u64,
+    // Priority fee per gas paid by each tx, sorted ascending
+    pub tips: Vec<u128>,
+}
+
+impl BlockGasData {
+    pub fn from_receipts(block_num: u128, base_fee: u64, receipts: &[TxReceipt]) -> Self {
+        let mut tips = receipts
+            .iter()
+            .map(|receipt| receipt.effective_gas_price.saturating_sub(base_fee as u128))
+            .collect::<Vec<_>>();
+        tips.sort();
+
+        Self {
+            block_num,
+            base_fee,
+            gas_used: receipts.iter().map(|receipt| receipt.gas_used).sum(),
+            tips,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeeHistory {
+    pub oldest_block: u128,
+    // One entry per returned block plus the base fee of the next block
+    pub base_fee_per_gas: Vec<u64>,
+    pub gas_used_ratio: Vec<f64>,
+    pub reward: Vec<Vec<u128>>,
+}
+
+#[derive(Default)]
+pub struct GasOracle {
+    blocks: VecDeque<BlockGasData>,
+}
+
+impl GasOracle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_block(&mut self, data: BlockGasData) {
+        if self.blocks.len() == MAX_HISTORY_BLOCKS {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back(data);
+    }
+
+    pub fn latest_block_num(&self) -> Option<u128> {
+        self.blocks.back().map(|block| block.block_num)
+    }
+
+    fn next_base_fee(&self) -> u64 {
+        self.blocks
+            .back()
+            .map(|block| block.base_fee)
+            .unwrap_or(BASE_FEE)
+    }
+
+    /// Suggest a legacy gas price: the next base fee plus a percentile of the
+    /// priority fees paid over the recent blocks.
+    pub fn suggest_gas_price(&self) -> u128 {
+        let mut tips = self
+            .blocks
+            .iter()
+            .rev()
+            .take(SUGGESTION_BLOCKS)
+            .flat_map(|block| block.tips.iter().copied())
+            .collect::<Vec<_>>();
+        tips.sort();
+
+        self.next_base_fee() as u128 + percentile(&tips, SUGGESTION_PERCENTILE)
+    }
+
+    /// Fee history of up to `block_count` blocks ending at `newest_block`.
+    pub fn fee_history(
+        &self,
+        block_count: usize,
+        newest_block: u128,
+        reward_percentiles: &[f64],
+    ) -> Result<FeeHistory, String> {
+        if reward_percentiles.windows(2).any(|pair| pair[0] > pair[1])
+            || reward_percentiles
+                .iter()
+                .any(|p| !(0.0..=100.0).contains(p))
+        {
+            return Err("Reward percentiles must be increasing and within [0, 100]".to_string());
+        }
+
+        let blocks = self
+            .blocks
+            .iter()
+            .filter(|block| block.block_num <= newest_block)
+            .collect::<Vec<_>>();
+        let blocks = &blocks[blocks.len().saturating_sub(block_count)..];
+
+        let Some(oldest) = blocks.first() else {
+            return Ok(FeeHistory {
+                oldest_block: newest_block,
+                base_fee_per_gas: vec![],
+                gas_used_ratio: vec![],
+                reward: vec![],
+            });
+        };
+
+        let mut base_fee_per_gas = blocks
+            .iter()
+            .map(|block| block.base_fee)
+            .collect::<Vec<_>>();
+        // The base fee is fixed, so the next block pays the same as the newest one
+        base_fee_per_gas.push(blocks[blocks.len() - 1].base_fee);
+
+        Ok(FeeHistory {
+            oldest_block: oldest.block_num,
+            base_fee_per_gas,
+            gas_used_ratio: blocks
+                .iter()
+                .map(|block| block.gas_used as f64 / BLOCK_GAS_LIMIT as f64)
+                .collect(),
+            reward: blocks
+                .iter()
+                .map(|block| {
+                    reward_percentiles
+                        .iter()
+                        .map(|p| percentile(&block.tips, *p))
+                        .collect()
+                })
+                .collect(),
+        })
+    }
+}
+
+// Value at percentile `p` of an ascending list, 0 when empty
+fn percentile(sorted: &[u128], p: f64) -> u128 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let index = ((sorted.len() - 1) as f64 * p / 100.0).round() as usize;
+    sorted[index]
+}
+
+// Global gas price oracle, fed by the evm block builder
+lazy_static::lazy_static! {
+    pub static ref GAS_ORACLE: Arc<RwLock<GasOracle>> = Arc::new(RwLock::new(GasOracle::new()));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(block_num: u128, tips: &[u128]) -> BlockGasData {
+        let receipts = tips
+            .iter()
+            .map(|tip| TxReceipt {
+                output: vec![],
+                gas_used: 21000,
+                effective_gas_price: 100 + tip,
+                success: true,
+                contract_address: None,
+            })
+            .collect::<Vec<_>>();
+        BlockGasData::from_receipts(block_num, 100, &receipts)
+    }
+
+    #[test]
+    fn test_suggestion_follows_recent_tips() {
+        let mut oracle = GasOracle::new();
+        assert_eq!(oracle.suggest_gas_price(), BASE_FEE as u128);
+
+        oracle.record_block(block(1, &[3, 1]));
+        oracle.record_block(block(2, &[10]));
+        oracle.record_block(block(3, &[7, 5, 2]));
+
+        // Tips 1, 2, 3, 5, 7, 10: the 60th percentile is 5
+        assert_eq!(oracle.suggest_gas_price(), 105);
+    }
+
+    #[test]
+    fn test_fee_history() {
+        let mut oracle = GasOracle::new();
+        oracle.record_block(block(1, &[3, 1]));
+        oracle.record_block(block(2, &[]));
+        oracle.record_block(block(3, &[7, 5, 2]));
+
+        let history = oracle.fee_history(2, 3, &[0.0, 50.0, 100.0]).unwrap();
+        assert_eq!(history.oldest_block, 2);
+        assert_eq!(history.base_fee_per_gas, vec![100, 100, 100]);
+        assert_eq!(
+            history.gas_used_ratio,
+            vec![0.0, 63000.0 / BLOCK_GAS_LIMIT as f64]
+        );
+        assert_eq!(history.reward, vec![vec![0, 0, 0], vec![2, 5, 7]]);
+
+        assert!(oracle.fee_history(2, 3, &[50.0, 10.0]).is_err());
+    }
+}
@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use alloy_consensus::{Transaction, TxEnvelope, TypedTransaction};
 use alloy_eips::Decodable2718;
-use alloy_primitives::{Address, Bytes, U256};
+use alloy_primitives::{Address, B256, Bytes, U256};
 use revm::DatabaseCommit;
 use revm::context::ContextTr;
 use revm::context::TxEnv;
@@ -11,7 +11,8 @@ use tokio::sync::RwLock;
 use crate::evm::storage::EvmDatabase;
 
 pub struct Mempool {
-    pub txns: Vec<TxEnv>,
+    // Pending txns with their tx hash
+    pub txns: Vec<(B256, TxEnv)>,
 }
 
 impl Mempool {
@@ -19,16 +20,18 @@ impl Mempool {
         Self { txns: Vec::new() }
     }
 
-    pub async fn add_evm_txn(&mut self, param: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let txn = parse_raw_transaction(param.as_bytes())?;
-        self.txns.push(txn);
-        Ok(String::from(""))
+    pub async fn add_evm_txn(&mut self, param: &str) -> Result<B256, Box<dyn std::error::Error>> {
+        let (tx_hash, txn) = parse_raw_transaction(param.as_bytes())?;
+        self.txns.push((tx_hash, txn));
+        Ok(tx_hash)
     }
 }
 
-fn parse_raw_transaction(raw_tx: &[u8]) -> Result<TxEnv, Box<dyn std::error::Error>> {
+fn parse_raw_transaction(raw_tx: &[u8]) -> Result<(B256, TxEnv), Box<dyn std::error::Error>> {
     let mut data = raw_tx;
-    let transaction = TxEnvelope::decode_2718(&mut &mut data)?.into_typed_transaction();
+    let envelope = TxEnvelope::decode_2718(&mut &mut data)?;
+    let tx_hash = *envelope.tx_hash();
+    let transaction = envelope.into_typed_transaction();
 
     let tx = match transaction {
         TypedTransaction::Legacy(tx) => Some(TxEnv {
@@ -47,10 +50,27 @@ fn parse_raw_transaction(raw_tx: &[u8]) -> Result<TxEnv, Box<dyn std::error::Err
             max_fee_per_blob_gas: 0,
             authorization_list: vec![],
         }),
+        // For 1559 txs revm treats `gas_price` as the max fee per gas
+        TypedTransaction::Eip1559(tx) => Some(TxEnv {
+            tx_type: 2,
+            caller: tx.to.into_to().unwrap_or_default(),
+            gas_limit: tx.gas_limit,
+            gas_price: tx.max_fee_per_gas,
+            kind: tx.kind(),
+            value: tx.value,
+            data: tx.input,
+            nonce: tx.nonce,
+            chain_id: Some(tx.chain_id),
+            access_list: tx.access_list,
+            gas_priority_fee: Some(tx.max_priority_fee_per_gas),
+            blob_hashes: vec![],
+            max_fee_per_blob_gas: 0,
+            authorization_list: vec![],
+        }),
         _ => None,
     };
 
-    Ok(tx.unwrap())
+    Ok((tx_hash, tx.unwrap()))
 }
 
 // Global evm mempool instance
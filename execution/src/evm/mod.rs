@@ -1,13 +1,17 @@
 pub mod block_builder;
 pub mod executor;
+pub mod gas_oracle;
 pub mod mempool;
 pub mod storage;
 pub mod trie;
 
+use alloy_primitives::B256;
 use axum::{extract::Json, http::StatusCode, response::Json as ResponseJson};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 
+use crate::evm::block_builder::EVM_RECEIPTS;
+use crate::evm::gas_oracle::GAS_ORACLE;
 use crate::evm::mempool::EVM_MEMPOOL;
 
 #[derive(Deserialize)]
@@ -65,10 +69,65 @@ pub async fn handle_evm_request(
             Ok(ResponseJson(EvmResponse::success(result, id)))
         }
         "eth_gasPrice" => {
-            // response: return gas price as 0x3b9aca00 (1 Gwei)
-            let result = json!("0x3b9aca00");
+            // response: base fee plus a percentile of the recent priority fees
+            let gas_price = GAS_ORACLE.read().await.suggest_gas_price();
+            let result = json!(format!("0x{:x}", gas_price));
             Ok(ResponseJson(EvmResponse::success(result, id)))
         }
+        "eth_feeHistory" => {
+            // params: [blockCount, newestBlock, rewardPercentiles]
+            let oracle = GAS_ORACLE.read().await;
+            let block_count = request.params.first().and_then(parse_quantity);
+            let newest_block = match request.params.get(1) {
+                Some(Value::String(tag)) if tag == "latest" || tag == "pending" => {
+                    Some(oracle.latest_block_num().unwrap_or_default())
+                }
+                Some(value) => parse_quantity(value),
+                None => None,
+            };
+            let reward_percentiles = request
+                .params
+                .get(2)
+                .and_then(|v| v.as_array())
+                .map(|values| values.iter().filter_map(|v| v.as_f64()).collect::<Vec<_>>())
+                .unwrap_or_default();
+
+            let (Some(block_count), Some(newest_block)) = (block_count, newest_block) else {
+                let error = json!({
+                    "code": -32602,
+                    "message": "Invalid params"
+                });
+                return Ok(ResponseJson(EvmResponse::error(error, id)));
+            };
+
+            match oracle.fee_history(block_count as usize, newest_block, &reward_percentiles) {
+                Ok(history) => {
+                    let to_hex = |value: u128| format!("0x{:x}", value);
+                    let result = json!({
+                        "oldestBlock": to_hex(history.oldest_block),
+                        "baseFeePerGas": history
+                            .base_fee_per_gas
+                            .iter()
+                            .map(|fee| to_hex(*fee as u128))
+                            .collect::<Vec<_>>(),
+                        "gasUsedRatio": history.gas_used_ratio,
+                        "reward": history
+                            .reward
+                            .iter()
+                            .map(|rewards| rewards.iter().map(|r| to_hex(*r)).collect::<Vec<_>>())
+                            .collect::<Vec<_>>(),
+                    });
+                    Ok(ResponseJson(EvmResponse::success(result, id)))
+                }
+                Err(e) => {
+                    let error = json!({
+                        "code": -32602,
+                        "message": e
+                    });
+                    Ok(ResponseJson(EvmResponse::error(error, id)))
+                }
+            }
+        }
         "eth_getBalance" => {
             // response: return balance as 0x3635c9adc5dea00000 (1000 ETH in wei)
             let result = json!("0x3635c9adc5dea00000");
@@ -105,8 +164,27 @@ pub async fn handle_evm_request(
             }
         }
         "eth_getTransactionReceipt" => {
-            // response: return null (transaction not found)
-            let result = json!(null);
+            // response: null until the transaction is included in a block
+            let tx_hash = request
+                .params
+                .first()
+                .and_then(|v| v.as_str())
+                .and_then(|hash| hash.parse::<B256>().ok());
+            let receipts = EVM_RECEIPTS.read().await;
+            let result = match tx_hash.and_then(|hash| receipts.get(&hash).map(|r| (hash, r))) {
+                Some((tx_hash, block_receipt)) => {
+                    let receipt = &block_receipt.receipt;
+                    json!({
+                        "transactionHash": format!("{:#x}", tx_hash),
+                        "blockNumber": format!("0x{:x}", block_receipt.block_num),
+                        "status": if receipt.success { "0x1" } else { "0x0" },
+                        "gasUsed": format!("0x{:x}", receipt.gas_used),
+                        "effectiveGasPrice": format!("0x{:x}", receipt.effective_gas_price),
+                        "contractAddress": receipt.contract_address.map(|a| format!("{:#x}", a)),
+                    })
+                }
+                None => json!(null),
+            };
             Ok(ResponseJson(EvmResponse::success(result, id)))
         }
         "eth_getBlockByNumber" => {
@@ -166,3 +244,12 @@ pub async fn handle_evm_request(
         }
     }
 }
+
+// Parse a JSON-RPC quantity, given either as a hex string or a number
+fn parse_quantity(value: &Value) -> Option<u128> {
+    match value {
+        Value::String(hex) => u128::from_str_radix(hex.trim_start_matches("0x"), 16).ok(),
+        Value::Number(number) => number.as_u64().map(u128::from),
+        _ => None,
+    }
+}
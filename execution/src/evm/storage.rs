@@ -4,6 +4,7 @@ use alloy_primitives::U256;
 use alloy_rlp::Decodable;
 use alloy_trie::Nibbles;
 use alloy_trie::nodes::TrieNode;
+use common::db::{OpenDbError, open_db};
 use revm::database::DBErrorMarker;
 use revm::database::{Database, DatabaseRef};
 use revm::primitives::StorageKey;
@@ -37,99 +38,139 @@ impl PersistentDb {
         }
     }
 
-    pub fn get_account(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.account_table
-            .get(key)
-            .ok()
-            .flatten()
-            .map(|v| v.to_vec())
+    pub fn get_account(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DatabaseError> {
+        Ok(self.account_table.get(key)?.map(|v| v.to_vec()))
     }
 
-    pub fn set_account(&mut self, key: &[u8], value: Vec<u8>) {
-        self.account_table.insert(key, value).unwrap();
+    pub fn set_account(&mut self, key: &[u8], value: Vec<u8>) -> Result<(), DatabaseError> {
+        self.account_table.insert(key, value)?;
+        Ok(())
     }
 
-    pub fn get_code(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.code_table.get(key).ok().flatten().map(|v| v.to_vec())
+    pub fn get_code(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DatabaseError> {
+        Ok(self.code_table.get(key)?.map(|v| v.to_vec()))
     }
 
-    pub fn set_code(&mut self, key: &[u8], value: Vec<u8>) {
-        self.code_table.insert(key, value).unwrap();
+    pub fn set_code(&mut self, key: &[u8], value: Vec<u8>) -> Result<(), DatabaseError> {
+        self.code_table.insert(key, value)?;
+        Ok(())
     }
 
-    pub fn get_storage(&self, key: &[u8]) -> Option<Vec<u8>> {
-        Some(self.storage_table.get(key).unwrap()?.to_vec())
+    pub fn get_storage(
+        &self,
+        address: &Address,
+        slot: &StorageKey,
+    ) -> Result<Option<StorageValue>, DatabaseError> {
+        Ok(self
+            .storage_table
+            .get(storage_key(address, slot))?
+            .map(|v| U256::from_be_slice(&v)))
     }
 
-    pub fn set_storage(&mut self, key: &[u8], value: Vec<u8>) {
-        self.storage_table.insert(key, value).unwrap();
+    pub fn set_storage(
+        &mut self,
+        address: &Address,
+        slot: &StorageKey,
+        value: &StorageValue,
+    ) -> Result<(), DatabaseError> {
+        self.storage_table
+            .insert(storage_key(address, slot), value.to_be_bytes_vec())?;
+        Ok(())
     }
 
-    pub fn set_account_trie_node(&mut self, key: &[u8], value: Vec<u8>) {
-        self.account_trie.insert(key, value).unwrap();
+    pub fn set_account_trie_node(
+        &mut self,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<(), DatabaseError> {
+        self.account_trie.insert(key, value)?;
+        Ok(())
     }
 
-    pub fn get_account_trie_node(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.account_trie
-            .get(key)
-            .ok()
-            .flatten()
-            .map(|v| v.to_vec())
+    pub fn get_account_trie_node(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DatabaseError> {
+        Ok(self.account_trie.get(key)?.map(|v| v.to_vec()))
     }
 }
 
-
 pub struct EvmDatabase {
     pub account_infos: Vec<AccountInfo>,
     pub persistent_db: PersistentDb,
 }
 
 impl EvmDatabase {
-    pub fn new() -> Self {
-        let db = sled::open("evm_db").unwrap();
+    pub fn new() -> Result<Self, OpenDbError> {
+        Self::with_path("evm_db")
+    }
+
+    /// Open the EVM state database at `path`, so separate instances don't share a sled lock.
+    pub fn with_path(path: &str) -> Result<Self, OpenDbError> {
+        Self::with_db(&open_db(path)?).map_err(|source| OpenDbError::Sled {
+            path: path.to_string(),
+            source,
+        })
+    }
 
+    /// Open the EVM state trees on an already opened sled database.
+    pub fn with_db(db: &sled::Db) -> Result<Self, sled::Error> {
         let persistent_db = PersistentDb::new(
-            db.open_tree("account_table").unwrap(),
-            db.open_tree("code_table").unwrap(),
-            db.open_tree("storage_table").unwrap(),
-            db.open_tree("account_tree").unwrap(),
-            db.open_tree("storage_tree").unwrap(),
+            db.open_tree("account_table")?,
+            db.open_tree("code_table")?,
+            db.open_tree("storage_table")?,
+            db.open_tree("account_tree")?,
+            db.open_tree("storage_tree")?,
         );
 
-        Self {
+        Ok(Self {
             account_infos: vec![],
             persistent_db: persistent_db,
-        }
+        })
     }
 
-    pub fn save_account(&mut self, address: &Address, account: &AccountInfo) {
-        self.persistent_db
-            .set_account(&address.to_vec(), serde_json::to_vec(account).unwrap());
+    pub fn save_account(
+        &mut self,
+        address: &Address,
+        account: &AccountInfo,
+    ) -> Result<(), DatabaseError> {
+        // Code lives in the code table, keyed by its hash
+        let value = serde_json::to_vec(&account.copy_without_code())
+            .map_err(|e| DatabaseError::DbError(e.into()))?;
+        self.persistent_db.set_account(&address.to_vec(), value)
     }
 
-    pub fn save_code(&mut self, address: &Address, code: &Bytecode) {
+    pub fn save_code(&mut self, code_hash: &B256, code: &Bytecode) -> Result<(), DatabaseError> {
         self.persistent_db
-            .set_code(&address.to_vec(), code.bytes_slice().to_vec());
+            .set_code(code_hash.as_slice(), code.original_bytes().to_vec())
     }
 
-    pub fn save_storage(&mut self, key: &StorageKey, value: &StorageValue) {
-        self.persistent_db
-            .set_storage(&key.to_be_bytes_vec(), value.to_be_bytes_vec());
+    pub fn save_storage(
+        &mut self,
+        address: &Address,
+        slot: &StorageKey,
+        value: &StorageValue,
+    ) -> Result<(), DatabaseError> {
+        self.persistent_db.set_storage(address, slot, value)
     }
 
-    pub fn insert_account_trie_node(&mut self, key: &Nibbles, node: &TrieNode) {
+    pub fn insert_account_trie_node(
+        &mut self,
+        key: &Nibbles,
+        node: &TrieNode,
+    ) -> Result<(), DatabaseError> {
         let mut rlp_data = Vec::new();
         let _rlp_node = node.rlp(&mut rlp_data);
         self.persistent_db
-            .set_account_trie_node(&key.to_vec(), rlp_data);
+            .set_account_trie_node(&key.to_vec(), rlp_data)
     }
 
-    pub fn get_account_trie_node(&self, key: &Nibbles) -> Option<TrieNode> {
-        let rlp_value = self
-            .persistent_db
-            .get_account_trie_node(&key.to_vec())
-            .unwrap();
-        Some(TrieNode::decode(&mut rlp_value.as_slice()).ok()?)
+    pub fn get_account_trie_node(&self, key: &Nibbles) -> Result<Option<TrieNode>, DatabaseError> {
+        match self.persistent_db.get_account_trie_node(&key.to_vec())? {
+            Some(rlp_value) => {
+                let node = TrieNode::decode(&mut rlp_value.as_slice())
+                    .map_err(|e| DatabaseError::DbError(e.into()))?;
+                Ok(Some(node))
+            }
+            None => Ok(None),
+        }
     }
 }
 
@@ -161,7 +202,7 @@ impl DatabaseRef for EvmDatabase {
     type Error = DatabaseError;
 
     fn basic_ref(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
-        match self.persistent_db.get_account(address.as_slice()) {
+        match self.persistent_db.get_account(address.as_slice())? {
             Some(data) => {
                 let account =
                     serde_json::from_slice(&data).map_err(|e| DatabaseError::DbError(e.into()))?;
@@ -172,12 +213,8 @@ impl DatabaseRef for EvmDatabase {
     }
 
     fn code_by_hash_ref(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
-        match self.persistent_db.get_code(code_hash.as_slice()) {
-            Some(data) => {
-                let code =
-                    serde_json::from_slice(&data).map_err(|e| DatabaseError::DbError(e.into()))?;
-                Ok(code)
-            }
+        match self.persistent_db.get_code(code_hash.as_slice())? {
+            Some(data) => Ok(Bytecode::new_raw(data.into())),
             None => Ok(Bytecode::default()), // Code doesn't exist
         }
     }
@@ -187,12 +224,10 @@ impl DatabaseRef for EvmDatabase {
         address: Address,
         index: StorageKey,
     ) -> Result<StorageValue, Self::Error> {
-        let slot = storage_key(&address, &index);
-
-        match self.persistent_db.get_storage(&slot) {
-            Some(value) => Ok(U256::from_be_slice(&value)),
-            None => Ok(U256::default()),
-        }
+        Ok(self
+            .persistent_db
+            .get_storage(&address, &index)?
+            .unwrap_or_default())
     }
 
     fn block_hash_ref(&self, _number: u64) -> Result<B256, Self::Error> {
@@ -200,6 +235,7 @@ impl DatabaseRef for EvmDatabase {
     }
 }
 
+// Slots are namespaced by contract address so different contracts don't collide
 fn storage_key(contract_address: &Address, slot: &StorageKey) -> Vec<u8> {
     let mut key = Vec::with_capacity(64); // 32 + 32 字节
     key.extend_from_slice(contract_address.as_slice());
@@ -215,3 +251,80 @@ pub enum DatabaseError {
 }
 
 impl DBErrorMarker for DatabaseError {}
+
+impl From<sled::Error> for DatabaseError {
+    fn from(e: sled::Error) -> Self {
+        DatabaseError::DbError(e.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_database() -> (sled::Db, EvmDatabase) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let database = EvmDatabase::with_db(&db).unwrap();
+        (db, database)
+    }
+
+    #[test]
+    fn test_with_path_isolated() {
+        let base = std::env::temp_dir().join(format!("evm_db_isolation_{}", std::process::id()));
+        let path_a = base.join("a");
+        let path_b = base.join("b");
+
+        // Both databases stay open at the same time
+        let mut db_a = EvmDatabase::with_path(path_a.to_str().unwrap()).unwrap();
+        let db_b = EvmDatabase::with_path(path_b.to_str().unwrap()).unwrap();
+
+        let address = Address::from([0x7; 20]);
+        let account = AccountInfo::new(U256::from(42), 0, B256::default(), Bytecode::default());
+        db_a.save_account(&address, &account).unwrap();
+
+        assert_eq!(
+            db_a.basic_ref(address).unwrap().map(|acc| acc.balance),
+            Some(U256::from(42))
+        );
+        assert!(db_b.basic_ref(address).unwrap().is_none());
+
+        drop(db_a);
+        drop(db_b);
+        let _ = std::fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn test_dropped_tree_returns_error() {
+        let (db, mut database) = temp_database();
+        // Simulate a broken backing store under a live handle
+        db.drop_tree("storage_table").unwrap();
+        db.drop_tree("account_table").unwrap();
+
+        let address = Address::from([0x9; 20]);
+        assert!(
+            database
+                .save_storage(&address, &U256::from(1), &U256::from(2))
+                .is_err()
+        );
+        assert!(database.storage_ref(address, U256::from(1)).is_err());
+        assert!(database.basic_ref(address).is_err());
+    }
+
+    #[test]
+    fn test_storage_round_trip() {
+        let (_db, mut database) = temp_database();
+
+        let contract = Address::from([0xc; 20]);
+        let other = Address::from([0xd; 20]);
+        let slot = U256::from(3);
+        database
+            .save_storage(&contract, &slot, &U256::from(1234))
+            .unwrap();
+
+        assert_eq!(
+            database.storage_ref(contract, slot).unwrap(),
+            U256::from(1234)
+        );
+        assert_eq!(database.storage_ref(other, slot).unwrap(), U256::ZERO);
+    }
+}
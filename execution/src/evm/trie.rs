@@ -1,20 +1,20 @@
 use alloy_primitives::{
-    B256,
+    B256, keccak256,
     map::foldhash::{HashMap, HashMapExt},
 };
 use alloy_rlp::{BufMut, Encodable};
 use alloy_trie::{
-    Nibbles,
+    EMPTY_ROOT_HASH, Nibbles,
     nodes::{BranchNode, ExtensionNode, RlpNode, TrieNode},
 };
-use tiny_keccak::{Hasher, Sha3};
 
 type StateCommitment = alloy_trie::proof::DecodedProofRetainer;
 
+/// Propagates the updated nodes up to the root and returns the new root hash.
 pub fn calculate_trie_updates(
     mut nodes_to_update: HashMap<Nibbles, TrieNode>,
     trie_updates: &mut HashMap<Nibbles, TrieNode>,
-) {
+) -> B256 {
     while !nodes_to_update.is_empty() {
         let mut next_to_update: HashMap<Nibbles, TrieNode> = HashMap::new();
         for (path, node) in nodes_to_update.iter() {
@@ -22,10 +22,14 @@ pub fn calculate_trie_updates(
                 let path_vec = path.to_vec();
                 let parent_vec = path_vec[0..path_vec.len() - 1].to_vec();
                 let parent_path = Nibbles::from_nibbles(parent_vec);
-                let parent_node = trie_updates.get(&parent_path).unwrap();
+                // Nothing to propagate into when the parent isn't part of the fetched trie
+                let Some(parent_node) = trie_updates.get(&parent_path) else {
+                    continue;
+                };
                 match parent_node {
                     TrieNode::Branch(branch_node) => {
-                        let child_index = path_vec[path_vec.len()];
+                        // The child's slot in its parent branch is the last nibble of its path
+                        let child_index = path_vec[path_vec.len() - 1];
 
                         // Create child_rlp
                         let new_child_rlp = node_to_rlp(node);
@@ -76,36 +80,119 @@ pub fn calculate_trie_updates(
         }
         nodes_to_update = next_to_update;
     }
+
+    // The root is always referenced by hash, whatever its size
+    match trie_updates.get(&Nibbles::default()) {
+        Some(TrieNode::EmptyRoot) | None => EMPTY_ROOT_HASH,
+        Some(root) => keccak_node(&encode_node(root)),
+    }
+}
+
+fn encode_node(node: &TrieNode) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    node.encode(&mut encoded as &mut dyn BufMut);
+    encoded
 }
 
+// Nodes shorter than 32 bytes are inlined in their parent, larger ones are referenced by hash
 fn node_to_rlp(node: &TrieNode) -> Option<RlpNode> {
-    let new_child_rlp = match node {
-        TrieNode::Leaf(leaf_node) => {
-            // Encode the leaf node to get its RLP representation
-            let mut encoded = Vec::new();
-            leaf_node.encode(&mut encoded as &mut dyn BufMut);
-            RlpNode::from_raw(&encoded)
-        }
-        TrieNode::Branch(branch) => {
-            let mut encoded = Vec::new();
-            branch.encode(&mut encoded as &mut dyn BufMut);
-            RlpNode::from_raw(&encoded)
-        }
-        TrieNode::Extension(ext) => {
-            let mut encoded = Vec::new();
-            ext.encode(&mut encoded as &mut dyn BufMut);
-            RlpNode::from_raw(&encoded)
-        }
-        TrieNode::EmptyRoot => None,
-    };
-    new_child_rlp
+    if let TrieNode::EmptyRoot = node {
+        return None;
+    }
+    let encoded = encode_node(node);
+    if encoded.len() < 32 {
+        RlpNode::from_raw(&encoded)
+    } else {
+        Some(RlpNode::word_rlp(&keccak_node(&encoded)))
+    }
 }
 
 fn keccak_node(encoded_node: &Vec<u8>) -> B256 {
-    let mut sha3 = Sha3::v256();
-    let mut output = [0u8; 32];
+    keccak256(encoded_node)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloy_primitives::U256;
+    use alloy_trie::{HashBuilder, TrieAccount, TrieMask, nodes::LeafNode};
+
+    // A leaf small enough to be referenced inline by its parent
+    fn small_leaf(nibble: u8) -> TrieNode {
+        TrieNode::Leaf(LeafNode::new(Nibbles::from_nibbles([nibble]), vec![0x01]))
+    }
+
+    #[test]
+    fn test_update_leaf_under_branch() {
+        let existing = node_to_rlp(&small_leaf(0x3)).unwrap();
+        let root = BranchNode::new(vec![existing.clone()], TrieMask::new(1 << 3));
+        let mut trie_updates = HashMap::new();
+        trie_updates.insert(Nibbles::default(), TrieNode::Branch(root));
+
+        let leaf = small_leaf(0xa);
+        let mut nodes_to_update = HashMap::new();
+        nodes_to_update.insert(Nibbles::from_nibbles([0x5]), leaf.clone());
 
-    sha3.update(encoded_node);
-    sha3.finalize(&mut output);
-    B256::from(output)
+        calculate_trie_updates(nodes_to_update, &mut trie_updates);
+
+        match trie_updates.get(&Nibbles::default()).unwrap() {
+            TrieNode::Branch(branch) => {
+                assert!(branch.state_mask.is_bit_set(0x3));
+                assert!(branch.state_mask.is_bit_set(0x5));
+                assert_eq!(branch.stack, vec![existing, node_to_rlp(&leaf).unwrap()]);
+            }
+            _ => panic!("root must remain a branch node"),
+        }
+    }
+
+    fn account_rlp(nonce: u64, balance: u64) -> Vec<u8> {
+        let account = TrieAccount {
+            nonce,
+            balance: U256::from(balance),
+            ..Default::default()
+        };
+        let mut encoded = Vec::new();
+        account.encode(&mut encoded as &mut dyn BufMut);
+        encoded
+    }
+
+    #[test]
+    fn test_root_matches_ethereum_trie() {
+        // Two hashed account keys diverging at the first nibble
+        let mut keys = [B256::repeat_byte(0x11), B256::repeat_byte(0xa2)];
+        keys.sort();
+        let values = [account_rlp(1, 1_000_000), account_rlp(7, 42)];
+
+        let mut expected = HashBuilder::default();
+        for (key, value) in keys.iter().zip(values.iter()) {
+            expected.add_leaf(Nibbles::unpack(key), value);
+        }
+        let expected_root = expected.root();
+
+        // Start from the branch holding the first account, then insert the second one
+        let first_path = Nibbles::unpack(keys[0]);
+        let first_leaf = TrieNode::Leaf(LeafNode::new(first_path.slice(1..), values[0].clone()));
+        let first_rlp = node_to_rlp(&first_leaf).unwrap();
+        let root = BranchNode::new(
+            vec![first_rlp],
+            TrieMask::new(1 << first_path.get(0).unwrap()),
+        );
+        let mut trie_updates = HashMap::new();
+        trie_updates.insert(Nibbles::default(), TrieNode::Branch(root));
+
+        let second_path = Nibbles::unpack(keys[1]);
+        let second_leaf = TrieNode::Leaf(LeafNode::new(second_path.slice(1..), values[1].clone()));
+        let mut nodes_to_update = HashMap::new();
+        nodes_to_update.insert(second_path.slice(..1), second_leaf);
+
+        let root = calculate_trie_updates(nodes_to_update, &mut trie_updates);
+        assert_eq!(root, expected_root);
+    }
+
+    #[test]
+    fn test_empty_trie_root() {
+        let mut trie_updates = HashMap::new();
+        let root = calculate_trie_updates(HashMap::new(), &mut trie_updates);
+        assert_eq!(root, EMPTY_ROOT_HASH);
+    }
 }
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::exchange::matching::{OrderBook, OrderBookSnapshot};
+use common::db::BOOK_HISTORY_TREE;
+
+// Key of a pair's book as of a block: the pair id, then the block number big-endian so a
+// pair's records iterate in block order
+fn history_key(pair_id: &str, block_num: u128) -> Vec<u8> {
+    let mut key = history_prefix(pair_id);
+    key.extend_from_slice(&block_num.to_be_bytes());
+    key
+}
+
+fn history_prefix(pair_id: &str) -> Vec<u8> {
+    format!("{}/", pair_id).into_bytes()
+}
+
+/// Resting orders of each pair's book as blocks were produced, kept on disk to look back at
+/// for dispute resolution. A book is only recorded at blocks where it changed, so the book at
+/// a block is the latest record at or before it.
+#[derive(Clone, Debug)]
+pub struct BookHistory {
+    tree: sled::Tree,
+    // Seq of each pair's last recorded book, to skip books that didn't change
+    recorded_seqs: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl BookHistory {
+    pub fn open(db: &sled::Db) -> anyhow::Result<Self> {
+        Ok(Self {
+            tree: db.open_tree(BOOK_HISTORY_TREE)?,
+            recorded_seqs: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Record `book`, a copy of `pair_id`'s resting orders, as of block `block_num`, unless
+    /// the book is unchanged since it was last recorded.
+    pub fn record(&self, block_num: u128, pair_id: &str, book: &OrderBook) -> anyhow::Result<()> {
+        let mut recorded_seqs = self
+            .recorded_seqs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if recorded_seqs.get(pair_id) == Some(&book.seq()) {
+            return Ok(());
+        }
+        self.tree.insert(
+            history_key(pair_id, block_num),
+            serde_json::to_vec(&book.snapshot())?,
+        )?;
+        recorded_seqs.insert(pair_id.to_string(), book.seq());
+        Ok(())
+    }
+
+    /// The resting orders of `pair_id`'s book as of block `block_num`, with the block they
+    /// were recorded at. None if the pair had no book recorded yet at that block.
+    pub fn at(&self, pair_id: &str, block_num: u128) -> anyhow::Result<Option<(u128, OrderBook)>> {
+        let prefix = history_prefix(pair_id);
+        let record = self
+            .tree
+            .range(prefix.clone()..=history_key(pair_id, block_num))
+            .next_back()
+            .transpose()?;
+        let Some((key, data)) = record else {
+            return Ok(None);
+        };
+        let recorded_at = u128::from_be_bytes(key[prefix.len()..].try_into()?);
+        let snapshot: OrderBookSnapshot = serde_json::from_slice(&data)?;
+        Ok(Some((recorded_at, OrderBook::restore(snapshot))))
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use common::db::FUNDS_LOG_TREE;
+use common::state::State;
+use common::traces::{BalanceDeltas, Funding, Transfer};
+
+// Funds moved outside of trading, which the exchange applies right away and seals later
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum FundsMove {
+    Transfer(Transfer),
+    Funding(Funding),
+}
+
+impl FundsMove {
+    fn add_deltas(&self, deltas: &mut BalanceDeltas) {
+        match self {
+            FundsMove::Transfer(transfer) => transfer.add_deltas(deltas),
+            FundsMove::Funding(funding) => funding.add_deltas(deltas),
+        }
+    }
+}
+
+/// Transfers, deposits and withdrawals applied to the state but not sealed into a block yet,
+/// in the order they were made. Each is logged before it's acknowledged, so a crash before its
+/// block is sealed doesn't lose it. Keyed by big-endian sequence numbers, which keep
+/// increasing across restarts.
+pub struct FundsLog {
+    db: sled::Db,
+    tree: sled::Tree,
+    // Sequence number of the newest entry logged, 0 if none
+    last_seq: AtomicU64,
+}
+
+impl FundsLog {
+    pub fn open(db: &sled::Db) -> anyhow::Result<Self> {
+        let tree = db.open_tree(FUNDS_LOG_TREE)?;
+        let last_seq = match tree.last()? {
+            Some((key, _)) => u64::from_be_bytes(key.as_ref().try_into()?),
+            None => 0,
+        };
+        Ok(Self {
+            db: db.clone(),
+            tree,
+            last_seq: AtomicU64::new(last_seq),
+        })
+    }
+
+    /// Log `funds` durably. Done under STATE's write lock, before the move is applied, so
+    /// entries are in the order the state saw them.
+    pub fn append(&self, funds: &FundsMove) -> anyhow::Result<()> {
+        // Never handed out twice, even across restarts
+        let seq = self.db.generate_id()? + 1;
+        self.tree
+            .insert(seq.to_be_bytes(), serde_json::to_vec(funds)?)?;
+        self.tree.flush()?;
+        self.last_seq.store(seq, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Sequence number of the newest entry. Read under STATE's write lock when the queued
+    /// moves are taken into a block, it's the last one that block seals.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::SeqCst)
+    }
+
+    /// Drop the entries up to `seq`, once a sealed block covers them.
+    pub fn forget_through(&self, seq: u64) -> anyhow::Result<()> {
+        let mut batch = sled::Batch::default();
+        for entry in self.tree.range(..=seq.to_be_bytes()) {
+            let (key, _) = entry?;
+            batch.remove(key);
+        }
+        self.tree.apply_batch(batch)?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    /// Apply the entries after `sealed_seq` to `state`, the balances of the block that sealed
+    /// up to it, in log order. Returns them, to be sealed into the next block.
+    pub fn replay(&self, sealed_seq: u64, state: &mut State) -> anyhow::Result<Vec<FundsMove>> {
+        let mut replayed = Vec::new();
+        for entry in self.tree.range((sealed_seq + 1).to_be_bytes()..) {
+            let (_, value) = entry?;
+            let funds: FundsMove = serde_json::from_slice(&value)?;
+            let mut deltas = BalanceDeltas::new();
+            funds.add_deltas(&mut deltas);
+            state
+                .apply_deltas(&deltas)
+                .map_err(|e| anyhow::anyhow!("Replaying logged {:?}: {}", funds, e))?;
+            replayed.push(funds);
+        }
+        Ok(replayed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::traces::FundingKind;
+
+    fn funding(kind: FundingKind, amount: u64) -> FundsMove {
+        FundsMove::Funding(Funding {
+            kind,
+            user_id: "log_user".to_string(),
+            token: "FLA".to_string(),
+            amount,
+        })
+    }
+
+    fn transfer(amount: u64) -> FundsMove {
+        FundsMove::Transfer(Transfer {
+            from: "log_user".to_string(),
+            to: "log_peer".to_string(),
+            token: "FLA".to_string(),
+            amount,
+        })
+    }
+
+    #[test]
+    fn test_funds_survive_crash_before_their_block() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut sealed = State::new();
+        let sealed_seq = {
+            let log = FundsLog::open(&db).unwrap();
+            // Sealed into a block with the state
+            log.append(&funding(FundingKind::Deposit, 10)).unwrap();
+            sealed.set_user_balance("log_user".to_string(), "FLA".to_string(), 10);
+            let sealed_seq = log.last_seq();
+            // Acknowledged, then the node dies before the next block
+            log.append(&funding(FundingKind::Deposit, 7)).unwrap();
+            log.append(&transfer(4)).unwrap();
+            log.append(&funding(FundingKind::Withdrawal, 2)).unwrap();
+            sealed_seq
+        };
+
+        // On restart only what the sealed block didn't cover is applied again
+        let log = FundsLog::open(&db).unwrap();
+        let mut state = sealed.clone();
+        let replayed = log.replay(sealed_seq, &mut state).unwrap();
+        assert_eq!(
+            replayed,
+            vec![
+                funding(FundingKind::Deposit, 7),
+                transfer(4),
+                funding(FundingKind::Withdrawal, 2)
+            ]
+        );
+        assert_eq!(state.get_user_balance("log_user", "FLA"), 11);
+        assert_eq!(state.get_user_balance("log_peer", "FLA"), 4);
+
+        // New entries follow the replayed ones, and sealing them leaves nothing to replay
+        log.append(&funding(FundingKind::Deposit, 1)).unwrap();
+        assert!(log.last_seq() > sealed_seq);
+        log.forget_through(log.last_seq()).unwrap();
+        let mut state = sealed.clone();
+        assert!(log.replay(0, &mut state).unwrap().is_empty());
+    }
+}
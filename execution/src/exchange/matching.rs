@@ -1,16 +1,70 @@
-use crate::exchange::MATCHED_TRACES;
+use crate::exchange::{ENGINE_EVENTS, MATCHED_TRACES, order_span};
+use common::clock::{Clock, SystemClock};
 use common::order::{Order, OrderStatus};
-use common::traces::MatchedTrace;
-use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap};
+use common::traces::{EngineEvent, MatchedTrace};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tiny_keccak::{Hasher, Sha3};
 
-#[derive(Clone, Debug, serde::Serialize)]
+// Number of price levels per side covered by the order book checksum
+pub const CHECKSUM_DEPTH: usize = 10;
+// Trades a live book keeps in memory, the latest ones; the trade log has all of them
+pub const MAX_RECENT_TRADES: usize = 1000;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Trade {
     pub buy_order_id: String,
     pub sell_order_id: String,
     pub price: u64,
     pub quantity: u64,
-    pub timestamp: u64,
+    pub timestamp: u64, // match time, unix millis
+}
+
+static LAST_TRADE_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+// Match time in unix millis. Never goes backwards, even if the wall clock does,
+// so trades are ordered by timestamp across all pairs and blocks.
+fn next_trade_timestamp() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0);
+    let last = LAST_TRADE_TIMESTAMP.fetch_max(now, AtomicOrdering::SeqCst);
+    last.max(now)
+}
+
+// A single fill of an incoming order against a resting (maker) order
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Fill {
+    pub maker_order_id: String,
+    pub price: u64,
+    pub quantity: u64,
+}
+
+// Order-by-order difference between two states of a book's resting orders, as returned by
+// `OrderBook::diff`
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct BookDiff {
+    pub added: Vec<Order>,    // resting only in the newer book
+    pub removed: Vec<String>, // ids resting only in the older book: filled or cancelled since
+    pub changed: Vec<Order>,  // resting in both with a different remaining size, as they are now
+}
+
+impl BookDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+// Outcome of adding an order to the book
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct MatchResult {
+    pub fills: Vec<Fill>,
+    pub resting_remaining: u64,
+    pub status: OrderStatus,
 }
 
 // Wrapper for orders in buy heap (max heap by price, then FIFO by time)
@@ -19,7 +73,7 @@ struct BuyOrder(Order);
 
 impl PartialEq for BuyOrder {
     fn eq(&self, other: &Self) -> bool {
-        self.0.price == other.0.price && self.0.created_at == other.0.created_at
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -35,8 +89,11 @@ impl Ord for BuyOrder {
     fn cmp(&self, other: &Self) -> Ordering {
         // Higher price first, then earlier time (FIFO)
         match self.0.price.cmp(&other.0.price) {
-            Ordering::Equal => other.0.created_at.cmp(&self.0.created_at), // Earlier time first
-            other => other,                                                // Higher price first
+            // Earlier time first, then earlier arrival
+            Ordering::Equal => {
+                (other.0.created_at, other.0.sequence).cmp(&(self.0.created_at, self.0.sequence))
+            }
+            other => other, // Higher price first
         }
     }
 }
@@ -47,7 +104,7 @@ struct SellOrder(Order);
 
 impl PartialEq for SellOrder {
     fn eq(&self, other: &Self) -> bool {
-        self.0.price == other.0.price && self.0.created_at == other.0.created_at
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -63,16 +120,101 @@ impl Ord for SellOrder {
     fn cmp(&self, other: &Self) -> Ordering {
         // Lower price first, then earlier time (FIFO)
         match other.0.price.cmp(&self.0.price) {
-            Ordering::Equal => other.0.created_at.cmp(&self.0.created_at), // Earlier time first
-            other => other,                                                // Lower price first
+            // Earlier time first, then earlier arrival
+            Ordering::Equal => {
+                (other.0.created_at, other.0.sequence).cmp(&(self.0.created_at, self.0.sequence))
+            }
+            other => other, // Lower price first
+        }
+    }
+}
+
+/// How an incoming order is shared among the resting orders of a price level it reaches
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum MatchingPolicy {
+    // The earliest resting order fills first
+    #[default]
+    PriceTime,
+    // Each resting order gets a share proportional to its remaining size
+    ProRata,
+}
+
+// Split `quantity` over the resting orders of one price level, given in time priority.
+// Pro-rata shares are rounded down and the units left over go one each in time priority, so
+// the whole quantity is allocated and no order gets more than its remaining size.
+fn allocate(policy: MatchingPolicy, quantity: u64, level: &[Order]) -> Vec<u64> {
+    let total: u128 = level
+        .iter()
+        .map(|order| order.remaining_amount() as u128)
+        .sum();
+    if policy == MatchingPolicy::PriceTime || quantity as u128 >= total {
+        let mut left = quantity;
+        return level
+            .iter()
+            .map(|order| {
+                let share = left.min(order.remaining_amount());
+                left -= share;
+                share
+            })
+            .collect();
+    }
+
+    let mut shares: Vec<u64> = level
+        .iter()
+        .map(|order| (quantity as u128 * order.remaining_amount() as u128 / total) as u64)
+        .collect();
+    let mut left = quantity - shares.iter().sum::<u64>();
+    for (share, order) in shares.iter_mut().zip(level) {
+        if left == 0 {
+            break;
+        }
+        if *share < order.remaining_amount() {
+            *share += 1;
+            left -= 1;
         }
     }
+    shares
 }
 
 pub struct OrderBook {
     buy_orders: BinaryHeap<BuyOrder>,
     sell_orders: BinaryHeap<SellOrder>,
     pub order_map: HashMap<String, Order>, // order_id -> Order for quick lookup
+    // Latest trades, oldest first; at most `MAX_RECENT_TRADES` of them on a live book
+    pub trades: VecDeque<Trade>,
+    // Trades made since `take_new_trades` last took them
+    new_trades: Vec<Trade>,
+    // Armed stop orders in arrival order, off the book until their trigger price trades
+    stop_orders: Vec<String>,
+    policy: MatchingPolicy,
+    // Most orders the book holds open, see `set_max_orders`
+    max_orders: Option<usize>,
+    // Orders open on the book, resting or armed stops. Kept up to date as orders rest and
+    // leave, so the cap is checked without walking the book
+    open_orders: usize,
+    // Where settled and cancelled orders go once off the book, see `set_archive`
+    archive: Option<sled::Tree>,
+    // Simulated books use this counter as their clock and keep their traces to themselves
+    simulation_clock: Option<u64>,
+    // Stamps order status changes, the wall clock unless `set_clock` replaced it
+    clock: Arc<dyn Clock>,
+    // Bumped on every change to the book: each placement, fill and cancel or reduce
+    seq: u64,
+    // Last `Order::sequence` handed out, so equal-time orders keep their arrival order
+    order_seq: u64,
+    // What the book did since it last published, in order; see `replay_events`
+    events: Vec<EngineEvent>,
+    // Fills that traded below the buy's limit price, whose price improvement is still locked
+    improved_fills: Vec<MatchedTrace>,
+}
+
+// Everything needed to rebuild a book with the exact same priorities, e.g. across a restart
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct OrderBookSnapshot {
+    pub orders: Vec<Order>, // every order in the book's map, in arrival order
+    pub trades: Vec<Trade>,
+    pub seq: u64,
+    pub order_seq: u64,
 }
 
 impl OrderBook {
@@ -81,9 +223,299 @@ impl OrderBook {
             buy_orders: BinaryHeap::new(),
             sell_orders: BinaryHeap::new(),
             order_map: HashMap::new(),
+            trades: VecDeque::new(),
+            new_trades: Vec::new(),
+            stop_orders: Vec::new(),
+            policy: MatchingPolicy::default(),
+            max_orders: None,
+            open_orders: 0,
+            archive: None,
+            simulation_clock: None,
+            clock: Arc::new(SystemClock),
+            seq: 0,
+            order_seq: 0,
+            events: Vec::new(),
+            improved_fills: Vec::new(),
+        }
+    }
+
+    /// A book for replaying scripted orders: timestamps come from a counter instead of the
+    /// wall clock, and its matched traces never reach the block builder
+    pub fn simulated() -> Self {
+        Self {
+            simulation_clock: Some(0),
+            ..Self::new()
+        }
+    }
+
+    /// Copy of the book's resting orders for what-if matching. Like a simulated book, its
+    /// matches never reach the block builder; only the last trade of the history is copied.
+    pub fn simulated_copy(&self) -> Self {
+        Self {
+            buy_orders: self.buy_orders.clone(),
+            sell_orders: self.sell_orders.clone(),
+            order_map: self.order_map.clone(),
+            // The last trade decides which stops fire
+            trades: self.trades.back().cloned().into_iter().collect(),
+            new_trades: Vec::new(),
+            stop_orders: self.stop_orders.clone(),
+            policy: self.policy,
+            max_orders: self.max_orders,
+            open_orders: self.open_orders,
+            // Only read: simulated books archive nothing
+            archive: self.archive.clone(),
+            simulation_clock: Some(0),
+            clock: self.clock.clone(),
+            seq: self.seq,
+            order_seq: self.order_seq,
+            events: Vec::new(),
+            improved_fills: Vec::new(),
+        }
+    }
+
+    /// Stamp order status changes with `clock` instead of the wall clock
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn matching_policy(&self) -> MatchingPolicy {
+        self.policy
+    }
+
+    /// Share fills among same-price resting orders by `policy` from the next order on
+    pub fn set_matching_policy(&mut self, policy: MatchingPolicy) {
+        self.policy = policy;
+    }
+
+    /// Cap the orders the book holds open, resting or armed stops, e.g. so a spammer can't
+    /// flood it with far-from-market orders. A full book still takes orders that cross it
+    /// or would rest ahead of the worst order on their side; others are rejected.
+    pub fn set_max_orders(&mut self, max_orders: Option<usize>) {
+        self.max_orders = max_orders;
+    }
+
+    /// Move orders out of the book's map into `tree` once they are settled, or cancelled and
+    /// dropped from the book, so the map only holds live orders. Archived orders can still be
+    /// looked up and their ids stay taken. Without an archive the map keeps every order.
+    pub fn set_archive(&mut self, tree: sled::Tree) {
+        self.archive = Some(tree);
+    }
+
+    // Take a settled or cancelled order out of the map and into the archive, if there is one
+    fn retire(&mut self, order_id: &str) {
+        let Some(archive) = &self.archive else {
+            return;
+        };
+        if self.simulation_clock.is_some() {
+            return;
+        }
+        let Some(order) = self.order_map.get(order_id) else {
+            return;
+        };
+        let archived = serde_json::to_vec(order)
+            .map_err(|e| e.to_string())
+            .and_then(|value| archive.insert(order_id, value).map_err(|e| e.to_string()));
+        match archived {
+            Ok(_) => {
+                self.order_map.remove(order_id);
+            }
+            // Kept in the map, which still serves it and keeps its id taken
+            Err(e) => tracing::error!("Failed to archive order {}: {}", order_id, e),
+        }
+    }
+
+    // An order retired to the archive
+    fn archived_order(&self, order_id: &str) -> Result<Option<Order>, String> {
+        let Some(archive) = &self.archive else {
+            return Ok(None);
+        };
+        let value = archive
+            .get(order_id)
+            .map_err(|e| format!("Failed to read the order archive: {}", e))?;
+        value
+            .map(|value| serde_json::from_slice(&value).map_err(|e| e.to_string()))
+            .transpose()
+    }
+
+    // Whether a full book takes `order`: it has to trade right away or beat the worst order
+    // resting on its side. Armed stops never do
+    fn admits_when_full(&self, order: &Order, armed_stop: bool) -> bool {
+        if armed_stop {
+            return false;
+        }
+        let (best_opposite, worst_same_side) = if order.side {
+            (
+                self.get_best_ask(),
+                self.iter_bids().last().map(|bid| bid.price),
+            )
+        } else {
+            (
+                self.get_best_bid(),
+                self.iter_asks().last().map(|ask| ask.price),
+            )
+        };
+        let crosses = best_opposite.is_some_and(|price| {
+            if order.side {
+                price <= order.price
+            } else {
+                price >= order.price
+            }
+        });
+        let beats_worst = worst_same_side.is_none_or(|price| {
+            if order.side {
+                order.price > price
+            } else {
+                order.price < price
+            }
+        });
+        crosses || beats_worst
+    }
+
+    /// Sequence number of the latest change to the book. It grows by exactly one per change,
+    /// so a client seeing a gap knows it missed an update and has to re-sync.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Snapshot of the book, including the counters that order its queues. Archived orders
+    /// are left out.
+    pub fn snapshot(&self) -> OrderBookSnapshot {
+        let mut orders: Vec<Order> = self.order_map.values().cloned().collect();
+        orders.sort_by_key(|order| order.sequence);
+        OrderBookSnapshot {
+            orders,
+            trades: self.trades.iter().cloned().collect(),
+            seq: self.seq,
+            order_seq: self.order_seq,
+        }
+    }
+
+    /// Rebuild a book from a snapshot. Open orders keep their `created_at` and `sequence`, so
+    /// they match in exactly the order they would have without the restart.
+    pub fn restore(snapshot: OrderBookSnapshot) -> Self {
+        let mut book = Self::new();
+        for order in snapshot.orders {
+            let resting = matches!(
+                order.status,
+                OrderStatus::Pending | OrderStatus::PartiallyFilled
+            ) && order.remaining_amount() > 0;
+            if resting {
+                book.open_orders += 1;
+            }
+            if resting && order.trigger_price.is_some() {
+                book.stop_orders.push(order.id.clone());
+            } else if resting && order.side {
+                book.buy_orders.push(BuyOrder(order.clone()));
+            } else if resting {
+                book.sell_orders.push(SellOrder(order.clone()));
+            }
+            book.order_map.insert(order.id.clone(), order);
+        }
+        book.trades = snapshot.trades.into();
+        let excess = book.trades.len().saturating_sub(MAX_RECENT_TRADES);
+        book.trades.drain(..excess);
+        book.seq = snapshot.seq;
+        book.order_seq = snapshot.order_seq;
+        book
+    }
+
+    /// Load resting orders straight into the book without matching them, e.g. to replay a
+    /// saved book. Orders are queued by `created_at`, ties keeping their order in `orders`.
+    /// The batch is rejected as a whole if an order isn't open, reuses an id, or would cross
+    /// the other side of the book.
+    pub fn bootstrap(&mut self, mut orders: Vec<Order>) -> Result<(), String> {
+        let mut best_bid = self.get_best_bid();
+        let mut best_ask = self.get_best_ask();
+        let mut ids = HashSet::new();
+        for order in &orders {
+            let open = matches!(
+                order.status,
+                OrderStatus::Pending | OrderStatus::PartiallyFilled
+            );
+            if !open || order.remaining_amount() == 0 || order.trigger_price.is_some() {
+                return Err(format!("Order {} is not resting", order.id));
+            }
+            if self.order_map.contains_key(&order.id) || !ids.insert(&order.id) {
+                return Err(format!("Duplicate order id {}", order.id));
+            }
+            if order.side {
+                best_bid = best_bid.max(Some(order.price));
+            } else {
+                best_ask = Some(best_ask.map_or(order.price, |ask| ask.min(order.price)));
+            }
+        }
+        if let (Some(bid), Some(ask)) = (best_bid, best_ask)
+            && bid >= ask
+        {
+            return Err(format!(
+                "Bootstrapped orders cross: bid {} >= ask {}",
+                bid, ask
+            ));
+        }
+
+        orders.sort_by_key(|order| order.created_at);
+        for mut order in orders {
+            self.seq += 1;
+            self.order_seq += 1;
+            order.sequence = self.order_seq;
+            self.order_map.insert(order.id.clone(), order.clone());
+            self.open_orders += 1;
+            if order.side {
+                self.buy_orders.push(BuyOrder(order));
+            } else {
+                self.sell_orders.push(SellOrder(order));
+            }
+        }
+        debug_assert_eq!(self.validate(), Ok(()));
+        Ok(())
+    }
+
+    fn next_timestamp(&mut self) -> u64 {
+        match &mut self.simulation_clock {
+            Some(clock) => {
+                *clock += 1;
+                *clock
+            }
+            None => next_trade_timestamp(),
+        }
+    }
+
+    // Log an event for the block builder; simulated books keep theirs to themselves too
+    fn record(&mut self, event: EngineEvent) {
+        if self.simulation_clock.is_none() {
+            self.events.push(event);
         }
     }
 
+    // Hand the traces of one match to the block builder, all at once, with the events
+    // recorded up to it
+    async fn publish_traces(&mut self, new_traces: Vec<MatchedTrace>) {
+        if (new_traces.is_empty() && self.events.is_empty()) || self.simulation_clock.is_some() {
+            return;
+        }
+        let mut traces = MATCHED_TRACES.write().await;
+        traces.extend(new_traces);
+        ENGINE_EVENTS.write().await.append(&mut self.events);
+    }
+
+    /// Trades made since the last call, e.g. for the trade log: the book only keeps the
+    /// latest `MAX_RECENT_TRADES`.
+    pub fn take_new_trades(&mut self) -> Vec<Trade> {
+        std::mem::take(&mut self.new_trades)
+    }
+
+    /// Fills matched since the last call whose buy traded below its limit price, for the
+    /// caller to refund their `price_improvement`. Simulated books never report any.
+    pub fn take_improved_fills(&mut self) -> Vec<MatchedTrace> {
+        std::mem::take(&mut self.improved_fills)
+    }
+
+    /// Hand the events recorded since the last match to the block builder, e.g. after a
+    /// cancel. Placing an order publishes its own.
+    pub async fn publish_events(&mut self) {
+        self.publish_traces(vec![]).await;
+    }
+
     fn is_order_cancelled(&self, order_id: &str) -> bool {
         self.order_map
             .get(order_id)
@@ -91,13 +523,13 @@ impl OrderBook {
             .unwrap_or(true)
     }
 
-    pub async fn add_order(&mut self, mut order: Order) {
+    pub async fn add_order(&mut self, mut order: Order) -> Result<MatchResult, String> {
         let order_id = order.id.clone();
         let order_side = order.side;
         let order_amount = order.amount;
         let order_price = order.price;
 
-        log::info!(
+        tracing::info!(
             "Adding order to order book: id={}, side={}, amount={}, price={}",
             order_id,
             if order_side { "buy" } else { "sell" },
@@ -105,188 +537,1727 @@ impl OrderBook {
             order_price
         );
 
+        let armed_stop = self.arms_stop(&order);
+        self.check_admission(&order, armed_stop, 0)?;
+        self.record(EngineEvent::Accepted(order.clone()));
+
+        self.seq += 1;
+        self.order_seq += 1;
+        order.sequence = self.order_seq;
+
+        if armed_stop {
+            tracing::info!("Stop order {} armed", order_id);
+            let result = MatchResult {
+                fills: vec![],
+                resting_remaining: order.remaining_amount(),
+                status: order.status.clone(),
+            };
+            self.stop_orders.push(order_id);
+            self.order_map.insert(order.id.clone(), order);
+            self.open_orders += 1;
+            self.publish_events().await;
+            debug_assert_eq!(self.validate(), Ok(()));
+            return Ok(result);
+        }
+        order.trigger_price = None;
+
+        let result = self.match_and_rest(order).await;
+        self.trigger_stops().await;
+        self.publish_events().await;
+        debug_assert_eq!(self.validate(), Ok(()));
+        Ok(result)
+    }
+
+    /// Cancel open order `order_id` and add `order` in its place, as one step: if the book
+    /// would turn `order` away, or `reserve` does when handed the order being replaced (e.g.
+    /// for want of funds), the book is left as it was. Returns the replaced order, now
+    /// cancelled, and the match of the new one.
+    pub async fn replace_order(
+        &mut self,
+        order_id: &str,
+        order: Order,
+        reserve: impl FnOnce(&Order) -> Result<(), String>,
+    ) -> Result<(Order, MatchResult), String> {
+        if !self.order_map.contains_key(order_id) && self.archived_order(order_id)?.is_some() {
+            return Err(format!("Order {} is no longer open", order_id));
+        }
+        let replaced = match self.order_map.get(order_id) {
+            Some(replaced)
+                if matches!(
+                    replaced.status,
+                    OrderStatus::Pending | OrderStatus::PartiallyFilled
+                ) =>
+            {
+                replaced
+            }
+            Some(_) => return Err(format!("Order {} is no longer open", order_id)),
+            None => return Err("Order not found".to_string()),
+        };
+        if replaced.user_id != order.user_id {
+            tracing::warn!(
+                "Rejecting replacement of order {}: placed by another user",
+                order_id
+            );
+            return Err(format!("Order {} belongs to another user", order_id));
+        }
+        // The new order takes the replaced one's place, so it counts against a full book
+        let armed_stop = self.arms_stop(&order);
+        self.check_admission(&order, armed_stop, 1)?;
+        reserve(replaced)?;
+
+        let cancelled = self.cancel_order(order_id, None)?;
+        let result = self.add_order(order).await?;
+        Ok((cancelled, result))
+    }
+
+    // Whether `order` is a stop the last trade hasn't reached, which waits off the book
+    fn arms_stop(&self, order: &Order) -> bool {
+        let fires = self
+            .last_trade_price()
+            .is_some_and(|last_price| order.is_triggered_at(last_price));
+        order.trigger_price.is_some() && !fires
+    }
+
+    // Why the book turns `order` away, if it does, once `leaving` of its open orders made
+    // room for it
+    fn check_admission(
+        &self,
+        order: &Order,
+        armed_stop: bool,
+        leaving: usize,
+    ) -> Result<(), String> {
+        // A reused id would overwrite the existing order and double its freeze
+        if self.order_map.contains_key(&order.id) || self.archived_order(&order.id)?.is_some() {
+            tracing::warn!("Rejecting duplicate order id {}", order.id);
+            return Err(format!("Duplicate order id {}", order.id));
+        }
+
+        let Some(max_orders) = self.max_orders else {
+            return Ok(());
+        };
+        let open_orders = self.open_orders - leaving;
+        if open_orders >= max_orders && !self.admits_when_full(order, armed_stop) {
+            tracing::warn!(
+                "Rejecting order {}: book full with {} open orders",
+                order.id,
+                open_orders
+            );
+            return Err(format!(
+                "Order book full at {} orders, only orders that trade or beat the worst price on their side are accepted",
+                max_orders
+            ));
+        }
+        Ok(())
+    }
+
+    // Match an order against the book and rest what's left of it
+    async fn match_and_rest(&mut self, mut order: Order) -> MatchResult {
+        let order_id = order.id.clone();
         if order.side {
             // Buy order - match against sell orders
-            log::debug!("Matching buy order {} against sell orders", order_id);
-            self.match_buy_order(&mut order).await;
+            tracing::debug!("Matching buy order {} against sell orders", order_id);
+            let fills = self.match_order(&mut order).await;
             let remaining = order.remaining_amount();
+            let status = order.status.clone();
+            // Filled orders stay in the map too, so their id can't be reused
+            self.order_map.insert(order.id.clone(), order.clone());
             if remaining > 0 {
-                self.order_map.insert(order.id.clone(), order.clone());
+                self.open_orders += 1;
                 self.buy_orders.push(BuyOrder(order));
             } else {
-                log::info!("Buy order {} fully filled", order_id);
+                tracing::info!("Buy order {} fully filled", order_id);
+            }
+            MatchResult {
+                fills,
+                resting_remaining: remaining,
+                status,
             }
         } else {
             // Sell order - match against buy orders
-            log::debug!("Matching sell order {} against buy orders", order_id);
-            self.match_sell_order(&mut order).await;
+            tracing::debug!("Matching sell order {} against buy orders", order_id);
+            let fills = self.match_order(&mut order).await;
             let remaining = order.remaining_amount();
+            let status = order.status.clone();
+            // Filled orders stay in the map too, so their id can't be reused
+            self.order_map.insert(order.id.clone(), order.clone());
             if remaining > 0 {
-                self.order_map.insert(order.id.clone(), order.clone());
+                self.open_orders += 1;
                 self.sell_orders.push(SellOrder(order));
             } else {
-                log::info!("Sell order {} fully filled", order_id);
+                tracing::info!("Sell order {} fully filled", order_id);
+            }
+            MatchResult {
+                fills,
+                resting_remaining: remaining,
+                status,
             }
         }
     }
 
-    async fn match_buy_order(&mut self, buy_order: &mut Order) {
-        let mut updated_sells = Vec::new();
+    fn last_trade_price(&self) -> Option<u64> {
+        self.trades.back().map(|trade| trade.price)
+    }
 
-        let mut traces = MATCHED_TRACES.write().await;
+    // Fire the armed stops the last trade price has reached, until none is left to fire: a
+    // fired stop's own trades can reach further stops
+    async fn trigger_stops(&mut self) {
+        while let Some(last_price) = self.last_trade_price() {
+            let order_map = &self.order_map;
+            let mut fired = vec![];
+            let mut dropped = vec![];
+            self.stop_orders.retain(|order_id| {
+                let order = &order_map[order_id];
+                if matches!(order.status, OrderStatus::Cancelled) {
+                    dropped.push(order_id.clone());
+                    return false;
+                }
+                if order.is_triggered_at(last_price) {
+                    // order_map holds the live copy (e.g. size reduced by a partial cancel)
+                    fired.push(order.clone());
+                    return false;
+                }
+                true
+            });
+            for order_id in dropped {
+                self.retire(&order_id);
+            }
+            if fired.is_empty() {
+                break;
+            }
+
+            // Price-time order: the stops the price moved through first, then by arrival
+            fired.sort_by_key(|order| {
+                let trigger_price = order.trigger_price.unwrap_or(last_price);
+                (Reverse(trigger_price.abs_diff(last_price)), order.sequence)
+            });
+            for mut order in fired {
+                // Off the stops, and counted again if it rests
+                self.open_orders -= 1;
+                order_span(&order.id)
+                    .in_scope(|| tracing::info!(last_price, "Stop order triggered"));
+                order.trigger_price = None;
+                self.seq += 1;
+                self.match_and_rest(order).await;
+            }
+        }
+    }
 
-        while let Some(SellOrder(mut sell_order)) = self.sell_orders.pop() {
-            // Skip cancelled orders
-            if self.is_order_cancelled(&sell_order.id) {
+    // Best resting order on the side `taker_side` trades against, dropping cancelled ones
+    fn pop_maker(&mut self, taker_side: bool) -> Option<Order> {
+        loop {
+            let order = if taker_side {
+                self.sell_orders.pop().map(|SellOrder(order)| order)
+            } else {
+                self.buy_orders.pop().map(|BuyOrder(order)| order)
+            }?;
+            if self.is_order_cancelled(&order.id) {
+                // Its last place on the book, so nothing refers to it anymore
+                self.retire(&order.id);
                 continue;
             }
+            // order_map holds the live copy (e.g. size reduced by a partial cancel)
+            return Some(self.order_map[&order.id].clone());
+        }
+    }
 
-            if sell_order.price > buy_order.price {
+    fn push_resting(&mut self, order: Order) {
+        if order.side {
+            self.buy_orders.push(BuyOrder(order));
+        } else {
+            self.sell_orders.push(SellOrder(order));
+        }
+    }
+
+    async fn match_order(&mut self, taker: &mut Order) -> Vec<Fill> {
+        let mut updated_makers = Vec::new();
+        let mut fills = Vec::new();
+
+        let mut traces = Vec::new();
+
+        while taker.remaining_amount() > 0 {
+            let Some(maker) = self.pop_maker(taker.side) else {
+                break;
+            };
+            let crosses = if taker.side {
+                maker.price <= taker.price
+            } else {
+                maker.price >= taker.price
+            };
+            if !crosses {
                 // No match possible, put back and break
-                self.sell_orders.push(SellOrder(sell_order));
+                self.push_resting(maker);
                 break;
             }
 
-            let trade_quantity =
-                std::cmp::min(buy_order.remaining_amount(), sell_order.remaining_amount());
-            let _trade_price = sell_order.price; // Price-time priority: use maker's price
+            // The resting orders sharing the taker's fill, in time priority. Price-time fills
+            // one order at a time; pro-rata takes the whole price level at once
+            let mut level = vec![maker];
+            if self.policy == MatchingPolicy::ProRata {
+                while let Some(next) = self.pop_maker(taker.side) {
+                    if next.price != level[0].price {
+                        self.push_resting(next);
+                        break;
+                    }
+                    level.push(next);
+                }
+            }
 
-            // MatchedTrace
-            traces.push(MatchedTrace {
-                buy_order: buy_order.clone(),
-                sell_order: sell_order.clone(),
-                matched_amount: trade_quantity,
-            });
+            let allocations = allocate(self.policy, taker.remaining_amount(), &level);
+            for (mut maker, quantity) in level.into_iter().zip(allocations) {
+                if quantity > 0 {
+                    self.fill_against(taker, &mut maker, quantity, &mut traces, &mut fills);
+                }
+                if maker.remaining_amount() > 0 {
+                    updated_makers.push(maker);
+                }
+            }
+        }
 
-            // Update orders
-            buy_order.fill(trade_quantity);
-            sell_order.fill(trade_quantity);
+        // Put back unmatched resting orders
+        for maker in updated_makers {
+            self.push_resting(maker);
+        }
+
+        self.publish_traces(traces).await;
+        fills
+    }
+
+    // Trade `quantity` between an incoming order and a resting one, at the resting order's price
+    fn fill_against(
+        &mut self,
+        taker: &mut Order,
+        maker: &mut Order,
+        quantity: u64,
+        traces: &mut Vec<MatchedTrace>,
+        fills: &mut Vec<Fill>,
+    ) {
+        let price = maker.price;
+        let (buy_order, sell_order) = if taker.side {
+            (&*taker, &*maker)
+        } else {
+            (&*maker, &*taker)
+        };
+
+        // MatchedTrace
+        let trace = MatchedTrace {
+            buy_order: buy_order.clone(),
+            sell_order: sell_order.clone(),
+            matched_amount: quantity,
+            matched_price: price,
+        };
+        // The prover rejects a block holding a malformed trace
+        debug_assert_eq!(trace.validate(), Ok(()));
+
+        // Logged under both orders' spans, so the fill shows up in either journey
+        order_span(&maker.id).in_scope(|| tracing::info!(price, quantity, "Order filled"));
+        fills.push(Fill {
+            maker_order_id: maker.id.clone(),
+            price,
+            quantity,
+        });
+        self.seq += 1;
+        let timestamp = self.next_timestamp();
+        let trade = Trade {
+            buy_order_id: trace.buy_order.id.clone(),
+            sell_order_id: trace.sell_order.id.clone(),
+            price,
+            quantity,
+            timestamp,
+        };
+        self.new_trades.push(trade.clone());
+        // Simulations are short-lived and read back every trade they made
+        if self.simulation_clock.is_none() && self.trades.len() == MAX_RECENT_TRADES {
+            self.trades.pop_front();
+        }
+        self.trades.push_back(trade);
+        self.record(EngineEvent::Fill {
+            pair_id: taker.pair_id.clone(),
+            buy_order_id: trace.buy_order.id.clone(),
+            sell_order_id: trace.sell_order.id.clone(),
+            price,
+            quantity,
+        });
+        if self.simulation_clock.is_none() && trace.price_improvement() > 0 {
+            self.improved_fills.push(trace.clone());
+        }
+        traces.push(trace);
 
-            // Update order in map
-            self.order_map
-                .insert(sell_order.id.clone(), sell_order.clone());
+        // Update orders
+        taker.fill(quantity);
+        maker.fill(quantity);
 
-            if sell_order.remaining_amount() > 0 {
-                updated_sells.push(SellOrder(sell_order));
+        // Update order in map
+        if maker.remaining_amount() == 0 {
+            self.open_orders -= 1;
+        }
+        self.order_map.insert(maker.id.clone(), maker.clone());
+    }
+
+    /// Cancel a resting order. With `reduce_by` set, only shrink its remaining size by that
+    /// amount; the order keeps its queue priority. Reducing by the full remaining size is a
+    /// full cancel.
+    pub fn cancel_order(
+        &mut self,
+        order_id: &str,
+        reduce_by: Option<u64>,
+    ) -> Result<Order, String> {
+        tracing::info!(
+            "Attempting to cancel order: {}, reduce_by={:?}",
+            order_id,
+            reduce_by
+        );
+
+        let archived = if self.order_map.contains_key(order_id) {
+            None
+        } else {
+            self.archived_order(order_id)?
+        };
+        let order = match (self.order_map.get_mut(order_id), archived) {
+            (Some(order), _) => order,
+            (None, Some(order)) if matches!(order.status, OrderStatus::Cancelled) => {
+                tracing::warn!("Order {} is already cancelled", order_id);
+                return Err("Order already cancelled".to_string());
+            }
+            (None, Some(_)) => {
+                tracing::warn!("Order {} is already filled", order_id);
+                return Err("Order already filled".to_string());
+            }
+            (None, None) => {
+                tracing::warn!("Order {} not found for cancellation", order_id);
+                return Err("Order not found".to_string());
+            }
+        };
+
+        // Check if already cancelled
+        if matches!(order.status, OrderStatus::Cancelled) {
+            tracing::warn!("Order {} is already cancelled", order_id);
+            return Err("Order already cancelled".to_string());
+        }
+        if matches!(order.status, OrderStatus::Filled | OrderStatus::Settled) {
+            tracing::warn!("Order {} is already filled", order_id);
+            return Err("Order already filled".to_string());
+        }
+
+        let remaining = order.remaining_amount();
+        match reduce_by {
+            Some(0) => return Err("Reduce amount must be positive".to_string()),
+            Some(amount) if amount > remaining => {
+                tracing::warn!(
+                    "Cannot reduce order {} by {}: only {} remaining",
+                    order_id,
+                    amount,
+                    remaining
+                );
+                return Err("Reduce amount exceeds remaining amount".to_string());
             }
+            Some(amount) if amount < remaining => {
+                // The heap copy is refreshed from order_map when popped, so priority is kept.
+                order.amount -= amount;
+                self.seq += 1;
+                let event = EngineEvent::Cancelled {
+                    pair_id: order.pair_id.clone(),
+                    order_id: order_id.to_string(),
+                    reduce_by,
+                };
 
-            if buy_order.remaining_amount() == 0 {
-                break;
+                tracing::info!(
+                    "Order {} reduced by {}, remaining: {}",
+                    order_id,
+                    amount,
+                    order.remaining_amount()
+                );
+                let order = order.clone();
+                self.record(event);
+                debug_assert_eq!(self.validate(), Ok(()));
+                return Ok(order);
             }
+            _ => {}
         }
 
-        // Put back unmatched sell orders
-        for sell in updated_sells {
-            self.sell_orders.push(sell);
+        tracing::info!(
+            "Order {} found, cancelling. Side: {}, remaining: {}",
+            order_id,
+            if order.side { "buy" } else { "sell" },
+            remaining
+        );
+
+        // This order will be skipped (pop) when matching (lazy removal).
+        order.set_status_with_clock(OrderStatus::Cancelled, self.clock.as_ref());
+        self.open_orders -= 1;
+        self.seq += 1;
+
+        tracing::info!("Order {} successfully cancelled", order_id);
+        let order = order.clone();
+        self.record(EngineEvent::Cancelled {
+            pair_id: order.pair_id.clone(),
+            order_id: order_id.to_string(),
+            reduce_by,
+        });
+        debug_assert_eq!(self.validate(), Ok(()));
+        Ok(order)
+    }
+
+    /// Mark a filled order as settled once its last fill is sealed into a block, and archive
+    /// it if the book has an archive
+    pub fn settle_order(&mut self, order_id: &str) {
+        if let Some(order) = self.order_map.get_mut(order_id)
+            && matches!(order.status, OrderStatus::Filled)
+        {
+            order.set_status_with_clock(OrderStatus::Settled, self.clock.as_ref());
+            self.retire(order_id);
         }
     }
 
-    async fn match_sell_order(&mut self, sell_order: &mut Order) {
-        let mut updated_buys = Vec::new();
+    /// The order from the book's map, or from its archive once it left it
+    pub fn get_order(&self, order_id: &str) -> Option<Order> {
+        if let Some(order) = self.order_map.get(order_id) {
+            return Some(order.clone());
+        }
+        self.archived_order(order_id).unwrap_or_else(|e| {
+            tracing::error!("Failed to look up order {}: {}", order_id, e);
+            None
+        })
+    }
 
-        let mut traces = MATCHED_TRACES.write().await;
+    /// Orders open on the book: resting ones and armed stops
+    pub fn open_orders(&self) -> usize {
+        self.open_orders
+    }
 
-        while let Some(BuyOrder(mut buy_order)) = self.buy_orders.pop() {
-            // Skip and drop cancelled orders
-            if self.is_order_cancelled(&buy_order.id) {
+    pub fn get_best_bid(&self) -> Option<u64> {
+        // BinaryHeap iteration is in arbitrary order, so take the max over live orders
+        self.buy_orders
+            .iter()
+            .filter(|buy_order| !self.is_order_cancelled(&buy_order.0.id))
+            .max()
+            .map(|buy_order| buy_order.0.price)
+    }
+
+    pub fn get_best_ask(&self) -> Option<u64> {
+        self.sell_orders
+            .iter()
+            .filter(|sell_order| !self.is_order_cancelled(&sell_order.0.id))
+            .max()
+            .map(|sell_order| sell_order.0.price)
+    }
+
+    /// Resting buy orders in matching priority order (best first)
+    pub fn iter_bids(&self) -> std::vec::IntoIter<&Order> {
+        self.iter_orders_by_side(true)
+    }
+
+    /// Resting sell orders in matching priority order (best first)
+    pub fn iter_asks(&self) -> std::vec::IntoIter<&Order> {
+        self.iter_orders_by_side(false)
+    }
+
+    /// Resting orders of one side in matching priority order, skipping cancelled ones.
+    /// The heaps are left untouched; a sorted snapshot is taken instead.
+    pub fn iter_orders_by_side(&self, side: bool) -> std::vec::IntoIter<&Order> {
+        let ids: Vec<&str> = if side {
+            let mut bids: Vec<&BuyOrder> = self.buy_orders.iter().collect();
+            bids.sort_by(|a, b| b.cmp(a));
+            bids.into_iter().map(|order| order.0.id.as_str()).collect()
+        } else {
+            let mut asks: Vec<&SellOrder> = self.sell_orders.iter().collect();
+            asks.sort_by(|a, b| b.cmp(a));
+            asks.into_iter().map(|order| order.0.id.as_str()).collect()
+        };
+
+        // order_map holds the live copy (status, fills) of each resting order
+        ids.into_iter()
+            .filter(|id| !self.is_order_cancelled(id))
+            .filter_map(|id| self.order_map.get(id))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Up to `depth` price levels of one side as (price, total remaining size), best first
+    pub fn price_levels(&self, side: bool, depth: usize) -> Vec<(u64, u64)> {
+        let mut levels: Vec<(u64, u64)> = Vec::new();
+        for order in self.iter_orders_by_side(side) {
+            let remaining = order.remaining_amount();
+            if remaining == 0 {
                 continue;
             }
-
-            if buy_order.price < sell_order.price {
-                // No match possible, put back and break
-                self.buy_orders.push(BuyOrder(buy_order));
+            if let Some((price, size)) = levels.last_mut()
+                && *price == order.price
+            {
+                *size += remaining;
+                continue;
+            }
+            if levels.len() == depth {
                 break;
             }
+            levels.push((order.price, remaining));
+        }
+        levels
+    }
 
-            let trade_quantity =
-                std::cmp::min(sell_order.remaining_amount(), buy_order.remaining_amount());
+    /// Checksum of the top `CHECKSUM_DEPTH` levels, so clients can verify their local book.
+    /// Sha3-256 over the asks then the bids, each side as its level count followed by every
+    /// level's price and size (all big-endian u64); the checksum is the first 4 bytes.
+    pub fn checksum(&self) -> u32 {
+        let mut sha3 = Sha3::v256();
+        for side in [false, true] {
+            let levels = self.price_levels(side, CHECKSUM_DEPTH);
+            sha3.update(&(levels.len() as u64).to_be_bytes());
+            for (price, size) in levels {
+                sha3.update(&price.to_be_bytes());
+                sha3.update(&size.to_be_bytes());
+            }
+        }
 
-            // MatchedTrace
-            traces.push(MatchedTrace {
-                buy_order: buy_order.clone(),
-                sell_order: sell_order.clone(),
-                matched_amount: trade_quantity,
-            });
+        let mut output = [0u8; 32];
+        sha3.finalize(&mut output);
+        u32::from_be_bytes([output[0], output[1], output[2], output[3]])
+    }
 
-            // Update orders
-            sell_order.fill(trade_quantity);
-            buy_order.fill(trade_quantity);
+    /// Copy of just the resting orders and the book's `seq`, e.g. to diff against later.
+    /// Filled, cancelled and armed stop orders are left out.
+    pub fn resting_copy(&self) -> Self {
+        let mut orders: Vec<Order> = self.iter_bids().chain(self.iter_asks()).cloned().collect();
+        orders.sort_by_key(|order| order.sequence);
+        let mut book = Self::restore(OrderBookSnapshot {
+            orders,
+            trades: vec![],
+            seq: self.seq,
+            order_seq: self.order_seq,
+        });
+        book.policy = self.policy;
+        book
+    }
 
-            // Update order in map
-            self.order_map
-                .insert(buy_order.id.clone(), buy_order.clone());
+    /// What changed in the resting orders going from this book to `other`, e.g. from what a
+    /// client last saw to the live book. Orders are listed in `other`'s priority, bids first;
+    /// removed ids in this book's.
+    pub fn diff(&self, other: &OrderBook) -> BookDiff {
+        let resting = |book: &OrderBook| -> Vec<Order> {
+            book.iter_bids()
+                .chain(book.iter_asks())
+                .filter(|order| order.remaining_amount() > 0)
+                .cloned()
+                .collect()
+        };
+        let before = resting(self);
+        let after = resting(other);
+        let before_by_id: HashMap<&str, &Order> = before
+            .iter()
+            .map(|order| (order.id.as_str(), order))
+            .collect();
+        let after_ids: HashSet<&str> = after.iter().map(|order| order.id.as_str()).collect();
 
-            if buy_order.remaining_amount() > 0 {
-                updated_buys.push(BuyOrder(buy_order));
+        let mut diff = BookDiff::default();
+        for order in &after {
+            match before_by_id.get(order.id.as_str()) {
+                None => diff.added.push(order.clone()),
+                Some(old) if old.remaining_amount() != order.remaining_amount() => {
+                    diff.changed.push(order.clone())
+                }
+                Some(_) => {}
             }
+        }
+        diff.removed = before
+            .iter()
+            .filter(|order| !after_ids.contains(order.id.as_str()))
+            .map(|order| order.id.clone())
+            .collect();
+        diff
+    }
 
-            if sell_order.remaining_amount() == 0 {
-                break;
+    /// Check the book's internal consistency: every heap entry has an `order_map` entry of the
+    /// same side and price, every open order rests exactly once (or waits as a stop), no
+    /// resting order is used up, and the live book isn't crossed. Cancelled entries left in
+    /// the heaps by lazy removal are fine. Checked after every change in debug builds.
+    pub fn validate(&self) -> Result<(), String> {
+        let heap_entries = self
+            .buy_orders
+            .iter()
+            .map(|BuyOrder(order)| order)
+            .chain(self.sell_orders.iter().map(|SellOrder(order)| order));
+        let mut resting = HashSet::new();
+        for entry in heap_entries {
+            let order = self
+                .order_map
+                .get(&entry.id)
+                .ok_or_else(|| format!("Resting order {} is missing from the map", entry.id))?;
+            if order.side != entry.side || order.price != entry.price {
+                return Err(format!(
+                    "Resting order {} differs from its map entry",
+                    entry.id
+                ));
+            }
+            if matches!(order.status, OrderStatus::Cancelled) {
+                continue;
+            }
+            if !resting.insert(order.id.as_str()) {
+                return Err(format!("Order {} rests more than once", order.id));
+            }
+            if order.remaining_amount() == 0
+                || matches!(order.status, OrderStatus::Filled | OrderStatus::Settled)
+            {
+                return Err(format!("Filled order {} is still resting", order.id));
+            }
+        }
+
+        for order_id in &self.stop_orders {
+            if !self.order_map.contains_key(order_id) {
+                return Err(format!("Stop order {} is missing from the map", order_id));
             }
         }
+        let mut open_orders = 0;
+        for order in self.order_map.values() {
+            let open = matches!(
+                order.status,
+                OrderStatus::Pending | OrderStatus::PartiallyFilled
+            );
+            if open && !resting.contains(order.id.as_str()) && !self.stop_orders.contains(&order.id)
+            {
+                return Err(format!("Open order {} is not on the book", order.id));
+            }
+            open_orders += usize::from(open);
+        }
+        if open_orders != self.open_orders {
+            return Err(format!(
+                "Book counts {} open orders, {} are open",
+                self.open_orders, open_orders
+            ));
+        }
 
-        // Put back unmatched buy orders
-        for buy in updated_buys {
-            self.buy_orders.push(buy);
+        if let (Some(bid), Some(ask)) = (self.get_best_bid(), self.get_best_ask())
+            && bid >= ask
+        {
+            return Err(format!("Book is crossed: bid {} >= ask {}", bid, ask));
         }
+        Ok(())
     }
+}
 
-    pub fn cancel_order(&mut self, order_id: &str) -> Option<Order> {
-        log::info!("Attempting to cancel order: {}", order_id);
+/// Replay a scripted order sequence through a simulated book and return every trade and the
+/// final book. Orders are stamped from the book's clock in script order, so time priority
+/// follows the script; orders the book rejects (e.g. duplicate ids) are skipped.
+pub async fn simulate(orders: Vec<Order>) -> (Vec<Trade>, OrderBook) {
+    let mut book = OrderBook::simulated();
+    for mut order in orders {
+        order.created_at = book.next_timestamp();
+        order.updated_at = order.created_at;
+        if let Err(e) = book.add_order(order).await {
+            tracing::warn!("Simulation skipped an order: {}", e);
+        }
+    }
+    (book.trades.iter().cloned().collect(), book)
+}
 
-        // Check if order exists and get its side
-        let (order_side, already_cancelled) = if let Some(order) = self.order_map.get(order_id) {
-            (order.side, matches!(order.status, OrderStatus::Cancelled))
-        } else {
-            log::warn!("Order {} not found for cancellation", order_id);
-            return None;
+/// Apply engine events, e.g. a block's, to the books they were recorded on, keyed by pair. A
+/// pair without a book starts from an empty simulated one with the default matching policy.
+/// Every accepted order is matched again, and the fills logged after it must be exactly the
+/// trades that produces, in order; a fill the books don't reproduce is an error.
+pub async fn replay_events(
+    books: &mut HashMap<String, OrderBook>,
+    events: &[EngineEvent],
+) -> Result<(), String> {
+    // Trades the books made that the log has yet to show
+    let mut expected: VecDeque<Trade> = VecDeque::new();
+    for event in events {
+        let book = books
+            .entry(event.pair_id().to_string())
+            .or_insert_with(OrderBook::simulated);
+        match event {
+            EngineEvent::Accepted(order) => {
+                if let Some(trade) = expected.front() {
+                    return Err(format!(
+                        "Order {} accepted before the fill of {} and {}",
+                        order.id, trade.buy_order_id, trade.sell_order_id
+                    ));
+                }
+                book.take_new_trades();
+                book.add_order(order.clone()).await?;
+                expected.extend(book.take_new_trades());
+            }
+            EngineEvent::Fill {
+                buy_order_id,
+                sell_order_id,
+                price,
+                quantity,
+                ..
+            } => {
+                let matches = expected.pop_front().is_some_and(|trade| {
+                    trade.buy_order_id == *buy_order_id
+                        && trade.sell_order_id == *sell_order_id
+                        && trade.price == *price
+                        && trade.quantity == *quantity
+                });
+                if !matches {
+                    return Err(format!(
+                        "Fill of {} and {} doesn't replay",
+                        buy_order_id, sell_order_id
+                    ));
+                }
+            }
+            EngineEvent::Cancelled {
+                order_id,
+                reduce_by,
+                ..
+            } => {
+                if let Some(trade) = expected.front() {
+                    return Err(format!(
+                        "Order {} cancelled before the fill of {} and {}",
+                        order_id, trade.buy_order_id, trade.sell_order_id
+                    ));
+                }
+                book.cancel_order(order_id, *reduce_by)?;
+            }
+        }
+    }
+    match expected.front() {
+        Some(trade) => Err(format!(
+            "Fill of {} and {} is missing from the events",
+            trade.buy_order_id, trade.sell_order_id
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::clock::MockClock;
+
+    #[tokio::test]
+    async fn test_add_order_returns_fills() {
+        let pair_id = "MRA_MRB".to_string();
+        let mut book = OrderBook::new();
+
+        let sell_1 = Order::new(
+            "mr_sell_1".to_string(),
+            "seller".to_string(),
+            pair_id.clone(),
+            5,
+            100,
+            false,
+        );
+        let sell_2 = Order::new(
+            "mr_sell_2".to_string(),
+            "seller".to_string(),
+            pair_id.clone(),
+            5,
+            101,
+            false,
+        );
+        let rested = book.add_order(sell_1).await.unwrap();
+        assert!(rested.fills.is_empty());
+        assert_eq!(rested.resting_remaining, 5);
+        assert_eq!(rested.status, OrderStatus::Pending);
+        book.add_order(sell_2).await.unwrap();
+
+        let buy = Order::new(
+            "mr_buy".to_string(),
+            "buyer".to_string(),
+            pair_id.clone(),
+            12,
+            101,
+            true,
+        );
+        let result = book.add_order(buy).await.unwrap();
+
+        assert_eq!(result.fills.len(), 2);
+        assert_eq!(result.fills[0].maker_order_id, "mr_sell_1");
+        assert_eq!(result.fills[0].price, 100);
+        assert_eq!(result.fills[1].maker_order_id, "mr_sell_2");
+        assert_eq!(result.fills[1].price, 101);
+        assert_eq!(result.resting_remaining, 2);
+        assert_eq!(result.status, OrderStatus::PartiallyFilled);
+
+        // Every fill must correspond to the trace emitted for the block builder
+        let traces = MATCHED_TRACES.read().await;
+        let traces: Vec<_> = traces
+            .iter()
+            .filter(|trace| trace.buy_order.id == "mr_buy")
+            .collect();
+        assert_eq!(traces.len(), result.fills.len());
+        for (trace, fill) in traces.iter().zip(result.fills.iter()) {
+            assert_eq!(trace.sell_order.id, fill.maker_order_id);
+            assert_eq!(trace.matched_amount, fill.quantity);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trades_at_resting_maker_price() {
+        let pair_id = "MPA_MPB".to_string();
+        let mut book = OrderBook::new();
+        let order = |id: &str, side, price| {
+            Order::new(
+                id.to_string(),
+                format!("{}_user", id),
+                pair_id.clone(),
+                4,
+                price,
+                side,
+            )
         };
 
-        // Check if already cancelled
-        if already_cancelled {
-            log::warn!("Order {} is already cancelled", order_id);
-            return self.order_map.get(order_id).cloned();
+        // A buy taker lifts a sell resting at 90 though it bid 110, and a sell taker hits a
+        // buy resting at 80 though it asked 60: both trade at the maker's price
+        book.add_order(order("mp_sell_maker", false, 90))
+            .await
+            .unwrap();
+        book.add_order(order("mp_buy_taker", true, 110))
+            .await
+            .unwrap();
+        book.add_order(order("mp_buy_maker", true, 80))
+            .await
+            .unwrap();
+        book.add_order(order("mp_sell_taker", false, 60))
+            .await
+            .unwrap();
+
+        let traces = MATCHED_TRACES.read().await;
+        for (taker_id, maker_id, maker_price) in [
+            ("mp_buy_taker", "mp_sell_maker", 90),
+            ("mp_sell_taker", "mp_buy_maker", 80),
+        ] {
+            let trace = traces
+                .iter()
+                .find(|trace| trace.buy_order.id == taker_id || trace.sell_order.id == taker_id)
+                .unwrap();
+            assert!(trace.buy_order.id == maker_id || trace.sell_order.id == maker_id);
+            assert_eq!(trace.matched_price, maker_price);
+            let trade = book
+                .trades
+                .iter()
+                .find(|trade| trade.buy_order_id == taker_id || trade.sell_order_id == taker_id)
+                .unwrap();
+            assert_eq!(trade.price, maker_price);
         }
+    }
 
-        if let Some(order) = self.order_map.get_mut(order_id) {
-            log::info!(
-                "Order {} found, cancelling. Side: {}, remaining: {}",
-                order_id,
-                if order_side { "buy" } else { "sell" },
-                order.remaining_amount()
+    #[tokio::test]
+    async fn test_published_trace_reads_as_common_trace() {
+        let pair_id = "CTA_CTB".to_string();
+        let mut book = OrderBook::new();
+        let order = |id: &str, side| {
+            Order::new(
+                id.to_string(),
+                format!("{}_user", id),
+                pair_id.clone(),
+                7,
+                3,
+                side,
+            )
+        };
+        book.add_order(order("ct_sell", false)).await.unwrap();
+        book.add_order(order("ct_buy", true)).await.unwrap();
+
+        // Blocks carry traces as JSON, which the prover decodes as `common`'s trace
+        let trace = MATCHED_TRACES
+            .read()
+            .await
+            .iter()
+            .find(|trace| trace.buy_order.id == "ct_buy")
+            .cloned()
+            .unwrap();
+        let decoded: common::traces::MatchedTrace =
+            serde_json::from_slice(&serde_json::to_vec(&trace).unwrap()).unwrap();
+        assert_eq!(decoded.validate(), Ok(()));
+        assert_eq!(decoded.id(), trace.id());
+        assert_eq!(decoded.sell_order.user_id, "ct_sell_user");
+        assert_eq!((decoded.matched_amount, decoded.matched_price), (7, 3));
+    }
+
+    #[tokio::test]
+    async fn test_iter_orders_priority() {
+        let pair_id = "ITA_ITB".to_string();
+        let mut book = OrderBook::new();
+
+        for (i, price) in [103, 101, 105, 102].iter().enumerate() {
+            let buy = Order::new(
+                format!("it_buy_{}", i),
+                "bidder".to_string(),
+                pair_id.clone(),
+                1,
+                *price,
+                true,
+            );
+            book.add_order(buy).await.unwrap();
+        }
+        for (i, price) in [210, 207, 215, 208].iter().enumerate() {
+            let sell = Order::new(
+                format!("it_sell_{}", i),
+                "asker".to_string(),
+                pair_id.clone(),
+                1,
+                *price,
+                false,
             );
+            book.add_order(sell).await.unwrap();
+        }
+        book.cancel_order("it_buy_2", None).unwrap();
 
-            // This order will be skipped (pop) when matching (lazy removal).
-            order.set_status(OrderStatus::Cancelled);
-            let cancelled_order = order.clone();
+        let bids: Vec<u64> = book.iter_bids().map(|order| order.price).collect();
+        let asks: Vec<u64> = book.iter_asks().map(|order| order.price).collect();
+        assert_eq!(bids, vec![103, 102, 101]);
+        assert_eq!(asks, vec![207, 208, 210, 215]);
+        assert_eq!(book.get_best_bid(), Some(103));
+        assert_eq!(book.get_best_ask(), Some(207));
+    }
 
-            log::info!("Order {} successfully cancelled", order_id);
-            Some(cancelled_order)
-        } else {
-            log::warn!("Order {} disappeared during cancellation", order_id);
-            None
+    #[tokio::test]
+    async fn test_checksum_tracks_book_changes() {
+        let pair_id = "CKA_CKB".to_string();
+        let mut book = OrderBook::new();
+        for (i, (price, side)) in [(100, true), (100, true), (99, true), (105, false)]
+            .iter()
+            .enumerate()
+        {
+            let order = Order::new(
+                format!("ck_{}", i),
+                "ck_maker".to_string(),
+                pair_id.clone(),
+                2,
+                *price,
+                *side,
+            );
+            book.add_order(order).await.unwrap();
+        }
+        assert_eq!(
+            book.price_levels(true, CHECKSUM_DEPTH),
+            vec![(100, 4), (99, 2)]
+        );
+        assert_eq!(book.price_levels(true, 1), vec![(100, 4)]);
+
+        // Queries don't change it
+        let initial = book.checksum();
+        assert_eq!(book.checksum(), initial);
+        book.iter_bids().count();
+        assert_eq!(book.checksum(), initial);
+
+        // A resting order changes it, cancelling it restores the previous book
+        let buy = Order::new(
+            "ck_new_buy".to_string(),
+            "ck_maker".to_string(),
+            pair_id.clone(),
+            1,
+            98,
+            true,
+        );
+        book.add_order(buy).await.unwrap();
+        let with_new_level = book.checksum();
+        assert_ne!(with_new_level, initial);
+        book.cancel_order("ck_new_buy", None).unwrap();
+        assert_eq!(book.checksum(), initial);
+
+        // So does shrinking a level
+        book.cancel_order("ck_0", Some(1)).unwrap();
+        assert_ne!(book.checksum(), initial);
+    }
+
+    #[tokio::test]
+    async fn test_seq_is_gap_free() {
+        let pair_id = "SQA_SQB".to_string();
+        let mut book = OrderBook::new();
+        let order = |id: &str, side: bool, amount: u64, price: u64| {
+            Order::new(
+                id.to_string(),
+                "sq_user".to_string(),
+                pair_id.clone(),
+                amount,
+                price,
+                side,
+            )
+        };
+        let mut seqs = vec![book.seq()];
+
+        // Placements resting without fills: one change each
+        book.add_order(order("sq_sell_1", false, 5, 100))
+            .await
+            .unwrap();
+        seqs.push(book.seq());
+        book.add_order(order("sq_sell_2", false, 5, 101))
+            .await
+            .unwrap();
+        seqs.push(book.seq());
+
+        // A taker crossing both: its placement plus two fills
+        book.add_order(order("sq_buy", true, 7, 101)).await.unwrap();
+        seqs.push(book.seq());
+
+        // A reduce and a cancel
+        book.cancel_order("sq_sell_2", Some(1)).unwrap();
+        seqs.push(book.seq());
+        book.cancel_order("sq_sell_2", None).unwrap();
+        seqs.push(book.seq());
+
+        // Rejected operations change nothing
+        assert!(book.cancel_order("sq_sell_2", None).is_err());
+        assert!(
+            book.add_order(order("sq_sell_1", false, 1, 100))
+                .await
+                .is_err()
+        );
+        seqs.push(book.seq());
+
+        assert_eq!(seqs, vec![0, 1, 2, 5, 6, 7, 7]);
+    }
+
+    #[tokio::test]
+    async fn test_partial_cancel() {
+        let pair_id = "PCA_PCB".to_string();
+        let mut book = OrderBook::new();
+
+        let mut first = Order::new(
+            "pc_sell_1".to_string(),
+            "maker".to_string(),
+            pair_id.clone(),
+            10,
+            100,
+            false,
+        );
+        first.created_at = 1;
+        let mut second = Order::new(
+            "pc_sell_2".to_string(),
+            "maker".to_string(),
+            pair_id.clone(),
+            10,
+            100,
+            false,
+        );
+        second.created_at = 2;
+        book.add_order(first).await.unwrap();
+        book.add_order(second).await.unwrap();
+
+        // Reducing more than remaining is rejected and leaves the order untouched
+        assert!(book.cancel_order("pc_sell_1", Some(11)).is_err());
+        assert_eq!(book.get_order("pc_sell_1").unwrap().remaining_amount(), 10);
+
+        // Reducing below remaining keeps the order resting at the front of the queue
+        let reduced = book.cancel_order("pc_sell_1", Some(4)).unwrap();
+        assert_eq!(reduced.remaining_amount(), 6);
+        assert_eq!(reduced.status, OrderStatus::Pending);
+
+        let buy = Order::new(
+            "pc_buy".to_string(),
+            "taker".to_string(),
+            pair_id.clone(),
+            6,
+            100,
+            true,
+        );
+        let result = book.add_order(buy).await.unwrap();
+        assert_eq!(result.fills.len(), 1);
+        assert_eq!(result.fills[0].maker_order_id, "pc_sell_1");
+        assert_eq!(result.fills[0].quantity, 6);
+        assert_eq!(
+            book.get_order("pc_sell_1").unwrap().status,
+            OrderStatus::Filled
+        );
+
+        // Reducing by the whole remaining size is a full cancel, stamped by the book's clock
+        book.set_clock(Arc::new(MockClock::new(42_000)));
+        let cancelled = book.cancel_order("pc_sell_2", Some(10)).unwrap();
+        assert_eq!(cancelled.status, OrderStatus::Cancelled);
+        assert_eq!(cancelled.updated_at, 42);
+        assert_eq!(book.get_best_ask(), None);
+    }
+
+    #[tokio::test]
+    async fn test_trade_timestamps_non_decreasing() {
+        let pair_id = "TSA_TSB".to_string();
+        let mut book = OrderBook::new();
+
+        for i in 0..20 {
+            let sell = Order::new(
+                format!("ts_sell_{}", i),
+                "maker".to_string(),
+                pair_id.clone(),
+                1,
+                100 + i,
+                false,
+            );
+            book.add_order(sell).await.unwrap();
+        }
+        for i in 0..4 {
+            let buy = Order::new(
+                format!("ts_buy_{}", i),
+                "taker".to_string(),
+                pair_id.clone(),
+                5,
+                200,
+                true,
+            );
+            book.add_order(buy).await.unwrap();
         }
+
+        assert_eq!(book.trades.len(), 20);
+        assert!(
+            book.trades
+                .make_contiguous()
+                .windows(2)
+                .all(|pair| pair[0].timestamp <= pair[1].timestamp)
+        );
+        // Timestamps are match times, not the (second-granularity) order creation times
+        assert!(book.trades[0].timestamp > book.get_order("ts_sell_0").unwrap().created_at);
+    }
+
+    #[tokio::test]
+    async fn test_simulate_replays_exact_trades() {
+        let order = |id: &str, side: bool, amount: u64, price: u64| {
+            Order::new(
+                id.to_string(),
+                format!("{}_user", id),
+                "SMA_SMB".to_string(),
+                amount,
+                price,
+                side,
+            )
+        };
+        let script = vec![
+            order("s1", false, 5, 101),
+            order("s2", false, 5, 100),
+            order("s3", false, 5, 100),
+            order("b1", true, 8, 100),
+            order("b2", true, 6, 102),
+            order("b3", true, 4, 99),
+            order("s4", false, 3, 98),
+        ];
+
+        let (trades, book) = simulate(script.clone()).await;
+        let summary: Vec<(&str, &str, u64, u64, u64)> = trades
+            .iter()
+            .map(|trade| {
+                (
+                    trade.buy_order_id.as_str(),
+                    trade.sell_order_id.as_str(),
+                    trade.price,
+                    trade.quantity,
+                    trade.timestamp,
+                )
+            })
+            .collect();
+        // Orders take timestamps 1..=7 in script order, each trade the next tick after
+        assert_eq!(
+            summary,
+            vec![
+                ("b1", "s2", 100, 5, 5),
+                ("b1", "s3", 100, 3, 6),
+                ("b2", "s3", 100, 2, 8),
+                ("b2", "s1", 101, 4, 9),
+                ("b3", "s4", 99, 3, 12),
+            ]
+        );
+        assert_eq!(book.price_levels(true, CHECKSUM_DEPTH), vec![(99, 1)]);
+        assert_eq!(book.price_levels(false, CHECKSUM_DEPTH), vec![(101, 1)]);
+
+        // Replaying gives the same result, and nothing reaches the block builder
+        let (replayed, replayed_book) = simulate(script).await;
+        assert_eq!(
+            replayed
+                .iter()
+                .map(|trade| (trade.timestamp, trade.quantity))
+                .collect::<Vec<_>>(),
+            trades
+                .iter()
+                .map(|trade| (trade.timestamp, trade.quantity))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(replayed_book.checksum(), book.checksum());
+        assert!(
+            !MATCHED_TRACES
+                .read()
+                .await
+                .iter()
+                .any(|trace| trace.buy_order.pair_id == "SMA_SMB")
+        );
     }
 
-    pub fn get_order(&self, order_id: &str) -> Option<&Order> {
-        self.order_map.get(order_id)
+    #[tokio::test]
+    async fn test_stop_order_waits_for_trigger() {
+        let pair_id = "SPA_SPB".to_string();
+        let order = |id: &str, amount, price, side| {
+            Order::new(
+                id.to_string(),
+                format!("{}_user", id),
+                pair_id.clone(),
+                amount,
+                price,
+                side,
+            )
+        };
+        let mut book = OrderBook::simulated();
+        book.add_order(order("stop_ask_1", 2, 100, false))
+            .await
+            .unwrap();
+        book.add_order(order("stop_ask_2", 5, 106, false))
+            .await
+            .unwrap();
+
+        // Buy up to 110 once a trade prints at 105 or above
+        let stop = order("stop_buy", 5, 110, true).with_trigger(105);
+        let armed = book.add_order(stop).await.unwrap();
+        assert!(armed.fills.is_empty());
+        assert_eq!(book.get_best_bid(), None);
+
+        // Trades below the trigger leave it armed, also across a restore
+        book.add_order(order("stop_taker_1", 2, 100, true))
+            .await
+            .unwrap();
+        assert_eq!(book.get_order("stop_buy").unwrap().filled_amount, 0);
+        assert_eq!(book.get_best_bid(), None);
+        assert_eq!(OrderBook::restore(book.snapshot()).get_best_bid(), None);
+
+        // A trade at 106 fires it: it takes the rest of the ask and rests the remainder
+        book.add_order(order("stop_taker_2", 1, 106, true))
+            .await
+            .unwrap();
+        let fired = book.get_order("stop_buy").unwrap();
+        assert_eq!(fired.filled_amount, 4);
+        assert_eq!(fired.trigger_price, None);
+        assert_eq!(book.get_best_bid(), Some(110));
+        assert_eq!(book.get_best_ask(), None);
     }
 
-    pub fn get_best_bid(&self) -> Option<u64> {
-        for buy_order in &self.buy_orders {
-            if !self.is_order_cancelled(&buy_order.0.id) {
-                return Some(buy_order.0.price);
+    #[tokio::test]
+    async fn test_restored_book_keeps_time_priority() {
+        // Same price and the same creation second: only arrival order separates them
+        async fn place_sells(book: &mut OrderBook, pair_id: &str) {
+            for i in 0..4 {
+                let mut sell = Order::new(
+                    format!("{}_sell_{}", pair_id, i),
+                    format!("fifo_seller_{}", i),
+                    pair_id.to_string(),
+                    2,
+                    10,
+                    false,
+                );
+                sell.created_at = 1_000;
+                book.add_order(sell).await.unwrap();
             }
         }
-        None
+        let sweep = |pair_id: &str| {
+            Order::new(
+                format!("{}_buy", pair_id),
+                "fifo_buyer".to_string(),
+                pair_id.to_string(),
+                6,
+                10,
+                true,
+            )
+        };
+        let maker_ids = |result: MatchResult, pair_id: &str| {
+            result
+                .fills
+                .iter()
+                .map(|fill| fill.maker_order_id.replace(pair_id, ""))
+                .collect::<Vec<_>>()
+        };
+
+        let mut live = OrderBook::new();
+        place_sells(&mut live, "FFA_FFB").await;
+        let live_result = live.add_order(sweep("FFA_FFB")).await.unwrap();
+
+        let mut before_restart = OrderBook::new();
+        place_sells(&mut before_restart, "FRA_FRB").await;
+        let persisted = serde_json::to_vec(&before_restart.snapshot()).unwrap();
+        let mut restored = OrderBook::restore(serde_json::from_slice(&persisted).unwrap());
+        assert_eq!(restored.seq(), before_restart.seq());
+        let restored_result = restored.add_order(sweep("FRA_FRB")).await.unwrap();
+
+        let expected = vec!["_sell_0", "_sell_1", "_sell_2"];
+        assert_eq!(maker_ids(live_result, "FFA_FFB"), expected);
+        assert_eq!(maker_ids(restored_result, "FRA_FRB"), expected);
+
+        // New orders keep queueing behind the restored ones
+        let mut late = Order::new(
+            "FRA_FRB_sell_late".to_string(),
+            "fifo_seller_late".to_string(),
+            "FRA_FRB".to_string(),
+            2,
+            10,
+            false,
+        );
+        late.created_at = 1_000;
+        restored.add_order(late).await.unwrap();
+        let ids = restored
+            .iter_asks()
+            .map(|order| order.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["FRA_FRB_sell_3", "FRA_FRB_sell_late"]);
     }
 
-    pub fn get_best_ask(&self) -> Option<u64> {
-        for sell_order in &self.sell_orders {
-            if !self.is_order_cancelled(&sell_order.0.id) {
-                return Some(sell_order.0.price);
+    #[tokio::test]
+    async fn test_pro_rata_vs_price_time_allocation() {
+        // Three asks at 10 in arrival order, one ask behind them at 11; a buy takes `amount`
+        async fn fills_under(policy: MatchingPolicy, amount: u64) -> Vec<(String, u64, u64)> {
+            let mut book = OrderBook::simulated();
+            book.set_matching_policy(policy);
+            for (id, size, price) in [
+                ("pr_1", 10, 10),
+                ("pr_2", 20, 10),
+                ("pr_3", 30, 10),
+                ("pr_4", 20, 11),
+            ] {
+                let sell = Order::new(
+                    id.to_string(),
+                    "pr_seller".to_string(),
+                    "PRA_PRB".to_string(),
+                    size,
+                    price,
+                    false,
+                );
+                book.add_order(sell).await.unwrap();
             }
+            let buy = Order::new(
+                "pr_buy".to_string(),
+                "pr_buyer".to_string(),
+                "PRA_PRB".to_string(),
+                amount,
+                11,
+                true,
+            );
+            let result = book.add_order(buy).await.unwrap();
+            result
+                .fills
+                .into_iter()
+                .map(|fill| (fill.maker_order_id, fill.quantity, fill.price))
+                .collect()
         }
-        None
+        let fill = |id: &str, quantity, price| (id.to_string(), quantity, price);
+
+        // Price-time fills the earliest ask first
+        assert_eq!(
+            fills_under(MatchingPolicy::PriceTime, 30).await,
+            vec![fill("pr_1", 10, 10), fill("pr_2", 20, 10)]
+        );
+        // Pro-rata splits the same buy 1:2:3 across the level
+        assert_eq!(
+            fills_under(MatchingPolicy::ProRata, 30).await,
+            vec![
+                fill("pr_1", 5, 10),
+                fill("pr_2", 10, 10),
+                fill("pr_3", 15, 10)
+            ]
+        );
+        // Rounded-down shares of 7 are 1, 2 and 3; the unit left goes to the earliest ask
+        assert_eq!(
+            fills_under(MatchingPolicy::ProRata, 7).await,
+            vec![
+                fill("pr_1", 2, 10),
+                fill("pr_2", 2, 10),
+                fill("pr_3", 3, 10)
+            ]
+        );
+        // A buy clearing the whole level moves on to the next price under either policy
+        for policy in [MatchingPolicy::PriceTime, MatchingPolicy::ProRata] {
+            assert_eq!(
+                fills_under(policy, 70).await,
+                vec![
+                    fill("pr_1", 10, 10),
+                    fill("pr_2", 20, 10),
+                    fill("pr_3", 30, 10),
+                    fill("pr_4", 10, 11)
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_catches_corruption() {
+        let order = |id: &str, price, side| {
+            Order::new(
+                id.to_string(),
+                "vc_user".to_string(),
+                "VCA_VCB".to_string(),
+                5,
+                price,
+                side,
+            )
+        };
+        // Simulated, so the fill's trace stays out of the block builder's queue
+        let mut book = OrderBook::simulated();
+        book.add_order(order("vc_bid", 9, true)).await.unwrap();
+        book.add_order(order("vc_ask", 11, false)).await.unwrap();
+        book.add_order(order("vc_ask_2", 13, false)).await.unwrap();
+        book.add_order(order("vc_gone", 12, false)).await.unwrap();
+        book.cancel_order("vc_gone", None).unwrap();
+        book.add_order(order("vc_take", 11, true)).await.unwrap();
+        // Lazily removed and fully filled orders are part of a consistent book
+        assert_eq!(book.validate(), Ok(()));
+
+        let mut missing = book.simulated_copy();
+        missing.order_map.remove("vc_bid");
+        assert_eq!(
+            missing.validate(),
+            Err("Resting order vc_bid is missing from the map".to_string())
+        );
+
+        let mut filled = book.simulated_copy();
+        filled.order_map.get_mut("vc_bid").unwrap().fill(5);
+        assert_eq!(
+            filled.validate(),
+            Err("Filled order vc_bid is still resting".to_string())
+        );
+
+        let mut unlisted = book.simulated_copy();
+        let stray = order("vc_stray", 8, true);
+        unlisted.order_map.insert(stray.id.clone(), stray);
+        assert_eq!(
+            unlisted.validate(),
+            Err("Open order vc_stray is not on the book".to_string())
+        );
+
+        let mut crossed = book.simulated_copy();
+        let high_bid = order("vc_high_bid", 13, true);
+        crossed
+            .order_map
+            .insert(high_bid.id.clone(), high_bid.clone());
+        crossed.buy_orders.push(BuyOrder(high_bid));
+        crossed.open_orders += 1;
+        assert_eq!(
+            crossed.validate(),
+            Err("Book is crossed: bid 13 >= ask 13".to_string())
+        );
+
+        let mut miscounted = book.simulated_copy();
+        miscounted.open_orders += 1;
+        assert_eq!(
+            miscounted.validate(),
+            Err("Book counts 3 open orders, 2 are open".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_book_diff() {
+        let order = |id: &str, amount, price, side| {
+            Order::new(
+                id.to_string(),
+                "diff_user".to_string(),
+                "BDA_BDB".to_string(),
+                amount,
+                price,
+                side,
+            )
+        };
+        let mut book = OrderBook::simulated();
+        for (id, price, side) in [
+            ("bd_bid_1", 8, true),
+            ("bd_bid_2", 9, true),
+            ("bd_ask_1", 11, false),
+            ("bd_ask_2", 12, false),
+        ] {
+            book.add_order(order(id, 5, price, side)).await.unwrap();
+        }
+        let before = book.resting_copy();
+        assert!(before.diff(&book).is_empty());
+
+        // One order of each kind of change: partly filled, reduced, cancelled, filled, added
+        book.add_order(order("bd_take", 7, 12, true)).await.unwrap();
+        book.cancel_order("bd_bid_1", Some(2)).unwrap();
+        book.cancel_order("bd_bid_2", None).unwrap();
+        book.add_order(order("bd_bid_3", 4, 7, true)).await.unwrap();
+
+        let diff = before.diff(&book);
+        let ids = |orders: &[Order]| -> Vec<(String, u64)> {
+            orders
+                .iter()
+                .map(|order| (order.id.clone(), order.remaining_amount()))
+                .collect()
+        };
+        assert_eq!(ids(&diff.added), vec![("bd_bid_3".to_string(), 4)]);
+        assert_eq!(
+            ids(&diff.changed),
+            vec![("bd_bid_1".to_string(), 3), ("bd_ask_2".to_string(), 3)]
+        );
+        assert_eq!(diff.removed, vec!["bd_bid_2", "bd_ask_1"]);
+
+        // Reversed, the same changes undo each other
+        let undo = book.diff(&before);
+        assert_eq!(
+            ids(&undo.added),
+            vec![("bd_bid_2".to_string(), 5), ("bd_ask_1".to_string(), 5)]
+        );
+        assert_eq!(undo.removed, vec!["bd_bid_3"]);
+        assert_eq!(
+            ids(&undo.changed),
+            vec![("bd_bid_1".to_string(), 5), ("bd_ask_2".to_string(), 5)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replayed_events_reproduce_the_book() {
+        let pair_id = "EVA_EVB".to_string();
+        let order = |id: &str, amount, price, side| {
+            Order::new(
+                id.to_string(),
+                "ev_user".to_string(),
+                pair_id.clone(),
+                amount,
+                price,
+                side,
+            )
+        };
+        let mut live = OrderBook::new();
+        live.add_order(order("ev_sell_1", 5, 101, false))
+            .await
+            .unwrap();
+        live.add_order(order("ev_sell_2", 4, 102, false))
+            .await
+            .unwrap();
+        live.add_order(order("ev_buy_1", 6, 99, true))
+            .await
+            .unwrap();
+        let mut stop = order("ev_stop", 3, 105, true);
+        stop.trigger_price = Some(102);
+        live.add_order(stop).await.unwrap();
+        live.add_order(order("ev_sell_3", 2, 103, false))
+            .await
+            .unwrap();
+        live.cancel_order("ev_buy_1", Some(2)).unwrap();
+        // Lifts both asks up to 102, which fires the stop into the ask at 103
+        live.add_order(order("ev_buy_2", 8, 102, true))
+            .await
+            .unwrap();
+        live.cancel_order("ev_sell_3", None).unwrap_err();
+        live.add_order(order("ev_sell_4", 3, 100, false))
+            .await
+            .unwrap();
+        live.cancel_order("ev_sell_4", None).unwrap();
+        live.publish_events().await;
+
+        let events: Vec<EngineEvent> = ENGINE_EVENTS
+            .read()
+            .await
+            .iter()
+            .filter(|event| event.pair_id() == pair_id)
+            .cloned()
+            .collect();
+        let mut books = HashMap::new();
+        replay_events(&mut books, &events).await.unwrap();
+        let replayed = &books[&pair_id];
+
+        let trades = |book: &OrderBook| {
+            book.trades
+                .iter()
+                .map(|trade| {
+                    (
+                        trade.buy_order_id.clone(),
+                        trade.sell_order_id.clone(),
+                        trade.price,
+                        trade.quantity,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(trades(replayed), trades(&live));
+        assert_eq!(live.trades.len(), 4);
+        let resting = |book: &OrderBook| {
+            book.iter_bids()
+                .chain(book.iter_asks())
+                .map(|order| (order.id.clone(), order.remaining_amount()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(resting(replayed), resting(&live));
+        assert_eq!(resting(&live), vec![("ev_buy_1".to_string(), 4)]);
+
+        // A fill the books don't make is caught
+        let mut forged = events.clone();
+        if let Some(EngineEvent::Fill { quantity, .. }) = forged
+            .iter_mut()
+            .find(|event| matches!(event, EngineEvent::Fill { .. }))
+        {
+            *quantity += 1;
+        }
+        assert!(replay_events(&mut HashMap::new(), &forged).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_live_book_keeps_recent_trades() {
+        let old_trade = |n: u64| Trade {
+            buy_order_id: format!("rt_old_buy_{}", n),
+            sell_order_id: format!("rt_old_sell_{}", n),
+            price: 7,
+            quantity: 1,
+            timestamp: n,
+        };
+        let mut book = OrderBook::restore(OrderBookSnapshot {
+            orders: vec![],
+            trades: (0..MAX_RECENT_TRADES as u64 + 5).map(old_trade).collect(),
+            seq: 0,
+            order_seq: 0,
+        });
+        assert_eq!(book.trades.len(), MAX_RECENT_TRADES);
+        assert_eq!(book.trades[0].timestamp, 5);
+
+        let order = |id: &str, side| {
+            Order::new(
+                id.to_string(),
+                "rt_user".to_string(),
+                "RTA_RTB".to_string(),
+                2,
+                9,
+                side,
+            )
+        };
+        book.add_order(order("rt_sell", false)).await.unwrap();
+        book.add_order(order("rt_buy", true)).await.unwrap();
+
+        // The oldest trade made room, and only the new one is handed out for logging
+        assert_eq!(book.trades.len(), MAX_RECENT_TRADES);
+        assert_eq!(book.trades[0].timestamp, 6);
+        assert_eq!(book.trades.back().unwrap().buy_order_id, "rt_buy");
+        let new_trades = book.take_new_trades();
+        assert_eq!(new_trades.len(), 1);
+        assert_eq!(new_trades[0].sell_order_id, "rt_sell");
+        assert!(book.take_new_trades().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_closed_orders_move_to_archive() {
+        let order = |id: &str, price, side| {
+            Order::new(
+                id.to_string(),
+                "ar_user".to_string(),
+                "ARA_ARB".to_string(),
+                5,
+                price,
+                side,
+            )
+        };
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut book = OrderBook::new();
+        book.set_archive(db.open_tree("order_archive/ARA_ARB").unwrap());
+
+        book.add_order(order("ar_sell", 10, false)).await.unwrap();
+        book.add_order(order("ar_buy", 10, true)).await.unwrap();
+        book.add_order(order("ar_gone", 12, false)).await.unwrap();
+        book.add_order(order("ar_ask", 13, false)).await.unwrap();
+        book.cancel_order("ar_gone", None).unwrap();
+        // Filled and cancelled orders wait in the map until settled or off the book
+        assert_eq!(book.order_map.len(), 4);
+
+        book.settle_order("ar_sell");
+        book.settle_order("ar_buy");
+        // Takes the last ask, dropping the cancelled one ahead of it on the way
+        book.add_order(order("ar_take", 13, true)).await.unwrap();
+        let mut live: Vec<&String> = book.order_map.keys().collect();
+        live.sort();
+        assert_eq!(live, vec!["ar_ask", "ar_take"]);
+        assert_eq!(book.validate(), Ok(()));
+
+        // Archived orders are still served, and their ids stay taken
+        assert!(matches!(
+            book.get_order("ar_sell").unwrap().status,
+            OrderStatus::Settled
+        ));
+        assert!(matches!(
+            book.get_order("ar_gone").unwrap().status,
+            OrderStatus::Cancelled
+        ));
+        assert_eq!(
+            book.cancel_order("ar_gone", None).unwrap_err(),
+            "Order already cancelled"
+        );
+        assert_eq!(
+            book.add_order(order("ar_buy", 1, true)).await.unwrap_err(),
+            "Duplicate order id ar_buy"
+        );
     }
 }
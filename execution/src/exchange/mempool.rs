@@ -1,25 +1,607 @@
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock, mpsc, oneshot};
 
-use crate::exchange::STATE;
-use crate::exchange::matching::{OrderBook, Trade};
-use common::order::Order;
-use std::collections::HashMap;
-use std::sync::Arc;
+use crate::exchange::matching::{BookDiff, MatchResult, MatchingPolicy, OrderBook, Trade};
+use crate::exchange::{
+    MATCHED_TRACES, MAX_PENDING_TRACES, NODE_DB, SETTLING_TRACES, STATE, STATE_LOCK_TIMEOUT, STATS,
+    TRADE_LOG, USER_TIERS, order_span, trace_backlog_depth,
+};
+use common::db::{ORDER_ARCHIVE_TREE_PREFIX, PAIR_HALTS_TREE};
+use common::math::{MAX_PRICE_DECIMALS, locked_notional, notional, scale_decimal};
+use common::order::{Order, OrderStatus, parse_pair};
+use common::state::State;
+use common::traces::MatchedTrace;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+// Book states per pair kept for clients to resync from, see `Mempool::resync`
+pub const RESYNC_POINTS: usize = 32;
+// Longest client order id accepted
+pub const MAX_CLIENT_ORDER_ID_LEN: usize = 64;
+
+// Mismatch between a user's recorded frozen balance and what their open orders require
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct FrozenDiscrepancy {
+    pub token: String,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+// Trading rules of one pair. The default rules accept any order
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PairConfig {
+    // Smallest accepted order value in quote base units, to keep dust orders off the book
+    pub min_notional: u128,
+    // How fills are shared among the resting orders of a price level
+    #[serde(default)]
+    pub matching_policy: MatchingPolicy,
+    // Most orders the pair's book holds open, see `OrderBook::set_max_orders`. No cap if unset
+    #[serde(default)]
+    pub max_orders: Option<usize>,
+    // Decimals of the pair's prices: a price `p` is `p / 10^price_decimals` quote base units
+    // per base unit. Can only change while the pair has no open orders
+    #[serde(default)]
+    pub price_decimals: u8,
+    // Decimals of the base and quote tokens, to read prices given in whole tokens
+    #[serde(default)]
+    pub base_decimals: u8,
+    #[serde(default)]
+    pub quote_decimals: u8,
+}
+
+impl PairConfig {
+    /// Convert a decimal price in whole quote tokens per whole base token, as a user
+    /// quotes it, to the pair's integer price.
+    pub fn price_from_decimal(&self, price: &str) -> Result<u64, String> {
+        let shift =
+            self.quote_decimals as i32 - self.base_decimals as i32 + self.price_decimals as i32;
+        scale_decimal(price, shift).map_err(|e| e.to_string())
+    }
+}
+
+// Work handed to a pair's matching task
+enum EngineCommand {
+    // Carries the caller's span so matching logs stay attached to the order
+    Place(
+        Order,
+        tracing::Span,
+        oneshot::Sender<Result<MatchResult, String>>,
+    ),
+    Cancel {
+        order_id: String,
+        reduce_by: Option<u64>,
+        reply: oneshot::Sender<Result<Order, String>>,
+    },
+    CancelBatch {
+        order_ids: Vec<String>,
+        reply: oneshot::Sender<Vec<Result<Order, String>>>,
+    },
+    Replace {
+        order_id: String,
+        order: Order,
+        reserve: Reserve,
+        // Longest wait for the state lock before the replacement is turned away
+        lock_timeout: Duration,
+        span: tracing::Span,
+        reply: oneshot::Sender<Result<(Order, MatchResult), String>>,
+    },
+}
+
+// Takes the funds of a replacement order, handed the order it replaces as it is on the book.
+// Runs in the matching task, before the book changes, with the state lock the task took
+type Reserve = Box<dyn FnOnce(&mut State, &Order) -> Result<(), String> + Send>;
+
+// Handle to the task matching the orders of a single pair. Commands for a pair are applied
+// one at a time in arrival order, while different pairs match concurrently.
+#[derive(Clone)]
+pub struct PairEngine {
+    pub book: Arc<RwLock<OrderBook>>, // shared with the task for read-only queries
+    sender: mpsc::UnboundedSender<EngineCommand>,
+    // Resting orders of the last `RESYNC_POINTS` states served to clients, oldest first
+    resync_points: Arc<Mutex<VecDeque<OrderBook>>>,
+}
+
+impl PairEngine {
+    pub fn spawn(book: OrderBook) -> Self {
+        let book = Arc::new(RwLock::new(book));
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+
+        let task_book = book.clone();
+        tokio::spawn(async move {
+            while let Some(command) = receiver.recv().await {
+                match command {
+                    EngineCommand::Place(order, span, reply) => {
+                        let mut book = task_book.write().await;
+                        let pair_id = order.pair_id.clone();
+                        let started = Instant::now();
+                        let result = book.add_order(order).instrument(span.clone()).await;
+                        STATS.record_match(started.elapsed());
+                        log_trades(&pair_id, &book.take_new_trades(), &span);
+                        let improved_fills = book.take_improved_fills();
+                        drop(book);
+                        refund_price_improvement(&improved_fills).await;
+                        let _ = reply.send(result);
+                    }
+                    EngineCommand::Replace {
+                        order_id,
+                        order,
+                        reserve,
+                        lock_timeout,
+                        span,
+                        reply,
+                    } => {
+                        // Taken here rather than by the caller, so nobody holds the state lock
+                        // while waiting on the task; and before the book's, like everywhere
+                        let Ok(mut state_db) =
+                            tokio::time::timeout(lock_timeout, STATE.write()).await
+                        else {
+                            let _ = reply.send(Err(
+                                "Exchange busy settling a block, retry later".to_string()
+                            ));
+                            continue;
+                        };
+                        let mut book = task_book.write().await;
+                        let pair_id = order.pair_id.clone();
+                        let started = Instant::now();
+                        let result = book
+                            .replace_order(&order_id, order, |replaced| {
+                                reserve(&mut state_db.state, replaced)
+                            })
+                            .instrument(span.clone())
+                            .await;
+                        STATS.record_match(started.elapsed());
+                        log_trades(&pair_id, &book.take_new_trades(), &span);
+                        let improved_fills = book.take_improved_fills();
+                        drop(book);
+                        for trace in &improved_fills {
+                            state_db.state.refund_price_improvement(trace);
+                        }
+                        drop(state_db);
+                        let _ = reply.send(result);
+                    }
+                    EngineCommand::Cancel {
+                        order_id,
+                        reduce_by,
+                        reply,
+                    } => {
+                        let mut book = task_book.write().await;
+                        let result = book.cancel_order(&order_id, reduce_by);
+                        book.publish_events().await;
+                        drop(book);
+                        let _ = reply.send(result);
+                    }
+                    EngineCommand::CancelBatch { order_ids, reply } => {
+                        let mut book = task_book.write().await;
+                        let results = order_ids
+                            .iter()
+                            .map(|order_id| book.cancel_order(order_id, None))
+                            .collect();
+                        book.publish_events().await;
+                        drop(book);
+                        let _ = reply.send(results);
+                    }
+                }
+            }
+        });
+
+        Self {
+            book,
+            sender,
+            resync_points: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    // Keep `snapshot` as a state clients can resync from
+    fn remember(&self, snapshot: &OrderBook) {
+        let mut resync_points = self
+            .resync_points
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if resync_points
+            .back()
+            .is_some_and(|point| point.seq() == snapshot.seq())
+        {
+            return;
+        }
+        if resync_points.len() == RESYNC_POINTS {
+            resync_points.pop_front();
+        }
+        resync_points.push_back(snapshot.resting_copy());
+    }
+
+    fn resync_point(&self, seq: u64) -> Option<OrderBook> {
+        self.resync_points
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|point| point.seq() == seq)
+            .map(OrderBook::resting_copy)
+    }
+
+    async fn place_order(&self, order: Order) -> Result<MatchResult, String> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(EngineCommand::Place(order, tracing::Span::current(), reply))
+            .map_err(|_| "Matching engine stopped".to_string())?;
+        response
+            .await
+            .map_err(|_| "Matching engine stopped".to_string())?
+    }
+
+    async fn replace_order(
+        &self,
+        order_id: &str,
+        order: Order,
+        reserve: Reserve,
+        lock_timeout: Duration,
+    ) -> Result<(Order, MatchResult), String> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(EngineCommand::Replace {
+                order_id: order_id.to_string(),
+                order,
+                reserve,
+                lock_timeout,
+                span: tracing::Span::current(),
+                reply,
+            })
+            .map_err(|_| "Matching engine stopped".to_string())?;
+        response
+            .await
+            .map_err(|_| "Matching engine stopped".to_string())?
+    }
+
+    async fn cancel_order(&self, order_id: &str, reduce_by: Option<u64>) -> Result<Order, String> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(EngineCommand::Cancel {
+                order_id: order_id.to_string(),
+                reduce_by,
+                reply,
+            })
+            .map_err(|_| "Matching engine stopped".to_string())?;
+        response
+            .await
+            .map_err(|_| "Matching engine stopped".to_string())?
+    }
+
+    async fn cancel_orders(
+        &self,
+        order_ids: Vec<String>,
+    ) -> Result<Vec<Result<Order, String>>, String> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(EngineCommand::CancelBatch { order_ids, reply })
+            .map_err(|_| "Matching engine stopped".to_string())?;
+        response
+            .await
+            .map_err(|_| "Matching engine stopped".to_string())
+    }
+}
 
 // Global mempool state
 pub struct Mempool {
-    pub order_books: HashMap<String, OrderBook>, // pair_id -> OrderBook
+    pub order_books: std::sync::RwLock<HashMap<String, PairEngine>>, // pair_id -> matching task
+    pub max_pending_traces: usize, // backpressure: reject orders while the trace backlog is this deep
+    pub state_lock_timeout: Duration, // reject orders rather than queue behind a block being settled
+    pub pair_configs: std::sync::RwLock<HashMap<String, PairConfig>>, // pair_id -> trading rules
+    pub halted_pairs: std::sync::RwLock<HashSet<String>>, // pairs not accepting new orders
+    halt_store: Option<sled::Tree>,   // where halts are persisted, if anywhere
+    order_archive: Option<sled::Db>,  // where books archive their closed orders, if anywhere
+    // (user_id, client_order_id) -> (pair_id, order_id), for orders placed with a client id
+    client_orders: std::sync::RwLock<HashMap<(String, String), (String, String)>>,
+    // (pair_id, order_id) -> orders frozen for by `place_order` and not yet handed to their book
+    unplaced_orders: Mutex<HashMap<(String, String), Order>>,
+    // (pair_id, order_id) -> woken when the order's status may have changed, for the callers
+    // of `wait_for_order` waiting on it
+    order_watches: Mutex<HashMap<(String, String), Arc<Notify>>>,
 }
 
 impl Mempool {
     pub fn new() -> Self {
         Self {
-            order_books: HashMap::new(),
+            order_books: std::sync::RwLock::new(HashMap::new()),
+            max_pending_traces: MAX_PENDING_TRACES,
+            state_lock_timeout: STATE_LOCK_TIMEOUT,
+            pair_configs: std::sync::RwLock::new(HashMap::new()),
+            halted_pairs: std::sync::RwLock::new(HashSet::new()),
+            halt_store: None,
+            order_archive: None,
+            client_orders: std::sync::RwLock::new(HashMap::new()),
+            unplaced_orders: Mutex::new(HashMap::new()),
+            order_watches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Persist pair halts in `tree` so they survive a restart, starting out with the halts
+    /// already recorded in it.
+    pub fn with_halt_store(mut self, tree: sled::Tree) -> sled::Result<Self> {
+        let mut halted_pairs = HashSet::new();
+        for pair_id in tree.iter().keys() {
+            halted_pairs.insert(String::from_utf8_lossy(&pair_id?).into_owned());
+        }
+        self.halted_pairs = std::sync::RwLock::new(halted_pairs);
+        self.halt_store = Some(tree);
+        Ok(self)
+    }
+
+    /// Have each pair's book archive its settled and cancelled orders in `db`, see
+    /// `OrderBook::set_archive`, so they don't pile up in memory.
+    pub fn with_order_archive(mut self, db: &sled::Db) -> Self {
+        self.order_archive = Some(db.clone());
+        self
+    }
+
+    // Hand `book` the pair's archive tree, if orders are archived
+    fn attach_archive(&self, pair_id: &str, book: &mut OrderBook) {
+        let Some(db) = &self.order_archive else {
+            return;
+        };
+        match db.open_tree(format!("{}{}", ORDER_ARCHIVE_TREE_PREFIX, pair_id)) {
+            Ok(tree) => book.set_archive(tree),
+            Err(e) => tracing::error!("Failed to open the order archive of {}: {}", pair_id, e),
+        }
+    }
+
+    /// Stop accepting new orders on `pair_id`, e.g. during an incident. Resting orders stay on
+    /// the book and can still be cancelled.
+    pub fn halt_pair(&self, pair_id: &str) -> Result<(), String> {
+        self.set_halted(pair_id, true)
+    }
+
+    /// Accept new orders on a halted pair again
+    pub fn resume_pair(&self, pair_id: &str) -> Result<(), String> {
+        self.set_halted(pair_id, false)
+    }
+
+    pub fn is_halted(&self, pair_id: &str) -> bool {
+        self.halted_pairs
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(pair_id)
+    }
+
+    fn set_halted(&self, pair_id: &str, halted: bool) -> Result<(), String> {
+        parse_pair(pair_id).map_err(|e| e.to_string())?;
+        // Stored first, so a halt reported as done also holds after a restart
+        if let Some(tree) = &self.halt_store {
+            let stored = if halted {
+                tree.insert(pair_id, Vec::new())
+            } else {
+                tree.remove(pair_id)
+            };
+            stored
+                .and_then(|_| tree.flush())
+                .map_err(|e| format!("Failed to store halt of pair {}: {}", pair_id, e))?;
+        }
+        let mut halted_pairs = self
+            .halted_pairs
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if halted {
+            halted_pairs.insert(pair_id.to_string());
+            tracing::warn!("Trading on pair {} halted", pair_id);
+        } else {
+            halted_pairs.remove(pair_id);
+            tracing::info!("Trading on pair {} resumed", pair_id);
+        }
+        Ok(())
+    }
+
+    /// Set the trading rules of `pair_id`. A running book switches its matching policy
+    /// for the orders that come after. The price scale can't change under open orders,
+    /// whose prices are in the old one.
+    pub async fn set_pair_config(&self, pair_id: &str, config: PairConfig) -> Result<(), String> {
+        if config.price_decimals > MAX_PRICE_DECIMALS {
+            return Err(format!(
+                "Price scale of {} decimals exceeds the maximum of {}",
+                config.price_decimals, MAX_PRICE_DECIMALS
+            ));
+        }
+        let policy = config.matching_policy;
+        let max_orders = config.max_orders;
+        let price_decimals = config.price_decimals;
+        let engine = self.engine(pair_id);
+        // Held while the config changes, so no order is placed in between
+        let mut book = match &engine {
+            Some(engine) => Some(engine.book.write().await),
+            None => None,
+        };
+        if price_decimals != self.pair_config(pair_id).price_decimals {
+            let has_open_orders = book.as_ref().is_some_and(|book| book.open_orders() > 0);
+            if has_open_orders {
+                return Err(format!(
+                    "Pair {} has open orders, its price scale can't change",
+                    pair_id
+                ));
+            }
+        }
+        self.pair_configs
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(pair_id.to_string(), config);
+        if let Some(book) = book.as_mut() {
+            book.set_matching_policy(policy);
+            book.set_max_orders(max_orders);
+        }
+        Ok(())
+    }
+
+    /// Trading rules of `pair_id`, the default ones if none were set
+    pub fn pair_config(&self, pair_id: &str) -> PairConfig {
+        self.pair_configs
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(pair_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // The map only holds engine handles and every update is a single insert, so it stays
+    // consistent even if a holder panicked and poisoned the lock
+    /// Decimals of `token` as configured on the first pair, by pair id, it is the base or
+    /// quote token of. None if no configured pair lists it.
+    pub fn token_decimals(&self, token: &str) -> Option<u8> {
+        let pair_configs = self
+            .pair_configs
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut pair_ids: Vec<&String> = pair_configs.keys().collect();
+        pair_ids.sort();
+        pair_ids.into_iter().find_map(|pair_id| {
+            let (base_token, quote_token) = parse_pair(pair_id).ok()?;
+            let config = &pair_configs[pair_id];
+            if base_token == token {
+                Some(config.base_decimals)
+            } else if quote_token == token {
+                Some(config.quote_decimals)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn engine(&self, pair_id: &str) -> Option<PairEngine> {
+        self.order_books
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(pair_id)
+            .cloned()
+    }
+
+    // Get the pair's matching task, spawning it on the first order for the pair
+    fn engine_or_spawn(&self, pair_id: &str) -> PairEngine {
+        if let Some(engine) = self.engine(pair_id) {
+            return engine;
+        }
+        self.order_books
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(pair_id.to_string())
+            .or_insert_with(|| {
+                let config = self.pair_config(pair_id);
+                let mut book = OrderBook::new();
+                book.set_matching_policy(config.matching_policy);
+                book.set_max_orders(config.max_orders);
+                self.attach_archive(pair_id, &mut book);
+                PairEngine::spawn(book)
+            })
+            .clone()
+    }
+
+    /// Start matching a pair from an existing book, replacing any book the pair had.
+    pub fn insert_order_book(&self, pair_id: &str, mut book: OrderBook) {
+        self.attach_archive(pair_id, &mut book);
+        self.order_books
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(pair_id.to_string(), PairEngine::spawn(book));
+    }
+
+    /// Load resting orders of `pair_id` into its book without matching them, see
+    /// `OrderBook::bootstrap`. Nothing is frozen for them: the state is expected to hold their
+    /// freezes already, as it does when a saved book is replayed next to its saved state.
+    pub async fn bootstrap(&self, pair_id: &str, orders: Vec<Order>) -> Result<(), String> {
+        parse_pair(pair_id).map_err(|e| e.to_string())?;
+        if let Some(order) = orders.iter().find(|order| order.pair_id != pair_id) {
+            return Err(format!("Order {} is not for pair {}", order.id, pair_id));
         }
+        // Taking the book's lock waits for the commands the engine is applying
+        let engine = self.engine_or_spawn(pair_id);
+        let mut book = engine.book.write().await;
+        book.bootstrap(orders)
     }
 
-    pub async fn place_order(&mut self, order: Order) -> Result<String, String> {
-        log::info!(
+    /// Match `order` the way placing it now would, against a copy of its pair's book. Neither
+    /// the book nor any balance is touched, and no trace is produced.
+    pub async fn simulate_order(&self, order: Order) -> Result<MatchResult, String> {
+        parse_pair(&order.pair_id).map_err(|e| e.to_string())?;
+        let mut book = match self.engine(&order.pair_id) {
+            Some(engine) => engine.book.read().await.simulated_copy(),
+            None => {
+                let mut book = OrderBook::simulated();
+                book.set_matching_policy(self.pair_config(&order.pair_id).matching_policy);
+                book
+            }
+        };
+        book.add_order(order).await
+    }
+
+    /// Copy of the resting orders of `pair_id`'s book, remembered as a state clients can later
+    /// `resync` from.
+    pub async fn resting_snapshot(&self, pair_id: &str) -> Option<OrderBook> {
+        let engine = self.engine(pair_id)?;
+        let snapshot = engine.book.read().await.resting_copy();
+        engine.remember(&snapshot);
+        Some(snapshot)
+    }
+
+    /// Copy of the resting orders of every pair's book, by pair id
+    pub async fn resting_books(&self) -> Vec<(String, OrderBook)> {
+        let engines: Vec<(String, PairEngine)> = self
+            .order_books
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(pair_id, engine)| (pair_id.clone(), engine.clone()))
+            .collect();
+        let mut books = Vec::with_capacity(engines.len());
+        for (pair_id, engine) in engines {
+            let book = engine.book.read().await.resting_copy();
+            books.push((pair_id, book));
+        }
+        books
+    }
+
+    /// Changes to `pair_id`'s resting orders since the state at `seq` a client was served, and
+    /// the state they lead to. Only the last `RESYNC_POINTS` states served are kept; a client
+    /// further behind has to reload the whole book.
+    pub async fn resync(&self, pair_id: &str, seq: u64) -> Result<(BookDiff, OrderBook), String> {
+        let engine = self
+            .engine(pair_id)
+            .ok_or("Trading pair not found".to_string())?;
+        let base = engine.resync_point(seq).ok_or_else(|| {
+            format!(
+                "Book state at seq {} is no longer kept, reload the full book",
+                seq
+            )
+        })?;
+        let snapshot = engine.book.read().await.resting_copy();
+        engine.remember(&snapshot);
+        Ok((base.diff(&snapshot), snapshot))
+    }
+
+    /// Resting bid and ask count of every pair's book
+    pub async fn book_depths(&self) -> BTreeMap<String, (usize, usize)> {
+        let engines: Vec<(String, PairEngine)> = self
+            .order_books
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(pair_id, engine)| (pair_id.clone(), engine.clone()))
+            .collect();
+
+        let mut depths = BTreeMap::new();
+        for (pair_id, engine) in engines {
+            let book = engine.book.read().await;
+            depths.insert(pair_id, (book.iter_bids().len(), book.iter_asks().len()));
+        }
+        depths
+    }
+
+    fn engines(&self) -> Vec<PairEngine> {
+        self.order_books
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    #[tracing::instrument(name = "order", skip_all, fields(order_id = %order.id))]
+    pub async fn place_order(&self, mut order: Order) -> Result<MatchResult, String> {
+        tracing::info!(
             "Processing order in mempool: id={}, user_id={}, pair_id={}, amount={}, price={}, side={}",
             order.id,
             order.user_id,
@@ -29,119 +611,1681 @@ impl Mempool {
             if order.side { "buy" } else { "sell" }
         );
 
-        let mut state_db = STATE.write().await;
+        self.admit(&mut order).await?;
 
-        let user_id = order.user_id.clone();
-        let base_token = order.token_a.clone();
-        let quote_token = &order.token_b.clone();
-
-        // Check if user has sufficient balance
-        if order.side {
-            let user_balance = state_db.state.get_user_balance(&user_id, quote_token);
-            let frozen_balance = state_db.state.get_frozen(user_id.clone(), quote_token);
-            // Overflow checking
-            let order_cost = order
-                .amount
-                .checked_mul(order.price)
-                .ok_or("Arithmetic overflow: order amount * price too large")?;
-            let required_balance = order_cost
-                .checked_add(frozen_balance)
-                .ok_or("Arithmetic overflow: total required balance too large")?;
-
-            if user_balance < required_balance {
-                log::warn!(
-                    "Insufficient quote token balance for order {}: required={}, available={}",
-                    order.id,
-                    required_balance,
-                    user_balance
-                );
-                return Err("Insufficient quote token balance".to_string());
-            }
-            state_db
-                .state
-                .freeze(user_id, quote_token.to_owned(), required_balance);
+        // Buys grow the user's position in the base token, which their tier may cap
+        let position_cap = if order.side {
+            USER_TIERS.limits(&order.user_id).position_cap
         } else {
-            // Sell order: need base token balance
-            let user_balance = state_db.state.get_user_balance(&user_id, &base_token);
-            if user_balance < order.amount {
-                log::warn!(
-                    "Insufficient base token balance for order {}: required={}, available={}",
-                    order.id,
-                    order.amount,
-                    user_balance
-                );
-                return Err("Insufficient base token balance".to_string());
-            }
-            state_db
-                .state
-                .freeze(user_id, base_token.to_owned(), order.amount);
+            None
+        };
+        let open_buys = match position_cap {
+            Some(_) => self.open_buy_amount(&order.user_id, &order.token_a).await,
+            None => 0,
+        };
+
+        // Settling a block holds the state lock; turn the order away instead of stalling on it
+        let mut state_db = tokio::time::timeout(self.state_lock_timeout, STATE.write())
+            .await
+            .map_err(|_| "Exchange busy settling a block, retry later".to_string())?;
+        check_account(&state_db.state, &order)?;
+        if let Some(cap) = position_cap {
+            check_position_cap(&state_db.state, &order, cap, open_buys)?;
         }
+        freeze_order(&mut state_db.state, &order)?;
+        // Accounted for as frozen until the book has it, see `expected_frozen`
+        let unplaced_key = (order.pair_id.clone(), order.id.clone());
+        self.unplaced_orders
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(unplaced_key.clone(), order.clone());
+        drop(state_db);
 
-        // Get or create order book for this pair
-        let order_book = self
-            .order_books
-            .entry(order.pair_id.clone())
-            .or_insert_with(OrderBook::new);
+        // The balance is frozen, so matching can run without holding the state lock
+        let engine = self.engine_or_spawn(&order.pair_id);
 
-        log::info!(
+        tracing::info!(
             "Adding order {} to order book for pair {}",
             order.id,
             order.pair_id
         );
 
-        // Place order
-        order_book.add_order(order.clone()).await;
-        log::info!("Order {} processing completed successfully", order.id);
+        // Place order, releasing its freeze if the book turns it down (e.g. a duplicate id
+        // placed concurrently)
+        let result = engine.place_order(order.clone()).await;
+        if result.is_err() {
+            release_frozen(&order, order.amount, 0).await;
+        }
+        self.unplaced_orders
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&unplaced_key);
+        let result = result?;
+        tracing::info!(
+            "Order {} processing completed successfully: fills={}, status={:?}",
+            order.id,
+            result.fills.len(),
+            result.status
+        );
+        self.notify_order(&order.pair_id, &order.id);
+        for fill in &result.fills {
+            self.notify_order(&order.pair_id, &fill.maker_order_id);
+        }
 
-        Ok(order.id)
+        Ok(result)
     }
 
-    pub async fn cancel_order(&mut self, pair_id: &str, order_id: &str) -> Result<Order, String> {
-        if let Some(order_book) = self.order_books.get_mut(pair_id) {
-            if let Some(cancelled_order) = order_book.cancel_order(order_id) {
-                let user_id = cancelled_order.user_id.clone();
-                let base_token = cancelled_order.token_a.clone();
-                let quote_token = cancelled_order.token_b.clone();
+    /// Cancel open order `order_id` of `pair_id` and place `order` instead, all or nothing:
+    /// when the new order is turned away, e.g. for want of funds even counting those the old
+    /// one frees, the old order stays on the book as it was. The swap is made in one step of
+    /// the pair's matching task, holding the state lock. Returns the replaced order, now
+    /// cancelled, and the new one's match.
+    #[tracing::instrument(name = "order", skip_all, fields(order_id = %order.id))]
+    pub async fn replace_order(
+        &self,
+        pair_id: &str,
+        order_id: &str,
+        mut order: Order,
+    ) -> Result<(Order, MatchResult), String> {
+        tracing::info!(
+            "Replacing order {} of pair {} with order {}",
+            order_id,
+            pair_id,
+            order.id
+        );
+        if order.pair_id != pair_id {
+            return Err(format!("Replacement order must be on pair {}", pair_id));
+        }
+        let engine = self
+            .engine(pair_id)
+            .ok_or("Trading pair not found".to_string())?;
+        self.admit(&mut order).await?;
 
-                let mut state_db = STATE.write().await;
-                if cancelled_order.side {
-                    state_db.state.unfreeze(
-                        user_id,
-                        quote_token,
-                        cancelled_order.remaining_amount(),
-                    );
+        // The position cap applies as for a placement, minus what the replaced buy holds open
+        let position_cap = if order.side {
+            USER_TIERS.limits(&order.user_id).position_cap
+        } else {
+            None
+        };
+        let open_buys = match position_cap {
+            Some(_) => {
+                let replaced_buy = match self.get_order(pair_id, order_id).await {
+                    Some(replaced) if replaced.side && replaced.user_id == order.user_id => {
+                        replaced.remaining_amount()
+                    }
+                    _ => 0,
+                };
+                self.open_buy_amount(&order.user_id, &order.token_a)
+                    .await
+                    .saturating_sub(replaced_buy)
+            }
+            None => 0,
+        };
+
+        // Swap the old order's lock, for what's left of it once the task holds the book, for
+        // the new one's. Put it back when the new one doesn't fit
+        let new_order = order.clone();
+        let reserve: Reserve = Box::new(move |state: &mut State, replaced: &Order| {
+            check_account(state, &new_order)?;
+            if let Some(cap) = position_cap {
+                check_position_cap(state, &new_order, cap, open_buys)?;
+            }
+            unfreeze_order(state, replaced, replaced.remaining_amount(), 0);
+            freeze_order(state, &new_order).inspect_err(|_| {
+                let (token, amount) = order_lock(replaced, replaced.remaining_amount());
+                let frozen = state.get_frozen(replaced.user_id.clone(), &token);
+                state.set_frozen(replaced.user_id.clone(), token, frozen + amount);
+            })
+        });
+        let (replaced, result) = engine
+            .replace_order(order_id, order.clone(), reserve, self.state_lock_timeout)
+            .await?;
+        tracing::info!(
+            "Order {} replaced by order {}: fills={}, status={:?}",
+            order_id,
+            order.id,
+            result.fills.len(),
+            result.status
+        );
+        self.notify_order(pair_id, order_id);
+        self.notify_order(pair_id, &order.id);
+        for fill in &result.fills {
+            self.notify_order(pair_id, &fill.maker_order_id);
+        }
+
+        Ok((replaced, result))
+    }
+
+    // Checks an order has to pass before anything is frozen for it. Sets its price scale to
+    // the pair's
+    async fn admit(&self, order: &mut Order) -> Result<(), String> {
+        // Orders built with the lenient `Order::new` may carry a malformed pair
+        parse_pair(&order.pair_id).map_err(|e| e.to_string())?;
+
+        if self.is_halted(&order.pair_id) {
+            tracing::warn!(
+                "Rejecting order {}: pair {} is halted",
+                order.id,
+                order.pair_id
+            );
+            return Err(format!("Trading on pair {} is halted", order.pair_id));
+        }
+
+        // The order's price is in the pair's scale, which settlement reads off the order
+        let config = self.pair_config(&order.pair_id);
+        order.price_decimals = config.price_decimals;
+        let min_notional = config.min_notional;
+        let order_notional =
+            notional(order.amount, order.price, order.price_decimals).map_err(|e| e.to_string())?;
+        if order_notional < min_notional {
+            tracing::warn!(
+                "Rejecting order {}: notional {} below the minimum {}",
+                order.id,
+                order_notional,
+                min_notional
+            );
+            return Err(format!(
+                "Order notional {} is below the pair minimum of {}",
+                order_notional, min_notional
+            ));
+        }
+
+        // Backpressure: don't produce more traces while the block builder is behind
+        let backlog = trace_backlog_depth().await;
+        if backlog >= self.max_pending_traces {
+            tracing::warn!(
+                "Rejecting order {}: matched traces backlog full ({} >= {})",
+                order.id,
+                backlog,
+                self.max_pending_traces
+            );
+            return Err("Matched traces backlog full, retry later".to_string());
+        }
+
+        // Reject a reused id before anything is frozen for it
+        if let Some(engine) = self.engine(&order.pair_id)
+            && engine.book.read().await.get_order(&order.id).is_some()
+        {
+            tracing::warn!("Rejecting order {}: duplicate order id", order.id);
+            return Err(format!("Duplicate order id {}", order.id));
+        }
+        Ok(())
+    }
+
+    /// Place an order under the client's own id for it, by which it can then be looked up
+    /// with `client_order`. A user can't reuse a client order id, not even once the order it
+    /// named is closed.
+    pub async fn place_client_order(
+        &self,
+        order: Order,
+        client_order_id: &str,
+    ) -> Result<MatchResult, String> {
+        if client_order_id.is_empty() || client_order_id.len() > MAX_CLIENT_ORDER_ID_LEN {
+            return Err(format!(
+                "Client order id must be 1 to {} bytes",
+                MAX_CLIENT_ORDER_ID_LEN
+            ));
+        }
+        let key = (order.user_id.clone(), client_order_id.to_string());
+        // Claimed before placing, so two concurrent placements can't both take it
+        {
+            let mut client_orders = self
+                .client_orders
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            if client_orders.contains_key(&key) {
+                tracing::warn!(
+                    "Rejecting order {}: duplicate client order id {}",
+                    order.id,
+                    client_order_id
+                );
+                return Err(format!("Duplicate client order id {}", client_order_id));
+            }
+            client_orders.insert(key.clone(), (order.pair_id.clone(), order.id.clone()));
+        }
+
+        let result = self.place_order(order).await;
+        if result.is_err() {
+            // Nothing was placed under it, so the client may retry with the same id
+            self.client_orders
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&key);
+        }
+        result
+    }
+
+    /// Pair and order id of the order `user_id` placed under `client_order_id`
+    pub fn client_order(&self, user_id: &str, client_order_id: &str) -> Option<(String, String)> {
+        self.client_orders
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&(user_id.to_string(), client_order_id.to_string()))
+            .cloned()
+    }
+
+    #[tracing::instrument(name = "order", skip_all, fields(order_id = %order_id))]
+    pub async fn cancel_order(
+        &self,
+        pair_id: &str,
+        order_id: &str,
+        reduce_by: Option<u64>,
+    ) -> Result<Order, String> {
+        let engine = self
+            .engine(pair_id)
+            .ok_or("Trading pair not found".to_string())?;
+        let cancelled_order = engine.cancel_order(order_id, reduce_by).await?;
+
+        // Base amount taken off the book: the reduction, or everything left on a full cancel
+        let (released, still_open) = match cancelled_order.status {
+            OrderStatus::Cancelled => (cancelled_order.remaining_amount(), 0),
+            _ => (reduce_by.unwrap_or(0), cancelled_order.remaining_amount()),
+        };
+        release_frozen(&cancelled_order, released, still_open).await;
+        self.notify_order(pair_id, order_id);
+        Ok(cancelled_order)
+    }
+
+    /// Cancel a set of orders of one pair in a single pass over the book, returning the outcome
+    /// of each id in request order
+    pub async fn cancel_orders(
+        &self,
+        pair_id: &str,
+        order_ids: &[String],
+    ) -> Result<Vec<Result<Order, String>>, String> {
+        let engine = self
+            .engine(pair_id)
+            .ok_or("Trading pair not found".to_string())?;
+        let results = engine.cancel_orders(order_ids.to_vec()).await?;
+
+        let mut state_db = STATE.write().await;
+        for cancelled_order in results.iter().flatten() {
+            unfreeze_order(
+                &mut state_db.state,
+                cancelled_order,
+                cancelled_order.remaining_amount(),
+                0,
+            );
+            self.notify_order(pair_id, &cancelled_order.id);
+        }
+        Ok(results)
+    }
+
+    pub async fn get_order(&self, pair_id: &str, order_id: &str) -> Option<Order> {
+        let engine = self.engine(pair_id)?;
+        let book = engine.book.read().await;
+        book.get_order(order_id)
+    }
+
+    /// Wait until the order is filled, cancelled or settled, for at most `timeout`, and return
+    /// it as it is then; still open if the wait timed out. `None` if there is no such order.
+    pub async fn wait_for_order(
+        &self,
+        pair_id: &str,
+        order_id: &str,
+        timeout: Duration,
+    ) -> Option<Order> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let key = (pair_id.to_string(), order_id.to_string());
+        loop {
+            let watch = self
+                .order_watches
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(key.clone())
+                .or_default()
+                .clone();
+            // Registered before the status is read, so a change right after it isn't missed
+            let changed = watch.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            let order = self.get_order(pair_id, order_id).await?;
+            let closed = matches!(
+                order.status,
+                OrderStatus::Filled | OrderStatus::Cancelled | OrderStatus::Settled
+            );
+            if closed || tokio::time::timeout_at(deadline, changed).await.is_err() {
+                self.forget_watch(&key, &watch);
+                return if closed {
+                    Some(order)
                 } else {
-                    state_db.state.unfreeze(
-                        user_id,
-                        base_token,
-                        cancelled_order.remaining_amount(),
-                    );
+                    self.get_order(pair_id, order_id).await
+                };
+            }
+        }
+    }
+
+    // Drop the watch of an order unless another caller is still waiting on it
+    fn forget_watch(&self, key: &(String, String), watch: &Arc<Notify>) {
+        let mut order_watches = self
+            .order_watches
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Held by the map and by `watch`
+        if order_watches
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, watch))
+            && Arc::strong_count(watch) == 2
+        {
+            order_watches.remove(key);
+        }
+    }
+
+    // Wake whoever waits on the order, after its status may have changed. They check it again
+    fn notify_order(&self, pair_id: &str, order_id: &str) {
+        let watch = self
+            .order_watches
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&(pair_id.to_string(), order_id.to_string()));
+        if let Some(watch) = watch {
+            watch.notify_waiters();
+        }
+    }
+
+    /// Settle the orders whose final fill is one of `traces`, after their block is sealed
+    pub async fn settle_traces(&self, traces: &[MatchedTrace]) {
+        for trace in traces {
+            for order in [&trace.buy_order, &trace.sell_order] {
+                // Traces carry the order as it was before this fill
+                if order.filled_amount.saturating_add(trace.matched_amount) < order.amount {
+                    continue;
+                }
+                if let Some(engine) = self.engine(&order.pair_id) {
+                    engine.book.write().await.settle_order(&order.id);
+                    order_span(&order.id).in_scope(|| tracing::info!("Order settled"));
+                    self.notify_order(&order.pair_id, &order.id);
                 }
-                Ok(cancelled_order)
-            } else {
-                Err("Order not found".to_string())
             }
-        } else {
-            Err("Trading pair not found".to_string())
         }
     }
 
-    pub fn get_order(&self, pair_id: &str, order_id: &str) -> Option<&Order> {
-        self.order_books
-            .get(pair_id)
-            .and_then(|book| book.get_order(order_id))
+    pub fn get_order_book(&self, pair_id: &str) -> Option<Arc<RwLock<OrderBook>>> {
+        self.engine(pair_id).map(|engine| engine.book)
+    }
+
+    /// Frozen amount per token a user should have: what their open orders lock across all
+    /// books (`remaining * price` of the quote token for buys, `remaining` of the base token
+    /// for sells), what orders frozen for but not yet in their book lock, and what their fills
+    /// hold until settled. Read under the state lock, so nothing moves between the two.
+    pub async fn expected_frozen(&self, user_id: &str) -> BTreeMap<String, u64> {
+        let state_db = STATE.read().await;
+        self.expected_frozen_in(&state_db.state, user_id).await.0
+    }
+
+    // `expected_frozen` while the caller holds the state lock, with whether any of it is held
+    // by fills not settled yet. Each book, then the traces, are locked after the state, like
+    // everywhere, and kept until all are read so an order can't move between them unseen
+    async fn expected_frozen_in(
+        &self,
+        state: &State,
+        user_id: &str,
+    ) -> (BTreeMap<String, u64>, bool) {
+        let mut expected: BTreeMap<String, u64> = BTreeMap::new();
+        let mut add = |token: &str, amount: u64| {
+            let entry = expected.entry(token.to_string()).or_insert(0);
+            *entry = entry.saturating_add(amount);
+        };
+
+        let engines: Vec<(String, PairEngine)> = self
+            .order_books
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(pair_id, engine)| (pair_id.clone(), engine.clone()))
+            .collect();
+        let mut books = HashMap::new();
+        for (pair_id, engine) in &engines {
+            books.insert(pair_id.as_str(), engine.book.read().await);
+        }
+        for book in books.values() {
+            for order in book.order_map.values() {
+                if order.user_id == user_id
+                    && matches!(
+                        order.status,
+                        OrderStatus::Pending | OrderStatus::PartiallyFilled
+                    )
+                {
+                    let (token, amount) = order_lock(order, order.remaining_amount());
+                    add(&token, amount);
+                }
+            }
+        }
+        // Only place_order, under the state lock, adds to them; once its book has an order,
+        // the book accounts for it
+        for ((pair_id, order_id), order) in self
+            .unplaced_orders
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            let placed = books
+                .get(pair_id.as_str())
+                .is_some_and(|book| book.order_map.contains_key(order_id));
+            if order.user_id == user_id && !placed {
+                let (token, amount) = order_lock(order, order.amount);
+                add(&token, amount);
+            }
+        }
+
+        let mut settling = false;
+        let matched = MATCHED_TRACES.read().await;
+        let taken = SETTLING_TRACES.read().await;
+        for trace in matched.iter().chain(taken.iter()) {
+            if state.is_trace_released(trace) {
+                continue;
+            }
+            for (lock_user, token, amount) in trace.settlement_locks() {
+                if lock_user == user_id {
+                    add(token, amount);
+                    settling = true;
+                }
+            }
+        }
+        (expected, settling)
+    }
+
+    // Base amount still open on the user's buys of `token`, across all books
+    async fn open_buy_amount(&self, user_id: &str, token: &str) -> u64 {
+        let mut amount: u64 = 0;
+        for engine in self.engines() {
+            let order_book = engine.book.read().await;
+            for order in order_book.order_map.values() {
+                if order.user_id == user_id
+                    && order.side
+                    && order.token_a == token
+                    && matches!(
+                        order.status,
+                        OrderStatus::Pending | OrderStatus::PartiallyFilled
+                    )
+                {
+                    amount = amount.saturating_add(order.remaining_amount());
+                }
+            }
+        }
+        amount
+    }
+
+    /// Compare the user's frozen balances with what their orders and unsettled fills lock, see
+    /// `expected_frozen`, and report every mismatch. With `fix` set, the frozen balance is
+    /// overwritten with the expected amount; refused while any of the user's fills await
+    /// settlement, as the price improvement of one may still be on its way back.
+    pub async fn reconcile_frozen(
+        &self,
+        user_id: &str,
+        fix: bool,
+    ) -> Result<Vec<FrozenDiscrepancy>, String> {
+        if !fix {
+            let state_db = STATE.read().await;
+            let (expected, _) = self.expected_frozen_in(&state_db.state, user_id).await;
+            return Ok(frozen_discrepancies(&state_db.state, user_id, expected));
+        }
+
+        let mut state_db = STATE.write().await;
+        let (expected, settling) = self.expected_frozen_in(&state_db.state, user_id).await;
+        if settling {
+            return Err(format!(
+                "{} has fills awaiting settlement, retry after the next block",
+                user_id
+            ));
+        }
+        let discrepancies = frozen_discrepancies(&state_db.state, user_id, expected);
+        for discrepancy in &discrepancies {
+            state_db.state.set_frozen(
+                user_id.to_string(),
+                discrepancy.token.clone(),
+                discrepancy.expected,
+            );
+        }
+        Ok(discrepancies)
+    }
+}
+
+// Every token where the user's frozen balance isn't what `expected` says, tokens frozen but
+// not expected included
+fn frozen_discrepancies(
+    state: &State,
+    user_id: &str,
+    mut expected: BTreeMap<String, u64>,
+) -> Vec<FrozenDiscrepancy> {
+    if let Some(frozens) = state.user_frozens.get(user_id) {
+        for token in frozens.balances.keys() {
+            expected.entry(token.clone()).or_insert(0);
+        }
+    }
+
+    let mut discrepancies = Vec::new();
+    for (token, expected_amount) in expected {
+        let actual = state
+            .user_frozens
+            .get(user_id)
+            .map_or(0, |frozens| frozens.get_balance(&token));
+        if actual == expected_amount {
+            continue;
+        }
+        tracing::warn!(
+            "Frozen balance mismatch: user_id={}, token={}, expected={}, actual={}",
+            user_id,
+            token,
+            expected_amount,
+            actual
+        );
+        discrepancies.push(FrozenDiscrepancy {
+            token,
+            expected: expected_amount,
+            actual,
+        });
+    }
+    discrepancies
+}
+
+// Log trades a match made to the trade log, the book only keeps them in memory
+fn log_trades(pair_id: &str, trades: &[Trade], span: &tracing::Span) {
+    if let Err(e) = TRADE_LOG.append(pair_id, trades) {
+        span.in_scope(|| tracing::error!("Failed to log trades: {}", e));
+    }
+}
+
+// Token and amount an order locks while `remaining` of it is open: `remaining * price` of the
+// quote token for a buy, `remaining` of the base token for a sell
+fn order_lock(order: &Order, remaining: u64) -> (String, u64) {
+    if order.side {
+        (order.token_b.clone(), order.quote_locked(remaining))
+    } else {
+        (order.token_a.clone(), remaining)
+    }
+}
+
+// Reject an order of a user the exchange has no account for, i.e. who never deposited. A
+// sell of theirs would fail for want of balance anyway, but not a buy locking nothing
+fn check_account(state: &State, order: &Order) -> Result<(), String> {
+    if !state.has_account(&order.user_id) {
+        tracing::warn!(
+            "Rejecting order {}: unknown user {}",
+            order.id,
+            order.user_id
+        );
+        return Err(format!("Unknown user {}", order.user_id));
+    }
+    Ok(())
+}
+
+// Reject a buy that would take the user's position in the base token, counting what they
+// hold and `open_buys` still open on other buys, over their tier's `cap`
+fn check_position_cap(
+    state: &State,
+    order: &Order,
+    cap: u64,
+    open_buys: u64,
+) -> Result<(), String> {
+    let position = state.get_user_balance(&order.user_id, &order.token_a) as u128
+        + open_buys as u128
+        + order.amount as u128;
+    if position > cap as u128 {
+        tracing::warn!(
+            "Rejecting order {}: {} position {} above the tier cap {}",
+            order.id,
+            order.token_a,
+            position,
+            cap
+        );
+        return Err(format!(
+            "Order would bring the {} position to {}, above the tier cap of {}",
+            order.token_a, position, cap
+        ));
+    }
+    Ok(())
+}
+
+// Freeze what the whole order locks, failing if the user doesn't have it available
+fn freeze_order(state: &mut State, order: &Order) -> Result<(), String> {
+    if order.side {
+        // Locked at the limit price, rounded up so every fill is covered
+        let order_cost = locked_notional(order.amount, order.price, order.price_decimals)
+            .map_err(|e| e.to_string())?;
+        if let Err(e) = state.freeze(order.user_id.clone(), order.token_b.clone(), order_cost) {
+            tracing::warn!(
+                "Insufficient quote token balance for order {}: {}",
+                order.id,
+                e
+            );
+            return Err("Insufficient quote token balance".to_string());
+        }
+    } else {
+        // Sell order: need base token balance not backing other orders
+        if let Err(e) = state.freeze(order.user_id.clone(), order.token_a.clone(), order.amount) {
+            tracing::warn!(
+                "Insufficient base token balance for order {}: {}",
+                order.id,
+                e
+            );
+            return Err("Insufficient base token balance".to_string());
+        }
     }
+    Ok(())
+}
 
-    pub fn get_order_book(&self, pair_id: &str) -> Option<&OrderBook> {
-        self.order_books.get(pair_id)
+// Unfreeze what `amount` of the order's base size locked, with `still_open` of it left on the
+// book: the difference of the quote locks for a buy, so releases add up to the lock even as
+// it rounds, and `amount` of the base token for a sell
+async fn release_frozen(order: &Order, amount: u64, still_open: u64) {
+    let mut state_db = STATE.write().await;
+    unfreeze_order(&mut state_db.state, order, amount, still_open);
+}
+
+// Refund what buys that took cheaper asks locked beyond the price they pay, right as they're
+// matched rather than once their block settles. The rest of each fill's lock stays frozen
+// until then, as it covers the payment. Called by a matching task once it let go of its book;
+// no caller holds the state lock while waiting on a task, so taking it here can't deadlock
+async fn refund_price_improvement(improved_fills: &[MatchedTrace]) {
+    if improved_fills.is_empty() {
+        return;
+    }
+    let mut state_db = STATE.write().await;
+    for trace in improved_fills {
+        state_db.state.refund_price_improvement(trace);
     }
+}
 
-    pub fn get_trades(&self) -> Vec<Trade> {
-        vec![]
+fn unfreeze_order(state: &mut State, order: &Order, amount: u64, still_open: u64) {
+    if order.side {
+        state.unfreeze(
+            order.user_id.clone(),
+            order.token_b.clone(),
+            order.quote_locked(still_open.saturating_add(amount)) - order.quote_locked(still_open),
+        );
+    } else {
+        state.unfreeze(order.user_id.clone(), order.token_a.clone(), amount);
     }
 }
 
 // Global mempool instance
 lazy_static::lazy_static! {
-    pub static ref MEMPOOL: Arc<RwLock<Mempool>> = Arc::new(RwLock::new(Mempool::new()));
-}
\ No newline at end of file
+    pub static ref MEMPOOL: Arc<RwLock<Mempool>> = Arc::new(RwLock::new(
+        Mempool::new()
+            .with_halt_store(NODE_DB.open_tree(PAIR_HALTS_TREE).unwrap())
+            .unwrap()
+            .with_order_archive(&NODE_DB)
+    ));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exchange::MATCHED_TRACES;
+    use crate::exchange::trade_log::TradeFilter;
+
+    #[tokio::test]
+    async fn test_reconcile_detects_desynced_freeze() {
+        let user_id = "reconcile_user".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "RCB".to_string(), 10_000);
+        }
+
+        let mempool = Mempool::new();
+        let order = Order::new(
+            "reconcile_buy".to_string(),
+            user_id.clone(),
+            "RCA_RCB".to_string(),
+            10,
+            50,
+            true,
+        );
+        mempool.place_order(order).await.unwrap();
+        assert!(
+            mempool
+                .reconcile_frozen(&user_id, false)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // Simulate a freeze that drifted away from the open orders
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_frozen(user_id.clone(), "RCB".to_string(), 123);
+        }
+        let discrepancies = mempool.reconcile_frozen(&user_id, true).await.unwrap();
+        assert_eq!(discrepancies.len(), 1);
+        assert_eq!(discrepancies[0].token, "RCB");
+        assert_eq!(discrepancies[0].expected, 500);
+        assert_eq!(discrepancies[0].actual, 123);
+
+        // The fix restored the expected amount
+        assert!(
+            mempool
+                .reconcile_frozen(&user_id, false)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_partial_cancel_unfreezes_proportionally() {
+        let user_id = "partial_cancel_user".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "PMB".to_string(), 10_000);
+        }
+
+        let mempool = Mempool::new();
+        let order = Order::new(
+            "partial_cancel_buy".to_string(),
+            user_id.clone(),
+            "PMA_PMB".to_string(),
+            10,
+            20,
+            true,
+        );
+        mempool.place_order(order).await.unwrap();
+
+        let reduced = mempool
+            .cancel_order("PMA_PMB", "partial_cancel_buy", Some(4))
+            .await
+            .unwrap();
+        assert_eq!(reduced.remaining_amount(), 6);
+        {
+            let mut state_db = STATE.write().await;
+            assert_eq!(state_db.state.get_frozen(user_id.clone(), "PMB"), 120);
+        }
+
+        assert!(
+            mempool
+                .cancel_order("PMA_PMB", "partial_cancel_buy", Some(7))
+                .await
+                .is_err()
+        );
+
+        mempool
+            .cancel_order("PMA_PMB", "partial_cancel_buy", None)
+            .await
+            .unwrap();
+        let mut state_db = STATE.write().await;
+        assert_eq!(state_db.state.get_frozen(user_id, "PMB"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reject_order_of_unknown_user() {
+        let mempool = Mempool::new();
+        let order = |id: &str, user_id: &str, side| {
+            Order::new(
+                id.to_string(),
+                user_id.to_string(),
+                "UKA_UKB".to_string(),
+                10,
+                1,
+                side,
+            )
+        };
+
+        // Neither side is accepted for a user who never deposited, whatever it would lock
+        for (id, side) in [("unknown_buy", true), ("unknown_sell", false)] {
+            let err = mempool
+                .place_order(order(id, "never_deposited", side))
+                .await
+                .unwrap_err();
+            assert_eq!(err, "Unknown user never_deposited");
+        }
+        assert!(mempool.get_order("UKA_UKB", "unknown_buy").await.is_none());
+
+        // Once they deposit, they can trade
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("known_user".to_string(), "UKB".to_string(), 10);
+        }
+        assert!(
+            mempool
+                .place_order(order("known_buy", "known_user", true))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_partial_fill_refunds_price_improvement() {
+        let buyer = "improve_buyer".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(buyer.clone(), "IPB".to_string(), 10_000);
+            state_db
+                .state
+                .set_user_balance("improve_seller".to_string(), "IPA".to_string(), 100);
+        }
+        let order = |id: &str, user_id: &str, amount, price, side| {
+            Order::new(
+                id.to_string(),
+                user_id.to_string(),
+                "IPA_IPB".to_string(),
+                amount,
+                price,
+                side,
+            )
+        };
+
+        let mempool = Mempool::new();
+        for (id, price) in [("improve_ask_1", 100), ("improve_ask_2", 101)] {
+            mempool
+                .place_order(order(id, "improve_seller", 3, price, false))
+                .await
+                .unwrap();
+        }
+        // Takes both asks below its 105 limit and rests the other 4
+        let buy = order("improve_buy", &buyer, 10, 105, true);
+        let result = mempool.place_order(buy.clone()).await.unwrap();
+        assert_eq!(result.resting_remaining, 4);
+
+        // The remainder stays locked at the limit, the fills only for what they pay
+        let traces: Vec<MatchedTrace> = MATCHED_TRACES
+            .read()
+            .await
+            .iter()
+            .filter(|trace| trace.buy_order.id == "improve_buy")
+            .cloned()
+            .collect();
+        let paid: u64 = traces
+            .iter()
+            .map(|trace| trace.quote_amount().unwrap())
+            .sum();
+        assert_eq!(paid, 3 * 100 + 3 * 101);
+        assert_eq!(
+            STATE.write().await.state.get_frozen(buyer.clone(), "IPB"),
+            buy.quote_locked(4) + paid
+        );
+
+        // The unsettled fills account for their part of the freeze, and it isn't overwritten
+        // while they still hold it
+        assert!(
+            mempool
+                .reconcile_frozen(&buyer, false)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(mempool.reconcile_frozen(&buyer, true).await.is_err());
+
+        let mut state_db = STATE.write().await;
+
+        // Once the fills settle, only the resting remainder's lock is left
+        for trace in &traces {
+            assert!(state_db.state.release_trace(trace));
+        }
+        assert_eq!(state_db.state.get_frozen(buyer.clone(), "IPB"), 4 * 105);
+        drop(state_db);
+        assert!(
+            mempool
+                .reconcile_frozen(&buyer, false)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejected_replace_keeps_order_live() {
+        let user_id = "replace_user".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "RPB".to_string(), 100);
+            state_db
+                .state
+                .set_user_balance("replace_other".to_string(), "RPB".to_string(), 1_000);
+        }
+        let order = |id: &str, user_id: &str, price| {
+            Order::new(
+                id.to_string(),
+                user_id.to_string(),
+                "RPA_RPB".to_string(),
+                10,
+                price,
+                true,
+            )
+        };
+        let frozen = || async { STATE.write().await.state.get_frozen(user_id.clone(), "RPB") };
+
+        let mempool = Mempool::new();
+        mempool
+            .place_order(order("replace_old", &user_id, 5))
+            .await
+            .unwrap();
+        assert_eq!(frozen().await, 50);
+
+        // 200 is more than the 50 available plus the 50 the old order would free
+        let err = mempool
+            .replace_order("RPA_RPB", "replace_old", order("replace_new", &user_id, 20))
+            .await
+            .unwrap_err();
+        assert_eq!(err, "Insufficient quote token balance");
+        // Nor can another user replace it
+        let err = mempool
+            .replace_order(
+                "RPA_RPB",
+                "replace_old",
+                order("replace_other_new", "replace_other", 5),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err, "Order replace_old belongs to another user");
+
+        // The old order is still live with its lock, the new ones never made it
+        let old = mempool.get_order("RPA_RPB", "replace_old").await.unwrap();
+        assert_eq!(old.status, OrderStatus::Pending);
+        assert_eq!(frozen().await, 50);
+        assert!(mempool.get_order("RPA_RPB", "replace_new").await.is_none());
+        assert!(
+            mempool
+                .get_order("RPA_RPB", "replace_other_new")
+                .await
+                .is_none()
+        );
+        let depths = mempool.book_depths().await;
+        assert_eq!(depths.get("RPA_RPB"), Some(&(1, 0)));
+
+        // Funded by what the old order frees, the replacement goes through
+        let (replaced, result) = mempool
+            .replace_order("RPA_RPB", "replace_old", order("replace_new", &user_id, 10))
+            .await
+            .unwrap();
+        assert_eq!(replaced.status, OrderStatus::Cancelled);
+        assert_eq!(result.status, OrderStatus::Pending);
+        assert_eq!(frozen().await, 100);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_orders_batch() {
+        let user_id = "batch_cancel_user".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "BCA".to_string(), 100);
+        }
+
+        let mempool = Mempool::new();
+        for (i, amount) in [10, 20, 30].iter().enumerate() {
+            let sell = Order::new(
+                format!("batch_sell_{}", i),
+                user_id.clone(),
+                "BCA_BCB".to_string(),
+                *amount,
+                5 + i as u64,
+                false,
+            );
+            mempool.place_order(sell).await.unwrap();
+        }
+        {
+            let mut state_db = STATE.write().await;
+            assert_eq!(state_db.state.get_frozen(user_id.clone(), "BCA"), 60);
+        }
+
+        let order_ids = [
+            "batch_sell_0",
+            "batch_missing",
+            "batch_sell_2",
+            "batch_sell_0",
+        ]
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>();
+        let results = mempool.cancel_orders("BCA_BCB", &order_ids).await.unwrap();
+
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap().status, OrderStatus::Cancelled);
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap().id, "batch_sell_2");
+        // Already cancelled by the first entry
+        assert!(results[3].is_err());
+
+        {
+            let mut state_db = STATE.write().await;
+            assert_eq!(state_db.state.get_frozen(user_id.clone(), "BCA"), 20);
+        }
+        let book = mempool.get_order_book("BCA_BCB").unwrap();
+        let asks: Vec<String> = book
+            .read()
+            .await
+            .iter_asks()
+            .map(|order| order.id.clone())
+            .collect();
+        assert_eq!(asks, vec!["batch_sell_1".to_string()]);
+
+        assert!(mempool.cancel_orders("NO_PAIR", &order_ids).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reject_overflowing_notional() {
+        let user_id = "overflow_user".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "OFB".to_string(), u64::MAX);
+        }
+
+        let mempool = Mempool::new();
+        let order = Order::new(
+            "overflow_buy".to_string(),
+            user_id.clone(),
+            "OFA_OFB".to_string(),
+            1 << 32,
+            1 << 32,
+            true,
+        );
+        let result = mempool.place_order(order).await;
+        assert_eq!(
+            result.unwrap_err(),
+            "Arithmetic overflow: 4294967296 * 4294967296 exceeds the balance range"
+        );
+
+        let mut state_db = STATE.write().await;
+        assert_eq!(state_db.state.get_frozen(user_id, "OFB"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reject_same_token_pair() {
+        let user_id = "same_token_user".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "BTC".to_string(), 1_000);
+        }
+
+        let mempool = Mempool::new();
+        let order = Order::new(
+            "same_token_sell".to_string(),
+            user_id.clone(),
+            "BTC_BTC".to_string(),
+            1,
+            1,
+            false,
+        );
+        let result = mempool.place_order(order).await;
+        assert_eq!(
+            result.unwrap_err(),
+            "Pair BTC_BTC trades a token against itself"
+        );
+        assert!(mempool.get_order_book("BTC_BTC").is_none());
+
+        let mut state_db = STATE.write().await;
+        assert_eq!(state_db.state.get_frozen(user_id, "BTC"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reject_duplicate_order_id() {
+        let user_id = "duplicate_user".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "DPB".to_string(), 10_000);
+        }
+
+        let mempool = Mempool::new();
+        let order = Order::new(
+            "duplicate_buy".to_string(),
+            user_id.clone(),
+            "DPA_DPB".to_string(),
+            10,
+            20,
+            true,
+        );
+        mempool.place_order(order.clone()).await.unwrap();
+
+        let result = mempool.place_order(order).await;
+        assert_eq!(result.unwrap_err(), "Duplicate order id duplicate_buy");
+        {
+            let mut state_db = STATE.write().await;
+            assert_eq!(state_db.state.get_frozen(user_id.clone(), "DPB"), 200);
+        }
+
+        // The book itself turns the id down too
+        let book = mempool.get_order_book("DPA_DPB").unwrap();
+        let retry = Order::new(
+            "duplicate_buy".to_string(),
+            user_id,
+            "DPA_DPB".to_string(),
+            1,
+            20,
+            true,
+        );
+        assert!(book.write().await.add_order(retry).await.is_err());
+        assert_eq!(
+            book.read().await.get_order("duplicate_buy").unwrap().amount,
+            10
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reject_orders_when_backlog_full() {
+        let user_id = "backlog_user".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "BLA".to_string(), 1_000);
+        }
+
+        let mut mempool = Mempool::new();
+        // Pretend everything currently pending is already the maximum backlog
+        mempool.max_pending_traces = trace_backlog_depth().await;
+
+        let order = Order::new(
+            "backlog_sell".to_string(),
+            user_id.clone(),
+            "BLA_BLB".to_string(),
+            10,
+            1,
+            false,
+        );
+        let result = mempool.place_order(order.clone()).await;
+        assert_eq!(
+            result.unwrap_err(),
+            "Matched traces backlog full, retry later"
+        );
+        {
+            let mut state_db = STATE.write().await;
+            assert_eq!(state_db.state.get_frozen(user_id.clone(), "BLA"), 0);
+        }
+
+        // Once there is room again the order is accepted
+        mempool.max_pending_traces = usize::MAX;
+        assert!(mempool.place_order(order).await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_replace_alongside_improving_place() {
+        const ROUNDS: u64 = 200;
+        let pair_id = "DLA_DLB";
+        {
+            let mut state_db = STATE.write().await;
+            let state = &mut state_db.state;
+            state.set_user_balance("dl_seller".to_string(), "DLA".to_string(), ROUNDS);
+            state.set_user_balance("dl_buyer".to_string(), "DLB".to_string(), 105 * ROUNDS);
+            state.set_user_balance("dl_replacer".to_string(), "DLB".to_string(), 100);
+        }
+        let order = |id: String, user_id: &str, price, side| {
+            Order::new(id, user_id.to_string(), pair_id.to_string(), 1, price, side)
+        };
+        let mempool = Arc::new(Mempool::new());
+        mempool
+            .place_order(order("dl_quote_0".to_string(), "dl_replacer", 50, true))
+            .await
+            .unwrap();
+
+        // Each round a buy takes a cheaper ask, its refund needing the state lock, while the
+        // same pair's resting bid is replaced
+        for round in 0..ROUNDS {
+            mempool
+                .place_order(order(format!("dl_ask_{}", round), "dl_seller", 100, false))
+                .await
+                .unwrap();
+            let place = tokio::spawn({
+                let mempool = mempool.clone();
+                let buy = order(format!("dl_buy_{}", round), "dl_buyer", 105, true);
+                async move { mempool.place_order(buy).await }
+            });
+            let replace = tokio::spawn({
+                let mempool = mempool.clone();
+                let old_id = format!("dl_quote_{}", round);
+                let quote = order(format!("dl_quote_{}", round + 1), "dl_replacer", 50, true);
+                async move { mempool.replace_order(pair_id, &old_id, quote).await }
+            });
+            let (placed, replaced) = tokio::time::timeout(Duration::from_secs(5), async {
+                tokio::join!(place, replace)
+            })
+            .await
+            .expect("place and replace deadlocked");
+            assert_eq!(placed.unwrap().unwrap().fills.len(), 1);
+            replaced.unwrap().unwrap();
+        }
+
+        // What the fills pay, their price improvement refunded, and the one resting quote
+        let mut state_db = STATE.write().await;
+        assert_eq!(
+            state_db.state.get_frozen("dl_buyer".to_string(), "DLB"),
+            100 * ROUNDS
+        );
+        assert_eq!(
+            state_db.state.get_frozen("dl_replacer".to_string(), "DLB"),
+            50
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_pairs_match_concurrently() {
+        const PAIRS: usize = 4;
+        const BUYS: u64 = 25;
+
+        let mempool = Arc::new(Mempool::new());
+        for pair in 0..PAIRS {
+            let pair_id = format!("CC{}A_CC{}B", pair, pair);
+            {
+                let mut state_db = STATE.write().await;
+                state_db.state.set_user_balance(
+                    format!("concurrent_seller_{}", pair),
+                    format!("CC{}A", pair),
+                    BUYS,
+                );
+                state_db.state.set_user_balance(
+                    format!("concurrent_buyer_{}", pair),
+                    format!("CC{}B", pair),
+                    1_000_000,
+                );
+            }
+            let sell = Order::new(
+                format!("concurrent_sell_{}", pair),
+                format!("concurrent_seller_{}", pair),
+                pair_id,
+                BUYS,
+                10,
+                false,
+            );
+            mempool.place_order(sell).await.unwrap();
+        }
+
+        // Simulate slow matching on the first pair: its task can't take the book
+        let blocked_book = mempool.get_order_book("CC0A_CC0B").unwrap();
+        let blocked_guard = blocked_book.write().await;
+
+        let tasks = (0..PAIRS)
+            .map(|pair| {
+                let mempool = mempool.clone();
+                tokio::spawn(async move {
+                    let mut filled = 0;
+                    for i in 0..BUYS {
+                        let buy = Order::new(
+                            format!("concurrent_buy_{}_{}", pair, i),
+                            format!("concurrent_buyer_{}", pair),
+                            format!("CC{}A_CC{}B", pair, pair),
+                            1,
+                            10,
+                            true,
+                        );
+                        let result = mempool.place_order(buy).await.unwrap();
+                        filled += result.fills.iter().map(|fill| fill.quantity).sum::<u64>();
+                    }
+                    filled
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut tasks = tasks.into_iter();
+        let blocked_task = tasks.next().unwrap();
+
+        // Every other pair matches to completion while the first one is stuck
+        for task in tasks {
+            let filled = tokio::time::timeout(std::time::Duration::from_secs(10), task)
+                .await
+                .expect("pair blocked by another pair's matching")
+                .unwrap();
+            assert_eq!(filled, BUYS);
+        }
+        assert!(!blocked_task.is_finished());
+
+        drop(blocked_guard);
+        assert_eq!(blocked_task.await.unwrap(), BUYS);
+
+        for pair in 0..PAIRS {
+            let pair_id = format!("CC{}A_CC{}B", pair, pair);
+            let book = mempool.get_order_book(&pair_id).unwrap();
+            let book = book.read().await;
+            assert_eq!(book.get_best_ask(), None);
+            assert_eq!(book.trades.len(), BUYS as usize);
+            // Every trade made it to the trade log too
+            let filter = TradeFilter {
+                pair_id: Some(pair_id),
+                ..TradeFilter::default()
+            };
+            let logged = TRADE_LOG.query(&filter, None, 0, 100).unwrap();
+            assert_eq!(logged.len(), BUYS as usize);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_busy_state_lock_is_retryable() {
+        let mut mempool = Mempool::new();
+        mempool.state_lock_timeout = Duration::from_millis(20);
+        let order = Order::new(
+            "busy_buy".to_string(),
+            "busy_user".to_string(),
+            "BSA_BSB".to_string(),
+            1,
+            1,
+            true,
+        );
+
+        // As if a block were being settled
+        let state_db = STATE.write().await;
+        let err = mempool.place_order(order).await.unwrap_err();
+        drop(state_db);
+        assert_eq!(err, "Exchange busy settling a block, retry later");
+        assert!(mempool.get_order("BSA_BSB", "busy_buy").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_resting_orders() {
+        let pair_id = "BTS_BTQ";
+        let order = |id: &str, price: u64, side: bool| {
+            Order::new(
+                id.to_string(),
+                "bootstrap_user".to_string(),
+                pair_id.to_string(),
+                10,
+                price,
+                side,
+            )
+        };
+        let mempool = Mempool::new();
+        mempool
+            .bootstrap(
+                pair_id,
+                vec![
+                    order("boot_bid_1", 99, true),
+                    order("boot_bid_2", 100, true),
+                    order("boot_ask_1", 105, false),
+                    order("boot_ask_2", 102, false),
+                ],
+            )
+            .await
+            .unwrap();
+
+        let engine = mempool.engine(pair_id).unwrap();
+        {
+            let book = engine.book.read().await;
+            assert_eq!(book.get_best_bid(), Some(100));
+            assert_eq!(book.get_best_ask(), Some(102));
+            let bids: Vec<&str> = book.iter_bids().map(|order| order.id.as_str()).collect();
+            assert_eq!(bids, ["boot_bid_2", "boot_bid_1"]);
+        }
+
+        // A batch crossing the book is rejected as a whole
+        let err = mempool
+            .bootstrap(
+                pair_id,
+                vec![
+                    order("boot_bid_3", 101, true),
+                    order("boot_bid_4", 102, true),
+                ],
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err, "Bootstrapped orders cross: bid 102 >= ask 102");
+        let book = engine.book.read().await;
+        assert!(book.get_order("boot_bid_3").is_none());
+        assert_eq!(book.get_best_bid(), Some(100));
+    }
+
+    #[tokio::test]
+    async fn test_min_notional() {
+        let pair_id = "MNA_MNB";
+        let user_id = "min_notional_user".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "MNA".to_string(), 1_000);
+        }
+        let mempool = Mempool::new();
+        mempool
+            .set_pair_config(
+                pair_id,
+                PairConfig {
+                    min_notional: 500,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let sell = |id: &str, amount: u64, price: u64| {
+            Order::new(
+                id.to_string(),
+                user_id.clone(),
+                pair_id.to_string(),
+                amount,
+                price,
+                false,
+            )
+        };
+
+        // Just below the floor
+        let err = mempool
+            .place_order(sell("mn_below", 9, 55))
+            .await
+            .unwrap_err();
+        assert_eq!(err, "Order notional 495 is below the pair minimum of 500");
+        assert!(mempool.get_order(pair_id, "mn_below").await.is_none());
+
+        // At and just above the floor
+        mempool.place_order(sell("mn_at", 10, 50)).await.unwrap();
+        mempool.place_order(sell("mn_above", 1, 501)).await.unwrap();
+        assert!(mempool.get_order(pair_id, "mn_at").await.is_some());
+        assert!(mempool.get_order(pair_id, "mn_above").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_max_orders_caps_far_orders() {
+        let pair_id = "MOA_MOB";
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("mo_seller".to_string(), "MOA".to_string(), 1_000);
+            state_db
+                .state
+                .set_user_balance("mo_buyer".to_string(), "MOB".to_string(), 1_000);
+        }
+        let mempool = Mempool::new();
+        mempool
+            .set_pair_config(
+                pair_id,
+                PairConfig {
+                    max_orders: Some(3),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let order = |id: &str, user_id: &str, price: u64, side: bool| {
+            Order::new(
+                id.to_string(),
+                user_id.to_string(),
+                pair_id.to_string(),
+                10,
+                price,
+                side,
+            )
+        };
+        for (id, price) in [("mo_ask_1", 10), ("mo_ask_2", 11), ("mo_ask_3", 12)] {
+            mempool
+                .place_order(order(id, "mo_seller", price, false))
+                .await
+                .unwrap();
+        }
+
+        // Full: asks at or beyond the worst one are turned away, nothing stays frozen
+        for (id, price) in [("mo_far", 20), ("mo_at_worst", 12)] {
+            let err = mempool
+                .place_order(order(id, "mo_seller", price, false))
+                .await
+                .unwrap_err();
+            assert!(err.starts_with("Order book full at 3 orders"), "{}", err);
+            assert!(mempool.get_order(pair_id, id).await.is_none());
+        }
+        assert_eq!(
+            STATE
+                .read()
+                .await
+                .state
+                .get_available_balance("mo_seller", "MOA"),
+            970
+        );
+
+        // An ask ahead of the worst one still rests, and a buy crossing the book still fills
+        mempool
+            .place_order(order("mo_near", "mo_seller", 11, false))
+            .await
+            .unwrap();
+        let result = mempool
+            .place_order(order("mo_buy", "mo_buyer", 10, true))
+            .await
+            .unwrap();
+        assert_eq!(result.fills.len(), 1);
+        assert_eq!(result.fills[0].quantity, 10);
+    }
+
+    #[tokio::test]
+    async fn test_price_scale_with_differing_decimals() {
+        // 8 decimal base token quoted in a 6 decimal token, prices with 4 decimals
+        let pair_id = "PSA_PSB";
+        let config = PairConfig {
+            price_decimals: 4,
+            base_decimals: 8,
+            quote_decimals: 6,
+            ..Default::default()
+        };
+        {
+            let mut state_db = STATE.write().await;
+            state_db.state.set_user_balance(
+                "ps_seller".to_string(),
+                "PSA".to_string(),
+                100_000_000,
+            );
+            state_db
+                .state
+                .set_user_balance("ps_buyer".to_string(), "PSB".to_string(), 100_000_000);
+        }
+        let mempool = Mempool::new();
+        mempool
+            .set_pair_config(pair_id, config.clone())
+            .await
+            .unwrap();
+        let order = |id: &str, user_id: &str, price: &str, side| Order {
+            price_decimals: config.price_decimals,
+            ..Order::new(
+                id.to_string(),
+                user_id.to_string(),
+                pair_id.to_string(),
+                50_000_000, // 0.5 PSA
+                config.price_from_decimal(price).unwrap(),
+                side,
+            )
+        };
+
+        // 123.45 PSB per PSA is 1.2345 PSB base units per PSA base unit
+        assert_eq!(config.price_from_decimal("123.45"), Ok(12_345));
+        assert!(config.price_from_decimal("123.456").is_err());
+        mempool
+            .place_order(order("ps_sell", "ps_seller", "123.45", false))
+            .await
+            .unwrap();
+        // The buy locks 0.5 * 124 = 62 PSB at its limit and trades at the maker's price, which
+        // refunds the 0.275 PSB it won't pay right away
+        let result = mempool
+            .place_order(order("ps_buy", "ps_buyer", "124", true))
+            .await
+            .unwrap();
+        assert_eq!(result.fills.len(), 1);
+        assert_eq!(result.fills[0].price, 12_345);
+        {
+            let mut state_db = STATE.write().await;
+            assert_eq!(
+                state_db.state.get_frozen("ps_buyer".to_string(), "PSB"),
+                61_725_000
+            );
+        }
+        let buy = mempool.get_order(pair_id, "ps_buy").await.unwrap();
+        assert_eq!(buy.price_decimals, 4);
+
+        // 0.5 PSA at 123.45 settles 61.725 PSB
+        let trace = MatchedTrace {
+            buy_order: order("ps_buy", "ps_buyer", "124", true),
+            sell_order: order("ps_sell", "ps_seller", "123.45", false),
+            matched_amount: 50_000_000,
+            matched_price: 12_345,
+        };
+        assert_eq!(trace.quote_amount(), Ok(61_725_000));
+
+        // The scale can't change under the order left open
+        mempool
+            .place_order(order("ps_rest", "ps_seller", "130", false))
+            .await
+            .unwrap();
+        let err = mempool
+            .set_pair_config(
+                pair_id,
+                PairConfig {
+                    price_decimals: 2,
+                    ..config.clone()
+                },
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "Pair PSA_PSB has open orders, its price scale can't change"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_halted_pair_rejects_orders() {
+        let pair_id = "HLA_HLB";
+        let user_id = "halt_user".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "HLA".to_string(), 100);
+        }
+        let sell = |id: &str| {
+            Order::new(
+                id.to_string(),
+                user_id.clone(),
+                pair_id.to_string(),
+                10,
+                5,
+                false,
+            )
+        };
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = db.open_tree(PAIR_HALTS_TREE).unwrap();
+        let mempool = Mempool::new().with_halt_store(store.clone()).unwrap();
+        mempool.place_order(sell("halt_sell_1")).await.unwrap();
+
+        mempool.halt_pair(pair_id).unwrap();
+        let err = mempool.place_order(sell("halt_sell_2")).await.unwrap_err();
+        assert_eq!(err, "Trading on pair HLA_HLB is halted");
+        assert!(mempool.get_order(pair_id, "halt_sell_2").await.is_none());
+        // Other pairs keep trading
+        assert!(!mempool.is_halted("HLB_HLA"));
+
+        // Resting orders can still be taken off the book
+        let cancelled = mempool
+            .cancel_order(pair_id, "halt_sell_1", None)
+            .await
+            .unwrap();
+        assert_eq!(cancelled.status, OrderStatus::Cancelled);
+
+        // The halt outlives the mempool
+        let mempool = Mempool::new().with_halt_store(store).unwrap();
+        assert!(mempool.is_halted(pair_id));
+        assert!(mempool.place_order(sell("halt_sell_2")).await.is_err());
+
+        mempool.resume_pair(pair_id).unwrap();
+        mempool.place_order(sell("halt_sell_2")).await.unwrap();
+        assert!(mempool.get_order(pair_id, "halt_sell_2").await.is_some());
+    }
+}
@@ -1,17 +1,222 @@
+pub mod book_history;
+pub mod funds_log;
 pub mod matching;
 pub mod mempool;
+pub mod stats;
+pub mod tiers;
+pub mod trade_log;
 
 use std::sync::Arc;
+use std::time::Duration;
 
-use common::{state::StateDB, traces::MatchedTrace};
+use common::{
+    hasher::HashScheme,
+    state::StateDB,
+    traces::{EngineEvent, Funding, FundingKind, MatchedTrace, Transfer},
+};
 use tokio::sync::RwLock;
 
+use crate::exchange::funds_log::{FundsLog, FundsMove};
+use crate::exchange::stats::Stats;
+use crate::exchange::tiers::UserTiers;
+use crate::exchange::trade_log::TradeLog;
+
+// Default cap on matched traces waiting for the block builder before new orders are rejected.
+// Checked on admission rather than bounding a channel: the builder drains MATCHED_TRACES and
+// ENGINE_EVENTS together under one lock, which a channel's receiver couldn't do
+pub const MAX_PENDING_TRACES: usize = 100_000;
+// Default wait for the state lock before an order is turned away, e.g. while a block is settled
+pub const STATE_LOCK_TIMEOUT: Duration = Duration::from_millis(500);
+
 // Global traces instance
 lazy_static::lazy_static! {
     pub static ref MATCHED_TRACES: Arc<RwLock<Vec<MatchedTrace>>> = Arc::new(RwLock::new(vec![]));
 }
 
-// Global State instance
+// Traces the block builder took from MATCHED_TRACES for its next block and hasn't settled
+// yet; their fills still hold the frozen funds `MatchedTrace::settlement_locks` names. Taken
+// under MATCHED_TRACES' write lock, so a trace is always in one of them until it's settled
+lazy_static::lazy_static! {
+    pub static ref SETTLING_TRACES: Arc<RwLock<Vec<MatchedTrace>>> = Arc::new(RwLock::new(vec![]));
+}
+
+// The matching engines' events, in the order each book applied them, waiting to be sealed
+// into the next block. Only pushed and drained while MATCHED_TRACES' write lock is held, so a
+// block's events and its traces cover the same fills
+lazy_static::lazy_static! {
+    pub static ref ENGINE_EVENTS: Arc<RwLock<Vec<EngineEvent>>> = Arc::new(RwLock::new(vec![]));
+}
+
+// Transfers already applied to STATE, waiting to be sealed into the next block. Only pushed
+// and drained while STATE's write lock is held, so a block's root always covers its transfers
+lazy_static::lazy_static! {
+    pub static ref PENDING_TRANSFERS: Arc<RwLock<Vec<Transfer>>> = Arc::new(RwLock::new(vec![]));
+}
+
+// Deposits and withdrawals already applied to STATE, waiting to be sealed into the next block.
+// Like transfers, only pushed and drained while STATE's write lock is held
+lazy_static::lazy_static! {
+    pub static ref PENDING_FUNDING: Arc<RwLock<Vec<Funding>>> = Arc::new(RwLock::new(vec![]));
+}
+
+/// Apply a deposit or withdrawal to the state and queue it for the next block, both under
+/// STATE's write lock so the first root reflecting it is that of the block sealing it. It's
+/// logged to FUNDS_LOG first, so once acknowledged it survives a restart. A withdrawal can
+/// only take funds not backing open orders.
+pub async fn apply_funding(funding: Funding) -> Result<(), String> {
+    funding.validate()?;
+    let deposit_cap = USER_TIERS.limits(&funding.user_id).deposit_cap;
+    let mut state_db = STATE.write().await;
+    match funding.kind {
+        FundingKind::Deposit => {
+            if let Some(cap) = deposit_cap {
+                let balance = state_db
+                    .state
+                    .get_user_balance(&funding.user_id, &funding.token)
+                    as u128
+                    + funding.amount as u128;
+                if balance > cap as u128 {
+                    tracing::warn!(
+                        "Rejected deposit: user_id={}, token={}, amount={}, tier cap={}",
+                        funding.user_id,
+                        funding.token,
+                        funding.amount,
+                        cap
+                    );
+                    return Err(format!(
+                        "Deposit would bring the {} balance to {}, above the tier cap of {}",
+                        funding.token, balance, cap
+                    ));
+                }
+            }
+            log_funds(FundsMove::Funding(funding.clone()))?;
+            state_db.state.add_user_balance(
+                funding.user_id.clone(),
+                funding.token.clone(),
+                funding.amount,
+            );
+        }
+        FundingKind::Withdrawal => {
+            let available = state_db
+                .state
+                .get_available_balance(&funding.user_id, &funding.token);
+            if available < funding.amount {
+                tracing::warn!(
+                    "Rejected withdraw: user_id={}, token={}, amount={}, available={}",
+                    funding.user_id,
+                    funding.token,
+                    funding.amount,
+                    available
+                );
+                return Err("Insufficient available balance".to_string());
+            }
+            log_funds(FundsMove::Funding(funding.clone()))?;
+            state_db.state.sub_user_balance(
+                funding.user_id.clone(),
+                funding.token.clone(),
+                funding.amount,
+            );
+        }
+    }
+    PENDING_FUNDING.write().await.push(funding);
+    Ok(())
+}
+
+/// Move funds between two users and queue the transfer for the next block, both under
+/// STATE's write lock, after logging it to FUNDS_LOG. Like withdrawals, only funds not backing
+/// open orders can be moved.
+pub async fn apply_transfer(transfer: Transfer) -> Result<(), String> {
+    transfer.validate()?;
+    let mut state_db = STATE.write().await;
+    let available = state_db
+        .state
+        .get_available_balance(&transfer.from, &transfer.token);
+    if available < transfer.amount {
+        tracing::warn!(
+            "Rejected transfer: from={}, token={}, amount={}, available={}",
+            transfer.from,
+            transfer.token,
+            transfer.amount,
+            available
+        );
+        return Err("Insufficient available balance".to_string());
+    }
+
+    log_funds(FundsMove::Transfer(transfer.clone()))?;
+    state_db.state.sub_user_balance(
+        transfer.from.clone(),
+        transfer.token.clone(),
+        transfer.amount,
+    );
+    state_db
+        .state
+        .add_user_balance(transfer.to.clone(), transfer.token.clone(), transfer.amount);
+    PENDING_TRANSFERS.write().await.push(transfer);
+    Ok(())
+}
+
+// Nothing is applied if the move can't be logged
+fn log_funds(funds: FundsMove) -> Result<(), String> {
+    FUNDS_LOG.append(&funds).map_err(|e| {
+        tracing::error!("Failed to log {:?}: {}", funds, e);
+        "Failed to record the funds move, retry later".to_string()
+    })
+}
+
+/// Span tying log lines to one order's journey (place, match, block, settle): all of them
+/// carry `order{order_id=..}`, so one order's logs can be filtered out.
+pub fn order_span(order_id: &str) -> tracing::Span {
+    tracing::info_span!("order", order_id = %order_id)
+}
+
+// Number of matched traces not yet picked up by the block builder
+pub async fn trace_backlog_depth() -> usize {
+    MATCHED_TRACES.read().await.len()
+}
+
+// The node's sled database. The exchange state, blocks and trades as well as the EVM each
+// open their own trees of it. Fails with a clear message if another node already has it open
+lazy_static::lazy_static! {
+    pub static ref NODE_DB: sled::Db = open_node_db();
+}
+
+#[cfg(not(test))]
+fn open_node_db() -> sled::Db {
+    common::db::open_db(common::db::NODE_DB_PATH).unwrap_or_else(|e| panic!("{}", e))
+}
+
+// Tests share a throwaway database instead of writing one into the working directory
+#[cfg(test)]
+fn open_node_db() -> sled::Db {
+    sled::Config::new().temporary(true).open().unwrap()
+}
+
+// Global State instance. Tokio's RwLock isn't poisoned when a holder panics, so a failure
+// while settling a block can't wedge the exchange.
+lazy_static::lazy_static! {
+    pub static ref STATE: Arc<RwLock<StateDB>> = Arc::new(RwLock::new(StateDB::with_db(
+        &NODE_DB,
+        HashScheme::from_env()
+    )));
+}
+
+// Global trade history, kept on disk so it survives restarts
+lazy_static::lazy_static! {
+    pub static ref TRADE_LOG: TradeLog = TradeLog::open(&NODE_DB).unwrap();
+}
+
+// Global log of the transfers, deposits and withdrawals not sealed yet, so they survive a
+// restart
+lazy_static::lazy_static! {
+    pub static ref FUNDS_LOG: FundsLog = FundsLog::open(&NODE_DB).unwrap();
+}
+
+// Global compliance tiers, kept on disk
+lazy_static::lazy_static! {
+    pub static ref USER_TIERS: UserTiers = UserTiers::open(&NODE_DB).unwrap();
+}
+
+// Global matching and block production counters, served by /stats
 lazy_static::lazy_static! {
-    pub static ref STATE: Arc<RwLock<StateDB>> = Arc::new(RwLock::new(StateDB::new("state_db")));
+    pub static ref STATS: Stats = Stats::new();
 }
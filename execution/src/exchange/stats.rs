@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// Matching latencies kept for the percentiles, older samples are dropped
+pub const LATENCY_SAMPLES: usize = 10_000;
+// Window blocks are counted over for the block rate
+pub const BLOCK_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+// Matching latency over the most recent samples, in microseconds
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LatencySummary {
+    pub samples: usize,
+    pub avg_micros: u64,
+    pub p50_micros: u64,
+    pub p90_micros: u64,
+    pub p99_micros: u64,
+    pub max_micros: u64,
+}
+
+/// Operational counters of the exchange: how long matching an order takes and how often
+/// blocks are sealed.
+#[derive(Default)]
+pub struct Stats {
+    latencies: Mutex<VecDeque<Duration>>,
+    block_seals: Mutex<VecDeque<Instant>>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record how long the book took to rest or fill one order.
+    pub fn record_match(&self, elapsed: Duration) {
+        // Only plain pushes and pops happen under the locks, so a poisoned one is still usable
+        let mut latencies = self
+            .latencies
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if latencies.len() == LATENCY_SAMPLES {
+            latencies.pop_front();
+        }
+        latencies.push_back(elapsed);
+    }
+
+    pub fn record_block(&self) {
+        let now = Instant::now();
+        let mut block_seals = self
+            .block_seals
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        block_seals.push_back(now);
+        while block_seals
+            .front()
+            .is_some_and(|sealed| now.duration_since(*sealed) > BLOCK_RATE_WINDOW)
+        {
+            block_seals.pop_front();
+        }
+    }
+
+    pub fn matching_latency(&self) -> LatencySummary {
+        let mut micros: Vec<u64> = self
+            .latencies
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|latency| latency.as_micros() as u64)
+            .collect();
+        if micros.is_empty() {
+            return LatencySummary::default();
+        }
+        micros.sort_unstable();
+
+        // Nearest-rank percentile
+        let percentile = |p: usize| micros[(micros.len() * p).div_ceil(100).max(1) - 1];
+        LatencySummary {
+            samples: micros.len(),
+            avg_micros: micros.iter().sum::<u64>() / micros.len() as u64,
+            p50_micros: percentile(50),
+            p90_micros: percentile(90),
+            p99_micros: percentile(99),
+            max_micros: micros[micros.len() - 1],
+        }
+    }
+
+    /// Blocks sealed during the last minute.
+    pub fn blocks_per_minute(&self) -> usize {
+        let now = Instant::now();
+        self.block_seals
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|sealed| now.duration_since(**sealed) <= BLOCK_RATE_WINDOW)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_latency_percentiles() {
+        let stats = Stats::new();
+        assert_eq!(stats.matching_latency(), LatencySummary::default());
+
+        for micros in (1..=100).rev() {
+            stats.record_match(Duration::from_micros(micros));
+        }
+        stats.record_block();
+        stats.record_block();
+
+        let latency = stats.matching_latency();
+        assert_eq!(latency.samples, 100);
+        assert_eq!(latency.avg_micros, 50);
+        assert_eq!(
+            (latency.p50_micros, latency.p90_micros, latency.p99_micros),
+            (50, 90, 99)
+        );
+        assert_eq!(latency.max_micros, 100);
+        assert_eq!(stats.blocks_per_minute(), 2);
+    }
+}
@@ -0,0 +1,203 @@
+use common::db::TIERS_TREE;
+
+// Keys of the tiers tree: tier definitions by name, and each user's assigned tier
+static TIER_PREFIX: &str = "tier_";
+static USER_PREFIX: &str = "user_";
+
+// Limits of a KYC tier, in units of the token concerned. A limit left unset doesn't apply
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TierLimits {
+    // Highest balance of a token a deposit may bring the user to
+    #[serde(default)]
+    pub deposit_cap: Option<u64>,
+    // Highest balance of a token the user may reach through buy orders, counting the amount
+    // still open on their buys of it
+    #[serde(default)]
+    pub position_cap: Option<u64>,
+}
+
+/// Compliance tiers and which users are in them, kept on disk. Users without a tier have no
+/// limits.
+pub struct UserTiers {
+    tree: sled::Tree,
+}
+
+impl UserTiers {
+    pub fn open(db: &sled::Db) -> anyhow::Result<Self> {
+        Ok(Self {
+            tree: db.open_tree(TIERS_TREE)?,
+        })
+    }
+
+    /// Define or redefine tier `name`. Users already in it get the new limits.
+    pub fn set_tier(&self, name: &str, limits: &TierLimits) -> anyhow::Result<()> {
+        let key = format!("{}{}", TIER_PREFIX, name);
+        self.tree.insert(key, serde_json::to_vec(limits)?)?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    pub fn get_tier(&self, name: &str) -> anyhow::Result<Option<TierLimits>> {
+        let key = format!("{}{}", TIER_PREFIX, name);
+        match self.tree.get(key)? {
+            Some(limits) => Ok(Some(serde_json::from_slice(&limits)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Put `user_id` in tier `tier`, which has to be defined already.
+    pub fn assign(&self, user_id: &str, tier: &str) -> anyhow::Result<()> {
+        if self.get_tier(tier)?.is_none() {
+            anyhow::bail!("Unknown tier {}", tier);
+        }
+        self.tree
+            .insert(format!("{}{}", USER_PREFIX, user_id), tier.as_bytes())?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    pub fn tier_of(&self, user_id: &str) -> anyhow::Result<Option<String>> {
+        let tier = self.tree.get(format!("{}{}", USER_PREFIX, user_id))?;
+        Ok(tier.map(|tier| String::from_utf8_lossy(&tier).into_owned()))
+    }
+
+    /// Limits applying to `user_id`. A storage error fails closed, refusing everything the
+    /// tier could limit, rather than letting a capped user through.
+    pub fn limits(&self, user_id: &str) -> TierLimits {
+        let limits = self.tier_of(user_id).and_then(|tier| match tier {
+            Some(tier) => Ok(self.get_tier(&tier)?.unwrap_or_default()),
+            None => Ok(TierLimits::default()),
+        });
+        limits.unwrap_or_else(|e| {
+            tracing::error!("Failed to read the tier of user {}: {}", user_id, e);
+            TierLimits {
+                deposit_cap: Some(0),
+                position_cap: Some(0),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exchange::mempool::Mempool;
+    use crate::exchange::{STATE, USER_TIERS, apply_funding};
+    use common::order::Order;
+    use common::traces::{Funding, FundingKind};
+
+    #[tokio::test]
+    async fn test_deposit_cap() {
+        let user_id = "tier_depositor";
+        USER_TIERS
+            .set_tier(
+                "tier_test_deposit",
+                &TierLimits {
+                    deposit_cap: Some(1_000),
+                    position_cap: None,
+                },
+            )
+            .unwrap();
+        USER_TIERS.assign(user_id, "tier_test_deposit").unwrap();
+        STATE
+            .write()
+            .await
+            .state
+            .set_user_balance(user_id.to_string(), "TDA".to_string(), 0);
+        let deposit = |amount| Funding {
+            kind: FundingKind::Deposit,
+            user_id: user_id.to_string(),
+            token: "TDA".to_string(),
+            amount,
+        };
+
+        apply_funding(deposit(600)).await.unwrap();
+        // Up to the cap is fine, one more unit isn't
+        let err = apply_funding(deposit(401)).await.unwrap_err();
+        assert_eq!(
+            err,
+            "Deposit would bring the TDA balance to 1001, above the tier cap of 1000"
+        );
+        apply_funding(deposit(400)).await.unwrap();
+        let state = &STATE.read().await.state;
+        assert_eq!(state.get_user_balance(user_id, "TDA"), 1_000);
+    }
+
+    #[tokio::test]
+    async fn test_position_cap() {
+        let user_id = "tier_trader";
+        USER_TIERS
+            .set_tier(
+                "tier_test_position",
+                &TierLimits {
+                    deposit_cap: None,
+                    position_cap: Some(100),
+                },
+            )
+            .unwrap();
+        USER_TIERS.assign(user_id, "tier_test_position").unwrap();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.to_string(), "TPA".to_string(), 40);
+            state_db
+                .state
+                .set_user_balance(user_id.to_string(), "TPB".to_string(), 10_000);
+        }
+        let order = |id: &str, amount, price, side| {
+            Order::new(
+                id.to_string(),
+                user_id.to_string(),
+                "TPA_TPB".to_string(),
+                amount,
+                price,
+                side,
+            )
+        };
+
+        // 40 held and 50 on an open buy leave room for 10 more
+        let mempool = Mempool::new();
+        mempool
+            .place_order(order("tp_buy_1", 50, 1, true))
+            .await
+            .unwrap();
+        let err = mempool
+            .place_order(order("tp_buy_2", 11, 1, true))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "Order would bring the TPA position to 101, above the tier cap of 100"
+        );
+        mempool
+            .place_order(order("tp_buy_3", 10, 1, true))
+            .await
+            .unwrap();
+
+        // Selling only reduces the position; priced above the buys so it rests
+        mempool
+            .place_order(order("tp_sell_1", 40, 2, false))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_tiers_persist() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tiers = UserTiers::open(&db).unwrap();
+        let limits = TierLimits {
+            deposit_cap: Some(5),
+            position_cap: Some(7),
+        };
+        tiers.set_tier("basic", &limits).unwrap();
+        assert!(tiers.assign("alice", "unknown").is_err());
+        tiers.assign("alice", "basic").unwrap();
+        drop(tiers);
+
+        let tiers = UserTiers::open(&db).unwrap();
+        assert_eq!(tiers.tier_of("alice").unwrap().as_deref(), Some("basic"));
+        assert_eq!(tiers.limits("alice"), limits);
+        assert_eq!(tiers.limits("bob"), TierLimits::default());
+    }
+}
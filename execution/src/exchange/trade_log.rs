@@ -0,0 +1,313 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sled::Transactional;
+use sled::transaction::ConflictableTransactionResult;
+
+use crate::exchange::matching::Trade;
+use common::db::{TRADE_TIMES_TREE, TRADES_TREE};
+
+// Largest page a trade history query returns
+pub const MAX_TRADES_PAGE: usize = 1_000;
+pub const DEFAULT_TRADES_PAGE: usize = 100;
+// Most matches a trade history query skips, deeper pages are reached with `before_id`
+pub const MAX_TRADES_OFFSET: usize = 10_000;
+
+// A trade as stored in the log: ids increase in the order trades were logged
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TradeRecord {
+    pub id: u64,
+    pub pair_id: String,
+    #[serde(flatten)]
+    pub trade: Trade,
+}
+
+// Which trades a history query returns; time bounds are inclusive unix millis
+#[derive(Clone, Debug, Default)]
+pub struct TradeFilter {
+    pub pair_id: Option<String>,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+}
+
+impl TradeFilter {
+    fn matches(&self, record: &TradeRecord) -> bool {
+        self.pair_id
+            .as_ref()
+            .is_none_or(|pair_id| *pair_id == record.pair_id)
+            && self
+                .start_time
+                .is_none_or(|start| record.trade.timestamp >= start)
+            && self
+                .end_time
+                .is_none_or(|end| record.trade.timestamp <= end)
+    }
+}
+
+/// Persistent log of every trade, keyed by big-endian id. Pairs log their trades
+/// independently, so ids can be slightly out of match time order across pairs; a second tree
+/// keyed by match time then id is what queries scan.
+pub struct TradeLog {
+    tree: sled::Tree,
+    // (timestamp, id) -> nothing, big-endian, for every trade in `tree`
+    times: sled::Tree,
+    next_id: AtomicU64,
+}
+
+// Key of a trade in the time index, ordered by match time then id
+fn time_key(timestamp: u64, id: u64) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key[..8].copy_from_slice(&timestamp.to_be_bytes());
+    key[8..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+impl TradeLog {
+    pub fn open(db: &sled::Db) -> anyhow::Result<Self> {
+        let tree = db.open_tree(TRADES_TREE)?;
+        let times = db.open_tree(TRADE_TIMES_TREE)?;
+        // A log from before the time index, indexed once
+        if times.is_empty() {
+            for entry in tree.iter() {
+                let (_, value) = entry?;
+                let record: TradeRecord = serde_json::from_slice(&value)?;
+                times.insert(time_key(record.trade.timestamp, record.id), &[])?;
+            }
+        }
+        // Continue after the last logged trade
+        let next_id = match tree.last()? {
+            Some((key, _)) => u64::from_be_bytes(key.as_ref().try_into()?) + 1,
+            None => 1,
+        };
+        Ok(Self {
+            tree,
+            times,
+            next_id: AtomicU64::new(next_id),
+        })
+    }
+
+    /// Append the trades of one match of `pair_id`, in the order they were made.
+    pub fn append(&self, pair_id: &str, trades: &[Trade]) -> anyhow::Result<()> {
+        if trades.is_empty() {
+            return Ok(());
+        }
+        let first_id = self
+            .next_id
+            .fetch_add(trades.len() as u64, Ordering::SeqCst);
+
+        let mut records = Vec::with_capacity(trades.len());
+        for (id, trade) in (first_id..).zip(trades) {
+            let record = TradeRecord {
+                id,
+                pair_id: pair_id.to_string(),
+                trade: trade.clone(),
+            };
+            records.push((id, trade.timestamp, serde_json::to_vec(&record)?));
+        }
+        // Logged and indexed together, so a query never sees one without the other
+        (&self.tree, &self.times)
+            .transaction(|(tree, times)| -> ConflictableTransactionResult<()> {
+                for (id, timestamp, value) in &records {
+                    tree.insert(&id.to_be_bytes(), value.as_slice())?;
+                    times.insert(&time_key(*timestamp, *id), &[])?;
+                }
+                Ok(())
+            })
+            .map_err(|e| anyhow::anyhow!("Failed to log trades: {:?}", e))?;
+        Ok(())
+    }
+
+    /// Page of the trades matching `filter`, newest match time first and by id within the
+    /// same millisecond. Only trades after the one with id `before_id` in that order are
+    /// considered, then `offset` (at most `MAX_TRADES_OFFSET`) matches are skipped and up to
+    /// `limit` (at most `MAX_TRADES_PAGE`) returned. The time bounds narrow the scan rather
+    /// than filter it.
+    pub fn query(
+        &self,
+        filter: &TradeFilter,
+        before_id: Option<u64>,
+        offset: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<TradeRecord>> {
+        anyhow::ensure!(
+            offset <= MAX_TRADES_OFFSET,
+            "Offset {} is above {}",
+            offset,
+            MAX_TRADES_OFFSET
+        );
+        let limit = limit.min(MAX_TRADES_PAGE);
+
+        let start = time_key(filter.start_time.unwrap_or(0), 0);
+        let mut end = time_key(filter.end_time.unwrap_or(u64::MAX), u64::MAX);
+        if let Some(before_id) = before_id {
+            let cursor = self
+                .record(before_id)?
+                .ok_or_else(|| anyhow::anyhow!("No trade with id {}", before_id))?;
+            end = end.min(time_key(cursor.trade.timestamp, before_id));
+        }
+        if start >= end {
+            return Ok(vec![]);
+        }
+
+        let mut page = Vec::new();
+        let mut skipped = 0;
+        for entry in self.times.range(start..end).rev() {
+            if page.len() >= limit {
+                break;
+            }
+            let (key, _) = entry?;
+            let id = u64::from_be_bytes(key[8..].try_into()?);
+            let record = self
+                .record(id)?
+                .ok_or_else(|| anyhow::anyhow!("Indexed trade {} is missing", id))?;
+            if !filter.matches(&record) {
+                continue;
+            }
+            if skipped < offset {
+                skipped += 1;
+                continue;
+            }
+            page.push(record);
+        }
+        Ok(page)
+    }
+
+    fn record(&self, id: u64) -> anyhow::Result<Option<TradeRecord>> {
+        match self.tree.get(id.to_be_bytes())? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn trade(n: u64) -> Trade {
+        Trade {
+            buy_order_id: format!("buy_{}", n),
+            sell_order_id: format!("sell_{}", n),
+            price: 100 + n,
+            quantity: 1,
+            timestamp: 1_000 + n,
+        }
+    }
+
+    #[test]
+    fn test_page_through_trade_history() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let log = TradeLog::open(&db).unwrap();
+        // 250 trades alternating between two pairs
+        for n in 0..250 {
+            let pair_id = if n % 2 == 0 { "LGA_LGB" } else { "LGC_LGB" };
+            log.append(pair_id, &[trade(n)]).unwrap();
+        }
+
+        // Follow the before_id cursor from the newest trade back to the first
+        let mut seen = Vec::new();
+        let mut before_id = None;
+        loop {
+            let page = log
+                .query(&TradeFilter::default(), before_id, 0, 100)
+                .unwrap();
+            if page.is_empty() {
+                break;
+            }
+            before_id = page.last().map(|record| record.id);
+            seen.extend(page.into_iter().map(|record| record.trade.timestamp));
+        }
+        let expected: Vec<u64> = (0..250).rev().map(|n| 1_000 + n).collect();
+        assert_eq!(seen, expected);
+
+        // Offset paging within a pair and time range
+        let filter = TradeFilter {
+            pair_id: Some("LGC_LGB".to_string()),
+            start_time: Some(1_100),
+            end_time: Some(1_199),
+        };
+        let first = log.query(&filter, None, 0, 30).unwrap();
+        let second = log.query(&filter, None, 30, 30).unwrap();
+        assert_eq!(first.len(), 30);
+        assert_eq!(second.len(), 20);
+        assert_eq!(first[0].trade.timestamp, 1_199);
+        assert_eq!(second.last().unwrap().trade.timestamp, 1_101);
+        assert!(
+            first
+                .iter()
+                .chain(&second)
+                .all(|record| record.pair_id == "LGC_LGB")
+        );
+
+        // Reopening continues the ids instead of overwriting the history
+        drop(log);
+        let log = TradeLog::open(&db).unwrap();
+        log.append("LGA_LGB", &[trade(250)]).unwrap();
+        let newest = log.query(&TradeFilter::default(), None, 0, 1).unwrap();
+        assert_eq!(newest[0].id, 251);
+        assert_eq!(
+            log.query(&TradeFilter::default(), None, 0, 1_000)
+                .unwrap()
+                .len(),
+            251
+        );
+    }
+
+    #[test]
+    fn test_time_bounds_follow_match_time() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let log = TradeLog::open(&db).unwrap();
+        // One pair logs a trade matched after one another pair logs later
+        log.append("LTA_LTB", &[trade(0), trade(1)]).unwrap();
+        log.append("LTC_LTB", &[trade(5)]).unwrap();
+        log.append("LTA_LTB", &[trade(3), trade(4)]).unwrap();
+        log.append("LTA_LTB", &[trade(2)]).unwrap();
+
+        let timestamps = |records: Vec<TradeRecord>| -> Vec<u64> {
+            records
+                .into_iter()
+                .map(|record| record.trade.timestamp)
+                .collect()
+        };
+        let filter = TradeFilter {
+            start_time: Some(1_003),
+            ..TradeFilter::default()
+        };
+        assert_eq!(
+            timestamps(log.query(&filter, None, 0, 10).unwrap()),
+            vec![1_005, 1_004, 1_003]
+        );
+        let filter = TradeFilter {
+            start_time: Some(1_001),
+            end_time: Some(1_004),
+            ..TradeFilter::default()
+        };
+        assert_eq!(
+            timestamps(log.query(&filter, None, 1, 10).unwrap()),
+            vec![1_003, 1_002, 1_001]
+        );
+
+        // Pages follow match time too, from the cursor's place in it
+        let first = log.query(&TradeFilter::default(), None, 0, 3).unwrap();
+        let cursor = first.last().unwrap().id;
+        assert_eq!(timestamps(first), vec![1_005, 1_004, 1_003]);
+        let second = log
+            .query(&TradeFilter::default(), Some(cursor), 0, 3)
+            .unwrap();
+        assert_eq!(timestamps(second), vec![1_002, 1_001, 1_000]);
+
+        // Deep offsets are refused rather than scanned
+        assert!(
+            log.query(&TradeFilter::default(), None, MAX_TRADES_OFFSET + 1, 10)
+                .is_err()
+        );
+
+        // A log kept before the time index gets indexed when opened
+        drop(log);
+        db.drop_tree(TRADE_TIMES_TREE).unwrap();
+        let log = TradeLog::open(&db).unwrap();
+        assert_eq!(
+            timestamps(log.query(&TradeFilter::default(), None, 0, 10).unwrap()),
+            vec![1_005, 1_004, 1_003, 1_002, 1_001, 1_000]
+        );
+    }
+}
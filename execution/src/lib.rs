@@ -1,4 +1,6 @@
+pub mod api;
 pub mod block;
+pub mod client;
 pub mod evm;
 pub mod exchange;
 pub mod server;
@@ -1,15 +1,44 @@
-use execution::{block::block_builder::BlockBuilder, server};
+use common::genesis::Genesis;
+use execution::{
+    block::block_builder::{BlockBuilder, MAX_BLOCK_NOTIONAL_ENV},
+    exchange::NODE_DB,
+    server,
+};
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() {
-    // Initialize logger
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    log::info!("Starting ZKVM Order Book Exchange...");
+    // Initialize logging, `log` records from the evm modules are forwarded to it as well
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+    tracing::info!("Starting ZKVM Order Book Exchange...");
 
     // Start BlockBuilder
-    let block_builder = BlockBuilder::new("block_db").unwrap();
+    let mut block_builder = BlockBuilder::with_db(&NODE_DB).unwrap();
+    // Optionally seal blocks early once their trades add up to a large notional
+    if let Ok(limit) = std::env::var(MAX_BLOCK_NOTIONAL_ENV) {
+        let limit = limit
+            .parse()
+            .unwrap_or_else(|e| panic!("Invalid {} {}: {}", MAX_BLOCK_NOTIONAL_ENV, limit, e));
+        block_builder.max_block_notional = Some(limit);
+    }
+    // Pick the balances up where the last sealed block left them, before anything is replayed
+    block_builder
+        .restore_state()
+        .await
+        .unwrap_or_else(|e| panic!("{}", e));
+    // Block 0 holds the configured genesis balances, the root the first proof starts from
+    let genesis = Genesis::from_env().unwrap_or_else(|e| panic!("{}", e));
+    block_builder
+        .seal_genesis(&genesis)
+        .await
+        .unwrap_or_else(|e| panic!("{}", e));
+    let sealed_blocks = block_builder.clone();
     tokio::spawn(async move { block_builder.start_block_generation().await });
 
     // Start server
-    server::start().await;
+    server::start(sealed_blocks).await;
 }
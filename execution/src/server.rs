@@ -1,123 +1,61 @@
+use crate::api::{
+    ApiResponse, AssignTierRequest, BalanceResponse, BlockResponse, BookAtBlockRequest,
+    BookAtBlockResponse, BookStats, CancelBatchRequest, CancelBatchResult, CancelOrderRequest,
+    DecimalQuery, DepositRequest, GetBalanceRequest, GetOrderBookRequest, GetOrderRequest,
+    GetTradesRequest, L3Order, L3OrderBookResponse, OrderBookResponse, PairHaltRequest,
+    PairHaltResponse, PlaceAndWaitRequest, PlaceAndWaitResponse, PlaceOrderRequest,
+    PlaceOrderResponse, PortfolioRequest, PortfolioResponse, ReconcileRequest, ReconcileResponse,
+    ReplaceOrderRequest, ReplaceOrderResponse, ResyncRequest, ResyncResponse, SetTierRequest,
+    SimulateOrderResponse, StatsResponse, TokenBalance, TradesResponse, TransferRequest,
+    WithdrawRequest,
+};
+use crate::block::block_builder::{BlockBuilder, SealedBlock};
 use crate::evm::handle_evm_request;
-use crate::exchange::STATE;
-use crate::exchange::matching::Trade;
-use crate::exchange::mempool::MEMPOOL;
+use crate::exchange::mempool::{MEMPOOL, Mempool};
+use crate::exchange::trade_log::{
+    DEFAULT_TRADES_PAGE, MAX_TRADES_OFFSET, MAX_TRADES_PAGE, TradeFilter,
+};
+use crate::exchange::{
+    STATE, STATS, TRADE_LOG, USER_TIERS, apply_funding, apply_transfer, trace_backlog_depth,
+};
 use axum::{
-    Router, extract::Json, http::StatusCode, response::Json as ResponseJson, routing::post,
+    Extension, Router,
+    extract::{
+        DefaultBodyLimit, Json, Path, Query,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::StatusCode,
+    response::{Json as ResponseJson, Response},
+    routing::{get, post},
 };
-use common::order::Order;
-use serde::{Deserialize, Serialize};
+use common::math::format_decimal;
+use common::order::{Order, OrderStatus};
+use common::traces::{Funding, FundingKind, Transfer};
+use std::collections::BTreeSet;
 use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
 use tower_http::cors::{Any, CorsLayer};
 
-#[derive(Deserialize)]
-pub struct DepositRequest {
-    pub user_id: String,
-    pub token: String,
-    pub amount: u64,
-}
-
-#[derive(Deserialize)]
-pub struct WithdrawRequest {
-    pub user_id: String,
-    pub token: String,
-    pub amount: u64,
-}
-
-#[derive(Deserialize)]
-pub struct PlaceOrderRequest {
-    pub user_id: String,
-    pub pair_id: String,
-    pub amount: u64,
-    pub price: u64,
-    pub side: bool, // true for buy, false for sell
-}
-
-#[derive(Deserialize)]
-pub struct CancelOrderRequest {
-    pub pair_id: String,
-    pub order_id: String,
-}
-
-#[derive(Deserialize)]
-pub struct GetBalanceRequest {
-    pub user_id: String,
-    pub token: String,
-}
-
-#[derive(Deserialize)]
-pub struct GetOrderRequest {
-    pub pair_id: String,
-    pub order_id: String,
-}
-
-#[derive(Deserialize)]
-pub struct GetOrderBookRequest {
-    pub pair_id: String,
-}
-
-#[derive(Deserialize)]
-pub struct SubmitEvmTxnRequest {
-    pub rlp_data: String, // Hex-encoded RLP transaction data
-}
-
-#[derive(Serialize)]
-pub struct ApiResponse<T> {
-    pub success: bool,
-    pub data: Option<T>,
-    pub error: Option<String>,
-}
-
-#[derive(Serialize)]
-pub struct PlaceOrderResponse {
-    pub order_id: String,
-}
-
-#[derive(Serialize)]
-pub struct BalanceResponse {
-    pub balance: u64,
-}
-
-#[derive(Serialize)]
-pub struct OrderBookResponse {
-    pub best_bid: Option<u64>,
-    pub best_ask: Option<u64>,
-}
-
-#[derive(Serialize)]
-pub struct SubmitEvmTxnResponse {
-    pub tx_hash: String,
-}
-
-impl<T> ApiResponse<T> {
-    pub fn success(data: T) -> Self {
-        Self {
-            success: true,
-            data: Some(data),
-            error: None,
-        }
-    }
-
-    pub fn error(error: String) -> Self {
-        Self {
-            success: false,
-            data: None,
-            error: Some(error),
-        }
-    }
-}
+// Largest request body the routers accept, larger ones get 413 Payload Too Large
+pub static MAX_BODY_BYTES: usize = 1024 * 1024;
+// How long /order/place_and_wait waits for the order to close, by default and at most
+pub const DEFAULT_WAIT_MS: u64 = 10_000;
+pub const MAX_WAIT_MS: u64 = 60_000;
 
-pub async fn start() {
+/// Serve the exchange and EVM APIs. `block_builder` is the node's builder, whose sealed blocks
+/// `/ws/blocks` streams.
+pub async fn start(block_builder: BlockBuilder) {
     // Create exchange API router
-    let exchange_app = create_exchange_router();
+    let exchange_app = create_exchange_router(MAX_BODY_BYTES);
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any);
-    let exchange_app = exchange_app.layer(cors);
+    let exchange_app = exchange_app.layer(cors).layer(Extension(block_builder));
 
     // Create EVM API router
-    let evm_app = create_evm_router();
+    let evm_app = create_evm_router(MAX_BODY_BYTES);
     let evm_cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
@@ -126,12 +64,12 @@ pub async fn start() {
 
     // Start exchange server on port 3030
     let exchange_addr: SocketAddr = "[::1]:3030".parse().unwrap();
-    log::info!("Exchange server running on http://{}", exchange_addr);
+    tracing::info!("Exchange server running on http://{}", exchange_addr);
     let exchange_listener = tokio::net::TcpListener::bind(exchange_addr).await.unwrap();
 
     // Start EVM server on port 8545
     let evm_addr: SocketAddr = "[::1]:8545".parse().unwrap();
-    log::info!("EVM server running on http://{}", evm_addr);
+    tracing::info!("EVM server running on http://{}", evm_addr);
     let evm_listener = tokio::net::TcpListener::bind(evm_addr).await.unwrap();
 
     // Run both servers concurrently
@@ -141,66 +79,193 @@ pub async fn start() {
     }
 }
 
-fn create_exchange_router() -> Router {
+pub(crate) fn create_exchange_router(body_limit: usize) -> Router {
     Router::new()
         .route("/deposit", post(handle_deposit))
         .route("/withdraw", post(handle_withdraw))
+        .route("/transfer", post(handle_transfer))
         .route("/order/place", post(handle_place_order))
+        .route("/order/place_and_wait", post(handle_place_and_wait))
+        .route("/order/simulate", post(handle_simulate_order))
+        .route("/order/replace", post(handle_replace_order))
         .route("/order/cancel", post(handle_cancel_order))
+        .route("/order/cancel_batch", post(handle_cancel_batch))
         .route("/balance", post(handle_get_balance))
+        .route("/portfolio", post(handle_get_portfolio))
         .route("/order/get", post(handle_get_order))
         .route("/orderbook", post(handle_get_orderbook))
-        .route("/trades", post(handle_get_trades))
+        .route("/orderbook/l3", post(handle_get_orderbook_l3))
+        .route("/orderbook/resync", post(handle_resync_orderbook))
+        .route("/orderbook/at", get(handle_get_orderbook_at))
+        .route("/block/:num", get(handle_get_block))
+        .route(
+            "/trades",
+            get(handle_get_trades_query).post(handle_get_trades),
+        )
+        .route("/stats", get(handle_get_stats))
+        .route("/ws/blocks", get(handle_blocks_ws))
+        // Read-only GET variants of the POST queries above
+        .route("/balance/:user_id/:token", get(handle_get_balance_path))
+        .route("/portfolio/:user_id", get(handle_get_portfolio_path))
+        .route("/order/:pair_id/:order_id", get(handle_get_order_path))
+        .route("/orderbook/:pair_id", get(handle_get_orderbook_path))
+        .route("/orderbook/:pair_id/l3", get(handle_get_orderbook_l3_path))
+        .route(
+            "/admin/reconcile",
+            get(handle_reconcile_query).post(handle_reconcile),
+        )
+        .route("/admin/pair/halt", post(handle_halt_pair))
+        .route("/admin/pair/resume", post(handle_resume_pair))
+        .route("/admin/tier", post(handle_set_tier))
+        .route("/admin/tier/assign", post(handle_assign_tier))
+        .layer(DefaultBodyLimit::max(body_limit))
+}
+
+async fn handle_blocks_ws(
+    ws: WebSocketUpgrade,
+    Extension(block_builder): Extension<BlockBuilder>,
+) -> Response {
+    ws.on_upgrade(move |socket| stream_sealed_blocks(socket, block_builder))
+}
+
+// Send the recent block headers, then each block's header as it's sealed, in block order and
+// without gaps, until the client goes away. Blocks a slow client's events skipped are read
+// back from the block store.
+async fn stream_sealed_blocks(mut socket: WebSocket, block_builder: BlockBuilder) {
+    let (recent, mut sealed_blocks) = block_builder.subscribe_with_recent();
+    let mut last_sent = None;
+    for header in recent {
+        if send_header(&mut socket, &header).await.is_err() {
+            return;
+        }
+        last_sent = Some(header.block_num);
+    }
+
+    loop {
+        let header = tokio::select! {
+            event = sealed_blocks.recv() => match event {
+                Ok(header) => header,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Block stream fell {} blocks behind, backfilling", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            },
+            // Anything but a message, e.g. a close or an error, ends the stream
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+
+        let first_missing = last_sent.map_or(header.block_num, |num| num + 1);
+        for block_num in first_missing..header.block_num {
+            let missed = match block_builder.get_block(block_num).await {
+                Ok(Some(block)) => SealedBlock::from(&block),
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::error!("Failed to read block {} for the stream: {}", block_num, e);
+                    return;
+                }
+            };
+            if send_header(&mut socket, &missed).await.is_err() {
+                return;
+            }
+        }
+        if header.block_num < first_missing {
+            continue;
+        }
+        if send_header(&mut socket, &header).await.is_err() {
+            return;
+        }
+        last_sent = Some(header.block_num);
+    }
+}
+
+async fn send_header(socket: &mut WebSocket, header: &SealedBlock) -> Result<(), axum::Error> {
+    let json = serde_json::to_string(header).map_err(axum::Error::new)?;
+    socket.send(Message::Text(json)).await
 }
 
-fn create_evm_router() -> Router {
-    Router::new().route("/", post(handle_evm_request))
+fn create_evm_router(body_limit: usize) -> Router {
+    Router::new()
+        .route("/", post(handle_evm_request))
+        .layer(DefaultBodyLimit::max(body_limit))
 }
 
 async fn handle_deposit(
     Json(request): Json<DepositRequest>,
 ) -> Result<ResponseJson<ApiResponse<()>>, StatusCode> {
-    log::info!(
+    tracing::info!(
         "Deposit request: user_id={}, token={}, amount={}",
         request.user_id,
         request.token,
         request.amount
     );
 
-    let mut state_db = STATE.write().await;
-
-    state_db.state.add_user_balance(
-        request.user_id.clone(),
-        request.token.clone(),
-        request.amount,
-    );
-    Ok(ResponseJson(ApiResponse::success(())))
+    // Sealed into the next block like a trade, so the prover reproduces its root
+    let deposit = Funding {
+        kind: FundingKind::Deposit,
+        user_id: request.user_id,
+        token: request.token,
+        amount: request.amount,
+    };
+    match apply_funding(deposit).await {
+        Ok(()) => Ok(ResponseJson(ApiResponse::success(()))),
+        Err(e) => Ok(ResponseJson(ApiResponse::error(e))),
+    }
 }
 
 async fn handle_withdraw(
     Json(request): Json<WithdrawRequest>,
 ) -> Result<ResponseJson<ApiResponse<()>>, StatusCode> {
-    log::info!(
+    tracing::info!(
         "Withdraw request: user_id={}, token={}, amount={}",
         request.user_id,
         request.token,
         request.amount
     );
 
-    let mut state_db = STATE.write().await;
+    // Frozen funds back open orders and can't be withdrawn
+    let withdrawal = Funding {
+        kind: FundingKind::Withdrawal,
+        user_id: request.user_id,
+        token: request.token,
+        amount: request.amount,
+    };
+    match apply_funding(withdrawal).await {
+        Ok(()) => Ok(ResponseJson(ApiResponse::success(()))),
+        Err(e) => Ok(ResponseJson(ApiResponse::error(e))),
+    }
+}
 
-    state_db.state.sub_user_balance(
-        request.user_id.clone(),
-        request.token.clone(),
-        request.amount,
+async fn handle_transfer(
+    Json(request): Json<TransferRequest>,
+) -> Result<ResponseJson<ApiResponse<()>>, StatusCode> {
+    tracing::info!(
+        "Transfer request: from={}, to={}, token={}, amount={}",
+        request.from,
+        request.to,
+        request.token,
+        request.amount
     );
-    Ok(ResponseJson(ApiResponse::success(())))
+
+    let transfer = Transfer {
+        from: request.from,
+        to: request.to,
+        token: request.token,
+        amount: request.amount,
+    };
+    match apply_transfer(transfer).await {
+        Ok(()) => Ok(ResponseJson(ApiResponse::success(()))),
+        Err(e) => Ok(ResponseJson(ApiResponse::error(e))),
+    }
 }
 
 async fn handle_place_order(
     Json(request): Json<PlaceOrderRequest>,
 ) -> Result<ResponseJson<ApiResponse<PlaceOrderResponse>>, StatusCode> {
-    log::info!(
+    tracing::info!(
         "Received order request: user_id={}, pair_id={}, amount={}, price={}, side={}",
         request.user_id,
         request.pair_id,
@@ -209,67 +274,325 @@ async fn handle_place_order(
         if request.side { "buy" } else { "sell" }
     );
 
-    let mut mempool = MEMPOOL.write().await;
+    // Orders for different pairs match concurrently, so a read lock is enough
+    let mempool = MEMPOOL.read().await;
+    match place_request(&mempool, request).await {
+        Ok(response) => Ok(ResponseJson(ApiResponse::success(response))),
+        Err(e) => Ok(ResponseJson(ApiResponse::error(e))),
+    }
+}
 
+// Place the order a request describes under a fresh order id
+async fn place_request(
+    mempool: &Mempool,
+    request: PlaceOrderRequest,
+) -> Result<PlaceOrderResponse, String> {
     // Generate unique order ID
     let order_id = format!("order_{}", rand::random::<u64>());
+    let client_order_id = request.client_order_id.clone();
+    let order = request_order(mempool, &order_id, request)?;
 
-    let order = Order::new(
-        order_id.clone(),
-        request.user_id,
-        request.pair_id,
-        request.amount,
-        request.price,
-        request.side,
-    );
-
-    log::info!(
+    tracing::info!(
         "Created order: id={}, user_id={}, pair_id={}",
         order_id,
         order.user_id,
         order.pair_id
     );
 
-    match mempool.place_order(order.clone()).await {
-        Ok(order_id) => {
-            log::info!("Order processed successfully: order_id = {}", order_id,);
-            let response = PlaceOrderResponse { order_id };
-            Ok(ResponseJson(ApiResponse::success(response)))
+    let placed = match &client_order_id {
+        Some(client_order_id) => mempool.place_client_order(order, client_order_id).await,
+        None => mempool.place_order(order).await,
+    };
+    match placed {
+        Ok(result) => {
+            tracing::info!("Order processed successfully: order_id = {}", order_id,);
+            Ok(PlaceOrderResponse {
+                order_id,
+                client_order_id,
+                fills: result.fills,
+                status: result.status,
+            })
+        }
+        Err(e) => {
+            tracing::error!("Failed to process order: id={}, error={}", order_id, e);
+            Err(e)
         }
+    }
+}
+
+// The order a request describes, under `order_id`
+fn request_order(
+    mempool: &Mempool,
+    order_id: &str,
+    request: PlaceOrderRequest,
+) -> Result<Order, String> {
+    let price = request_price(mempool, &request)?;
+    match Order::try_new(
+        order_id.to_string(),
+        request.user_id,
+        request.pair_id,
+        request.amount,
+        price,
+        request.side,
+    ) {
+        Ok(order) => Ok(match request.trigger_price {
+            Some(trigger_price) => order.with_trigger(trigger_price),
+            None => order,
+        }),
+        Err(e) => {
+            tracing::error!("Rejected order: id={}, error={}", order_id, e);
+            Err(e.to_string())
+        }
+    }
+}
+
+async fn handle_replace_order(
+    Json(request): Json<ReplaceOrderRequest>,
+) -> Result<ResponseJson<ApiResponse<ReplaceOrderResponse>>, StatusCode> {
+    tracing::info!(
+        "Replace order request: pair_id={}, order_id={}",
+        request.pair_id,
+        request.order_id
+    );
+    if request.new_order.client_order_id.is_some() {
+        return Ok(ResponseJson(ApiResponse::error(
+            "A replacement order can't take a client_order_id".to_string(),
+        )));
+    }
+
+    let mempool = MEMPOOL.read().await;
+    let order_id = format!("order_{}", rand::random::<u64>());
+    let order = match request_order(&mempool, &order_id, request.new_order) {
+        Ok(order) => order,
+        Err(e) => return Ok(ResponseJson(ApiResponse::error(e))),
+    };
+    match mempool
+        .replace_order(&request.pair_id, &request.order_id, order)
+        .await
+    {
+        Ok((replaced, result)) => Ok(ResponseJson(ApiResponse::success(ReplaceOrderResponse {
+            replaced,
+            order_id,
+            fills: result.fills,
+            status: result.status,
+        }))),
         Err(e) => {
-            log::error!("Failed to process order: id={}, error={}", order_id, e);
+            tracing::error!(
+                "Failed to replace order: pair_id={}, order_id={}, error={}",
+                request.pair_id,
+                request.order_id,
+                e
+            );
             Ok(ResponseJson(ApiResponse::error(e)))
         }
     }
 }
 
+async fn handle_place_and_wait(
+    Json(request): Json<PlaceAndWaitRequest>,
+) -> Result<ResponseJson<ApiResponse<PlaceAndWaitResponse>>, StatusCode> {
+    let timeout_ms = request.timeout_ms.unwrap_or(DEFAULT_WAIT_MS);
+    if timeout_ms > MAX_WAIT_MS {
+        return Ok(ResponseJson(ApiResponse::error(format!(
+            "timeout_ms can be at most {}",
+            MAX_WAIT_MS
+        ))));
+    }
+    let pair_id = request.order.pair_id.clone();
+
+    let mempool = MEMPOOL.read().await;
+    let placed = match place_request(&mempool, request.order).await {
+        Ok(placed) => placed,
+        Err(e) => return Ok(ResponseJson(ApiResponse::error(e))),
+    };
+    let order = mempool
+        .wait_for_order(
+            &pair_id,
+            &placed.order_id,
+            Duration::from_millis(timeout_ms),
+        )
+        .await;
+    match order {
+        Some(order) => Ok(ResponseJson(ApiResponse::success(PlaceAndWaitResponse {
+            order_id: placed.order_id,
+            client_order_id: placed.client_order_id,
+            fills: placed.fills,
+            timed_out: !matches!(
+                order.status,
+                OrderStatus::Filled | OrderStatus::Cancelled | OrderStatus::Settled
+            ),
+            order,
+        }))),
+        None => Ok(ResponseJson(ApiResponse::error(
+            "Order not found".to_string(),
+        ))),
+    }
+}
+
+async fn handle_simulate_order(
+    Json(request): Json<PlaceOrderRequest>,
+) -> Result<ResponseJson<ApiResponse<SimulateOrderResponse>>, StatusCode> {
+    let mempool = MEMPOOL.read().await;
+    let price = match request_price(&mempool, &request) {
+        Ok(price) => price,
+        Err(e) => return Ok(ResponseJson(ApiResponse::error(e))),
+    };
+    // Never enters the real book, so the id only has to be unique within the copy
+    let order = match Order::try_new(
+        format!("simulated_{}", rand::random::<u64>()),
+        request.user_id,
+        request.pair_id,
+        request.amount,
+        price,
+        request.side,
+    ) {
+        Ok(order) => match request.trigger_price {
+            Some(trigger_price) => order.with_trigger(trigger_price),
+            None => order,
+        },
+        Err(e) => return Ok(ResponseJson(ApiResponse::error(e.to_string()))),
+    };
+
+    let result = match mempool.simulate_order(order).await {
+        Ok(result) => result,
+        Err(e) => return Ok(ResponseJson(ApiResponse::error(e))),
+    };
+
+    let filled_amount: u64 = result.fills.iter().map(|fill| fill.quantity).sum();
+    let filled_value: u128 = result
+        .fills
+        .iter()
+        .map(|fill| fill.quantity as u128 * fill.price as u128)
+        .sum();
+    let response = SimulateOrderResponse {
+        average_price: (filled_amount > 0).then(|| filled_value as f64 / filled_amount as f64),
+        filled_amount,
+        fills: result.fills,
+        resting_remaining: result.resting_remaining,
+        status: result.status,
+    };
+    Ok(ResponseJson(ApiResponse::success(response)))
+}
+
+// Price of an order request in its pair's scale, converting a decimal price
+fn request_price(mempool: &Mempool, request: &PlaceOrderRequest) -> Result<u64, String> {
+    match &request.decimal_price {
+        None => Ok(request.price),
+        Some(_) if request.price != 0 => {
+            Err("Give either price or decimal_price, not both".to_string())
+        }
+        Some(price) => mempool
+            .pair_config(&request.pair_id)
+            .price_from_decimal(price),
+    }
+}
+
+// The order a cancel or lookup names: by id, or by the client order id it was placed with
+fn resolve_order_id(
+    mempool: &Mempool,
+    pair_id: &str,
+    order_id: Option<String>,
+    user_id: Option<&str>,
+    client_order_id: Option<&str>,
+) -> Result<String, String> {
+    if let Some(order_id) = order_id {
+        return Ok(order_id);
+    }
+    let (Some(user_id), Some(client_order_id)) = (user_id, client_order_id) else {
+        return Err("Either order_id or user_id and client_order_id are required".to_string());
+    };
+    match mempool.client_order(user_id, client_order_id) {
+        Some((order_pair_id, order_id)) if order_pair_id == pair_id => Ok(order_id),
+        _ => Err("Order not found".to_string()),
+    }
+}
+
 async fn handle_cancel_order(
     Json(request): Json<CancelOrderRequest>,
 ) -> Result<ResponseJson<ApiResponse<Order>>, StatusCode> {
-    log::info!(
-        "Cancel order request: pair_id={}, order_id={}",
+    tracing::info!(
+        "Cancel order request: pair_id={}, order_id={:?}, client_order_id={:?}, reduce_by={:?}",
         request.pair_id,
-        request.order_id
+        request.order_id,
+        request.client_order_id,
+        request.reduce_by
     );
 
-    let mut mempool = MEMPOOL.write().await;
+    let mempool = MEMPOOL.read().await;
+    let order_id = match resolve_order_id(
+        &mempool,
+        &request.pair_id,
+        request.order_id,
+        request.user_id.as_deref(),
+        request.client_order_id.as_deref(),
+    ) {
+        Ok(order_id) => order_id,
+        Err(e) => return Ok(ResponseJson(ApiResponse::error(e))),
+    };
     match mempool
-        .cancel_order(&request.pair_id, &request.order_id)
+        .cancel_order(&request.pair_id, &order_id, request.reduce_by)
         .await
     {
         Ok(cancelled_order) => {
-            log::info!(
+            tracing::info!(
                 "Order cancelled successfully: pair_id={}, order_id={}",
                 request.pair_id,
-                request.order_id
+                order_id
             );
             Ok(ResponseJson(ApiResponse::success(cancelled_order)))
         }
         Err(e) => {
-            log::error!(
+            tracing::error!(
                 "Failed to cancel order: pair_id={}, order_id={}, error={}",
                 request.pair_id,
-                request.order_id,
+                order_id,
+                e
+            );
+            Ok(ResponseJson(ApiResponse::error(e)))
+        }
+    }
+}
+
+async fn handle_cancel_batch(
+    Json(request): Json<CancelBatchRequest>,
+) -> Result<ResponseJson<ApiResponse<Vec<CancelBatchResult>>>, StatusCode> {
+    tracing::info!(
+        "Cancel batch request: pair_id={}, orders={}",
+        request.pair_id,
+        request.order_ids.len()
+    );
+
+    let mempool = MEMPOOL.read().await;
+    match mempool
+        .cancel_orders(&request.pair_id, &request.order_ids)
+        .await
+    {
+        Ok(results) => {
+            let results = request
+                .order_ids
+                .into_iter()
+                .zip(results)
+                .map(|(order_id, result)| match result {
+                    Ok(order) => CancelBatchResult {
+                        order_id,
+                        success: true,
+                        order: Some(order),
+                        error: None,
+                    },
+                    Err(e) => CancelBatchResult {
+                        order_id,
+                        success: false,
+                        order: None,
+                        error: Some(e),
+                    },
+                })
+                .collect();
+            Ok(ResponseJson(ApiResponse::success(results)))
+        }
+        Err(e) => {
+            tracing::error!(
+                "Failed to cancel batch: pair_id={}, error={}",
+                request.pair_id,
                 e
             );
             Ok(ResponseJson(ApiResponse::error(e)))
@@ -280,28 +603,137 @@ async fn handle_cancel_order(
 async fn handle_get_balance(
     Json(request): Json<GetBalanceRequest>,
 ) -> Result<ResponseJson<ApiResponse<BalanceResponse>>, StatusCode> {
+    let decimals = match request.decimal {
+        true => MEMPOOL.read().await.token_decimals(&request.token),
+        false => None,
+    };
     let state_db = STATE.read().await;
     let balance = state_db
         .state
         .get_user_balance(&request.user_id, &request.token);
-    let response = BalanceResponse { balance };
+    let mut response = BalanceResponse {
+        balance,
+        balance_decimal: decimals.map(|decimals| format_decimal(balance, decimals)),
+        state_root: None,
+        proof: None,
+    };
+    if request.with_proof {
+        // The live root, which the next block commits to unless the state changes first
+        response.state_root = state_db.state.calculate_state_root();
+        response.proof = state_db.state.gen_merkle_proof(&request.user_id);
+    }
+
+    Ok(ResponseJson(ApiResponse::success(response)))
+}
+
+async fn handle_get_balance_path(
+    Path((user_id, token)): Path<(String, String)>,
+    Query(query): Query<DecimalQuery>,
+) -> Result<ResponseJson<ApiResponse<BalanceResponse>>, StatusCode> {
+    handle_get_balance(Json(GetBalanceRequest {
+        user_id,
+        token,
+        with_proof: false,
+        decimal: query.decimal,
+    }))
+    .await
+}
+
+async fn handle_get_portfolio(
+    Json(request): Json<PortfolioRequest>,
+) -> Result<ResponseJson<ApiResponse<PortfolioResponse>>, StatusCode> {
+    let mempool = MEMPOOL.read().await;
+    let state_db = STATE.read().await;
+    let state = &state_db.state;
+
+    // Every token the user holds or has frozen
+    let mut tokens = BTreeSet::new();
+    for accounts in [&state.user_balances, &state.user_frozens] {
+        if let Some(account) = accounts.get(&request.user_id) {
+            tokens.extend(account.balances.keys().cloned());
+        }
+    }
+
+    let balances = tokens
+        .into_iter()
+        .map(|token| {
+            let frozen = state
+                .user_frozens
+                .get(&request.user_id)
+                .map(|account| account.get_balance(&token))
+                .unwrap_or(0);
+            let total = state.get_user_balance(&request.user_id, &token);
+            let available = state.get_available_balance(&request.user_id, &token);
+            let decimals = match request.decimal {
+                true => mempool.token_decimals(&token),
+                false => None,
+            };
+            let format = |amount| decimals.map(|decimals| format_decimal(amount, decimals));
+            TokenBalance {
+                total_decimal: format(total),
+                frozen_decimal: format(frozen),
+                available_decimal: format(available),
+                total,
+                frozen,
+                available,
+                token,
+            }
+        })
+        .collect();
+    let response = PortfolioResponse {
+        user_id: request.user_id,
+        balances,
+    };
 
     Ok(ResponseJson(ApiResponse::success(response)))
 }
 
+async fn handle_get_portfolio_path(
+    Path(user_id): Path<String>,
+    Query(query): Query<DecimalQuery>,
+) -> Result<ResponseJson<ApiResponse<PortfolioResponse>>, StatusCode> {
+    handle_get_portfolio(Json(PortfolioRequest {
+        user_id,
+        decimal: query.decimal,
+    }))
+    .await
+}
+
 async fn handle_get_order(
     Json(request): Json<GetOrderRequest>,
 ) -> Result<ResponseJson<ApiResponse<Order>>, StatusCode> {
     let mempool = MEMPOOL.read().await;
+    let order_id = match resolve_order_id(
+        &mempool,
+        &request.pair_id,
+        request.order_id,
+        request.user_id.as_deref(),
+        request.client_order_id.as_deref(),
+    ) {
+        Ok(order_id) => order_id,
+        Err(e) => return Ok(ResponseJson(ApiResponse::error(e))),
+    };
 
-    match mempool.get_order(&request.pair_id, &request.order_id) {
-        Some(order) => Ok(ResponseJson(ApiResponse::success(order.clone()))),
+    match mempool.get_order(&request.pair_id, &order_id).await {
+        Some(order) => Ok(ResponseJson(ApiResponse::success(order))),
         None => Ok(ResponseJson(ApiResponse::error(
             "Order not found".to_string(),
         ))),
     }
 }
 
+async fn handle_get_order_path(
+    Path((pair_id, order_id)): Path<(String, String)>,
+) -> Result<ResponseJson<ApiResponse<Order>>, StatusCode> {
+    handle_get_order(Json(GetOrderRequest {
+        pair_id,
+        order_id: Some(order_id),
+        user_id: None,
+        client_order_id: None,
+    }))
+    .await
+}
+
 async fn handle_get_orderbook(
     Json(request): Json<GetOrderBookRequest>,
 ) -> Result<ResponseJson<ApiResponse<OrderBookResponse>>, StatusCode> {
@@ -309,9 +741,12 @@ async fn handle_get_orderbook(
 
     match mempool.get_order_book(&request.pair_id) {
         Some(order_book) => {
+            let order_book = order_book.read().await;
             let response = OrderBookResponse {
                 best_bid: order_book.get_best_bid(),
                 best_ask: order_book.get_best_ask(),
+                checksum: order_book.checksum(),
+                seq: order_book.seq(),
             };
             Ok(ResponseJson(ApiResponse::success(response)))
         }
@@ -321,8 +756,1159 @@ async fn handle_get_orderbook(
     }
 }
 
-async fn handle_get_trades() -> Result<ResponseJson<ApiResponse<Vec<Trade>>>, StatusCode> {
+async fn handle_get_orderbook_path(
+    Path(pair_id): Path<String>,
+) -> Result<ResponseJson<ApiResponse<OrderBookResponse>>, StatusCode> {
+    handle_get_orderbook(Json(GetOrderBookRequest { pair_id })).await
+}
+
+async fn handle_get_orderbook_l3(
+    Json(request): Json<GetOrderBookRequest>,
+) -> Result<ResponseJson<ApiResponse<L3OrderBookResponse>>, StatusCode> {
     let mempool = MEMPOOL.read().await;
-    let trades = mempool.get_trades().clone();
-    Ok(ResponseJson(ApiResponse::success(trades)))
+
+    // Remembered, so the client can resync from this state later
+    match mempool.resting_snapshot(&request.pair_id).await {
+        Some(order_book) => {
+            let response = L3OrderBookResponse {
+                bids: to_l3_orders(order_book.iter_bids()),
+                asks: to_l3_orders(order_book.iter_asks()),
+                seq: order_book.seq(),
+            };
+            Ok(ResponseJson(ApiResponse::success(response)))
+        }
+        None => Ok(ResponseJson(ApiResponse::error(
+            "Trading pair not found".to_string(),
+        ))),
+    }
+}
+
+async fn handle_resync_orderbook(
+    Json(request): Json<ResyncRequest>,
+) -> Result<ResponseJson<ApiResponse<ResyncResponse>>, StatusCode> {
+    let mempool = MEMPOOL.read().await;
+
+    match mempool.resync(&request.pair_id, request.seq).await {
+        Ok((diff, order_book)) => {
+            let response = ResyncResponse {
+                from_seq: request.seq,
+                seq: order_book.seq(),
+                checksum: order_book.checksum(),
+                diff,
+            };
+            Ok(ResponseJson(ApiResponse::success(response)))
+        }
+        Err(e) => Ok(ResponseJson(ApiResponse::error(e))),
+    }
+}
+
+async fn handle_get_orderbook_at(
+    Extension(block_builder): Extension<BlockBuilder>,
+    Query(request): Query<BookAtBlockRequest>,
+) -> Result<ResponseJson<ApiResponse<BookAtBlockResponse>>, StatusCode> {
+    match block_builder.book_at(&request.pair_id, request.block_num as u128) {
+        Ok(Some((recorded_at, order_book))) => {
+            let response = BookAtBlockResponse {
+                block_num: request.block_num,
+                recorded_at: recorded_at as u64,
+                seq: order_book.seq(),
+                bids: to_l3_orders(order_book.iter_bids()),
+                asks: to_l3_orders(order_book.iter_asks()),
+            };
+            Ok(ResponseJson(ApiResponse::success(response)))
+        }
+        Ok(None) => Ok(ResponseJson(ApiResponse::error(format!(
+            "No {} book recorded at or before block {}",
+            request.pair_id, request.block_num
+        )))),
+        Err(e) => {
+            tracing::error!("Failed to read the {} book history: {}", request.pair_id, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_get_block(
+    Extension(block_builder): Extension<BlockBuilder>,
+    Path(block_num): Path<u64>,
+) -> Result<ResponseJson<ApiResponse<BlockResponse>>, StatusCode> {
+    match block_builder.get_block(block_num as u128).await {
+        Ok(Some(block)) => Ok(ResponseJson(ApiResponse::success(BlockResponse {
+            hash: block.hash(),
+            block,
+        }))),
+        Ok(None) => Ok(ResponseJson(ApiResponse::error(format!(
+            "Block {} not found",
+            block_num
+        )))),
+        Err(e) => {
+            tracing::error!("Failed to read block {}: {}", block_num, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_get_orderbook_l3_path(
+    Path(pair_id): Path<String>,
+) -> Result<ResponseJson<ApiResponse<L3OrderBookResponse>>, StatusCode> {
+    handle_get_orderbook_l3(Json(GetOrderBookRequest { pair_id })).await
+}
+
+fn to_l3_orders<'a>(orders: impl Iterator<Item = &'a Order>) -> Vec<L3Order> {
+    orders
+        .enumerate()
+        .map(|(priority, order)| L3Order {
+            order_id: order.id.clone(),
+            user_id: order.user_id.clone(),
+            price: order.price,
+            remaining_amount: order.remaining_amount(),
+            priority,
+        })
+        .collect()
+}
+
+async fn handle_get_trades_query(
+    Query(request): Query<GetTradesRequest>,
+) -> Result<ResponseJson<ApiResponse<TradesResponse>>, StatusCode> {
+    handle_get_trades(Json(request)).await
+}
+
+async fn handle_get_trades(
+    Json(request): Json<GetTradesRequest>,
+) -> Result<ResponseJson<ApiResponse<TradesResponse>>, StatusCode> {
+    let limit = request
+        .limit
+        .unwrap_or(DEFAULT_TRADES_PAGE)
+        .clamp(1, MAX_TRADES_PAGE);
+    if request.offset > MAX_TRADES_OFFSET {
+        return Ok(ResponseJson(ApiResponse::error(format!(
+            "Offset can be at most {}, page further with before_id",
+            MAX_TRADES_OFFSET
+        ))));
+    }
+    let filter = TradeFilter {
+        pair_id: request.pair_id,
+        start_time: request.start_time,
+        end_time: request.end_time,
+    };
+
+    match TRADE_LOG.query(&filter, request.before_id, request.offset, limit) {
+        Ok(trades) => {
+            // A short page means there is nothing older left to fetch
+            let next_before_id = if trades.len() == limit {
+                trades.last().map(|record| record.id)
+            } else {
+                None
+            };
+            Ok(ResponseJson(ApiResponse::success(TradesResponse {
+                trades,
+                next_before_id,
+            })))
+        }
+        Err(e) => {
+            tracing::error!("Failed to read the trade log: {}", e);
+            Ok(ResponseJson(ApiResponse::error(
+                "Failed to read trade history".to_string(),
+            )))
+        }
+    }
+}
+
+async fn handle_get_stats() -> Result<ResponseJson<ApiResponse<StatsResponse>>, StatusCode> {
+    let books = MEMPOOL
+        .read()
+        .await
+        .book_depths()
+        .await
+        .into_iter()
+        .map(|(pair_id, (bids, asks))| BookStats {
+            pair_id,
+            bids,
+            asks,
+        })
+        .collect();
+
+    Ok(ResponseJson(ApiResponse::success(StatsResponse {
+        matching_latency: STATS.matching_latency(),
+        books,
+        pending_traces: trace_backlog_depth().await,
+        blocks_per_minute: STATS.blocks_per_minute(),
+    })))
+}
+
+async fn handle_reconcile_query(
+    Query(request): Query<ReconcileRequest>,
+) -> Result<ResponseJson<ApiResponse<ReconcileResponse>>, StatusCode> {
+    // Read-only check; corrections are only applied through POST
+    handle_reconcile(Json(ReconcileRequest {
+        user_id: request.user_id,
+        fix: false,
+    }))
+    .await
+}
+
+async fn handle_reconcile(
+    Json(request): Json<ReconcileRequest>,
+) -> Result<ResponseJson<ApiResponse<ReconcileResponse>>, StatusCode> {
+    tracing::info!(
+        "Reconcile request: user_id={}, fix={}",
+        request.user_id,
+        request.fix
+    );
+
+    let mempool = MEMPOOL.read().await;
+    match mempool
+        .reconcile_frozen(&request.user_id, request.fix)
+        .await
+    {
+        Ok(discrepancies) => Ok(ResponseJson(ApiResponse::success(ReconcileResponse {
+            user_id: request.user_id,
+            discrepancies,
+        }))),
+        Err(e) => Ok(ResponseJson(ApiResponse::error(e))),
+    }
+}
+
+async fn handle_halt_pair(
+    Json(request): Json<PairHaltRequest>,
+) -> Result<ResponseJson<ApiResponse<PairHaltResponse>>, StatusCode> {
+    tracing::info!("Halt pair request: pair_id={}", request.pair_id);
+
+    match MEMPOOL.read().await.halt_pair(&request.pair_id) {
+        Ok(()) => Ok(ResponseJson(ApiResponse::success(PairHaltResponse {
+            pair_id: request.pair_id,
+            halted: true,
+        }))),
+        Err(e) => Ok(ResponseJson(ApiResponse::error(e))),
+    }
+}
+
+async fn handle_resume_pair(
+    Json(request): Json<PairHaltRequest>,
+) -> Result<ResponseJson<ApiResponse<PairHaltResponse>>, StatusCode> {
+    tracing::info!("Resume pair request: pair_id={}", request.pair_id);
+
+    match MEMPOOL.read().await.resume_pair(&request.pair_id) {
+        Ok(()) => Ok(ResponseJson(ApiResponse::success(PairHaltResponse {
+            pair_id: request.pair_id,
+            halted: false,
+        }))),
+        Err(e) => Ok(ResponseJson(ApiResponse::error(e))),
+    }
+}
+
+async fn handle_set_tier(
+    Json(request): Json<SetTierRequest>,
+) -> Result<ResponseJson<ApiResponse<()>>, StatusCode> {
+    tracing::info!(
+        "Set tier request: name={}, deposit_cap={:?}, position_cap={:?}",
+        request.name,
+        request.limits.deposit_cap,
+        request.limits.position_cap
+    );
+
+    match USER_TIERS.set_tier(&request.name, &request.limits) {
+        Ok(()) => Ok(ResponseJson(ApiResponse::success(()))),
+        Err(e) => Ok(ResponseJson(ApiResponse::error(e.to_string()))),
+    }
+}
+
+async fn handle_assign_tier(
+    Json(request): Json<AssignTierRequest>,
+) -> Result<ResponseJson<ApiResponse<()>>, StatusCode> {
+    tracing::info!(
+        "Assign tier request: user_id={}, tier={}",
+        request.user_id,
+        request.tier
+    );
+
+    match USER_TIERS.assign(&request.user_id, &request.tier) {
+        Ok(()) => Ok(ResponseJson(ApiResponse::success(()))),
+        Err(e) => Ok(ResponseJson(ApiResponse::error(e.to_string()))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exchange::matching::{Fill, OrderBook};
+    use crate::exchange::mempool::PairConfig;
+    use common::order::OrderStatus;
+    use serde::Serialize;
+
+    #[tokio::test]
+    async fn test_orderbook_l3_matches_priority() {
+        let pair_id = "LTA_LTB".to_string();
+        let mut book = OrderBook::new();
+        for (i, (price, created_at)) in [(100, 2), (102, 3), (100, 1), (101, 1)].iter().enumerate()
+        {
+            let mut buy = Order::new(
+                format!("l3_buy_{}", i),
+                format!("l3_user_{}", i),
+                pair_id.clone(),
+                2,
+                *price,
+                true,
+            );
+            buy.created_at = *created_at;
+            book.add_order(buy).await.unwrap();
+        }
+        MEMPOOL.read().await.insert_order_book(&pair_id, book);
+
+        let response = handle_get_orderbook_l3(Json(GetOrderBookRequest {
+            pair_id: pair_id.clone(),
+        }))
+        .await
+        .unwrap();
+        let l3 = response.0.data.unwrap();
+        let bid_ids: Vec<&str> = l3
+            .bids
+            .iter()
+            .map(|order| order.order_id.as_str())
+            .collect();
+        assert_eq!(
+            bid_ids,
+            vec!["l3_buy_1", "l3_buy_3", "l3_buy_2", "l3_buy_0"]
+        );
+        assert!(l3.asks.is_empty());
+        assert!(
+            l3.bids
+                .iter()
+                .enumerate()
+                .all(|(i, order)| order.priority == i)
+        );
+
+        // A sell sweeping the book fills makers in exactly the L3 order
+        let sell = Order::new(
+            "l3_sell".to_string(),
+            "l3_seller".to_string(),
+            pair_id.clone(),
+            8,
+            1,
+            false,
+        );
+        let order_book = MEMPOOL.read().await.get_order_book(&pair_id).unwrap();
+        let result = order_book.write().await.add_order(sell).await.unwrap();
+        let fill_ids: Vec<&str> = result
+            .fills
+            .iter()
+            .map(|fill| fill.maker_order_id.as_str())
+            .collect();
+        assert_eq!(fill_ids, bid_ids);
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_respects_frozen_balance() {
+        let user_id = "withdraw_user".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "WDB".to_string(), 1_000);
+        }
+        let buy = Order::new(
+            "withdraw_buy".to_string(),
+            user_id.clone(),
+            "WDA_WDB".to_string(),
+            10,
+            60,
+            true,
+        );
+        MEMPOOL.read().await.place_order(buy).await.unwrap();
+
+        // 600 of the 1000 back the resting buy
+        let withdraw = |amount| {
+            handle_withdraw(Json(WithdrawRequest {
+                user_id: user_id.clone(),
+                token: "WDB".to_string(),
+                amount,
+            }))
+        };
+        let rejected = withdraw(401).await.unwrap();
+        assert!(!rejected.0.success);
+        assert_eq!(
+            rejected.0.error.as_deref(),
+            Some("Insufficient available balance")
+        );
+
+        assert!(withdraw(400).await.unwrap().0.success);
+        let state_db = STATE.read().await;
+        assert_eq!(state_db.state.get_user_balance(&user_id, "WDB"), 600);
+        assert_eq!(state_db.state.get_available_balance(&user_id, "WDB"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_between_users() {
+        let (alice, bob) = ("transfer_alice".to_string(), "transfer_bob".to_string());
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(alice.clone(), "TFB".to_string(), 1_000);
+        }
+        // 600 of alice's 1000 back a resting buy
+        let buy = Order::new(
+            "transfer_buy".to_string(),
+            alice.clone(),
+            "TFA_TFB".to_string(),
+            10,
+            60,
+            true,
+        );
+        MEMPOOL.read().await.place_order(buy).await.unwrap();
+
+        let transfer = |from: &str, to: &str, amount| {
+            handle_transfer(Json(TransferRequest {
+                from: from.to_string(),
+                to: to.to_string(),
+                token: "TFB".to_string(),
+                amount,
+            }))
+        };
+        let rejected = transfer(&alice, &bob, 401).await.unwrap();
+        assert_eq!(
+            rejected.0.error.as_deref(),
+            Some("Insufficient available balance")
+        );
+        let rejected = transfer(&alice, &alice, 100).await.unwrap();
+        assert_eq!(
+            rejected.0.error.as_deref(),
+            Some("transfer sender and receiver must differ")
+        );
+
+        assert!(transfer(&alice, &bob, 400).await.unwrap().0.success);
+        let state_db = STATE.read().await;
+        assert_eq!(state_db.state.get_user_balance(&alice, "TFB"), 600);
+        assert_eq!(state_db.state.get_available_balance(&alice, "TFB"), 0);
+        assert_eq!(state_db.state.get_user_balance(&bob, "TFB"), 400);
+    }
+
+    #[tokio::test]
+    async fn test_balance_with_proof() {
+        let user_id = "proof_user".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "PFA".to_string(), 70);
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "PFB".to_string(), 30);
+        }
+        let balance = |with_proof| {
+            handle_get_balance(Json(GetBalanceRequest {
+                user_id: user_id.clone(),
+                token: "PFA".to_string(),
+                with_proof,
+                decimal: false,
+            }))
+        };
+
+        let plain = balance(false).await.unwrap().0.data.unwrap();
+        assert_eq!(plain.balance, 70);
+        assert!(plain.state_root.is_none() && plain.proof.is_none());
+
+        // The proof holds the user's full leaf and leads to the returned root. Other tests change
+        // the shared state concurrently, so the root is only compared against the proof
+        let proved = balance(true).await.unwrap().0.data.unwrap();
+        let proof = proved.proof.unwrap();
+        assert_eq!(proof.balances["PFA"], 70);
+        assert_eq!(proof.balances["PFB"], 30);
+        let hash_scheme = STATE.read().await.state.hash_scheme;
+        assert!(proof.verify(hash_scheme, &proved.state_root.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_simulated_order_matches_placement() {
+        let (seller, buyer) = ("simulate_seller".to_string(), "simulate_buyer".to_string());
+        let pair_id = "SMA_SMB".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(seller.clone(), "SMA".to_string(), 100);
+            state_db
+                .state
+                .set_user_balance(buyer.clone(), "SMB".to_string(), 10_000);
+        }
+        for (i, (amount, price)) in [(4, 10), (5, 12), (6, 15)].into_iter().enumerate() {
+            let sell = Order::new(
+                format!("simulate_sell_{}", i),
+                seller.clone(),
+                pair_id.clone(),
+                amount,
+                price,
+                false,
+            );
+            MEMPOOL.read().await.place_order(sell).await.unwrap();
+        }
+        let request = || PlaceOrderRequest {
+            user_id: buyer.clone(),
+            pair_id: pair_id.clone(),
+            amount: 12,
+            price: 12,
+            decimal_price: None,
+            side: true,
+            trigger_price: None,
+            client_order_id: None,
+        };
+
+        let simulated = handle_simulate_order(Json(request()))
+            .await
+            .unwrap()
+            .0
+            .data
+            .unwrap();
+        assert_eq!(simulated.filled_amount, 9);
+        assert_eq!(simulated.average_price, Some(100.0 / 9.0));
+        assert_eq!(simulated.resting_remaining, 3);
+
+        // Nothing was frozen or taken off the book
+        assert_eq!(
+            STATE.write().await.state.get_frozen(buyer.clone(), "SMB"),
+            0
+        );
+        let book = MEMPOOL.read().await.get_order_book(&pair_id).unwrap();
+        assert_eq!(book.read().await.iter_asks().len(), 3);
+
+        let placed = handle_place_order(Json(request()))
+            .await
+            .unwrap()
+            .0
+            .data
+            .unwrap();
+        let fills = |fills: &[Fill]| -> Vec<(String, u64, u64)> {
+            fills
+                .iter()
+                .map(|fill| (fill.maker_order_id.clone(), fill.price, fill.quantity))
+                .collect()
+        };
+        assert_eq!(fills(&simulated.fills), fills(&placed.fills));
+        assert_eq!(simulated.status, placed.status);
+    }
+
+    #[tokio::test]
+    async fn test_get_variants_match_post() {
+        // Overlapping static and parameter routes must not conflict
+        let _ = create_exchange_router(MAX_BODY_BYTES);
+
+        let user_id = "get_variant_user".to_string();
+        let pair_id = "GVA_GVB".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "GVA".to_string(), 50);
+        }
+        let sell = Order::new(
+            "get_variant_sell".to_string(),
+            user_id.clone(),
+            pair_id.clone(),
+            5,
+            3,
+            false,
+        );
+        MEMPOOL.read().await.place_order(sell).await.unwrap();
+
+        fn json<T: Serialize>(response: ResponseJson<ApiResponse<T>>) -> serde_json::Value {
+            serde_json::to_value(response.0).unwrap()
+        }
+
+        let post = handle_get_balance(Json(GetBalanceRequest {
+            user_id: user_id.clone(),
+            token: "GVA".to_string(),
+            with_proof: false,
+            decimal: false,
+        }))
+        .await
+        .unwrap();
+        let get = handle_get_balance_path(
+            Path((user_id.clone(), "GVA".to_string())),
+            Query(DecimalQuery::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(json(get), json(post));
+
+        let post = handle_get_order(Json(GetOrderRequest {
+            pair_id: pair_id.clone(),
+            order_id: Some("get_variant_sell".to_string()),
+            user_id: None,
+            client_order_id: None,
+        }))
+        .await
+        .unwrap();
+        let get = handle_get_order_path(Path((pair_id.clone(), "get_variant_sell".to_string())))
+            .await
+            .unwrap();
+        let post = json(post);
+        assert_eq!(post["data"]["id"], "get_variant_sell");
+        assert_eq!(json(get), post);
+
+        let post = handle_get_orderbook(Json(GetOrderBookRequest {
+            pair_id: pair_id.clone(),
+        }))
+        .await
+        .unwrap();
+        let get = handle_get_orderbook_path(Path(pair_id.clone()))
+            .await
+            .unwrap();
+        let post = json(post);
+        assert_eq!(post["data"]["best_ask"], 3);
+        assert_eq!(json(get), post);
+
+        let post = handle_get_orderbook_l3(Json(GetOrderBookRequest {
+            pair_id: pair_id.clone(),
+        }))
+        .await
+        .unwrap();
+        let get = handle_get_orderbook_l3_path(Path(pair_id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(json(get), json(post));
+
+        // Unknown pairs fail the same way
+        let get = handle_get_orderbook_path(Path("NOPE_PAIR".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(json(get)["error"], "Trading pair not found");
+    }
+
+    #[tokio::test]
+    async fn test_decimal_balances() {
+        use axum::body::Body;
+        use axum::http::Request;
+        use tower::ServiceExt;
+
+        let user_id = "decimal_user".to_string();
+        MEMPOOL
+            .read()
+            .await
+            .set_pair_config(
+                "DCA_DCB",
+                PairConfig {
+                    base_decimals: 8,
+                    quote_decimals: 6,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "DCA".to_string(), 123_456_789);
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "DCB".to_string(), 2_500_000);
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "DCZ".to_string(), 7);
+            state_db
+                .state
+                .freeze(user_id.clone(), "DCA".to_string(), 100_000_000)
+                .unwrap();
+        }
+        let balance = |token: &str, decimal| {
+            handle_get_balance(Json(GetBalanceRequest {
+                user_id: user_id.clone(),
+                token: token.to_string(),
+                with_proof: false,
+                decimal,
+            }))
+        };
+
+        let dca = balance("DCA", true).await.unwrap().0.data.unwrap();
+        assert_eq!(dca.balance, 123_456_789);
+        assert_eq!(dca.balance_decimal.as_deref(), Some("1.23456789"));
+        let plain = balance("DCA", false).await.unwrap().0.data.unwrap();
+        assert!(plain.balance_decimal.is_none());
+        // No configured pair lists the token, so its decimals are unknown
+        let dcz = balance("DCZ", true).await.unwrap().0.data.unwrap();
+        assert!(dcz.balance_decimal.is_none());
+
+        let request = Request::get(format!("/portfolio/{}?decimal=true", user_id))
+            .body(Body::empty())
+            .unwrap();
+        let response = create_exchange_router(MAX_BODY_BYTES)
+            .oneshot(request)
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let balances = &response["data"]["balances"];
+        assert_eq!(balances[0]["token"], "DCA");
+        assert_eq!(balances[0]["total_decimal"], "1.23456789");
+        assert_eq!(balances[0]["frozen_decimal"], "1.00000000");
+        assert_eq!(balances[0]["available_decimal"], "0.23456789");
+        assert_eq!(balances[1]["total_decimal"], "2.500000");
+        assert!(balances[2].get("total_decimal").is_none());
+        assert_eq!(balances[2]["total"], 7);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_rejected() {
+        use axum::body::Body;
+        use axum::http::Request;
+        use tower::ServiceExt;
+
+        let json_request = |uri: &str, body: String| {
+            Request::post(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        // Valid JSON padded past the limit
+        let oversized = format!(r#"{{"user_id": "{}", "token": "BLT"}}"#, "x".repeat(2048));
+
+        let response = create_exchange_router(1024)
+            .oneshot(json_request("/balance", oversized.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = create_evm_router(1024)
+            .oneshot(json_request("/", oversized))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Bodies within the limit still go through
+        let response = create_exchange_router(1024)
+            .oneshot(json_request(
+                "/balance",
+                r#"{"user_id": "body_limit_user", "token": "BLT"}"#.to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_portfolio_lists_every_token() {
+        let user_id = "portfolio_user".to_string();
+        for (token, amount) in [("PFA", 100), ("PFB", 1_000), ("PFC", 7)] {
+            let deposit = handle_deposit(Json(DepositRequest {
+                user_id: user_id.clone(),
+                token: token.to_string(),
+                amount,
+            }))
+            .await
+            .unwrap();
+            assert!(deposit.0.success);
+        }
+        // Freeze 40 PFA behind a sell and 300 PFB behind a buy
+        for (pair_id, amount, price, side) in [("PFA_PFX", 40, 1, false), ("PFY_PFB", 30, 10, true)]
+        {
+            let order = Order::new(
+                format!("portfolio_{}", pair_id),
+                user_id.clone(),
+                pair_id.to_string(),
+                amount,
+                price,
+                side,
+            );
+            MEMPOOL.read().await.place_order(order).await.unwrap();
+        }
+
+        let response = handle_get_portfolio(Json(PortfolioRequest {
+            user_id: user_id.clone(),
+            decimal: false,
+        }))
+        .await
+        .unwrap();
+        let portfolio = response.0.data.unwrap();
+        assert_eq!(portfolio.user_id, user_id);
+        let balances = portfolio
+            .balances
+            .iter()
+            .map(|b| (b.token.as_str(), b.total, b.frozen, b.available))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            balances,
+            vec![
+                ("PFA", 100, 40, 60),
+                ("PFB", 1_000, 300, 700),
+                ("PFC", 7, 0, 7)
+            ]
+        );
+
+        let get = handle_get_portfolio_path(Path(user_id), Query(DecimalQuery::default()))
+            .await
+            .unwrap();
+        assert_eq!(get.0.data.unwrap().balances.len(), 3);
+
+        // Unknown users have an empty portfolio
+        let empty = handle_get_portfolio(Json(PortfolioRequest {
+            user_id: "portfolio_nobody".to_string(),
+            decimal: false,
+        }))
+        .await
+        .unwrap();
+        assert!(empty.0.data.unwrap().balances.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stats_reflect_activity() {
+        let pair_id = "SSA_SSB";
+        let mempool = MEMPOOL.read().await;
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("stats_seller".to_string(), "SSA".to_string(), 100);
+            state_db
+                .state
+                .set_user_balance("stats_buyer".to_string(), "SSB".to_string(), 1_000);
+        }
+        for (i, price) in [10, 11, 12].iter().enumerate() {
+            let sell = Order::new(
+                format!("stats_sell_{}", i),
+                "stats_seller".to_string(),
+                pair_id.to_string(),
+                5,
+                *price,
+                false,
+            );
+            mempool.place_order(sell).await.unwrap();
+        }
+        // Fills the cheapest ask and rests the rest as a bid
+        let buy = Order::new(
+            "stats_buy".to_string(),
+            "stats_buyer".to_string(),
+            pair_id.to_string(),
+            7,
+            10,
+            true,
+        );
+        mempool.place_order(buy).await.unwrap();
+        drop(mempool);
+
+        let stats = handle_get_stats().await.unwrap().0.data.unwrap();
+        let book = stats
+            .books
+            .iter()
+            .find(|book| book.pair_id == pair_id)
+            .unwrap();
+        assert_eq!((book.bids, book.asks), (1, 2));
+        // Other tests share the counters, so they hold at least this test's activity
+        assert!(stats.matching_latency.samples >= 4);
+        assert!(stats.matching_latency.max_micros >= stats.matching_latency.p50_micros);
+    }
+
+    #[tokio::test]
+    async fn test_place_and_wait_returns_once_filled() {
+        let pair_id = "PWA_PWB".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("pw_seller".to_string(), "PWA".to_string(), 100);
+            state_db
+                .state
+                .set_user_balance("pw_buyer".to_string(), "PWB".to_string(), 1_000);
+        }
+        let order = |user_id: &str, amount: u64, side: bool| PlaceOrderRequest {
+            user_id: user_id.to_string(),
+            pair_id: pair_id.clone(),
+            amount,
+            price: 5,
+            decimal_price: None,
+            side,
+            trigger_price: None,
+            client_order_id: None,
+        };
+        let place_and_wait = |order: PlaceOrderRequest, timeout_ms: u64| {
+            handle_place_and_wait(Json(PlaceAndWaitRequest {
+                order,
+                timeout_ms: Some(timeout_ms),
+            }))
+        };
+
+        // Nothing crosses it, so the wait runs out with the order still resting
+        let waited = place_and_wait(order("pw_seller", 10, false), 50)
+            .await
+            .unwrap()
+            .0
+            .data
+            .unwrap();
+        assert!(waited.timed_out);
+        assert_eq!(waited.order.status, OrderStatus::Pending);
+
+        // A sell waiting on the book returns as soon as a buy crosses it
+        let waiting = tokio::spawn(place_and_wait(order("pw_seller", 5, false), 5_000));
+        while MEMPOOL
+            .read()
+            .await
+            .book_depths()
+            .await
+            .get(&pair_id)
+            .is_none_or(|&(_, asks)| asks < 2)
+        {
+            tokio::task::yield_now().await;
+        }
+        let placed = handle_place_order(Json(order("pw_buyer", 15, true)))
+            .await
+            .unwrap()
+            .0
+            .data
+            .unwrap();
+        assert_eq!(placed.status, OrderStatus::Filled);
+        let waited = waiting.await.unwrap().unwrap().0.data.unwrap();
+        assert!(!waited.timed_out);
+        assert_eq!(waited.order.status, OrderStatus::Filled);
+        assert_eq!(waited.order.filled_amount, 5);
+
+        // An order filled on placement returns right away
+        let resting = handle_place_order(Json(order("pw_seller", 5, false)))
+            .await
+            .unwrap();
+        assert_eq!(resting.0.data.unwrap().status, OrderStatus::Pending);
+        let waited = place_and_wait(order("pw_buyer", 5, true), 5_000)
+            .await
+            .unwrap()
+            .0
+            .data
+            .unwrap();
+        assert!(!waited.timed_out);
+        assert_eq!(waited.fills.len(), 1);
+        assert_eq!(waited.order.status, OrderStatus::Filled);
+    }
+
+    #[tokio::test]
+    async fn test_replace_order_moves_a_resting_order() {
+        let pair_id = "RXA_RXB".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance("rx_maker".to_string(), "RXB".to_string(), 100);
+            state_db
+                .state
+                .set_user_balance("rx_seller".to_string(), "RXA".to_string(), 10);
+        }
+        let order = |user_id: &str, price: u64, side: bool| PlaceOrderRequest {
+            user_id: user_id.to_string(),
+            pair_id: pair_id.clone(),
+            amount: 10,
+            price,
+            decimal_price: None,
+            side,
+            trigger_price: None,
+            client_order_id: None,
+        };
+        let placed = handle_place_order(Json(order("rx_maker", 4, true)))
+            .await
+            .unwrap()
+            .0
+            .data
+            .unwrap();
+        let resting_sell = handle_place_order(Json(order("rx_seller", 5, false)))
+            .await
+            .unwrap();
+        assert_eq!(resting_sell.0.data.unwrap().status, OrderStatus::Pending);
+
+        // Moving the bid up to the ask trades it in the same step
+        let replaced = handle_replace_order(Json(ReplaceOrderRequest {
+            pair_id: pair_id.clone(),
+            order_id: placed.order_id.clone(),
+            new_order: order("rx_maker", 5, true),
+        }))
+        .await
+        .unwrap()
+        .0
+        .data
+        .unwrap();
+        assert_eq!(replaced.replaced.id, placed.order_id);
+        assert_eq!(replaced.replaced.status, OrderStatus::Cancelled);
+        assert_eq!(replaced.fills.len(), 1);
+        assert_eq!(replaced.status, OrderStatus::Filled);
+
+        // Only the new order's lock is left, awaiting settlement
+        let frozen = STATE
+            .write()
+            .await
+            .state
+            .get_frozen("rx_maker".to_string(), "RXB");
+        assert_eq!(frozen, 50);
+        let mempool = MEMPOOL.read().await;
+        let old = mempool.get_order(&pair_id, &placed.order_id).await.unwrap();
+        assert_eq!(old.status, OrderStatus::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_by_client_order_id() {
+        let user_id = "client_id_user".to_string();
+        let pair_id = "CIA_CIB".to_string();
+        STATE
+            .write()
+            .await
+            .state
+            .set_user_balance(user_id.clone(), "CIA".to_string(), 100);
+        let place = |client_order_id: &str| {
+            handle_place_order(Json(PlaceOrderRequest {
+                user_id: user_id.clone(),
+                pair_id: pair_id.clone(),
+                amount: 10,
+                price: 5,
+                decimal_price: None,
+                side: false,
+                trigger_price: None,
+                client_order_id: Some(client_order_id.to_string()),
+            }))
+        };
+
+        let placed = place("my-sell-1").await.unwrap().0.data.unwrap();
+        assert_eq!(placed.client_order_id.as_deref(), Some("my-sell-1"));
+        let duplicate = place("my-sell-1").await.unwrap().0;
+        assert_eq!(
+            duplicate.error.as_deref(),
+            Some("Duplicate client order id my-sell-1")
+        );
+
+        let by_client_id = || GetOrderRequest {
+            pair_id: pair_id.clone(),
+            order_id: None,
+            user_id: Some(user_id.clone()),
+            client_order_id: Some("my-sell-1".to_string()),
+        };
+        let order = handle_get_order(Json(by_client_id()))
+            .await
+            .unwrap()
+            .0
+            .data
+            .unwrap();
+        assert_eq!(order.id, placed.order_id);
+
+        // Client ids are per user: another user's lookup doesn't find it
+        let other_user = handle_get_order(Json(GetOrderRequest {
+            user_id: Some("client_id_other".to_string()),
+            ..by_client_id()
+        }))
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(other_user.error.as_deref(), Some("Order not found"));
+
+        let cancelled = handle_cancel_order(Json(CancelOrderRequest {
+            pair_id: pair_id.clone(),
+            order_id: None,
+            user_id: Some(user_id.clone()),
+            client_order_id: Some("my-sell-1".to_string()),
+            reduce_by: None,
+        }))
+        .await
+        .unwrap()
+        .0
+        .data
+        .unwrap();
+        assert_eq!(cancelled.id, placed.order_id);
+        assert_eq!(cancelled.status, OrderStatus::Cancelled);
+        assert_eq!(
+            STATE.write().await.state.get_frozen(user_id.clone(), "CIA"),
+            0
+        );
+
+        // Still taken once its order is closed
+        let reused = place("my-sell-1").await.unwrap().0;
+        assert_eq!(
+            reused.error.as_deref(),
+            Some("Duplicate client order id my-sell-1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resync_catches_client_up() {
+        let user_id = "resync_user".to_string();
+        let pair_id = "RSA_RSB".to_string();
+        {
+            let mut state_db = STATE.write().await;
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "RSA".to_string(), 100);
+            state_db
+                .state
+                .set_user_balance(user_id.clone(), "RSB".to_string(), 100);
+        }
+        async fn place(id: &str, price: u64, side: bool) {
+            let order = Order::new(
+                id.to_string(),
+                "resync_user".to_string(),
+                "RSA_RSB".to_string(),
+                5,
+                price,
+                side,
+            );
+            MEMPOOL.read().await.place_order(order).await.unwrap();
+        }
+        place("resync_bid", 2, true).await;
+        place("resync_ask", 9, false).await;
+
+        let request = || GetOrderBookRequest {
+            pair_id: pair_id.clone(),
+        };
+        let seen = handle_get_orderbook_l3(Json(request()))
+            .await
+            .unwrap()
+            .0
+            .data
+            .unwrap();
+        place("resync_ask_2", 8, false).await;
+        MEMPOOL
+            .read()
+            .await
+            .cancel_order(&pair_id, "resync_bid", None)
+            .await
+            .unwrap();
+
+        let resync = handle_resync_orderbook(Json(ResyncRequest {
+            pair_id: pair_id.clone(),
+            seq: seen.seq,
+        }))
+        .await
+        .unwrap()
+        .0
+        .data
+        .unwrap();
+        let added: Vec<&str> = resync
+            .diff
+            .added
+            .iter()
+            .map(|order| order.id.as_str())
+            .collect();
+        assert_eq!(added, vec!["resync_ask_2"]);
+        assert_eq!(resync.diff.removed, vec!["resync_bid"]);
+        assert!(resync.diff.changed.is_empty());
+
+        let book = handle_get_orderbook(Json(request()))
+            .await
+            .unwrap()
+            .0
+            .data
+            .unwrap();
+        assert_eq!((resync.seq, resync.checksum), (book.seq, book.checksum));
+
+        // The state the resync led to is itself a resync point
+        let again = handle_resync_orderbook(Json(ResyncRequest {
+            pair_id: pair_id.clone(),
+            seq: resync.seq,
+        }))
+        .await
+        .unwrap()
+        .0
+        .data
+        .unwrap();
+        assert!(again.diff.is_empty());
+        let unknown = handle_resync_orderbook(Json(ResyncRequest {
+            pair_id: pair_id.clone(),
+            seq: resync.seq + 1_000,
+        }))
+        .await
+        .unwrap()
+        .0;
+        assert!(unknown.error.unwrap().contains("reload the full book"));
+    }
 }
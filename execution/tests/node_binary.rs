@@ -0,0 +1,91 @@
+//! Runs the `execution` binary, the production node, and drives a trade through its API
+//! until the block builder has settled it. The node serves the fixed ports 3030 and 8545,
+//! so this fails while another node is running on the same machine.
+
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use execution::api::{DepositRequest, PlaceOrderRequest};
+use execution::client::ExchangeClient;
+
+// Kills the node when the test ends, also when it fails
+struct Node(Child);
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[tokio::test]
+async fn test_node_binary_settles_trades() {
+    // The node opens its database in the working directory
+    let work_dir = std::env::temp_dir().join(format!("node_binary_{}", std::process::id()));
+    std::fs::create_dir_all(&work_dir).unwrap();
+    let _node = Node(
+        Command::new(env!("CARGO_BIN_EXE_execution"))
+            .current_dir(&work_dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    let client = ExchangeClient::new("http://[::1]:3030");
+    let mut started = false;
+    for _ in 0..100 {
+        if client.get_balance("node_seller", "NBA").await.is_ok() {
+            started = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(started, "node did not start serving");
+
+    let deposit = |user_id: &str, token: &str| DepositRequest {
+        user_id: user_id.to_string(),
+        token: token.to_string(),
+        amount: 10,
+    };
+    client
+        .deposit(&deposit("node_seller", "NBA"))
+        .await
+        .unwrap();
+    client.deposit(&deposit("node_buyer", "NBB")).await.unwrap();
+    let order = |user_id: &str, side: bool| PlaceOrderRequest {
+        user_id: user_id.to_string(),
+        pair_id: "NBA_NBB".to_string(),
+        amount: 10,
+        price: 1,
+        decimal_price: None,
+        side,
+        trigger_price: None,
+        client_order_id: None,
+    };
+    client
+        .place_order(&order("node_seller", false))
+        .await
+        .unwrap();
+    let placed = client
+        .place_order(&order("node_buyer", true))
+        .await
+        .unwrap();
+    assert_eq!(placed.fills.len(), 1);
+
+    // The trade's traces reach the node's block builder, which settles them in a block
+    let mut settled = false;
+    for _ in 0..100 {
+        if client.get_balance("node_buyer", "NBA").await.unwrap() == 10 {
+            settled = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(settled, "trade was not settled");
+    assert_eq!(client.get_balance("node_seller", "NBB").await.unwrap(), 10);
+    assert_eq!(client.get_balance("node_seller", "NBA").await.unwrap(), 0);
+    assert_eq!(client.get_balance("node_buyer", "NBB").await.unwrap(), 0);
+
+    let _ = std::fs::remove_dir_all(&work_dir);
+}
@@ -1,50 +1,202 @@
 use anyhow::anyhow;
-use common::{block::Block, state::State};
-use share::ZkVMInput;
-use sp1_sdk::{HashableKey, ProverClient, SP1Stdin};
+use common::hasher::HashScheme;
+use share::{ZkVMInput, build_input};
+use sp1_sdk::{
+    EnvProver, ExecutionReport, HashableKey, ProverClient, SP1ProofWithPublicValues, SP1ProvingKey,
+    SP1PublicValues, SP1Stdin, SP1VerifyingKey,
+};
 use std::time::Instant;
 
 /// The ELF (executable and linkable format) file for the Succinct RISC-V zkVM.
 pub const BATCH_VERIFIER_ELF: &[u8] =
     include_bytes!("../../program/elf/riscv32im-succinct-zkvm-elf");
-const MAX_PROVE_BLOCKS: usize = 4096;
 
-pub fn prove(state: State, blocks: Vec<Block>) -> Result<Option<Vec<u8>>, anyhow::Error> {
-    if blocks.len() > MAX_PROVE_BLOCKS {
-        return Err(anyhow!(format!(
-            "check block_tracs, blocks len = {:?} exceeds MAX_PROVE_BLOCKS = {:?}",
-            blocks.len(),
-            MAX_PROVE_BLOCKS
-        )));
+// Limits a proving job must stay within
+#[derive(Clone, Copy, Debug)]
+pub struct ProveLimits {
+    // Most blocks proven in one batch, checked before the blocks are loaded
+    pub max_blocks: u64,
+    // Most cycles the dry-run may take before proving is attempted
+    pub max_cycles: u64,
+}
+
+impl Default for ProveLimits {
+    fn default() -> Self {
+        Self {
+            max_blocks: 4096,
+            max_cycles: 2_000_000_000,
+        }
     }
+}
 
-    let input = ZkVMInput { blocks, state };
+/// The SP1 steps `prove` takes on the batch verifier program, so the real prover and
+/// `MockProver` are interchangeable.
+pub trait BatchProver {
+    fn setup(&self) -> (SP1ProvingKey, SP1VerifyingKey);
+    fn execute(
+        &self,
+        stdin: &SP1Stdin,
+    ) -> Result<(SP1PublicValues, ExecutionReport), anyhow::Error>;
+    fn prove(
+        &self,
+        pk: &SP1ProvingKey,
+        stdin: &SP1Stdin,
+    ) -> Result<SP1ProofWithPublicValues, anyhow::Error>;
+    fn verify(
+        &self,
+        proof: &SP1ProofWithPublicValues,
+        vk: &SP1VerifyingKey,
+    ) -> Result<(), anyhow::Error>;
+}
 
-    // Execute the program in sp1-vm
+// The prover `SP1_PROVER` selects, proving for real unless it says otherwise
+impl BatchProver for EnvProver {
+    fn setup(&self) -> (SP1ProvingKey, SP1VerifyingKey) {
+        self.setup(BATCH_VERIFIER_ELF)
+    }
+
+    fn execute(
+        &self,
+        stdin: &SP1Stdin,
+    ) -> Result<(SP1PublicValues, ExecutionReport), anyhow::Error> {
+        self.execute(BATCH_VERIFIER_ELF, stdin)
+            .run()
+            .map_err(|e| anyhow!(format!("sp1-vm execution err: {:?}", e)))
+    }
+
+    fn prove(
+        &self,
+        pk: &SP1ProvingKey,
+        stdin: &SP1Stdin,
+    ) -> Result<SP1ProofWithPublicValues, anyhow::Error> {
+        self.prove(pk, stdin)
+            .plonk()
+            .run()
+            .map_err(|e| anyhow!(format!("proving failed: {:?}", e)))
+    }
+
+    fn verify(
+        &self,
+        proof: &SP1ProofWithPublicValues,
+        vk: &SP1VerifyingKey,
+    ) -> Result<(), anyhow::Error> {
+        self.verify(proof, vk)
+            .map_err(|e| anyhow!(format!("failed to verify proof: {:?}", e)))
+    }
+}
+
+/// Verification key of the batch verifier program, as the 0x-prefixed bytes32 on-chain
+/// verifiers are configured with. Proofs of any batch verify against this key, their public
+/// value being the `share::pi::calculate_pi_hash` of the batch.
+pub fn batch_verifier_vkey() -> String {
+    let (_, vk) = ProverClient::from_env().setup(BATCH_VERIFIER_ELF);
+    vk.bytes32()
+}
+
+/// Values the batch verifier commits to a proof. The guest commits only `pi_hash`, the
+/// `share::pi::calculate_pi_hash` binding the batch's starting and final state roots, its DA,
+/// fee and flow hashes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PublicInputs {
+    pub pi_hash: [u8; 32],
+}
+
+/// Read the committed public values back from a proof as returned by `prove`, the
+/// bincode-encoded `SP1ProofWithPublicValues` that SP1's own `save` writes.
+pub fn read_public_values(proof_bytes: &[u8]) -> Result<PublicInputs, anyhow::Error> {
+    let proof: SP1ProofWithPublicValues = bincode::deserialize(proof_bytes)
+        .map_err(|e| anyhow!(format!("failed to decode proof: {:?}", e)))?;
+    let mut public_values = proof.public_values;
+    Ok(PublicInputs {
+        pi_hash: public_values.read::<[u8; 32]>(),
+    })
+}
+
+// Guest input, written with SP1's native bincode encoding which the guest decodes with `io::read`
+fn build_stdin(input: &ZkVMInput) -> SP1Stdin {
     let mut stdin = SP1Stdin::new();
-    stdin.write(&serde_json::to_string(&input).unwrap());
+    stdin.write(input);
+    stdin
+}
+
+/// Prove the `length` blocks of `block_db` starting at `start`, on top of the state the block
+/// before them sealed, as packaged by `share::build_input`. `hash_scheme` must match the
+/// exchange's, or that state won't hash to its root. With `tokens` set, only the sub-tree of
+/// those tokens is proven. Ranges over `limits.max_blocks` are rejected before any block is
+/// loaded.
+pub fn prove_range(
+    block_db: &sled::Tree,
+    start: u64,
+    length: u64,
+    hash_scheme: HashScheme,
+    tokens: Option<Vec<String>>,
+    limits: ProveLimits,
+) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    if length > limits.max_blocks {
+        return Err(anyhow!(
+            "block range of {} blocks exceeds the prove limit of {} blocks",
+            length,
+            limits.max_blocks
+        ));
+    }
+    let mut input = build_input(block_db, start, length, hash_scheme)?;
+    input.tokens = tokens;
+    prove(input, limits)
+}
+
+// Dry-run the guest on `stdin` and return the number of cycles it took
+fn execute_cycles(client: &impl BatchProver, stdin: &SP1Stdin) -> Result<u64, anyhow::Error> {
+    let (_, execution_report) = client.execute(stdin)?;
+    Ok(execution_report.total_instruction_count())
+}
+
+/// Prove `input`, returning the encoded proof `read_public_values` reads back. With
+/// `input.tokens` set, only the sub-tree of those tokens is proven and every block must trade
+/// pairs made of them. Built with the `mock-prover` feature, the proof comes from
+/// `MockProver`.
+pub fn prove(input: ZkVMInput, limits: ProveLimits) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    #[cfg(not(feature = "mock-prover"))]
     let client = ProverClient::from_env();
+    #[cfg(feature = "mock-prover")]
+    let client = crate::mock_prover::MockProver::new();
+    prove_with(&client, input, limits)
+}
+
+fn prove_with(
+    client: &impl BatchProver,
+    input: ZkVMInput,
+    limits: ProveLimits,
+) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    if input.blocks.len() as u64 > limits.max_blocks {
+        return Err(anyhow!(format!(
+            "check block_tracs, blocks len = {:?} exceeds max_blocks = {:?}",
+            input.blocks.len(),
+            limits.max_blocks
+        )));
+    }
 
-    let (mut _public_values, execution_report) = client
-        .execute(BATCH_VERIFIER_ELF, &stdin.clone())
-        .run()
-        .map_err(|e| anyhow!(format!("sp1-vm execution err: {:?}", e)))?;
+    // Execute the program in sp1-vm
+    let stdin = build_stdin(&input);
 
+    let cycles = execute_cycles(client, &stdin)?;
     log::info!(
         "Program executed successfully, Number of cycles: {:?}",
-        execution_report.total_instruction_count()
+        cycles
     );
+    // The dry-run is cheap next to proving, so refuse batches too large to prove here
+    if cycles > limits.max_cycles {
+        return Err(anyhow!(format!(
+            "batch takes {:?} cycles, exceeds max_cycles = {:?}",
+            cycles, limits.max_cycles
+        )));
+    }
 
-    let (pk, vk) = client.setup(BATCH_VERIFIER_ELF);
+    let (pk, vk) = client.setup();
     log::info!("Batch ELF Verification Key: {:?}", vk.vk.bytes32());
 
     // Generate the proof
     let start = Instant::now();
-    let mut proof = client
-        .prove(&pk, &stdin)
-        .plonk()
-        .run()
-        .map_err(|e| anyhow!(format!("proving failed: {:?}", e)))?;
+    let proof = client.prove(&pk, &stdin)?;
 
     let duration_mins = start.elapsed().as_secs() / 60;
     log::info!(
@@ -53,14 +205,316 @@ pub fn prove(state: State, blocks: Vec<Block>) -> Result<Option<Vec<u8>>, anyhow
     );
 
     // Verify the proof.
-    client
-        .verify(&proof, &vk)
-        .map_err(|e| anyhow!(format!("failed to verify proof: {:?}", e)))?;
+    client.verify(&proof, &vk)?;
     log::info!("Successfully verified proof!");
 
-    // Deserialize the public values.
-    let pi_bytes = proof.public_values.read::<[u8; 32]>();
-    log::info!("pi_hash generated with sp1-vm prove: {}", pi_bytes.len());
+    let proof_bytes = bincode::serialize(&proof)
+        .map_err(|e| anyhow!(format!("failed to encode proof: {:?}", e)))?;
+    let public_inputs = read_public_values(&proof_bytes)?;
+    log::info!(
+        "pi_hash generated with sp1-vm prove: 0x{}",
+        hex::encode(public_inputs.pi_hash)
+    );
+
+    Ok(Some(proof_bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::block::Block;
+    use common::order::Order;
+    use common::state::State;
+    use common::traces::{Funding, FundingKind, MatchedTrace};
+    use share::{FeeConfig, apply_block_txns, calculate_txns_root, verify_batch};
+    use sp1_sdk::Prover;
+
+    #[test]
+    fn test_prove_range_rejects_range_over_limit() {
+        let block_db = sled::Config::new().temporary(true).open().unwrap();
+        let limits = ProveLimits {
+            max_blocks: 8,
+            ..ProveLimits::default()
+        };
+
+        // Rejected on the limit before trying to load the (missing) blocks
+        let err = prove_range(&block_db, 1, 9, HashScheme::Keccak, None, limits).unwrap_err();
+        assert!(err.to_string().contains("exceeds the prove limit"));
+    }
+
+    fn trace(buyer: &str, seller: &str) -> MatchedTrace {
+        MatchedTrace {
+            buy_order: Order::new(
+                format!("buy_{}", buyer),
+                buyer.to_string(),
+                "BTC_USDT".to_string(),
+                10,
+                1,
+                true,
+            ),
+            sell_order: Order::new(
+                format!("sell_{}", seller),
+                seller.to_string(),
+                "BTC_USDT".to_string(),
+                10,
+                1,
+                false,
+            ),
+            matched_amount: 10,
+            matched_price: 1,
+        }
+    }
+
+    // Anchor block holding the starting root, followed by one block settling `traces` and
+    // `funding`
+    fn batch(state: &State, traces: Vec<MatchedTrace>, funding: Vec<Funding>) -> Vec<Block> {
+        let mut post_state = state.clone();
+        apply_block_txns(&mut post_state, &traces, &[], &funding);
+        vec![
+            Block {
+                block_num: 1,
+                txns: vec![],
+                transfers: vec![],
+                funding: vec![],
+                order_updates: vec![],
+                events: vec![],
+                events_root: None,
+                txns_root: Some(calculate_txns_root(&[], &[], &[])),
+                state_root: state.calculate_state_root(),
+            },
+            Block {
+                block_num: 2,
+                txns_root: Some(calculate_txns_root(&traces, &[], &funding)),
+                txns: traces,
+                transfers: vec![],
+                funding,
+                order_updates: vec![],
+                events: vec![],
+                events_root: None,
+                state_root: post_state.calculate_state_root(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_guest_decodes_host_input() {
+        let mut state = State::new();
+        for user in ["alice", "bob"] {
+            state.set_user_balance(user.to_string(), "BTC".to_string(), 1_000);
+            state.set_user_balance(user.to_string(), "USDT".to_string(), 1_000);
+        }
+        let blocks = batch(&state, vec![trace("alice", "bob")], vec![]);
+        let input = ZkVMInput {
+            blocks,
+            state,
+            fee_config: FeeConfig::default(),
+            tokens: None,
+        };
+
+        // The guest must decode the input and commit the same pi_hash as native execution
+        let client = ProverClient::from_env();
+        let (mut public_values, _) = client
+            .execute(BATCH_VERIFIER_ELF, &build_stdin(&input))
+            .run()
+            .unwrap();
+        assert_eq!(public_values.read::<[u8; 32]>(), verify_batch(input));
+    }
+
+    #[test]
+    fn test_guest_proves_deposit_and_withdrawal() {
+        let mut state = State::new();
+        state.set_user_balance("alice".to_string(), "USDT".to_string(), 1_000);
+        state.set_user_balance("bob".to_string(), "BTC".to_string(), 1_000);
+        // carol deposits BTC and sells it in the same block; bob withdraws what he was paid
+        let funding = vec![
+            Funding {
+                kind: FundingKind::Deposit,
+                user_id: "carol".to_string(),
+                token: "BTC".to_string(),
+                amount: 10,
+            },
+            Funding {
+                kind: FundingKind::Withdrawal,
+                user_id: "bob".to_string(),
+                token: "USDT".to_string(),
+                amount: 10,
+            },
+        ];
+        let blocks = batch(
+            &state,
+            vec![trace("alice", "bob"), trace("alice", "carol")],
+            funding,
+        );
+        let input = ZkVMInput {
+            blocks,
+            state,
+            fee_config: FeeConfig::default(),
+            tokens: None,
+        };
+
+        let client = ProverClient::from_env();
+        let (mut public_values, _) = client
+            .execute(BATCH_VERIFIER_ELF, &build_stdin(&input))
+            .run()
+            .unwrap();
+        assert_eq!(public_values.read::<[u8; 32]>(), verify_batch(input));
+    }
+
+    #[test]
+    fn test_library_pi_hash_matches_guest() {
+        let mut state = State::new();
+        for user in ["alice", "bob"] {
+            state.set_user_balance(user.to_string(), "BTC".to_string(), 1_000);
+            state.set_user_balance(user.to_string(), "USDT".to_string(), 1_000);
+        }
+        let blocks = batch(&state, vec![trace("alice", "bob")], vec![]);
+        // Recomputed from the batch's roots alone, the way an outside verifier would
+        let expected = share::pi::calculate_pi_hash(
+            &blocks[0].state_root.unwrap(),
+            &blocks[1].state_root.unwrap(),
+            &share::pi::calculate_da_hash(&[
+                blocks[0].txns_root.unwrap(),
+                blocks[1].txns_root.unwrap(),
+            ]),
+            &FeeConfig::default().hash(),
+            &share::pi::calculate_flow_hash([]),
+        );
+        let input = ZkVMInput {
+            blocks,
+            state,
+            fee_config: FeeConfig::default(),
+            tokens: None,
+        };
+
+        let client = ProverClient::from_env();
+        let (mut public_values, _) = client
+            .execute(BATCH_VERIFIER_ELF, &build_stdin(&input))
+            .run()
+            .unwrap();
+        assert_eq!(public_values.read::<[u8; 32]>(), expected);
+        assert!(batch_verifier_vkey().starts_with("0x"));
+    }
+
+    #[test]
+    fn test_public_values_survive_stored_proof() {
+        let mut state = State::new();
+        for user in ["alice", "bob"] {
+            state.set_user_balance(user.to_string(), "BTC".to_string(), 1_000);
+            state.set_user_balance(user.to_string(), "USDT".to_string(), 1_000);
+        }
+        let blocks = batch(&state, vec![trace("alice", "bob")], vec![]);
+        let input = ZkVMInput {
+            blocks,
+            state,
+            fee_config: FeeConfig::default(),
+            tokens: None,
+        };
+
+        // The mock prover runs the guest for real but skips the proving work
+        let client = ProverClient::builder().mock().build();
+        let (pk, _) = client.setup(BATCH_VERIFIER_ELF);
+        let proof = client
+            .prove(&pk, &build_stdin(&input))
+            .plonk()
+            .run()
+            .unwrap();
 
-    Ok(Some(vec![]))
+        // Persisted the way `prove` returns it, then read back from disk
+        let path = std::env::temp_dir().join(format!("batch_proof_{}.bin", std::process::id()));
+        std::fs::write(&path, bincode::serialize(&proof).unwrap()).unwrap();
+        let stored = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let public_inputs = read_public_values(&stored).unwrap();
+        assert_eq!(public_inputs.pi_hash, verify_batch(input));
+        assert!(read_public_values(&stored[..stored.len() / 2]).is_err());
+    }
+
+    // The whole flow on the mock prover: blocks stored the way the block builder seals them,
+    // packaged, run through the guest and proven, the proof read back. Run with
+    // `cargo test -p host --features mock-prover`
+    #[cfg(feature = "mock-prover")]
+    #[test]
+    fn test_mock_prover_end_to_end() {
+        // Funded in the batch itself, so the range starts from the empty genesis state
+        let funding = vec![
+            Funding {
+                kind: FundingKind::Deposit,
+                user_id: "alice".to_string(),
+                token: "USDT".to_string(),
+                amount: 1_000,
+            },
+            Funding {
+                kind: FundingKind::Deposit,
+                user_id: "bob".to_string(),
+                token: "BTC".to_string(),
+                amount: 1_000,
+            },
+        ];
+        let blocks = batch(&State::new(), vec![trace("alice", "bob")], funding);
+        let block_db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .unwrap()
+            .open_tree(common::db::BLOCKS_TREE)
+            .unwrap();
+        for block in &blocks {
+            block_db
+                .insert(
+                    format!("block_{}", block.block_num),
+                    serde_json::to_vec(block).unwrap(),
+                )
+                .unwrap();
+        }
+
+        let prove = || {
+            prove_range(
+                &block_db,
+                1,
+                2,
+                HashScheme::Keccak,
+                None,
+                ProveLimits::default(),
+            )
+            .unwrap()
+            .unwrap()
+        };
+        let proof_bytes = prove();
+        // The dummy proof is deterministic, and commits the pi_hash the guest computed
+        assert_eq!(prove(), proof_bytes);
+        let input = build_input(&block_db, 1, 2, HashScheme::Keccak).unwrap();
+        assert_eq!(
+            read_public_values(&proof_bytes).unwrap().pi_hash,
+            verify_batch(input)
+        );
+    }
+
+    // Guest cycles of the same batch under each state hasher. Not run by default, it executes
+    // the guest several times: `cargo test -p host bench_state_hasher_cycles -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_state_hasher_cycles() {
+        let client = ProverClient::from_env();
+        for users in [16, 256] {
+            for hash_scheme in [HashScheme::Keccak, HashScheme::Poseidon] {
+                let mut state = State::with_hash_scheme(hash_scheme);
+                for i in 0..users {
+                    state.set_user_balance(format!("user_{}", i), "BTC".to_string(), 1_000);
+                    state.set_user_balance(format!("user_{}", i), "USDT".to_string(), 1_000);
+                }
+                let traces: Vec<MatchedTrace> = (0..users / 2)
+                    .map(|i| trace(&format!("user_{}", 2 * i), &format!("user_{}", 2 * i + 1)))
+                    .collect();
+                let input = ZkVMInput {
+                    blocks: batch(&state, traces, vec![]),
+                    state,
+                    fee_config: FeeConfig::default(),
+                    tokens: None,
+                };
+
+                let cycles = execute_cycles(&client, &build_stdin(&input)).unwrap();
+                println!("{:?} users={} cycles={}", hash_scheme, users, cycles);
+            }
+        }
+    }
 }
@@ -1,13 +1,34 @@
-use common::state::StateDB;
-use share::load_blocks;
+use common::db::{BLOCKS_TREE, NODE_DB_PATH, open_db};
+use common::hasher::HashScheme;
+use gen_stark::ProveLimits;
 
 mod gen_stark;
+#[cfg(feature = "mock-prover")]
+mod mock_prover;
 fn main() {
-    let mut state_db = StateDB::new("state_db");
-    state_db.load();
-    let state = state_db.state;
-    let blocks = load_blocks(101, 10).unwrap();
+    // `host vkey` prints the key proofs verify against, for configuring on-chain verifiers
+    if std::env::args().nth(1).as_deref() == Some("vkey") {
+        println!("{}", gen_stark::batch_verifier_vkey());
+        return;
+    }
 
-    let _ = gen_stark::prove(state, blocks);
+    // The exchange's blocks, in their tree of the node database
+    let block_db = open_db(NODE_DB_PATH)
+        .unwrap_or_else(|e| panic!("{}", e))
+        .open_tree(BLOCKS_TREE)
+        .unwrap();
+    let (start, length) = (101, 10);
+
+    // Prove forward from the root the block before the range commits to, rather than
+    // from the latest state
+    let _ = gen_stark::prove_range(
+        &block_db,
+        start,
+        length,
+        // Must match the exchange's setting, or the starting state won't hash to its root
+        HashScheme::from_env(),
+        None,
+        ProveLimits::default(),
+    );
     println!("Hello, world!");
 }
@@ -0,0 +1,66 @@
+use anyhow::anyhow;
+use sp1_sdk::{
+    CpuProver, ExecutionReport, Prover, ProverClient, SP1ProofWithPublicValues, SP1ProvingKey,
+    SP1PublicValues, SP1Stdin, SP1VerifyingKey,
+};
+
+use crate::gen_stark::{BATCH_VERIFIER_ELF, BatchProver};
+
+/// Prover for local development and CI, used by `prove` with the `mock-prover` feature. It
+/// still runs the guest, so every check of the batch applies and the committed pi_hash is the
+/// real one, but skips the proving work: the proof is SP1's deterministic mock proof, which
+/// only a mock verifier accepts.
+pub struct MockProver {
+    client: CpuProver,
+}
+
+impl MockProver {
+    pub fn new() -> Self {
+        Self {
+            client: ProverClient::builder().mock().build(),
+        }
+    }
+}
+
+impl Default for MockProver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BatchProver for MockProver {
+    fn setup(&self) -> (SP1ProvingKey, SP1VerifyingKey) {
+        self.client.setup(BATCH_VERIFIER_ELF)
+    }
+
+    fn execute(
+        &self,
+        stdin: &SP1Stdin,
+    ) -> Result<(SP1PublicValues, ExecutionReport), anyhow::Error> {
+        self.client
+            .execute(BATCH_VERIFIER_ELF, stdin)
+            .run()
+            .map_err(|e| anyhow!(format!("sp1-vm execution err: {:?}", e)))
+    }
+
+    fn prove(
+        &self,
+        pk: &SP1ProvingKey,
+        stdin: &SP1Stdin,
+    ) -> Result<SP1ProofWithPublicValues, anyhow::Error> {
+        self.client
+            .prove(pk, stdin)
+            .plonk()
+            .run()
+            .map_err(|e| anyhow!(format!("mock proving failed: {:?}", e)))
+    }
+
+    fn verify(
+        &self,
+        proof: &SP1ProofWithPublicValues,
+        vk: &SP1VerifyingKey,
+    ) -> Result<(), anyhow::Error> {
+        Prover::verify(&self.client, proof, vk)
+            .map_err(|e| anyhow!(format!("failed to verify mock proof: {:?}", e)))
+    }
+}
@@ -0,0 +1,138 @@
+//! End to end: orders placed on the exchange are matched, sealed into a block, loaded back
+//! with `share` and re-executed the way the guest does. The native run always happens; the
+//! guest run needs the SP1 toolchain and only happens with `SP1_E2E` set:
+//! `SP1_E2E=1 cargo test -p host --test place_to_prove`
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::block::Block;
+use common::db::BLOCKS_TREE;
+use common::hasher::HashScheme;
+use common::order::Order;
+use common::traces::{Funding, FundingKind};
+use execution::block::block_builder::BlockBuilder;
+use execution::exchange::mempool::MEMPOOL;
+use execution::exchange::{STATE, apply_funding};
+use share::{
+    FeeConfig, ZkVMInput, calculate_da_hash, calculate_flow_hash, calculate_pi_hash, load_blocks,
+    load_state_at_root, net_funding_flows, verify_batch,
+};
+use sp1_sdk::{ProverClient, SP1Stdin};
+
+const BATCH_VERIFIER_ELF: &[u8] = include_bytes!("../../program/elf/riscv32im-succinct-zkvm-elf");
+
+// Wait for the block builder to seal `block_num`
+async fn sealed_block(builder: &BlockBuilder, block_num: u128) -> Block {
+    for _ in 0..100 {
+        if let Some(block) = builder.get_block(block_num).await.unwrap() {
+            return block;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("block {} was not sealed", block_num);
+}
+
+// Cross a sell and a buy of `amount` at `price`; the buy takes the resting sell
+async fn cross(pair_id: &str, id: &str, amount: u64, price: u64) {
+    let mempool = MEMPOOL.read().await;
+    let sell = Order::new(
+        format!("{}_sell", id),
+        "e2e_seller".to_string(),
+        pair_id.to_string(),
+        amount,
+        price,
+        false,
+    );
+    mempool.place_order(sell).await.unwrap();
+    let buy = Order::new(
+        format!("{}_buy", id),
+        "e2e_buyer".to_string(),
+        pair_id.to_string(),
+        amount,
+        price,
+        true,
+    );
+    let result = mempool.place_order(buy).await.unwrap();
+    assert_eq!(result.fills.len(), 1);
+}
+
+// Run the exchange until two blocks are sealed and return the guest input proving the second
+// on top of the state the first one sealed, with the pi_hash it must commit
+async fn place_match_and_seal() -> (ZkVMInput, [u8; 32]) {
+    let pair_id = "E2A_E2B";
+    {
+        let mut state_db = STATE.write().await;
+        state_db
+            .state
+            .add_user_balance("e2e_seller".to_string(), "E2A".to_string(), 1_000);
+        state_db
+            .state
+            .add_user_balance("e2e_buyer".to_string(), "E2B".to_string(), 100_000);
+    }
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let builder = Arc::new(BlockBuilder::with_db(&db).unwrap());
+    let runner = builder.clone();
+    tokio::spawn(async move { runner.start_block_generation().await });
+
+    // The first block seals the deposits along with its trade and anchors the proof
+    cross(pair_id, "e2e_1", 10, 5).await;
+    let anchor = sealed_block(&builder, 1).await;
+    // Deposits and withdrawals landing mid-block are sealed with the trades, not out of band
+    for (kind, user_id, token) in [
+        (FundingKind::Deposit, "e2e_buyer", "E2B"),
+        (FundingKind::Withdrawal, "e2e_seller", "E2A"),
+    ] {
+        let funding = Funding {
+            kind,
+            user_id: user_id.to_string(),
+            token: token.to_string(),
+            amount: 100,
+        };
+        apply_funding(funding).await.unwrap();
+    }
+    cross(pair_id, "e2e_2", 4, 6).await;
+    let block = sealed_block(&builder, 2).await;
+    assert_eq!(block.funding.len(), 2);
+
+    let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
+    let anchor_root = anchor.state_root.unwrap();
+    let state = load_state_at_root(&blocks_tree, anchor_root, HashScheme::Keccak).unwrap();
+    let blocks = load_blocks(&blocks_tree, 2, 1, 1).unwrap();
+    assert_eq!(blocks[0].txns_root, block.txns_root);
+
+    let fee_config = FeeConfig::default();
+    let expected_pi_hash = calculate_pi_hash(
+        &anchor_root,
+        &block.state_root.unwrap(),
+        &calculate_da_hash(&[block.txns_root.unwrap()]),
+        &fee_config.hash(),
+        &calculate_flow_hash(&net_funding_flows(&blocks)),
+    );
+    let input = ZkVMInput {
+        blocks,
+        state,
+        fee_config,
+        tokens: None,
+    };
+    (input, expected_pi_hash)
+}
+
+#[tokio::test]
+async fn test_placed_orders_reach_a_provable_block() {
+    let (input, expected_pi_hash) = place_match_and_seal().await;
+
+    // Execute-only fast path: the guest's logic run natively, panicking on any mismatch
+    assert_eq!(verify_batch(input.clone()), expected_pi_hash);
+
+    if std::env::var("SP1_E2E").is_ok() {
+        let mut stdin = SP1Stdin::new();
+        stdin.write(&input);
+        let (mut public_values, _) = ProverClient::from_env()
+            .execute(BATCH_VERIFIER_ELF, &stdin)
+            .run()
+            .unwrap();
+        assert_eq!(public_values.read::<[u8; 32]>(), expected_pi_hash);
+    }
+}
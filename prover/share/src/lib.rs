@@ -1,46 +1,1223 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use common::{
-    block::Block,
+    block::{BALANCE_HISTORY_PREFIX, Block, parse_balance_history_key},
+    db::{NODE_DB_PATH, STATE_TREE, open_db},
+    hasher::HashScheme,
+    order::parse_pair,
     state::{Account, State},
+    traces::{Funding, FundingKind, MatchedTrace, Transfer, block_deltas},
 };
 use serde::{Deserialize, Serialize};
 
+pub mod pi;
+pub use common::block::calculate_txns_root;
+pub use pi::{calculate_da_hash, calculate_pi_hash};
+use tiny_keccak::{Hasher, Sha3};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ZkVMInput {
     pub blocks: Vec<Block>,
     pub state: State,
+    pub fee_config: FeeConfig,
+    // Prove only the sub-tree of these tokens instead of the full state
+    pub tokens: Option<Vec<String>>,
+}
+
+// Fee policy the batch was settled under. Its hash is part of the public inputs so
+// the settlement contract can reject proofs made with a different fee policy.
+// Settlement does not charge fees yet, so batches use the zero-fee default.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeeConfig {
+    pub maker_fee_bps: u64,
+    pub taker_fee_bps: u64,
+    pub fee_collector: String,
+}
+
+impl FeeConfig {
+    pub fn hash(&self) -> [u8; 32] {
+        let mut sha3 = Sha3::v256();
+        let mut output = [0u8; 32];
+
+        sha3.update(&self.maker_fee_bps.to_le_bytes());
+        sha3.update(&self.taker_fee_bps.to_le_bytes());
+        sha3.update(&(self.fee_collector.len() as u64).to_le_bytes());
+        sha3.update(self.fee_collector.as_bytes());
+
+        sha3.finalize(&mut output);
+        output
+    }
+}
+
+/// Re-execute a batch of blocks on top of `input.state` and return the pi_hash to commit.
+/// This is the guest program's logic, kept here so the host can run it natively as well.
+/// Panics on any inconsistency, which aborts proving inside the zkVM.
+pub fn verify_batch(input: ZkVMInput) -> [u8; 32] {
+    // There'd be no post state root to commit to
+    assert!(!input.blocks.is_empty(), "batch has no blocks");
+    if let Some(tokens) = &input.tokens {
+        return verify_token_batch(input.blocks, input.state, tokens, &input.fee_config);
+    }
+
+    let blocks = input.blocks;
+    let mut state = input.state;
+    let fee_config_hash = input.fee_config.hash();
+    // Blocks are applied on top of the state, which is the one the block before them sealed
+    let prev_state_root = state.calculate_state_root().unwrap_or_default();
+    let post_state_root = blocks.last().unwrap().state_root.unwrap_or_default();
+    let flows = net_funding_flows(&blocks);
+    let prev_totals = token_totals(&state);
+
+    let mut txns_roots: Vec<[u8; 32]> = vec![];
+
+    for block in blocks {
+        txns_roots.push(verify_block_txns(&block));
+        apply_block_txns(&mut state, &block.txns, &block.transfers, &block.funding);
+        // Calculate current block state root
+        let block_post_state_root = state.calculate_state_root().unwrap_or_default();
+        assert!(
+            block_post_state_root == block.state_root.unwrap_or_default(),
+            "block_post_state_root == block.state_root"
+        );
+    }
+    assert_conserved(&prev_totals, &token_totals(&state), &flows);
+
+    let da_hash = calculate_da_hash(&txns_roots);
+
+    // calculate pi hash
+    calculate_pi_hash(
+        &prev_state_root,
+        &post_state_root,
+        &da_hash,
+        &fee_config_hash,
+        &calculate_flow_hash(&flows),
+    )
+}
+
+/// Net external flow of each token over `blocks`: deposits minus withdrawals. Trades and
+/// transfers only move balances between users, so this is exactly how much each token's total
+/// on the exchange changes, and what has to move in or out of the exchange on L1.
+pub fn net_funding_flows(blocks: &[Block]) -> BTreeMap<String, i128> {
+    let mut flows = BTreeMap::new();
+    for funding in blocks.iter().flat_map(|block| &block.funding) {
+        let amount = match funding.kind {
+            FundingKind::Deposit => funding.amount as i128,
+            FundingKind::Withdrawal => -(funding.amount as i128),
+        };
+        *flows.entry(funding.token.clone()).or_insert(0) += amount;
+    }
+    flows
+}
+
+/// Hash committing to a batch's net flows, in token order. Tokens whose deposits and
+/// withdrawals cancel out are left out, so a batch without funding hashes no entry at all.
+pub fn calculate_flow_hash(flows: &BTreeMap<String, i128>) -> [u8; 32] {
+    pi::calculate_flow_hash(flows.iter().map(|(token, flow)| (token.as_str(), *flow)))
+}
+
+// Sum of all users' balances of each token
+fn token_totals(state: &State) -> BTreeMap<String, i128> {
+    let mut totals = BTreeMap::new();
+    for account in state.user_balances.values() {
+        for (token, balance) in &account.balances {
+            *totals.entry(token.clone()).or_insert(0) += *balance as i128;
+        }
+    }
+    totals
+}
+
+// Funds are conserved: each token's total changed by exactly its net external flow
+fn assert_conserved(
+    prev_totals: &BTreeMap<String, i128>,
+    post_totals: &BTreeMap<String, i128>,
+    flows: &BTreeMap<String, i128>,
+) {
+    let tokens = prev_totals
+        .keys()
+        .chain(post_totals.keys())
+        .chain(flows.keys());
+    for token in tokens {
+        let change = post_totals.get(token).unwrap_or(&0) - prev_totals.get(token).unwrap_or(&0);
+        let flow = *flows.get(token).unwrap_or(&0);
+        assert!(
+            change == flow,
+            "{} total changed by {} but its net funding flow is {}",
+            token,
+            change,
+            flow
+        );
+    }
+}
+
+/// Re-execute a batch touching only `tokens` and commit their sub-tree roots. Block state
+/// roots cover the full state, so the sub-tree roots are computed from `state` instead; every
+/// trace must trade a pair made of those tokens.
+fn verify_token_batch(
+    blocks: Vec<Block>,
+    mut state: State,
+    tokens: &[String],
+    fee_config: &FeeConfig,
+) -> [u8; 32] {
+    let prev_state_root = state
+        .calculate_state_root_for_tokens(tokens)
+        .unwrap_or_default();
+    let flows = net_funding_flows(&blocks);
+    let prev_totals = token_totals(&state);
+
+    let mut txns_roots: Vec<[u8; 32]> = vec![];
+    for block in blocks {
+        txns_roots.push(verify_block_txns(&block));
+
+        for trace in &block.txns {
+            let (base_token, quote_token) =
+                parse_pair(&trace.buy_order.pair_id).unwrap_or_else(|e| panic!("{}", e));
+            assert!(
+                tokens.contains(&base_token) && tokens.contains(&quote_token),
+                "trace pair outside the proven tokens"
+            );
+        }
+        for transfer in &block.transfers {
+            assert!(
+                tokens.contains(&transfer.token),
+                "transfer token outside the proven tokens"
+            );
+        }
+        for funding in &block.funding {
+            assert!(
+                tokens.contains(&funding.token),
+                "funding token outside the proven tokens"
+            );
+        }
+        apply_block_txns(&mut state, &block.txns, &block.transfers, &block.funding);
+    }
+    assert_conserved(&prev_totals, &token_totals(&state), &flows);
+
+    let post_state_root = state
+        .calculate_state_root_for_tokens(tokens)
+        .unwrap_or_default();
+    calculate_pi_hash(
+        &prev_state_root,
+        &post_state_root,
+        &calculate_da_hash(&txns_roots),
+        &fee_config.hash(),
+        &calculate_flow_hash(&flows),
+    )
+}
+
+/// Check a block's txns against its claimed txns_root and each trace's orders for consistency,
+/// before any of them is applied. Returns the txns root.
+fn verify_block_txns(block: &Block) -> [u8; 32] {
+    let txns_root = calculate_txns_root(&block.txns, &block.transfers, &block.funding);
+    assert!(
+        txns_root == block.txns_root.unwrap_or_default(),
+        "txns_root == block.txns_root"
+    );
+
+    for trace in &block.txns {
+        if let Err(e) = trace.validate() {
+            panic!("{}", e);
+        }
+    }
+    for transfer in &block.transfers {
+        if let Err(e) = transfer.validate() {
+            panic!("{}", e);
+        }
+    }
+    for funding in &block.funding {
+        if let Err(e) = funding.validate() {
+            panic!("{}", e);
+        }
+    }
+    txns_root
+}
+
+/// Settle a block's traces, transfers, deposits and withdrawals all at once from their net
+/// balance changes, which leaves the balances the exchange sealed. Panics if the block would
+/// leave any balance negative.
+pub fn apply_block_txns(
+    state: &mut State,
+    txns: &[MatchedTrace],
+    transfers: &[Transfer],
+    funding: &[Funding],
+) {
+    if let Err(e) =
+        block_deltas(txns, transfers, funding).and_then(|deltas| state.apply_deltas(&deltas))
+    {
+        panic!("{}", e);
+    }
 }
 
 pub fn load() -> State {
-    let db = sled::open("state_db").unwrap();
+    let db = open_db(NODE_DB_PATH)
+        .unwrap_or_else(|e| panic!("{}", e))
+        .open_tree(STATE_TREE)
+        .unwrap();
+    // Hashed the way the exchange is configured to hash it
+    let mut state = State::with_hash_scheme(HashScheme::from_env());
     if let Ok(Some(data)) = db.get("prev_state") {
         if let Ok(user_balances) = serde_json::from_slice::<HashMap<String, Account>>(&data) {
-            return State {
-                user_balances,
-                user_frozens: HashMap::new(),
+            state.user_balances = user_balances;
+        }
+    }
+    state
+}
+
+/// Load `length` sealed blocks starting at `start` from the block db. Ranges longer than
+/// `max_blocks` are rejected before any block is read.
+pub fn load_blocks(
+    db: &sled::Tree,
+    start: u64,
+    length: u64,
+    max_blocks: u64,
+) -> anyhow::Result<Vec<Block>> {
+    if length > max_blocks {
+        anyhow::bail!(
+            "block range of {} blocks exceeds the prove limit of {} blocks",
+            length,
+            max_blocks
+        );
+    }
+
+    let mut blocks = Vec::with_capacity(length as usize);
+    for i in start..start + length {
+        // Same key the block builder seals blocks under
+        let data = db
+            .get(format!("block_{}", i))?
+            .ok_or_else(|| anyhow::anyhow!("block {} not found", i))?;
+        blocks.push(serde_json::from_slice::<Block>(&data)?);
+    }
+    Ok(blocks)
+}
+
+/// Balances as of the latest sealed block committing to `root`, rebuilt from the balance
+/// history of the block db. Lets a batch be proven forward from a finalized checkpoint
+/// instead of whatever state is current. `hash_scheme` must be the one the blocks were sealed
+/// under, or the rebuilt state won't hash to `root`.
+pub fn load_state_at_root(
+    db: &sled::Tree,
+    root: [u8; 32],
+    hash_scheme: HashScheme,
+) -> anyhow::Result<State> {
+    let mut checkpoint = None;
+    for entry in db.scan_prefix("block_") {
+        let (_, data) = entry?;
+        let block = serde_json::from_slice::<Block>(&data)?;
+        if block.state_root == Some(root) {
+            checkpoint = checkpoint.max(Some(block.block_num));
+        }
+    }
+    let block_num = checkpoint
+        .ok_or_else(|| anyhow::anyhow!("no sealed block has state root 0x{}", to_hex(&root)))?;
+
+    let state = state_at_block(db, block_num, hash_scheme)?;
+    ensure_state_root(&state, root)?;
+    Ok(state)
+}
+
+// Balances as of the sealed block `block_num` from the balance history, empty before block 1
+fn state_at_block(
+    db: &sled::Tree,
+    block_num: u128,
+    hash_scheme: HashScheme,
+) -> anyhow::Result<State> {
+    // History entries are ordered by block, so the last one at or before the block wins
+    let mut state = State::with_hash_scheme(hash_scheme);
+    for entry in db.scan_prefix(BALANCE_HISTORY_PREFIX) {
+        let (key, value) = entry?;
+        let (user_id, token, recorded_at) = parse_balance_history_key(&key)
+            .ok_or_else(|| anyhow::anyhow!("invalid balance history key"))?;
+        if recorded_at <= block_num {
+            let balance = u64::from_be_bytes(value.as_ref().try_into()?);
+            state.set_user_balance(user_id, token, balance);
+        }
+    }
+    Ok(state)
+}
+
+/// Rebuild the state after block `end` by replaying the settlement of blocks `start..=end` in
+/// order, on top of the balances sealed by block `start - 1`. This is the prover's re-execution
+/// outside the zkVM: each block's txns and state root are checked as it is replayed, and the
+/// first mismatch is returned as an error. Deposits, withdrawals and transfers are sealed into
+/// the block after they were made and replayed with its trades, so the rebuilt balances
+/// include them.
+pub fn rebuild_state_from_blocks(
+    db: &sled::Tree,
+    start: u64,
+    end: u64,
+    hash_scheme: HashScheme,
+) -> anyhow::Result<State> {
+    if start == 0 || end < start {
+        anyhow::bail!("invalid block range {}..={}", start, end);
+    }
+    let blocks = load_blocks(db, start, end - start + 1, u64::MAX)?;
+
+    let mut state = state_before_block(db, start, hash_scheme)?;
+    for block in &blocks {
+        replay_block(&mut state, block)
+            .map_err(|e| anyhow::anyhow!("block {}: {}", block.block_num, e))?;
+    }
+    Ok(state)
+}
+
+/// Package `length` sealed blocks starting at `start` into the prover's input: the blocks,
+/// and the balances sealed by block `start - 1` they apply on top of, which must hash to that
+/// block's state root. The range is replayed natively first, so blocks the guest would reject
+/// fail here instead of after the prover has been set up.
+pub fn build_input(
+    db: &sled::Tree,
+    start: u64,
+    length: u64,
+    hash_scheme: HashScheme,
+) -> anyhow::Result<ZkVMInput> {
+    if start == 0 || length == 0 {
+        anyhow::bail!("invalid block range of {} blocks from {}", length, start);
+    }
+    let blocks = load_blocks(db, start, length, u64::MAX)?;
+    let state = state_before_block(db, start, hash_scheme)?;
+
+    let mut replayed = state.clone();
+    for (block_num, block) in (start as u128..).zip(&blocks) {
+        if block.block_num != block_num {
+            anyhow::bail!("block {} is stored as block {}", block.block_num, block_num);
+        }
+        replay_block(&mut replayed, block)
+            .map_err(|e| anyhow::anyhow!("block {}: {}", block_num, e))?;
+    }
+
+    Ok(ZkVMInput {
+        blocks,
+        state,
+        fee_config: FeeConfig::default(),
+        tokens: None,
+    })
+}
+
+// Balances sealed by the block before `start`, checked against its root when there is one
+fn state_before_block(
+    db: &sled::Tree,
+    start: u64,
+    hash_scheme: HashScheme,
+) -> anyhow::Result<State> {
+    let state = state_at_block(db, start as u128 - 1, hash_scheme)?;
+    if start > 1 {
+        let anchor = load_blocks(db, start - 1, 1, 1)?;
+        ensure_state_root(&state, anchor[0].state_root.unwrap_or_default())?;
+    }
+    Ok(state)
+}
+
+// Apply one block's settlement to `state` and check its txns and state roots
+fn replay_block(state: &mut State, block: &Block) -> anyhow::Result<()> {
+    if Some(calculate_txns_root(
+        &block.txns,
+        &block.transfers,
+        &block.funding,
+    )) != block.txns_root
+    {
+        anyhow::bail!("txns root mismatch");
+    }
+    for trace in &block.txns {
+        trace.validate().map_err(anyhow::Error::msg)?;
+    }
+    for transfer in &block.transfers {
+        transfer.validate().map_err(anyhow::Error::msg)?;
+    }
+    for funding in &block.funding {
+        funding.validate().map_err(anyhow::Error::msg)?;
+    }
+    block_deltas(&block.txns, &block.transfers, &block.funding)
+        .and_then(|deltas| state.apply_deltas(&deltas))
+        .map_err(anyhow::Error::msg)?;
+    ensure_state_root(state, block.state_root.unwrap_or_default())
+}
+
+/// Reject a starting state that doesn't hash to the checkpoint root it claims to be.
+pub fn ensure_state_root(state: &State, root: [u8; 32]) -> anyhow::Result<()> {
+    let actual = state.calculate_state_root().unwrap_or_default();
+    if actual != root {
+        anyhow::bail!(
+            "state root mismatch: expected 0x{}, loaded state hashes to 0x{}",
+            to_hex(&root),
+            to_hex(&actual)
+        );
+    }
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8; 32]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::block::balance_history_key;
+    use common::genesis::Genesis;
+    use common::order::Order;
+    use common::traces::FundingKind;
+
+    // Anchor block holding the starting root, followed by one block settling `traces`
+    fn build_batch(state: &State, traces: Vec<MatchedTrace>) -> Vec<Block> {
+        let anchor = Block {
+            block_num: 1,
+            txns: vec![],
+            transfers: vec![],
+            funding: vec![],
+            order_updates: vec![],
+            events: vec![],
+            events_root: None,
+            txns_root: Some(calculate_txns_root(&[], &[], &[])),
+            state_root: state.calculate_state_root(),
+        };
+        let mut post_state = state.clone();
+        apply_block_txns(&mut post_state, &traces, &[], &[]);
+        let block = Block {
+            block_num: 2,
+            txns_root: Some(calculate_txns_root(&traces, &[], &[])),
+            txns: traces,
+            transfers: vec![],
+            funding: vec![],
+            order_updates: vec![],
+            events: vec![],
+            events_root: None,
+            state_root: post_state.calculate_state_root(),
+        };
+        vec![anchor, block]
+    }
+
+    fn trace(buyer: &str, seller: &str, amount: u64) -> MatchedTrace {
+        MatchedTrace {
+            buy_order: Order::new(
+                format!("buy_{}", buyer),
+                buyer.to_string(),
+                "BTC_USDT".to_string(),
+                amount,
+                1,
+                true,
+            ),
+            sell_order: Order::new(
+                format!("sell_{}", seller),
+                seller.to_string(),
+                "BTC_USDT".to_string(),
+                amount,
+                1,
+                false,
+            ),
+            matched_amount: amount,
+            matched_price: 1,
+        }
+    }
+
+    #[test]
+    fn test_batch_chains_from_genesis() {
+        let mut genesis = Genesis::default();
+        for user in ["alice", "bob"] {
+            genesis.balances.insert(
+                user.to_string(),
+                [("BTC".to_string(), 1_000), ("USDT".to_string(), 1_000)].into(),
+            );
+        }
+        let state = genesis.state(HashScheme::Keccak);
+        let traces = vec![trace("alice", "bob", 10)];
+        let mut blocks = build_batch(&state, traces.clone());
+        // Block 0 as the node seals it: no txns, the genesis root
+        blocks[0].block_num = 0;
+        blocks[1].block_num = 1;
+        assert_eq!(
+            blocks[0].state_root,
+            Some(genesis.state_root(HashScheme::Keccak))
+        );
+
+        let pi_hash = verify_batch(ZkVMInput {
+            blocks: blocks.clone(),
+            state,
+            fee_config: FeeConfig::default(),
+            tokens: None,
+        });
+        let expected = calculate_pi_hash(
+            &genesis.state_root(HashScheme::Keccak),
+            &blocks[1].state_root.unwrap(),
+            &calculate_da_hash(&[
+                calculate_txns_root(&[], &[], &[]),
+                calculate_txns_root(&traces, &[], &[]),
+            ]),
+            &FeeConfig::default().hash(),
+            &calculate_flow_hash(&BTreeMap::new()),
+        );
+        assert_eq!(pi_hash, expected);
+    }
+
+    #[test]
+    fn test_verify_batch_is_deterministic() {
+        let users = ["alice", "bob", "carol", "dave"];
+        let mut state = State::new();
+        for user in users {
+            state.set_user_balance(user.to_string(), "BTC".to_string(), 1_000);
+            state.set_user_balance(user.to_string(), "USDT".to_string(), 1_000);
+        }
+        let blocks = build_batch(
+            &state,
+            vec![trace("alice", "bob", 10), trace("carol", "dave", 7)],
+        );
+
+        // Same balances, different HashMap instances and insertion order
+        let mut reordered = State::new();
+        for user in users.iter().rev() {
+            reordered.set_user_balance(user.to_string(), "USDT".to_string(), 1_000);
+            reordered.set_user_balance(user.to_string(), "BTC".to_string(), 1_000);
+        }
+
+        let first = verify_batch(ZkVMInput {
+            blocks: blocks.clone(),
+            state,
+            fee_config: FeeConfig::default(),
+            tokens: None,
+        });
+        let second = verify_batch(ZkVMInput {
+            blocks,
+            state: reordered,
+            fee_config: FeeConfig::default(),
+            tokens: None,
+        });
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_poseidon_batch_verifies_under_its_own_scheme() {
+        let mut state = State::with_hash_scheme(HashScheme::Poseidon);
+        for user in ["alice", "bob"] {
+            state.set_user_balance(user.to_string(), "BTC".to_string(), 1_000);
+            state.set_user_balance(user.to_string(), "USDT".to_string(), 1_000);
+        }
+        let blocks = build_batch(&state, vec![trace("alice", "bob", 10)]);
+        verify_batch(batch_input(state.clone(), blocks.clone()));
+
+        // The same balances hashed with keccak don't match the sealed roots
+        state.hash_scheme = HashScheme::Keccak;
+        let result = std::panic::catch_unwind(|| verify_batch(batch_input(state, blocks)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_binary_input_round_trip() {
+        let mut state = State::new();
+        for user in ["alice", "bob"] {
+            state.set_user_balance(user.to_string(), "BTC".to_string(), 1_000);
+            state.set_user_balance(user.to_string(), "USDT".to_string(), 1_000);
+        }
+        let input = ZkVMInput {
+            blocks: build_batch(&state, vec![trace("alice", "bob", 10)]),
+            state,
+            fee_config: FeeConfig {
+                maker_fee_bps: 2,
+                taker_fee_bps: 5,
+                fee_collector: "fee_collector".to_string(),
+            },
+            tokens: None,
+        };
+
+        // The encoding SP1Stdin::write uses and sp1_zkvm::io::read decodes
+        let encoded = bincode::serialize(&input).unwrap();
+        assert!(encoded.len() < serde_json::to_vec(&input).unwrap().len());
+        let decoded: ZkVMInput = bincode::deserialize(&encoded).unwrap();
+
+        assert_eq!(decoded.fee_config, input.fee_config);
+        assert_eq!(
+            decoded.state.calculate_state_root(),
+            input.state.calculate_state_root()
+        );
+        assert_eq!(verify_batch(decoded), verify_batch(input));
+    }
+
+    #[test]
+    fn test_token_subset_batch() {
+        let mut state = State::new();
+        for user in ["alice", "bob"] {
+            state.set_user_balance(user.to_string(), "BTC".to_string(), 1_000);
+            state.set_user_balance(user.to_string(), "USDT".to_string(), 1_000);
+            state.set_user_balance(user.to_string(), "ETH".to_string(), 1_000);
+        }
+        let tokens = vec!["BTC".to_string(), "USDT".to_string()];
+        let traces = vec![trace("alice", "bob", 10)];
+        let blocks = build_batch(&state, traces.clone());
+
+        let mut post_state = state.clone();
+        apply_block_txns(&mut post_state, &traces, &[], &[]);
+        let expected = calculate_pi_hash(
+            &state.calculate_state_root_for_tokens(&tokens).unwrap(),
+            &post_state.calculate_state_root_for_tokens(&tokens).unwrap(),
+            &calculate_da_hash(&[
+                calculate_txns_root(&[], &[], &[]),
+                calculate_txns_root(&traces, &[], &[]),
+            ]),
+            &FeeConfig::default().hash(),
+            &calculate_flow_hash(&BTreeMap::new()),
+        );
+
+        // The ETH balances don't take part in the subset proof
+        let mut partial_state = state.clone();
+        for user in ["alice", "bob"] {
+            partial_state
+                .user_balances
+                .get_mut(user)
+                .unwrap()
+                .balances
+                .remove("ETH");
+        }
+        let pi_hash = verify_batch(ZkVMInput {
+            blocks,
+            state: partial_state,
+            fee_config: FeeConfig::default(),
+            tokens: Some(tokens),
+        });
+        assert_eq!(pi_hash, expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "trace pair outside the proven tokens")]
+    fn test_token_subset_rejects_other_pairs() {
+        let mut state = State::new();
+        state.set_user_balance("alice".to_string(), "USDT".to_string(), 1_000);
+        state.set_user_balance("bob".to_string(), "BTC".to_string(), 1_000);
+        let blocks = build_batch(&state, vec![trace("alice", "bob", 10)]);
+
+        verify_batch(ZkVMInput {
+            blocks,
+            state,
+            fee_config: FeeConfig::default(),
+            tokens: Some(vec!["ETH".to_string(), "USDT".to_string()]),
+        });
+    }
+
+    fn batch_input(state: State, blocks: Vec<Block>) -> ZkVMInput {
+        ZkVMInput {
+            blocks,
+            state,
+            fee_config: FeeConfig::default(),
+            tokens: None,
+        }
+    }
+
+    fn funded_state() -> State {
+        let mut state = State::new();
+        for user in ["alice", "bob", "mallory"] {
+            state.set_user_balance(user.to_string(), "BTC".to_string(), 1_000);
+            state.set_user_balance(user.to_string(), "USDT".to_string(), 1_000);
+        }
+        state
+    }
+
+    #[test]
+    #[should_panic(expected = "txns_root == block.txns_root")]
+    fn test_forged_txns_rejected() {
+        let state = funded_state();
+        let mut blocks = build_batch(&state, vec![trace("alice", "bob", 10)]);
+
+        // Swap the trades while keeping the claimed roots
+        blocks[1].txns = vec![trace("mallory", "bob", 10)];
+        verify_batch(batch_input(state, blocks));
+    }
+
+    #[test]
+    #[should_panic(expected = "trace sell order must be a sell")]
+    fn test_inconsistent_trace_rejected() {
+        let state = funded_state();
+        let mut forged = trace("alice", "bob", 10);
+        forged.sell_order.side = true;
+        let blocks = build_batch(&state, vec![forged]);
+
+        verify_batch(batch_input(state, blocks));
+    }
+
+    #[test]
+    #[should_panic(expected = "trace buy order must be a buy")]
+    fn test_same_side_trace_rejected() {
+        let state = funded_state();
+        let mut forged = trace("alice", "bob", 10);
+        forged.buy_order.side = false;
+        let blocks = build_batch(&state, vec![forged]);
+
+        verify_batch(batch_input(state, blocks));
+    }
+
+    #[test]
+    #[should_panic(expected = "trace matched amount 10 exceeds remaining 4")]
+    fn test_over_matched_trace_rejected() {
+        let state = funded_state();
+        let mut forged = trace("alice", "bob", 10);
+        forged.sell_order.filled_amount = 6;
+        let blocks = build_batch(&state, vec![forged]);
+
+        verify_batch(batch_input(state, blocks));
+    }
+
+    #[test]
+    fn test_batch_with_empty_block() {
+        let mut state = State::new();
+        for user in ["alice", "bob"] {
+            state.set_user_balance(user.to_string(), "BTC".to_string(), 1_000);
+            state.set_user_balance(user.to_string(), "USDT".to_string(), 1_000);
+        }
+        let mut blocks = build_batch(&state, vec![trace("alice", "bob", 10)]);
+        // An empty block seals the root it started from, then trading resumes
+        let mut post_state = state.clone();
+        apply_block_txns(&mut post_state, &blocks[1].txns, &[], &[]);
+        let empty = Block {
+            block_num: 3,
+            txns: vec![],
+            transfers: vec![],
+            funding: vec![],
+            order_updates: vec![],
+            events: vec![],
+            events_root: None,
+            txns_root: Some(calculate_txns_root(&[], &[], &[])),
+            state_root: blocks[1].state_root,
+        };
+        let traces = vec![trace("bob", "alice", 4)];
+        apply_block_txns(&mut post_state, &traces, &[], &[]);
+        let last = Block {
+            block_num: 4,
+            txns_root: Some(calculate_txns_root(&traces, &[], &[])),
+            txns: traces,
+            transfers: vec![],
+            funding: vec![],
+            order_updates: vec![],
+            events: vec![],
+            events_root: None,
+            state_root: post_state.calculate_state_root(),
+        };
+        blocks.push(empty);
+        blocks.push(last);
+
+        let expected = calculate_pi_hash(
+            &state.calculate_state_root().unwrap(),
+            &post_state.calculate_state_root().unwrap(),
+            &calculate_da_hash(
+                &blocks
+                    .iter()
+                    .map(|block| block.txns_root.unwrap())
+                    .collect::<Vec<_>>(),
+            ),
+            &FeeConfig::default().hash(),
+            &calculate_flow_hash(&BTreeMap::new()),
+        );
+        let pi_hash = verify_batch(ZkVMInput {
+            blocks,
+            state,
+            fee_config: FeeConfig::default(),
+            tokens: None,
+        });
+        assert_eq!(pi_hash, expected);
+    }
+
+    #[test]
+    fn test_block_settles_on_net_balances() {
+        let mut state = State::new();
+        state.set_user_balance("alice".to_string(), "USDT".to_string(), 10);
+        state.set_user_balance("bob".to_string(), "BTC".to_string(), 10);
+
+        // bob spends the USDT he only receives from the first trade
+        let traces = vec![trace("alice", "bob", 10), trace("bob", "alice", 4)];
+        let blocks = build_batch(&state, traces);
+        verify_batch(batch_input(state, blocks));
+    }
+
+    #[test]
+    fn test_block_replays_transfers() {
+        let state = funded_state();
+        let mut blocks = build_batch(&state, vec![trace("alice", "bob", 10)]);
+
+        // The exchange seals the transfers made since the previous block next to its trades
+        let transfers = vec![Transfer {
+            from: "alice".to_string(),
+            to: "mallory".to_string(),
+            token: "USDT".to_string(),
+            amount: 300,
+        }];
+        let mut post_state = state.clone();
+        apply_block_txns(&mut post_state, &blocks[1].txns, &transfers, &[]);
+        assert_eq!(post_state.get_user_balance("mallory", "USDT"), 1_300);
+        blocks[1].txns_root = Some(calculate_txns_root(&blocks[1].txns, &transfers, &[]));
+        blocks[1].transfers = transfers;
+        blocks[1].state_root = post_state.calculate_state_root();
+
+        verify_batch(batch_input(state, blocks));
+    }
+
+    #[test]
+    fn test_block_replays_funding() {
+        let state = funded_state();
+        // zoe deposits while the block is being built and trades with what she deposited, so
+        // the block only settles with its funding
+        let mut blocks = build_batch(&state, vec![]);
+        blocks[1].txns = vec![trace("alice", "bob", 10), trace("zoe", "bob", 20)];
+        let funding = |kind, user_id: &str, token: &str, amount| Funding {
+            kind,
+            user_id: user_id.to_string(),
+            token: token.to_string(),
+            amount,
+        };
+        let funding = vec![
+            funding(FundingKind::Deposit, "zoe", "USDT", 20),
+            funding(FundingKind::Withdrawal, "bob", "USDT", 1_030),
+            funding(FundingKind::Deposit, "mallory", "BTC", 5),
+        ];
+
+        let mut post_state = state.clone();
+        apply_block_txns(&mut post_state, &blocks[1].txns, &[], &funding);
+        assert_eq!(post_state.get_user_balance("zoe", "BTC"), 20);
+        assert_eq!(post_state.get_user_balance("bob", "USDT"), 0);
+        assert_eq!(post_state.get_user_balance("mallory", "BTC"), 1_005);
+        blocks[1].txns_root = Some(calculate_txns_root(&blocks[1].txns, &[], &funding));
+        blocks[1].funding = funding;
+        blocks[1].state_root = post_state.calculate_state_root();
+
+        // Deposits and withdrawals are the only external flows, and the pi_hash commits to them
+        let flows = net_funding_flows(&blocks);
+        assert_eq!(
+            flows,
+            BTreeMap::from([("BTC".to_string(), 5), ("USDT".to_string(), -1_010)])
+        );
+        let expected = calculate_pi_hash(
+            &state.calculate_state_root().unwrap(),
+            &blocks[1].state_root.unwrap(),
+            &calculate_da_hash(&[blocks[0].txns_root.unwrap(), blocks[1].txns_root.unwrap()]),
+            &FeeConfig::default().hash(),
+            &calculate_flow_hash(&flows),
+        );
+        assert_ne!(
+            calculate_flow_hash(&flows),
+            calculate_flow_hash(&BTreeMap::new())
+        );
+
+        // Replaying the block reaches the root the exchange sealed, funding included
+        assert_eq!(verify_batch(batch_input(state, blocks)), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "batch has no blocks")]
+    fn test_empty_batch_rejected() {
+        verify_batch(batch_input(funded_state(), vec![]));
+    }
+
+    #[test]
+    #[should_panic(expected = "settlement leaves alice's BTC balance at -5")]
+    fn test_overdrawn_block_rejected() {
+        let mut state = State::new();
+        state.set_user_balance("alice".to_string(), "USDT".to_string(), 10);
+        state.set_user_balance("bob".to_string(), "BTC".to_string(), 10);
+
+        // Neither can pay for the second trade, even with what the first one brings in
+        let traces = vec![trace("alice", "bob", 10), trace("bob", "alice", 15)];
+        let blocks = build_batch(&state, traces);
+        verify_batch(batch_input(state, blocks));
+    }
+
+    #[test]
+    #[should_panic(expected = "order sell_bob trades USDT/BTC, not base BTC and quote USDT")]
+    fn test_swapped_token_roles_rejected() {
+        let state = funded_state();
+        let mut forged = trace("alice", "bob", 10);
+        forged.sell_order.token_a = "USDT".to_string();
+        forged.sell_order.token_b = "BTC".to_string();
+        let blocks = build_batch(&state, vec![forged]);
+
+        verify_batch(batch_input(state, blocks));
+    }
+
+    #[test]
+    #[should_panic(expected = "trace orders must trade the same pair")]
+    fn test_mismatched_trace_pairs_rejected() {
+        let state = funded_state();
+        let mut forged = trace("alice", "bob", 10);
+        forged.sell_order.pair_id = "ETH_USDT".to_string();
+        let blocks = build_batch(&state, vec![forged]);
+
+        verify_batch(batch_input(state, blocks));
+    }
+
+    #[test]
+    fn test_fee_config_binds_pi_hash() {
+        let prev_state_root = [1u8; 32];
+        let post_state_root = [2u8; 32];
+        let da_hash = [3u8; 32];
+        let flow_hash = [4u8; 32];
+
+        let zero_fees = FeeConfig::default();
+        let with_fees = FeeConfig {
+            maker_fee_bps: 2,
+            taker_fee_bps: 5,
+            fee_collector: "fee_collector".to_string(),
+        };
+        let other_collector = FeeConfig {
+            fee_collector: "someone_else".to_string(),
+            ..with_fees.clone()
+        };
+
+        let pi_zero = calculate_pi_hash(
+            &prev_state_root,
+            &post_state_root,
+            &da_hash,
+            &zero_fees.hash(),
+            &flow_hash,
+        );
+        let pi_fees = calculate_pi_hash(
+            &prev_state_root,
+            &post_state_root,
+            &da_hash,
+            &with_fees.hash(),
+            &flow_hash,
+        );
+        let pi_other = calculate_pi_hash(
+            &prev_state_root,
+            &post_state_root,
+            &da_hash,
+            &other_collector.hash(),
+            &flow_hash,
+        );
+
+        assert_ne!(pi_zero, pi_fees);
+        assert_ne!(pi_fees, pi_other);
+        assert_eq!(
+            pi_fees,
+            calculate_pi_hash(
+                &prev_state_root,
+                &post_state_root,
+                &da_hash,
+                &with_fees.hash(),
+                &flow_hash
+            )
+        );
+    }
+
+    #[test]
+    fn test_load_blocks_rejects_range_over_limit() {
+        // Blocks are read back from the keys the block builder seals them under
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        for block_num in 1..=5u128 {
+            let block = Block {
+                block_num,
+                txns: vec![],
+                transfers: vec![],
+                funding: vec![],
+                order_updates: vec![],
+                events: vec![],
+                events_root: None,
+                txns_root: Some(calculate_txns_root(&[], &[], &[])),
                 state_root: None,
             };
+            db.insert(
+                format!("block_{}", block_num),
+                serde_json::to_vec(&block).unwrap(),
+            )
+            .unwrap();
         }
+        let blocks = load_blocks(&db, 2, 4, 4).unwrap();
+        assert_eq!(
+            blocks
+                .iter()
+                .map(|block| block.block_num)
+                .collect::<Vec<_>>(),
+            vec![2, 3, 4, 5]
+        );
+        assert!(load_blocks(&db, 3, 4, 4).is_err()); // block 6 is missing
+
+        // Too long a range fails on the limit, before reaching the missing blocks
+        let err = load_blocks(&db, 1, 1_000, 4).unwrap_err();
+        assert!(err.to_string().contains("exceeds the prove limit"));
     }
-    State {
-        user_balances: HashMap::new(),
-        user_frozens: HashMap::new(),
-        state_root: None,
+
+    // Seal a block committing to `state` and record its balances as the block builder does
+    fn seal(db: &sled::Tree, block_num: u128, state: &State) {
+        seal_block(db, block_num, vec![], state);
     }
-}
 
-pub fn load_blocks(start: u64, length: u64) -> Option<Vec<Block>> {
-    let db = sled::open("block_db").ok()?;
-    let mut blocks = vec![];
-    for i in start..start + length {
-        if let Ok(Some(data)) = db.get(format!("user_balances_{}", i)) {
-            if let Ok(block) = serde_json::from_slice::<Block>(&data) {
-                blocks.push(block);
+    // Store a block settling `txns` into `state`, with its balance history
+    fn seal_block(db: &sled::Tree, block_num: u128, txns: Vec<MatchedTrace>, state: &State) {
+        seal_funded_block(db, block_num, txns, vec![], state);
+    }
+
+    // Like `seal_block`, also sealing deposits and withdrawals
+    fn seal_funded_block(
+        db: &sled::Tree,
+        block_num: u128,
+        txns: Vec<MatchedTrace>,
+        funding: Vec<Funding>,
+        state: &State,
+    ) {
+        let block = Block {
+            block_num,
+            txns_root: Some(calculate_txns_root(&txns, &[], &funding)),
+            txns,
+            transfers: vec![],
+            funding,
+            order_updates: vec![],
+            events: vec![],
+            events_root: None,
+            state_root: state.calculate_state_root(),
+        };
+        db.insert(
+            format!("block_{}", block_num),
+            serde_json::to_vec(&block).unwrap(),
+        )
+        .unwrap();
+        for (user_id, account) in &state.user_balances {
+            for (token, balance) in &account.balances {
+                db.insert(
+                    balance_history_key(user_id, token, block_num),
+                    &balance.to_be_bytes()[..],
+                )
+                .unwrap();
             }
-        } else {
-            return None;
         }
     }
-    Some(blocks)
+
+    #[test]
+    fn test_state_at_checkpoint_root() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let checkpoint = funded_state();
+        seal(&db, 1, &checkpoint);
+        let mut later = checkpoint.clone();
+        later.set_user_balance("alice".to_string(), "BTC".to_string(), 1);
+        seal(&db, 2, &later);
+
+        let root = checkpoint.calculate_state_root().unwrap();
+        let state = load_state_at_root(&db, root, HashScheme::Keccak).unwrap();
+        assert_eq!(state.get_user_balance("alice", "BTC"), 1_000);
+        assert_eq!(state.calculate_state_root(), Some(root));
+
+        let later_root = later.calculate_state_root().unwrap();
+        assert_eq!(
+            load_state_at_root(&db, later_root, HashScheme::Keccak)
+                .unwrap()
+                .get_user_balance("alice", "BTC"),
+            1
+        );
+
+        assert!(load_state_at_root(&db, [7u8; 32], HashScheme::Keccak).is_err());
+    }
+
+    #[test]
+    fn test_mismatched_checkpoint_root_rejected() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let checkpoint = funded_state();
+        let root = checkpoint.calculate_state_root().unwrap();
+        seal(&db, 1, &checkpoint);
+        // A balance recorded for the checkpoint that its root doesn't commit to
+        db.insert(
+            balance_history_key("alice", "BTC", 1),
+            &5u64.to_be_bytes()[..],
+        )
+        .unwrap();
+
+        let err = load_state_at_root(&db, root, HashScheme::Keccak).unwrap_err();
+        assert!(err.to_string().contains("state root mismatch"));
+        assert!(ensure_state_root(&checkpoint, root).is_ok());
+        assert!(ensure_state_root(&State::new(), root).is_err());
+    }
+
+    #[test]
+    fn test_rebuild_state_from_blocks() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut state = funded_state();
+        seal(&db, 1, &state);
+        let blocks = [
+            vec![trace("alice", "bob", 10)],
+            vec![trace("bob", "mallory", 4), trace("mallory", "alice", 3)],
+            vec![trace("alice", "mallory", 7)],
+        ];
+        for (i, traces) in blocks.into_iter().enumerate() {
+            apply_block_txns(&mut state, &traces, &[], &[]);
+            seal_block(&db, i as u128 + 2, traces, &state);
+        }
+
+        let rebuilt = rebuild_state_from_blocks(&db, 2, 4, HashScheme::Keccak).unwrap();
+        assert_eq!(rebuilt.calculate_state_root(), state.calculate_state_root());
+        assert_eq!(rebuilt.get_user_balance("alice", "BTC"), 1_014);
+        // Starting later replays from the balances sealed by the block before
+        let rebuilt = rebuild_state_from_blocks(&db, 4, 4, HashScheme::Keccak).unwrap();
+        assert_eq!(rebuilt.calculate_state_root(), state.calculate_state_root());
+
+        // A block whose root doesn't follow from its trades stops the replay there
+        let mut block: Block =
+            serde_json::from_slice(&db.get("block_3").unwrap().unwrap()).unwrap();
+        block.state_root = Some([7u8; 32]);
+        db.insert("block_3", serde_json::to_vec(&block).unwrap())
+            .unwrap();
+        let err = rebuild_state_from_blocks(&db, 2, 4, HashScheme::Keccak).unwrap_err();
+        assert!(err.to_string().starts_with("block 3: state root mismatch"));
+    }
+
+    #[test]
+    fn test_rebuild_state_across_deposit() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut state = funded_state();
+        seal(&db, 1, &state);
+        // zoe trades with what she deposited in the same block
+        let traces = vec![trace("zoe", "bob", 20)];
+        let funding = vec![Funding {
+            kind: FundingKind::Deposit,
+            user_id: "zoe".to_string(),
+            token: "USDT".to_string(),
+            amount: 20,
+        }];
+        apply_block_txns(&mut state, &traces, &[], &funding);
+        seal_funded_block(&db, 2, traces, funding, &state);
+        let traces = vec![trace("alice", "bob", 5)];
+        apply_block_txns(&mut state, &traces, &[], &[]);
+        seal_block(&db, 3, traces, &state);
+
+        let rebuilt = rebuild_state_from_blocks(&db, 2, 3, HashScheme::Keccak).unwrap();
+        assert_eq!(rebuilt.calculate_state_root(), state.calculate_state_root());
+        assert_eq!(rebuilt.get_user_balance("zoe", "BTC"), 20);
+        assert_eq!(rebuilt.get_user_balance("zoe", "USDT"), 0);
+        // And from the balances sealed right after it
+        let rebuilt = rebuild_state_from_blocks(&db, 3, 3, HashScheme::Keccak).unwrap();
+        assert_eq!(rebuilt.calculate_state_root(), state.calculate_state_root());
+    }
+
+    #[test]
+    fn test_build_input_chains_from_sealed_root() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut state = funded_state();
+        seal(&db, 1, &state);
+        for (block_num, traces) in [
+            (2, vec![trace("alice", "bob", 10)]),
+            (3, vec![trace("bob", "mallory", 4)]),
+        ] {
+            apply_block_txns(&mut state, &traces, &[], &[]);
+            seal_block(&db, block_num, traces, &state);
+        }
+
+        // The input starts from the root block 1 sealed, and the guest accepts it
+        let input = build_input(&db, 2, 2, HashScheme::Keccak).unwrap();
+        let block_1: Block = serde_json::from_slice(&db.get("block_1").unwrap().unwrap()).unwrap();
+        assert_eq!(input.state.calculate_state_root(), block_1.state_root);
+        assert_eq!(input.blocks.len(), 2);
+        let pi_hash = verify_batch(input.clone());
+        assert_eq!(
+            pi_hash,
+            calculate_pi_hash(
+                &block_1.state_root.unwrap(),
+                &state.calculate_state_root().unwrap(),
+                &calculate_da_hash(&[
+                    input.blocks[0].txns_root.unwrap(),
+                    input.blocks[1].txns_root.unwrap(),
+                ]),
+                &FeeConfig::default().hash(),
+                &calculate_flow_hash(&BTreeMap::new()),
+            )
+        );
+
+        assert!(build_input(&db, 0, 2, HashScheme::Keccak).is_err());
+        assert!(build_input(&db, 2, 3, HashScheme::Keccak).is_err());
+        // Balances that don't hash to block 1's root aren't a starting state
+        db.insert(
+            balance_history_key("alice", "BTC", 1),
+            &5u64.to_be_bytes()[..],
+        )
+        .unwrap();
+        let err = build_input(&db, 2, 2, HashScheme::Keccak).unwrap_err();
+        assert!(err.to_string().contains("state root mismatch"));
+    }
 }
@@ -0,0 +1,73 @@
+//! The proof's public input, computed exactly as the guest commits it. Only `core` and
+//! `tiny_keccak` are used, without allocating, so verifiers off the node (a contract's test
+//! harness, a light client) can recompute `pi_hash` from a batch's roots and compare it with a
+//! proof's public values.
+
+use tiny_keccak::{Hasher, Sha3};
+
+// Helper function to calculate hash with all blocks' txns for DA.
+pub fn calculate_da_hash(txns_roots: &[[u8; 32]]) -> [u8; 32] {
+    let mut sha3 = Sha3::v256();
+    let mut output = [0u8; 32];
+
+    for txns_root in txns_roots {
+        sha3.update(txns_root);
+    }
+
+    sha3.finalize(&mut output);
+    output
+}
+
+/// Hash of a batch's net deposit-minus-withdrawal flow per token, given in token order. Tokens
+/// whose flow nets to zero are skipped, so the hash only depends on the flows that move funds.
+pub fn calculate_flow_hash<'a>(flows: impl IntoIterator<Item = (&'a str, i128)>) -> [u8; 32] {
+    let mut sha3 = Sha3::v256();
+    let mut output = [0u8; 32];
+
+    for (token, flow) in flows.into_iter().filter(|(_, flow)| *flow != 0) {
+        sha3.update(&(token.len() as u64).to_le_bytes());
+        sha3.update(token.as_bytes());
+        sha3.update(&flow.to_le_bytes());
+    }
+
+    sha3.finalize(&mut output);
+    output
+}
+
+// Helper function to calculate public input for zk proof. `flow_hash` commits to the batch's
+// deposits and withdrawals, see `calculate_flow_hash`.
+pub fn calculate_pi_hash(
+    prev_state_root: &[u8; 32],
+    post_state_root: &[u8; 32],
+    da_hash: &[u8; 32],
+    fee_config_hash: &[u8; 32],
+    flow_hash: &[u8; 32],
+) -> [u8; 32] {
+    let mut sha3 = Sha3::v256();
+    let mut output = [0u8; 32];
+
+    sha3.update(prev_state_root);
+    sha3.update(post_state_root);
+    sha3.update(da_hash);
+    sha3.update(fee_config_hash);
+    sha3.update(flow_hash);
+
+    sha3.finalize(&mut output);
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Pinned so a change to the public input layout, which would break every deployed
+    // verifier, can't go unnoticed
+    #[test]
+    fn test_pi_hash_golden_vector() {
+        let pi_hash = calculate_pi_hash(&[1; 32], &[2; 32], &[3; 32], &[4; 32], &[5; 32]);
+        assert_eq!(
+            crate::to_hex(&pi_hash),
+            "3e2bc02557d92cddc5dfbe1a31ef9437cbbf7be376d4adf677ac45bfb223196f"
+        );
+    }
+}